@@ -0,0 +1,25 @@
+#![no_main]
+
+use std::sync::{Mutex, OnceLock};
+
+use chat_security::IdentityKey;
+use chat_security::x3dh::{InitialMessage, PrekeyStore};
+use libfuzzer_sys::fuzz_target;
+use rand::SeedableRng;
+use rand_chacha::ChaCha20Rng;
+
+fn recipient() -> &'static Mutex<(IdentityKey, PrekeyStore)> {
+    static RECIPIENT: OnceLock<Mutex<(IdentityKey, PrekeyStore)>> = OnceLock::new();
+    RECIPIENT.get_or_init(|| {
+        let identity = IdentityKey::from_bytes(&[7; 32]);
+        let (store, _) = PrekeyStore::generate(&identity, 4, &mut ChaCha20Rng::seed_from_u64(0));
+        Mutex::new((identity, store))
+    })
+}
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(message) = bincode::deserialize::<InitialMessage>(data) {
+        let (identity, store) = &mut *recipient().lock().unwrap();
+        let _ = store.open(identity, &message);
+    }
+});
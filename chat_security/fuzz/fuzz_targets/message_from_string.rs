@@ -0,0 +1,11 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: String| {
+    if let Ok(message) = chat_security::fuzzing::parse_message(data) {
+        // Whatever parses must survive a round trip through the wire format
+        let reparsed = chat_security::fuzzing::parse_message(message.to_string()).unwrap();
+        assert_eq!(reparsed.timestamp, message.timestamp);
+    }
+});
@@ -0,0 +1,10 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(frame) = chat_security::fuzzing::read_length_prefixed(data) {
+        assert!(frame.len() <= chat_security::MAX_FRAME_LEN);
+        assert!(frame.len() + 4 <= data.len());
+    }
+});
@@ -0,0 +1,107 @@
+#![no_main]
+
+//! Drives a pair of in-memory sessions through the handshake and then an arbitrary mix of legitimate
+//! messages and hostile traffic. Neither side may panic or block forever, whatever arrives.
+
+use std::thread;
+use std::time::Duration;
+
+use arbitrary::Arbitrary;
+use chat_security::{KeyExchange, MemoryTransport, Message, SessionBuilder};
+use libfuzzer_sys::fuzz_target;
+use rand::SeedableRng;
+use rand_chacha::ChaCha20Rng;
+
+#[derive(Arbitrary, Debug)]
+enum Action {
+    /// Alice sends a well-formed message
+    Send(String),
+    /// Raw bytes appear on Bob's side of the wire
+    Inject(Vec<u8>),
+    /// A byte already in flight to Bob gets flipped
+    Corrupt { index: u16, xor: u8 },
+    /// Bytes in flight to Bob are dropped
+    Truncate(u16),
+    /// Bob reads whatever is waiting
+    Receive,
+}
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+    hybrid: bool,
+    /// When set, Bob gets these bytes instead of a real initiator's handshake
+    handshake: Option<Vec<u8>>,
+    actions: Vec<Action>,
+}
+
+fn key_exchange(hybrid: bool) -> KeyExchange {
+    if hybrid { KeyExchange::Hybrid } else { KeyExchange::Classic }
+}
+
+fn receive(bob: &mut chat_security::SessionCryptData<MemoryTransport>) -> bool {
+    match bob.check_data_available() {
+        Ok(true) => bob.recieve_message().is_ok(),
+        Ok(false) => true,
+        Err(_) => false,
+    }
+}
+
+fuzz_target!(|input: Input| {
+    let (alice_end, bob_end) = MemoryTransport::pair();
+
+    if let Some(handshake) = input.handshake {
+        bob_end.with_inbox(|inbox| inbox.extend(handshake));
+        drop(alice_end);
+        let _ = SessionBuilder::new().rng(ChaCha20Rng::from_seed([2; 32])).recieve(bob_end);
+        return;
+    }
+
+    let kex = key_exchange(input.hybrid);
+    let alice = thread::spawn(move || {
+        SessionBuilder::new().key_exchange(kex).rng(ChaCha20Rng::from_seed([1; 32])).start(alice_end)
+    });
+    let mut bob = SessionBuilder::new()
+        .rng(ChaCha20Rng::from_seed([2; 32]))
+        .recieve(bob_end)
+        .expect("handshake between honest peers failed");
+    let mut alice = alice.join().unwrap().expect("handshake between honest peers failed");
+
+    // A partial frame must not stall Bob forever
+    bob.get_ref().set_read_timeout(Some(Duration::from_millis(1)));
+
+    for action in input.actions.into_iter().take(64) {
+        match action {
+            Action::Send(contents) => {
+                let message = Message {
+                    sender_id: "alice".to_string(),
+                    to_id: "bob".to_string(),
+                    contents,
+                    timestamp: alice.timestamp(),
+                };
+                if alice.send_message(message).is_err() {
+                    return;
+                }
+            }
+            Action::Inject(bytes) => bob.get_ref().with_inbox(|inbox| inbox.extend(bytes)),
+            Action::Corrupt { index, xor } => bob.get_ref().with_inbox(|inbox| {
+                let len = inbox.len();
+                if len > 0 {
+                    inbox[index as usize % len] ^= xor;
+                }
+            }),
+            Action::Truncate(count) => bob.get_ref().with_inbox(|inbox| {
+                let keep = inbox.len().saturating_sub(count as usize);
+                inbox.truncate(keep);
+            }),
+            Action::Receive => {
+                if !receive(&mut bob) {
+                    return;
+                }
+            }
+        }
+    }
+
+    // Hang up and let Bob drain what is left
+    drop(alice);
+    while receive(&mut bob) {}
+});
@@ -0,0 +1,46 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Source of wall-clock time, so tests can pin or skew it.
+pub trait Clock: Send + Sync{
+    fn now(&self) -> SystemTime;
+
+    /// Seconds since the unix epoch, as carried in `Message::timestamp`.
+    fn unix_time(&self) -> u64{
+        self.now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+    }
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock{
+    fn now(&self) -> SystemTime{
+        SystemTime::now()
+    }
+}
+
+/// Clock that only moves when told to. Clones share the same time.
+#[derive(Clone)]
+pub struct ManualClock{
+    now: Arc<Mutex<SystemTime>>,
+}
+
+impl ManualClock{
+    pub fn new(unix_time: u64) -> Self{
+        ManualClock{ now: Arc::new(Mutex::new(UNIX_EPOCH + Duration::from_secs(unix_time))) }
+    }
+
+    pub fn set(&self, time: SystemTime){
+        *self.now.lock().unwrap() = time;
+    }
+
+    pub fn advance(&self, by: Duration){
+        *self.now.lock().unwrap() += by;
+    }
+}
+
+impl Clock for ManualClock{
+    fn now(&self) -> SystemTime{
+        *self.now.lock().unwrap()
+    }
+}
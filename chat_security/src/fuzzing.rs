@@ -0,0 +1,21 @@
+use std::io;
+
+use crate::{EncryptedMessage, HandshakeData, Message};
+
+pub fn read_length_prefixed(mut data: &[u8]) -> Result<Vec<u8>, io::Error>{
+    HandshakeData::read_length_prefixed(&mut data)
+}
+
+pub fn decode_handshake(data: &[u8]) -> Result<(), io::Error>{
+    HandshakeData::decode(data).map(|_| ())
+}
+
+pub fn decode_encrypted_message(data: &[u8]) -> Result<(), io::Error>{
+    bincode::deserialize::<EncryptedMessage>(data)
+        .map(|_| ())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+pub fn parse_message(message: String) -> Result<Message, io::Error>{
+    Message::from_string(message)
+}
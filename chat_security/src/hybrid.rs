@@ -0,0 +1,62 @@
+use std::io::{Error, ErrorKind};
+
+use ml_kem::kem::{Decapsulate, DecapsulationKey, Encapsulate, EncapsulationKey};
+use ml_kem::{Ciphertext, EncodedSizeUser, KemCore, MlKem768, MlKem768Params};
+use rand::{CryptoRng, RngCore};
+use zeroize::{ZeroizeOnDrop, Zeroizing};
+
+/*
+    ML-KEM-768 half of the hybrid key exchange. The initiator sends an encapsulation key next to its
+    X25519 public key, the responder encapsulates to it, and both feed the resulting secret into the
+    session key alongside the X25519 one. Recorded traffic then stays confidential unless both X25519
+    and ML-KEM are broken.
+ */
+
+#[derive(ZeroizeOnDrop)]
+pub(crate) struct KemSecret(DecapsulationKey<MlKem768Params>);
+
+/// Generates a fresh keypair, returning the secret half and the encoded encapsulation key to offer.
+pub(crate) fn offer<R: RngCore + CryptoRng>(rng: &mut R) -> (KemSecret, Vec<u8>){
+    let (dk, ek) = MlKem768::generate(rng);
+    (KemSecret(dk), ek.as_bytes().to_vec())
+}
+
+/// Encapsulates to an offered key, returning the ciphertext to send back and the shared secret.
+pub(crate) fn accept<R: RngCore + CryptoRng>(offer: &[u8], rng: &mut R) -> Result<(Vec<u8>, Zeroizing<[u8; 32]>), std::io::Error>{
+    let encoded = offer.try_into()
+        .map_err(|_| Error::new(ErrorKind::InvalidData, "Invalid ML-KEM encapsulation key length"))?;
+    let ek = EncapsulationKey::<MlKem768Params>::from_bytes(encoded);
+    let (ciphertext, shared) = ek.encapsulate(rng)
+        .map_err(|_| Error::new(ErrorKind::InvalidData, "ML-KEM encapsulation failed"))?;
+    Ok((ciphertext.to_vec(), Zeroizing::new(shared.into())))
+}
+
+impl KemSecret{
+    pub(crate) fn finish(self, ciphertext: &[u8]) -> Result<Zeroizing<[u8; 32]>, std::io::Error>{
+        let ciphertext = Ciphertext::<MlKem768>::try_from(ciphertext)
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "Invalid ML-KEM ciphertext length"))?;
+        let shared = self.0.decapsulate(&ciphertext)
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "ML-KEM decapsulation failed"))?;
+        Ok(Zeroizing::new(shared.into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::OsRng;
+
+    #[test]
+    fn test_encapsulation_roundtrip() {
+        let (secret, offered) = offer(&mut OsRng);
+        let (ciphertext, responder_shared) = accept(&offered, &mut OsRng).unwrap();
+        assert_eq!(*secret.finish(&ciphertext).unwrap(), *responder_shared);
+    }
+
+    #[test]
+    fn test_malformed_offer_rejected() {
+        assert!(accept(&[0u8; 12], &mut OsRng).is_err());
+        let (secret, _) = offer(&mut OsRng);
+        assert!(secret.finish(&[0u8; 12]).is_err());
+    }
+}
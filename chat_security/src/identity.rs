@@ -0,0 +1,283 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Error, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use zeroize::{ZeroizeOnDrop, Zeroizing};
+
+/// Long-term signing key that identifies a user across sessions.
+#[derive(ZeroizeOnDrop)]
+pub struct IdentityKey{
+    signing: SigningKey,
+}
+
+impl IdentityKey{
+    pub fn generate() -> Self{
+        IdentityKey{ signing: SigningKey::generate(&mut OsRng) }
+    }
+
+    pub fn from_bytes(bytes: &[u8; 32]) -> Self{
+        IdentityKey{ signing: SigningKey::from_bytes(bytes) }
+    }
+
+    /// Loads the key stored at `path`, generating and saving a new one on first run.
+    ///
+    /// The file holds the 32 secret bytes as 64 hex digits.
+    pub fn load_or_generate(path: impl AsRef<Path>) -> Result<Self, std::io::Error>{
+        let path = path.as_ref();
+        match fs::read_to_string(path){
+            Ok(contents) => Self::from_hex(contents.trim()),
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                let identity = IdentityKey::generate();
+                identity.write_new(path)?;
+                Ok(identity)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Loads the key stored at `path`, which must already exist.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, std::io::Error>{
+        Self::from_hex(fs::read_to_string(path)?.trim())
+    }
+
+    /// Replaces the key stored at `path` with this one, in the format `load_or_generate` reads. The old
+    /// file stays in place until the new one is complete.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), std::io::Error>{
+        replace_file(path.as_ref(), |temporary| self.write_new(temporary))
+    }
+
+    /// Writes the key to a file that mustn't exist yet, readable only by us.
+    fn write_new(&self, path: &Path) -> Result<(), std::io::Error>{
+        let hex: String = self.to_bytes().iter().map(|b| format!("{:02x}", b)).collect();
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(path)?;
+        file.write_all(hex.as_bytes())?;
+        file.sync_all()
+    }
+
+    fn from_hex(hex: &str) -> Result<Self, std::io::Error>{
+        if hex.len() != 64 || !hex.is_ascii(){
+            return Err(Error::new(ErrorKind::InvalidData, "Invalid identity key file (expected 64 hex digits)"));
+        }
+        let mut bytes = Zeroizing::new([0u8; 32]);
+        for (i, byte) in bytes.iter_mut().enumerate(){
+            *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+                .map_err(|_| Error::new(ErrorKind::InvalidData, "Invalid identity key file (bad hex digit)"))?;
+        }
+        Ok(IdentityKey::from_bytes(&bytes))
+    }
+
+    pub fn to_bytes(&self) -> Zeroizing<[u8; 32]>{
+        Zeroizing::new(self.signing.to_bytes())
+    }
+
+    pub fn public(&self) -> IdentityPublic{
+        IdentityPublic(self.signing.verifying_key().to_bytes())
+    }
+
+    pub fn sign(&self, data: &[u8]) -> [u8; 64]{
+        self.signing.sign(data).to_bytes()
+    }
+
+    /// The same key as an X25519 secret (as libsodium's `crypto_sign_ed25519_sk_to_curve25519`), for X3DH.
+    pub(crate) fn dh_secret(&self) -> Zeroizing<[u8; 32]>{
+        Zeroizing::new(self.signing.to_scalar_bytes())
+    }
+}
+
+/// Public half of an `IdentityKey`, as exchanged during handshakes and pinned by peers.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct IdentityPublic([u8; 32]);
+
+impl IdentityPublic{
+    pub fn from_bytes(bytes: [u8; 32]) -> Self{
+        IdentityPublic(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32]{
+        &self.0
+    }
+
+    /// The X25519 public key matching `IdentityKey::dh_secret`.
+    pub(crate) fn dh_public(&self) -> Result<[u8; 32], std::io::Error>{
+        let key = VerifyingKey::from_bytes(&self.0)
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "Invalid identity key"))?;
+        Ok(key.to_montgomery().to_bytes())
+    }
+
+    pub fn verify(&self, data: &[u8], signature: &[u8; 64]) -> Result<(), std::io::Error>{
+        let key = VerifyingKey::from_bytes(&self.0)
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "Invalid identity key"))?;
+        key.verify(data, &Signature::from_bytes(signature))
+            .map_err(|_| Error::new(ErrorKind::PermissionDenied, "Invalid identity signature"))
+    }
+
+    /// Short human-comparable digest of the key, e.g. `3f2a 91c0 ...`
+    pub fn fingerprint(&self) -> String{
+        let digest = Sha256::digest(self.0);
+        digest[..16].chunks(2)
+            .map(|pair| format!("{:02x}{:02x}", pair[0], pair[1]))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    pub fn to_hex(&self) -> String{
+        self.0.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    pub fn from_hex(hex: &str) -> Result<Self, std::io::Error>{
+        let hex = hex.trim();
+        if hex.len() != 64 || !hex.is_ascii(){
+            return Err(Error::new(ErrorKind::InvalidData, "Invalid identity key (expected 64 hex digits)"));
+        }
+        let mut bytes = [0u8; 32];
+        for (i, byte) in bytes.iter_mut().enumerate(){
+            *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+                .map_err(|_| Error::new(ErrorKind::InvalidData, "Invalid identity key (bad hex digit)"))?;
+        }
+        Ok(IdentityPublic(bytes))
+    }
+}
+
+/// Identity keys of peers we have verified before, keyed by their display name.
+///
+/// File format is one `name hexkey` pair per line.
+#[derive(Default)]
+pub struct PinStore{
+    path: Option<PathBuf>,
+    pins: HashMap<String, IdentityPublic>,
+}
+
+impl PinStore{
+    pub fn new() -> Self{
+        Self::default()
+    }
+
+    /// Loads pins from `path`, starting empty if the file does not exist yet. `save` writes back to the same file.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, std::io::Error>{
+        let path = path.as_ref().to_path_buf();
+        let mut pins = HashMap::new();
+        match fs::read_to_string(&path){
+            Ok(contents) => {
+                for line in contents.lines().filter(|l| !l.trim().is_empty()){
+                    let (name, key) = line.rsplit_once(' ')
+                        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Invalid pin file format"))?;
+                    pins.insert(name.to_string(), IdentityPublic::from_hex(key)?);
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        Ok(PinStore{ path: Some(path), pins })
+    }
+
+    pub fn save(&self) -> Result<(), std::io::Error>{
+        let Some(path) = &self.path else { return Ok(()) };
+        let mut names = self.pins.keys().collect::<Vec<_>>();
+        names.sort();
+        let contents: String = names.into_iter()
+            .map(|name| format!("{} {}\n", name, self.pins[name].to_hex()))
+            .collect();
+        replace_file(path, |temporary| {
+            let mut file = fs::File::create_new(temporary)?;
+            file.write_all(contents.as_bytes())?;
+            file.sync_all()
+        })
+    }
+
+    pub fn pin(&mut self, name: &str, key: IdentityPublic){
+        self.pins.insert(name.to_string(), key);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&IdentityPublic>{
+        self.pins.get(name)
+    }
+
+    /// Every pinned name, sorted.
+    pub fn names(&self) -> Vec<String>{
+        let mut names = self.pins.keys().cloned().collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    /// Checks `key` against the pin recorded for `name`.
+    pub fn verify(&self, name: &str, key: &IdentityPublic) -> Result<(), std::io::Error>{
+        match self.pins.get(name){
+            Some(pinned) if pinned == key => Ok(()),
+            Some(_) => Err(Error::new(ErrorKind::PermissionDenied,
+                format!("Identity key for {} does not match the pinned key", name))),
+            None => Err(Error::new(ErrorKind::NotFound, format!("No pinned identity key for {}", name))),
+        }
+    }
+}
+
+/// Replaces `path` with a file `write` creates next to it, so a crash mid-write leaves the old one whole.
+fn replace_file(path: &Path, write: impl FnOnce(&Path) -> Result<(), std::io::Error>) -> Result<(), std::io::Error>{
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    let temporary = PathBuf::from(temporary);
+    match fs::remove_file(&temporary){
+        Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    write(&temporary)?;
+    fs::rename(&temporary, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign_verify() {
+        let identity = IdentityKey::generate();
+        let signature = identity.sign(b"hello");
+        assert!(identity.public().verify(b"hello", &signature).is_ok());
+        assert!(identity.public().verify(b"goodbye", &signature).is_err());
+    }
+
+    #[test]
+    fn test_generated_key_is_reloaded() {
+        let path = std::env::temp_dir().join(format!("rustchat-identity-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let first = IdentityKey::load_or_generate(&path).unwrap();
+        let second = IdentityKey::load_or_generate(&path).unwrap();
+        assert_eq!(first.public(), second.public());
+        let replacement = IdentityKey::generate();
+        replacement.save(&path).unwrap();
+        assert_eq!(IdentityKey::load_or_generate(&path).unwrap().public(), replacement.public());
+        assert_eq!(IdentityKey::load(&path).unwrap().public(), replacement.public());
+        fs::remove_file(&path).unwrap();
+        assert_eq!(IdentityKey::load(&path).err().unwrap().kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_pin_store_roundtrip() {
+        let path = std::env::temp_dir().join(format!("rustchat-pins-{}", std::process::id()));
+        let alice = IdentityKey::generate().public();
+        let mut pins = PinStore::load(&path).unwrap();
+        pins.pin("alice", alice);
+        pins.save().unwrap();
+
+        let pins = PinStore::load(&path).unwrap();
+        assert!(pins.verify("alice", &alice).is_ok());
+        assert_eq!(pins.verify("alice", &IdentityKey::generate().public()).unwrap_err().kind(), ErrorKind::PermissionDenied);
+        assert_eq!(pins.verify("bob", &alice).unwrap_err().kind(), ErrorKind::NotFound);
+
+        // Saving again replaces the file whole
+        let mut pins = pins;
+        pins.pin("bob", alice);
+        pins.save().unwrap();
+        assert_eq!(PinStore::load(&path).unwrap().names(), vec!["alice", "bob"]);
+        assert!(!std::env::temp_dir().join(format!("rustchat-pins-{}.tmp", std::process::id())).exists());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,69 +1,136 @@
 pub(crate) 
 use std::net::TcpStream;
 use std::io::{self, Error, ErrorKind, Read, Write};
+use std::sync::Arc;
 
 
 
 use bincode::serialize;
 use chacha20poly1305::XNonce;
 use rand::rngs::OsRng;
+use rand::{CryptoRng, RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
 use serde::{Deserialize, Serialize};
 use x25519_dalek::{EphemeralSecret, PublicKey};
-use sha2::Sha256;
+use sha2::{Digest, Sha256};
 use hkdf::Hkdf;
 use chacha20poly1305::{
     aead::{Aead, KeyInit, AeadCore},
     XChaCha20Poly1305
 };
+use zeroize::{Zeroizing, ZeroizeOnDrop};
 
+mod clock;
+mod hybrid;
+mod identity;
+#[cfg(any(test, feature = "test-util"))]
+pub mod nat;
+mod pake;
+pub mod sealed;
+mod transport;
+pub mod udp;
+pub mod x3dh;
 
+/// Entry points for the fuzz targets in `fuzz/`. Not part of the supported API.
+#[cfg(feature = "fuzzing")]
+#[doc(hidden)]
+pub mod fuzzing;
 
+pub use clock::{Clock, ManualClock, SystemClock};
+pub use identity::{IdentityKey, IdentityPublic, PinStore};
+pub use transport::{MemoryTransport, Transport};
+use pake::Cpace;
+
+/// Largest length prefix we accept from a peer. Frames are chat messages and handshakes, so anything
+/// near this is hostile; without a cap a bogus prefix makes us allocate up to 4 GiB.
+pub const MAX_FRAME_LEN: usize = 1 << 20;
+
+
+
+/// Key exchange used for a session. `Hybrid` offers ML-KEM-768 alongside X25519 and falls back to
+/// `Classic` only when the peer explicitly declines the offer; a peer that ignores it (a legacy build,
+/// or someone in the middle who stripped it) fails the handshake.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum KeyExchange{
+    Classic,
+    #[default]
+    Hybrid,
+}
+
+/// Post-quantum extension to the handshake: the initiator offers an ML-KEM encapsulation key and a
+/// hybrid-capable responder accepts with a ciphertext. A responder configured for `Classic` declines
+/// so the initiator can tell a refusal from a stripped offer.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+enum KemData{
+    Offer(Vec<u8>),
+    Accept(Vec<u8>),
+    Decline,
+}
+
+/*
+    Classic-only peers send exactly the 32 byte public key and ignore anything after it, so the
+    extension is appended as a trailing field.
+
+    Downgrades
+    When an offer was made both sides mix the handshake transcript into the session key, so a
+    forged Decline leaves the two ends with different keys. An answer with no extension at all is
+    refused by a hybrid initiator, since the offer may have been removed on the way.
+ */
 #[derive(Serialize, Deserialize)]
 struct HandshakeData{
-    public_key: [u8; 32]
+    public_key: [u8; 32],
+    kem: Option<KemData>,
 }
 #[allow(dead_code)]
 impl HandshakeData{
-    fn write_length_prefixed(stream: &mut TcpStream, data: &[u8]) -> Result<(), std::io::Error> {
+    fn write_length_prefixed<W: Write>(stream: &mut W, data: &[u8]) -> Result<(), std::io::Error> {
+        // One write, so Nagle doesn't hold the body back waiting on an ack for the length
         let length = (data.len() as u32).to_be_bytes();
-        stream.write_all(&length)?;
-        stream.write_all(data)?;
+        stream.write_all(&[&length[..], data].concat())?;
         stream.flush()?;
         Ok(())
     }
     
-    fn read_length_prefixed(stream: &mut TcpStream) -> Result<Vec<u8>, std::io::Error> {
+    fn read_length_prefixed<R: Read>(stream: &mut R) -> Result<Vec<u8>, std::io::Error> {
         let mut length_bytes = [0u8; 4];
         stream.read_exact(&mut length_bytes)?;
         let length = u32::from_be_bytes(length_bytes) as usize;
+        if length > MAX_FRAME_LEN {
+            return Err(Error::new(ErrorKind::InvalidData, "Frame too large"));
+        }
     
         let mut buffer = vec![0; length];
         stream.read_exact(&mut buffer)?;
         Ok(buffer)
     }
         
-    fn init_handshake(stream: &mut TcpStream, pub_key: PublicKey) -> Result<HandshakeData, std::io::Error>{
-        let handshake = HandshakeData{
-            public_key: pub_key.to_bytes(),
-        };
-        let serialized = serialize(&handshake).expect("Failed to serialize handshake data");
+    fn decode(buf: &[u8]) -> Result<HandshakeData, std::io::Error>{
+        if let Ok(public_key) = <[u8; 32]>::try_from(buf) {
+            return Ok(HandshakeData{ public_key, kem: None });
+        }
+        bincode::deserialize(buf)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+    }
+
+    fn init_handshake<S: Read + Write>(stream: &mut S, handshake: &HandshakeData) -> Result<HandshakeData, std::io::Error>{
+        let serialized = serialize(handshake).expect("Failed to serialize handshake data");
         
         Self::write_length_prefixed(stream, &serialized)?;
     
         let buf = Self::read_length_prefixed(stream)?;
-        let response: HandshakeData = bincode::deserialize(&buf)
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
-        return Ok(response);
+        Self::decode(&buf)
     }
 
-    fn recieve_handshake(stream: &mut TcpStream, pub_key: PublicKey) -> Result<HandshakeData, std::io::Error>{
+    /// Reads the initiator's handshake and answers with whatever `respond` builds from it.
+    fn recieve_handshake<S: Read + Write, F>(stream: &mut S, respond: F) -> Result<(HandshakeData, HandshakeData), std::io::Error>
+        where F: FnOnce(&HandshakeData) -> Result<HandshakeData, std::io::Error>{
         let buf = Self::read_length_prefixed(stream)?;
-        let response: HandshakeData = bincode::deserialize(&buf)
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
-        let buf = bincode::serialize(&HandshakeData{ public_key: pub_key.to_bytes() })
+        let request = Self::decode(&buf)?;
+        let response = respond(&request)?;
+        let buf = bincode::serialize(&response)
             .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
         Self::write_length_prefixed(stream, &buf)?;
-        return Ok(response);
+        Ok((request, response))
     }
 }
 
@@ -90,18 +157,30 @@ pub struct Message{
     pub contents: String,
     pub timestamp: u64,
 }
-impl ToString for Message{
-    fn to_string(&self) -> String{
-        let mut message = String::new();
-        message.push_str(&format!("{} -> {}", self.sender_id, self.to_id));
-        message.push_str(&format!("\n{}", self.timestamp));
-        message.push_str(&format!("\n{}", self.contents));
-        return message;
+// Debug output ends up in logs and panic messages, so it shows how long the contents are, never the text
+impl std::fmt::Debug for Message{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result{
+        f.debug_struct("Message")
+            .field("sender_id", &self.sender_id)
+            .field("to_id", &self.to_id)
+            .field("contents_len", &self.contents.len())
+            .field("timestamp", &self.timestamp)
+            .finish()
+    }
+}
+impl std::fmt::Display for Message{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result{
+        write!(f, "{} -> {}", self.sender_id, self.to_id)?;
+        write!(f, "\n{}", self.timestamp)?;
+        write!(f, "\n{}", self.contents)
     }
 }
 impl Message{
     fn from_string(message: String) -> Result<Self, std::io::Error>{
         let lines = message.lines().collect::<Vec<_>>();
+        if lines.len() < 2{
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid message format (Missing lines)"));
+        }
         let head_line = lines[0];
         let head_parts = head_line.split("->").collect::<Vec<_>>();
         if head_parts.len() != 2{
@@ -113,14 +192,13 @@ impl Message{
             .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid message format (Timestamp invalid)"))?;
         let contents = lines[2..].join("\n").trim().to_string();
 
-        return Ok(Message{
+        Ok(Message{
             sender_id,
             to_id,
             contents,
             timestamp,
-        });
-        
-    }   
+        })
+    }
 
     pub fn displayable(&self) -> String{
         format!("{}: {}", self.sender_id, self.contents)
@@ -130,94 +208,466 @@ impl Message{
 }
 
 
-pub struct SessionCryptData{
+/// Sent encrypted once the session key is established, binding the sender's long-term identity to this
+/// handshake.
+#[derive(Serialize, Deserialize)]
+struct IdentityProof{
+    name: String,
+    identity: IdentityPublic,
+    signature: Vec<u8>,
+}
+
+/// How the peers authenticate each other on top of the ephemeral X25519 exchange.
+enum Authentication<'a>{
+    None,
+    Identity{ identity: &'a IdentityKey, name: &'a str },
+    Pairing{ identity: &'a IdentityKey, name: &'a str, code: &'a str },
+}
+
+/// Randomness source for a session: ephemeral keys, pairing scalars and message nonces.
+pub trait SessionRng: RngCore + CryptoRng + Send{
+    /// Overwrites whatever state the generator keeps, so that neither the session's ephemeral secrets
+    /// nor its later output can be recovered from it.
+    fn wipe(&mut self);
+}
+
+// Reads from the OS each time and keeps nothing
+impl SessionRng for OsRng{
+    fn wipe(&mut self){}
+}
+
+// Seeded, for reproducible tests and fuzzing; the seed and key stream are held inline
+impl SessionRng for ChaCha20Rng{
+    fn wipe(&mut self){
+        *self = ChaCha20Rng::from_seed([0; 32]);
+        std::hint::black_box(self);
+    }
+}
+
+/// The session's `SessionRng`, wiped when dropped.
+struct SessionRandom(Box<dyn SessionRng>);
+
+impl RngCore for SessionRandom{
+    fn next_u32(&mut self) -> u32{
+        self.0.next_u32()
+    }
+
+    fn next_u64(&mut self) -> u64{
+        self.0.next_u64()
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]){
+        self.0.fill_bytes(dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error>{
+        self.0.try_fill_bytes(dest)
+    }
+}
+
+impl CryptoRng for SessionRandom {}
+
+impl Drop for SessionRandom{
+    fn drop(&mut self){
+        self.0.wipe();
+    }
+}
+
+impl ZeroizeOnDrop for SessionRandom {}
+
+/// Configures how a session is established. Defaults to an unauthenticated hybrid key exchange using the
+/// OS RNG and system clock; tests can inject a seeded RNG and a `ManualClock` for reproducible runs.
+pub struct SessionBuilder<'a>{
+    key_exchange: KeyExchange,
+    auth: Authentication<'a>,
+    rng: SessionRandom,
+    clock: Arc<dyn Clock>,
+}
+
+impl Default for SessionBuilder<'_>{
+    fn default() -> Self{
+        SessionBuilder{
+            key_exchange: KeyExchange::default(),
+            auth: Authentication::None,
+            rng: SessionRandom(Box::new(OsRng)),
+            clock: Arc::new(SystemClock),
+        }
+    }
+}
+
+impl<'a> SessionBuilder<'a>{
+    pub fn new() -> Self{
+        Self::default()
+    }
+
+    /// `KeyExchange::Classic` never offers ML-KEM as initiator and declines offers as responder.
+    pub fn key_exchange(mut self, kex: KeyExchange) -> Self{
+        self.key_exchange = kex;
+        self
+    }
+
+    /// Both peers prove their long-term identity; check the result with `verify_peer` or `peer`.
+    pub fn identity(mut self, identity: &'a IdentityKey, name: &'a str) -> Self{
+        self.auth = Authentication::Identity{ identity, name };
+        self
+    }
+
+    /// Authenticates the key exchange with a short code both users entered (CPace), then exchanges identities.
+    pub fn pairing(mut self, identity: &'a IdentityKey, name: &'a str, code: &'a str) -> Self{
+        self.auth = Authentication::Pairing{ identity, name, code };
+        self
+    }
+
+    pub fn rng(mut self, rng: impl SessionRng + 'static) -> Self{
+        self.rng = SessionRandom(Box::new(rng));
+        self
+    }
+
+    pub fn clock(mut self, clock: impl Clock + 'static) -> Self{
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Runs the handshake as the peer who initiated the connection.
+    pub fn start<T: Transport>(self, stream: T) -> Result<SessionCryptData<T>, std::io::Error>{
+        self.establish(stream, true)
+    }
+
+    /// Runs the handshake as the peer who accepted the connection.
+    pub fn recieve<T: Transport>(self, stream: T) -> Result<SessionCryptData<T>, std::io::Error>{
+        self.establish(stream, false)
+    }
+
+    fn establish<T: Transport>(self, mut stream: T, initiator: bool) -> Result<SessionCryptData<T>, std::io::Error>{
+        let SessionBuilder{ key_exchange: kex, auth, mut rng, clock } = self;
+        let self_secret = EphemeralSecret::random_from_rng(&mut rng);
+        let self_public = PublicKey::from(&self_secret);
+
+        let (request, response, pq_shared) = if initiator {
+            let (kem_secret, offer) = match kex {
+                KeyExchange::Hybrid => {
+                    let (secret, offer) = hybrid::offer(&mut rng);
+                    (Some(secret), Some(KemData::Offer(offer)))
+                }
+                KeyExchange::Classic => (None, None),
+            };
+            let request = HandshakeData{ public_key: self_public.to_bytes(), kem: offer };
+            let response = HandshakeData::init_handshake(&mut stream, &request)?;
+            let pq_shared = match (kem_secret, &response.kem) {
+                (Some(secret), Some(KemData::Accept(ciphertext))) => Some(secret.finish(ciphertext)?),
+                (Some(_), Some(KemData::Decline)) | (None, None) => None,
+                (Some(_), None) => return Err(Error::new(ErrorKind::InvalidData,
+                    "Peer ignored the post-quantum offer; refusing to fall back to X25519")),
+                _ => return Err(Error::new(ErrorKind::InvalidData, "Unexpected key exchange response")),
+            };
+            (request, response, pq_shared)
+        } else {
+            let mut pq_shared = None;
+            let (request, response) = HandshakeData::recieve_handshake(&mut stream, |request| {
+                let kem = match (&request.kem, kex) {
+                    (Some(KemData::Offer(offer)), KeyExchange::Hybrid) => {
+                        let (ciphertext, shared) = hybrid::accept(offer, &mut rng)?;
+                        pq_shared = Some(shared);
+                        Some(KemData::Accept(ciphertext))
+                    }
+                    (Some(KemData::Offer(_)), KeyExchange::Classic) => Some(KemData::Decline),
+                    _ => None,
+                };
+                Ok(HandshakeData{ public_key: self_public.to_bytes(), kem })
+            })?;
+            (request, response, pq_shared)
+        };
+        let peer_public = PublicKey::from(if initiator { response.public_key } else { request.public_key });
+        let transcript = SessionCryptData::<T>::transcript(&request, &response)?;
+
+        let shared = self_secret.diffie_hellman(&peer_public);
+        let mut key_material = Zeroizing::new(shared.as_bytes().to_vec());
+        if let Some(pq_shared) = &pq_shared {
+            key_material.extend_from_slice(pq_shared.as_ref());
+        }
+        if request.kem.is_some() {
+            key_material.extend_from_slice(&transcript);
+        }
+        if let Authentication::Pairing{ code, .. } = auth {
+            let cpace = Cpace::start(code.as_bytes(), &transcript, &mut rng);
+            let peer_share = SessionCryptData::<T>::exchange_share(&mut stream, initiator, cpace.share())?;
+            key_material.extend_from_slice(cpace.finish(&peer_share, initiator)?.as_ref());
+        }
+        let shared = SessionCryptData::<T>::derive_key(&key_material);
+
+        let cipher = XChaCha20Poly1305::new_from_slice(shared.as_ref()).unwrap();
+        let mut session = SessionCryptData{
+            cipher,
+            stream,
+            peer: None,
+            key_exchange: if pq_shared.is_some() { KeyExchange::Hybrid } else { KeyExchange::Classic },
+            rng,
+            clock,
+        };
+
+        match auth {
+            Authentication::None => {}
+            Authentication::Identity{ identity, name } | Authentication::Pairing{ identity, name, .. } => {
+                session.peer = Some(session.exchange_identity(identity, name, &transcript, initiator)?);
+            }
+        }
+
+        session.stream.set_nonblocking(true)?;
+        Ok(session)
+    }
+}
+
+/// Long-term identity the peer proved during the handshake.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PeerIdentity{
+    pub name: String,
+    pub key: IdentityPublic,
+}
+
+#[derive(ZeroizeOnDrop)]
+pub struct SessionCryptData<T: Transport = TcpStream>{
     cipher: XChaCha20Poly1305,
-    stream: TcpStream,
+    #[zeroize(skip)]
+    stream: T,
+    #[zeroize(skip)]
+    peer: Option<PeerIdentity>,
+    #[zeroize(skip)]
+    key_exchange: KeyExchange,
+    rng: SessionRandom,
+    #[zeroize(skip)]
+    clock: Arc<dyn Clock>,
 }
 
-impl SessionCryptData{
-    fn derive_key(shared_secret: &[u8]) -> [u8; 32] {
+impl<T: Transport> SessionCryptData<T>{
+    fn derive_key(shared_secret: &[u8]) -> Zeroizing<[u8; 32]> {
         let hk = Hkdf::<Sha256>::new(None, shared_secret);
-        let mut encryption_key = [0u8; 32];
-        hk.expand(b"chat encryption key", &mut encryption_key).unwrap();
+        let mut encryption_key = Zeroizing::new([0u8; 32]);
+        hk.expand(b"chat encryption key", encryption_key.as_mut()).unwrap();
         encryption_key
     }
-    
+
+    /// Hash of both handshake messages, initiator first. Used as the CPace session id and signed by identity
+    /// proofs, so a stripped hybrid offer is caught by authenticated sessions.
+    fn transcript(request: &HandshakeData, response: &HandshakeData) -> Result<[u8; 32], std::io::Error>{
+        let mut hasher = Sha256::new();
+        hasher.update(b"rustchat handshake");
+        for handshake in [request, response] {
+            hasher.update(bincode::serialize(handshake)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?);
+        }
+        Ok(hasher.finalize().into())
+    }
+
+    fn exchange_share(stream: &mut T, initiator: bool, share: [u8; 32]) -> Result<[u8; 32], std::io::Error>{
+        if initiator {
+            HandshakeData::write_length_prefixed(stream, &share)?;
+        }
+        let peer_share: [u8; 32] = HandshakeData::read_length_prefixed(stream)?
+            .try_into()
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "Invalid pairing share length"))?;
+        if !initiator {
+            HandshakeData::write_length_prefixed(stream, &share)?;
+        }
+        Ok(peer_share)
+    }
+
+    /// Each side signs the handshake transcript with its identity key and sends it under the new session
+    /// key. The initiator goes first; the responder only answers once the initiator's proof checks out, so a
+    /// peer holding the wrong key (or wrong pairing code) learns nothing about our identity.
+    fn exchange_identity(&mut self, identity: &IdentityKey, name: &str, transcript: &[u8; 32], initiator: bool)
+        -> Result<PeerIdentity, std::io::Error>{
+        let signed = |from_initiator: bool| [b"rustchat identity".as_slice(), transcript, &[from_initiator as u8]].concat();
+        let proof = IdentityProof{
+            name: name.to_string(),
+            identity: identity.public(),
+            signature: identity.sign(&signed(initiator)).to_vec(),
+        };
+        let proof = bincode::serialize(&proof)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+
+        if initiator {
+            self.send_frame(&proof)?;
+        }
+        let peer_proof = self.recieve_frame().map_err(|e| match e.kind() {
+            ErrorKind::PermissionDenied => Error::new(ErrorKind::PermissionDenied,
+                "Handshake failed: wrong pairing code or man in the middle"),
+            _ => e,
+        })?;
+        let peer_proof: IdentityProof = bincode::deserialize(&peer_proof)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        let signature: [u8; 64] = peer_proof.signature.as_slice().try_into()
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "Invalid identity signature length"))?;
+        peer_proof.identity.verify(&signed(!initiator), &signature)?;
+        if !initiator {
+            self.send_frame(&proof)?;
+        }
+
+        Ok(PeerIdentity{
+            name: peer_proof.name,
+            key: peer_proof.identity,
+        })
+    }
+
     /// This function is called by the peer who initiated the connection (i.e. the one sending the initial handshake
     /// message)
-    pub fn start_session(mut stream: TcpStream) -> Result<Self, std::io::Error>{
-        let self_secret = EphemeralSecret::random_from_rng(OsRng);
-        let self_public = PublicKey::from(&self_secret);
+    pub fn start_session(stream: T) -> Result<Self, std::io::Error>{
+        SessionBuilder::new().start(stream)
+    }
 
-        let peer_public = HandshakeData::init_handshake(&mut stream, self_public)?;
-        let peer_public = PublicKey::from(peer_public.public_key);
+    pub fn recieve_session(stream: T) -> Result<Self, std::io::Error>{
+        SessionBuilder::new().recieve(stream)
+    }
 
-        let shared = self_secret.diffie_hellman(&peer_public);
-        let shared = Self::derive_key(shared.as_bytes());
+    /// Like `start_session`, but both peers also prove their long-term identity. Check the result with
+    /// `verify_peer` or `peer`.
+    pub fn start_authenticated_session(stream: T, identity: &IdentityKey, name: &str) -> Result<Self, std::io::Error>{
+        SessionBuilder::new().identity(identity, name).start(stream)
+    }
 
-        let cipher = XChaCha20Poly1305::new_from_slice(&shared).unwrap();
+    pub fn recieve_authenticated_session(stream: T, identity: &IdentityKey, name: &str) -> Result<Self, std::io::Error>{
+        SessionBuilder::new().identity(identity, name).recieve(stream)
+    }
 
-        stream.set_nonblocking(true)?;
+    /// First-contact pairing: both users enter the same short code, which authenticates the key exchange
+    /// (CPace). On success the peer's identity key is pinned in `pins` under its display name, so later
+    /// sessions can be checked with `verify_peer`. Fails on both ends if either side already pinned a
+    /// different key under the other's name.
+    pub fn start_pairing(stream: T, identity: &IdentityKey, name: &str, code: &str, pins: &mut PinStore)
+        -> Result<Self, std::io::Error>{
+        let mut session = SessionBuilder::new().pairing(identity, name, code).start(stream)?;
+        session.confirm_pin(pins, true)?;
+        Ok(session)
+    }
 
-        return Ok(SessionCryptData{
-            cipher,
-            stream
-        });
-    
+    pub fn recieve_pairing(stream: T, identity: &IdentityKey, name: &str, code: &str, pins: &mut PinStore)
+        -> Result<Self, std::io::Error>{
+        let mut session = SessionBuilder::new().pairing(identity, name, code).recieve(stream)?;
+        session.confirm_pin(pins, false)?;
+        Ok(session)
     }
 
-    pub fn recieve_session(mut stream: TcpStream) -> Result<Self, std::io::Error>{
-        let self_secret = EphemeralSecret::random_from_rng(OsRng);
-        let self_public = PublicKey::from(&self_secret);
+    /// Each side tells the other whether it accepts the key it was shown before pinning it, so a refused
+    /// pin fails the pairing on both ends instead of leaving one of them believing the session is up.
+    fn confirm_pin(&mut self, pins: &mut PinStore, initiator: bool) -> Result<(), std::io::Error>{
+        let verdict = match &self.peer {
+            Some(peer) => match pins.verify(&peer.name, &peer.key){
+                Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+                result => result,
+            },
+            None => Ok(()),
+        };
+        let accepted = [verdict.is_ok() as u8];
+        let peer_accepted = self.blocking(|session| {
+            if initiator {
+                session.send_frame(&accepted)?;
+            }
+            let peer_accepted = session.recieve_frame()?;
+            if !initiator {
+                session.send_frame(&accepted)?;
+            }
+            Ok(peer_accepted)
+        })?;
+        verdict?;
+        if peer_accepted != [1] {
+            return Err(Error::new(ErrorKind::PermissionDenied, "Peer refused our identity key"));
+        }
+        self.pin_peer(pins)
+    }
 
-        let peer_public = HandshakeData::recieve_handshake(&mut stream, self_public)?;
-        let peer_public = PublicKey::from(peer_public.public_key);
+    /// Pins the identity the peer proved during the handshake, if any, and saves `pins`. Refuses to replace
+    /// a different key already pinned under the peer's name.
+    pub fn pin_peer(&self, pins: &mut PinStore) -> Result<(), std::io::Error>{
+        if let Some(peer) = &self.peer {
+            match pins.verify(&peer.name, &peer.key){
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    pins.pin(&peer.name, peer.key);
+                    pins.save()?;
+                }
+                result => result?,
+            }
+        }
+        Ok(())
+    }
 
-        let shared = self_secret.diffie_hellman(&peer_public);
-        let shared = Self::derive_key(shared.as_bytes());
+    /// The underlying transport.
+    pub fn get_ref(&self) -> &T{
+        &self.stream
+    }
 
-        let cipher = XChaCha20Poly1305::new_from_slice(&shared).unwrap();
+    /// Current time on the session's clock, in seconds since the unix epoch. Use it to stamp outgoing messages.
+    pub fn timestamp(&self) -> u64{
+        self.clock.unix_time()
+    }
 
-        stream.set_nonblocking(true)?;
-        return Ok(SessionCryptData{
-            cipher,
-            stream
-        });
+    /// Key exchange the peers actually agreed on.
+    pub fn key_exchange(&self) -> KeyExchange{
+        self.key_exchange
     }
 
-    pub fn send_message(&mut self, message: Message) -> Result<(), std::io::Error>{
-        self.stream.set_nonblocking(false)?;
-        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
-        let message = message.to_string();
-        let msg_bytes = bincode::serialize(&message)
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
-        let ciphertext = self.cipher.encrypt(&nonce, msg_bytes.as_ref())
+    /// Identity the peer proved during the handshake, if the session was authenticated.
+    pub fn peer(&self) -> Option<&PeerIdentity>{
+        self.peer.as_ref()
+    }
+
+    /// Checks the peer's proven identity against the key pinned for its name.
+    pub fn verify_peer(&self, pins: &PinStore) -> Result<(), std::io::Error>{
+        let peer = self.peer.as_ref()
+            .ok_or_else(|| Error::new(ErrorKind::PermissionDenied, "Session is not authenticated"))?;
+        pins.verify(&peer.name, &peer.key)
+    }
+
+    fn send_frame(&mut self, data: &[u8]) -> Result<(), std::io::Error>{
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut self.rng);
+        let ciphertext = self.cipher.encrypt(&nonce, data)
             .expect("Failed to encrypt message");
         let encrypted_message = EncryptedMessage {
             nonce: *nonce.as_ref(),
             ciphertext,
         };
         let serialized = serialize(&encrypted_message).expect("Failed to serialize encrypted message");
-        HandshakeData::write_length_prefixed(&mut self.stream, &serialized)?;
-        self.stream.set_nonblocking(true)?;
-        Ok(())
-
+        HandshakeData::write_length_prefixed(&mut self.stream, &serialized)
     }
 
-    pub fn recieve_message(&mut self) -> Result<Message, std::io::Error>{
-        self.stream.set_nonblocking(false)?;
+    fn recieve_frame(&mut self) -> Result<Vec<u8>, std::io::Error>{
         let buf = HandshakeData::read_length_prefixed(&mut self.stream)?;
         let encrypted_message: EncryptedMessage = bincode::deserialize(&buf)
             .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
         let nonce = XNonce::from_slice(&encrypted_message.nonce);
-        let decrypted = self.cipher.decrypt(nonce, encrypted_message.ciphertext.as_ref())
-            .expect("Failed to decrypt message");
-        let message: String = bincode::deserialize(&decrypted)
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
-        let message = Message::from_string(message)?;
+        self.cipher.decrypt(nonce, encrypted_message.ciphertext.as_ref())
+            .map_err(|_| Error::new(ErrorKind::PermissionDenied, "Failed to decrypt message"))
+    }
+
+    /// Runs `f` with the stream in blocking mode, switching back to non-blocking even when `f` fails so
+    /// `check_data_available` never ends up stuck in a blocking peek.
+    fn blocking<R>(&mut self, f: impl FnOnce(&mut Self) -> Result<R, std::io::Error>) -> Result<R, std::io::Error>{
+        self.stream.set_nonblocking(false)?;
+        let result = f(self);
         self.stream.set_nonblocking(true)?;
-        Ok(message)
+        result
+    }
 
+    pub fn send_message(&mut self, message: Message) -> Result<(), std::io::Error>{
+        let message = message.to_string();
+        let msg_bytes = bincode::serialize(&message)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        self.blocking(|session| session.send_frame(&msg_bytes))
+    }
+
+    /// Sends arbitrary bytes as one encrypted frame, for protocols other than chat running over a session.
+    pub fn send_data(&mut self, data: &[u8]) -> Result<(), std::io::Error>{
+        self.blocking(|session| session.send_frame(data))
+    }
+
+    /// Receives one frame sent with `send_data`.
+    pub fn recieve_data(&mut self) -> Result<Vec<u8>, std::io::Error>{
+        self.blocking(Self::recieve_frame)
+    }
+
+    pub fn recieve_message(&mut self) -> Result<Message, std::io::Error>{
+        let decrypted = self.blocking(Self::recieve_frame)?;
+        let message: String = bincode::deserialize(&decrypted)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        Message::from_string(message)
     }
     pub fn check_data_available(&mut self) -> Result<bool, io::Error> {
         let mut peek_buf = [0u8; 1];
@@ -230,19 +680,22 @@ impl SessionCryptData{
     }
     pub fn wait_data_available(&mut self) -> Result<(), io::Error>{
         loop{
-            match self.check_data_available(){
-                Ok(val) => if val{return Ok(());} else {continue;}
-                Err(e) => { return Err(e); }
+            if self.check_data_available()? {
+                return Ok(());
             }
         }
     }
 
 }
 
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use std::net::{TcpListener, TcpStream};
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha20Rng;
+    use std::sync::mpsc;
     use std::thread;
     use std::time::{SystemTime, UNIX_EPOCH};
 
@@ -267,17 +720,102 @@ mod tests {
         let client_thread = thread::spawn(move || {
             let client_secret = EphemeralSecret::random_from_rng(OsRng);
             let client_public = PublicKey::from(&client_secret);
-            HandshakeData::init_handshake(&mut client, client_public).unwrap()
+            let request = HandshakeData{ public_key: client_public.to_bytes(), kem: None };
+            HandshakeData::init_handshake(&mut client, &request).unwrap()
         });
 
         let server_secret = EphemeralSecret::random_from_rng(OsRng);
         let server_public = PublicKey::from(&server_secret);
-        let server_response = HandshakeData::recieve_handshake(&mut server, server_public).unwrap();
+        let (server_response, _) = HandshakeData::recieve_handshake(&mut server, |_| {
+            Ok(HandshakeData{ public_key: server_public.to_bytes(), kem: None })
+        }).unwrap();
         
         let client_response = client_thread.join().unwrap();
         
         assert_eq!(server_response.public_key.len(), 32);
-        assert_eq!(client_response.public_key.len(), 32);
+        assert_eq!(client_response.public_key, server_public.to_bytes());
+    }
+
+    fn negotiate(client_kex: KeyExchange, server_kex: KeyExchange) -> (KeyExchange, KeyExchange) {
+        let (client, server) = setup_tcp_pair();
+        let client_thread = thread::spawn(move || {
+            let mut session = SessionBuilder::new().key_exchange(client_kex).start(client).unwrap();
+            session.wait_data_available().unwrap();
+            assert_eq!(session.recieve_message().unwrap().contents, "hybrid?");
+            session.key_exchange()
+        });
+        let mut session = SessionBuilder::new().key_exchange(server_kex).recieve(server).unwrap();
+        session.send_message(Message {
+            sender_id: "server".to_string(),
+            to_id: "client".to_string(),
+            contents: "hybrid?".to_string(),
+            timestamp: 0,
+        }).unwrap();
+        (client_thread.join().unwrap(), session.key_exchange())
+    }
+
+    #[test]
+    fn test_hybrid_negotiation() {
+        assert_eq!(negotiate(KeyExchange::Hybrid, KeyExchange::Hybrid), (KeyExchange::Hybrid, KeyExchange::Hybrid));
+        assert_eq!(negotiate(KeyExchange::Hybrid, KeyExchange::Classic), (KeyExchange::Classic, KeyExchange::Classic));
+        assert_eq!(negotiate(KeyExchange::Classic, KeyExchange::Hybrid), (KeyExchange::Classic, KeyExchange::Classic));
+    }
+
+    #[test]
+    fn test_hybrid_with_legacy_peer() {
+        // A peer from before the hybrid extension only knows the bare 32 byte public key
+        #[derive(Serialize, Deserialize)]
+        struct LegacyHandshake {
+            public_key: [u8; 32],
+        }
+        let (client, mut server) = setup_tcp_pair();
+        let client_thread = thread::spawn(move || {
+            let mut session = SessionBuilder::new().key_exchange(KeyExchange::Classic).start(client).unwrap();
+            session.wait_data_available().unwrap();
+            (session.key_exchange(), session.recieve_message().unwrap().contents)
+        });
+
+        let legacy_secret = EphemeralSecret::random_from_rng(OsRng);
+        let legacy_public = PublicKey::from(&legacy_secret);
+        let request: LegacyHandshake = bincode::deserialize(&HandshakeData::read_length_prefixed(&mut server).unwrap()).unwrap();
+        let response = bincode::serialize(&LegacyHandshake{ public_key: legacy_public.to_bytes() }).unwrap();
+        HandshakeData::write_length_prefixed(&mut server, &response).unwrap();
+
+        let shared = legacy_secret.diffie_hellman(&PublicKey::from(request.public_key));
+        let mut legacy_session = SessionCryptData{
+            cipher: XChaCha20Poly1305::new_from_slice(SessionCryptData::<TcpStream>::derive_key(shared.as_bytes()).as_ref()).unwrap(),
+            stream: server,
+            peer: None,
+            key_exchange: KeyExchange::Classic,
+            rng: SessionRandom(Box::new(OsRng)),
+            clock: Arc::new(SystemClock),
+        };
+        legacy_session.send_message(Message {
+            sender_id: "legacy".to_string(),
+            to_id: "client".to_string(),
+            contents: "still here".to_string(),
+            timestamp: 0,
+        }).unwrap();
+
+        assert_eq!(client_thread.join().unwrap(), (KeyExchange::Classic, "still here".to_string()));
+    }
+
+    #[test]
+    fn test_stripped_kem_offer_is_refused() {
+        let (client, mut to_client) = setup_tcp_pair();
+        let (mut to_server, server) = setup_tcp_pair();
+        let client_thread = thread::spawn(move || SessionBuilder::new().start(client).map(|_| ()));
+        let server_thread = thread::spawn(move || SessionBuilder::new().recieve(server).map(|_| ()));
+
+        // Someone in the middle forwards the handshake with the ML-KEM offer removed
+        let request = HandshakeData::decode(&HandshakeData::read_length_prefixed(&mut to_client).unwrap()).unwrap();
+        assert!(matches!(request.kem, Some(KemData::Offer(_))));
+        HandshakeData::write_length_prefixed(&mut to_server, &request.public_key).unwrap();
+        let response = HandshakeData::read_length_prefixed(&mut to_server).unwrap();
+        HandshakeData::write_length_prefixed(&mut to_client, &response).unwrap();
+
+        assert!(client_thread.join().unwrap().is_err());
+        server_thread.join().unwrap().unwrap();
     }
 
     #[test]
@@ -314,8 +852,7 @@ mod tests {
             
             // Receive response
             session.wait_data_available().unwrap();
-            let response = session.recieve_message().unwrap();
-            response
+            session.recieve_message().unwrap()
         });
 
         let mut server_session = SessionCryptData::recieve_session(server).unwrap();
@@ -362,9 +899,23 @@ mod tests {
         assert_eq!(parsed.contents, "Hello!");
         assert_eq!(parsed.timestamp, 1234567890);
     }
+
+    #[test]
+    fn test_message_debug_hides_contents() {
+        let msg = Message {
+            sender_id: "alice".to_string(),
+            to_id: "bob".to_string(),
+            contents: "meet at the usual place".to_string(),
+            timestamp: 1234567890,
+        };
+        let debug = format!("{:?}", msg);
+        assert!(!debug.contains("usual place"));
+        assert!(debug.contains("contents_len: 23"));
+    }
     #[test]
     fn test_stream_data_check() {
         let (client, server) = setup_tcp_pair();
+        let (checked_tx, checked_rx) = mpsc::channel();
         
         let client_thread = thread::spawn(move || {
             let mut session = SessionCryptData::start_session(client).unwrap();
@@ -373,13 +924,14 @@ mod tests {
             session.stream.set_nonblocking(true).unwrap();
             
             // Initially there should be no data
-            assert_eq!(session.check_data_available().unwrap(), false);
+            assert!(!session.check_data_available().unwrap());
+            checked_tx.send(()).unwrap();
             
             // Wait for server data
             thread::sleep(std::time::Duration::from_millis(100));
             
             // Now there should be data
-            assert_eq!(session.check_data_available().unwrap(), true);
+            assert!(session.check_data_available().unwrap());
             
             // Verify we can still read the message
             let msg = session.recieve_message().unwrap();
@@ -387,6 +939,7 @@ mod tests {
         });
 
         let mut server_session = SessionCryptData::recieve_session(server).unwrap();
+        checked_rx.recv().unwrap();
         
         // Send a test message
         let msg = Message {
@@ -403,4 +956,265 @@ mod tests {
         
         client_thread.join().unwrap();
     }
+
+    #[test]
+    fn test_pairing_pins_identities() {
+        let (client, server) = setup_tcp_pair();
+        let alice = IdentityKey::generate();
+        let bob = IdentityKey::generate();
+        let (alice_public, bob_public) = (alice.public(), bob.public());
+
+        let client_thread = thread::spawn(move || {
+            let mut pins = PinStore::new();
+            SessionCryptData::start_pairing(client, &alice, "alice", "4711", &mut pins).unwrap();
+            pins
+        });
+        let mut bob_pins = PinStore::new();
+        let session = SessionCryptData::recieve_pairing(server, &bob, "bob", "4711", &mut bob_pins).unwrap();
+        let alice_pins = client_thread.join().unwrap();
+
+        assert_eq!(session.peer().unwrap().name, "alice");
+        assert_eq!(bob_pins.get("alice"), Some(&alice_public));
+        assert_eq!(alice_pins.get("bob"), Some(&bob_public));
+
+        // A later session is checked against the pins
+        let (client, server) = setup_tcp_pair();
+        let client_thread = thread::spawn(move || {
+            SessionCryptData::start_authenticated_session(client, &IdentityKey::generate(), "alice").unwrap()
+        });
+        let impostor = SessionCryptData::recieve_authenticated_session(server, &bob, "bob").unwrap();
+        client_thread.join().unwrap();
+        assert_eq!(impostor.verify_peer(&bob_pins).unwrap_err().kind(), ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn test_pairing_keeps_existing_pin() {
+        let (client, server) = setup_tcp_pair();
+        let alice = IdentityKey::generate();
+        let pinned = IdentityKey::generate().public();
+        let mut bob_pins = PinStore::new();
+        bob_pins.pin("alice", pinned);
+
+        // Knowing the code doesn't let a new key take over a name already pinned
+        let client_thread = thread::spawn(move || {
+            let mut alice_pins = PinStore::new();
+            let result = SessionCryptData::start_pairing(client, &alice, "alice", "4711", &mut alice_pins);
+            (result.err().map(|e| e.kind()), alice_pins.names())
+        });
+        let result = SessionCryptData::recieve_pairing(server, &IdentityKey::generate(), "bob", "4711", &mut bob_pins);
+        assert_eq!(result.err().unwrap().kind(), ErrorKind::PermissionDenied);
+        assert_eq!(bob_pins.get("alice"), Some(&pinned));
+
+        // Alice is told the pairing failed rather than left with a session bob has given up on
+        assert_eq!(client_thread.join().unwrap(), (Some(ErrorKind::PermissionDenied), Vec::<String>::new()));
+
+        // Same when the initiator is the one holding the conflicting pin
+        let (client, server) = setup_tcp_pair();
+        let client_thread = thread::spawn(move || {
+            let mut alice_pins = PinStore::new();
+            alice_pins.pin("bob", pinned);
+            SessionCryptData::start_pairing(client, &IdentityKey::generate(), "alice", "4711", &mut alice_pins).err().map(|e| e.kind())
+        });
+        let mut bob_pins = PinStore::new();
+        let result = SessionCryptData::recieve_pairing(server, &IdentityKey::generate(), "bob", "4711", &mut bob_pins);
+        assert_eq!(result.err().unwrap().kind(), ErrorKind::PermissionDenied);
+        assert!(bob_pins.get("alice").is_none());
+        assert_eq!(client_thread.join().unwrap(), Some(ErrorKind::PermissionDenied));
+    }
+
+    #[test]
+    fn test_pairing_wrong_code() {
+        let (client, server) = setup_tcp_pair();
+
+        let client_thread = thread::spawn(move || {
+            let mut pins = PinStore::new();
+            let result = SessionCryptData::start_pairing(client, &IdentityKey::generate(), "alice", "4711", &mut pins);
+            (result.is_err(), pins.get("bob").is_none())
+        });
+        let mut pins = PinStore::new();
+        let result = SessionCryptData::recieve_pairing(server, &IdentityKey::generate(), "bob", "1174", &mut pins);
+
+        assert_eq!(result.err().unwrap().kind(), ErrorKind::PermissionDenied);
+        assert!(pins.get("alice").is_none());
+        assert_eq!(client_thread.join().unwrap(), (true, true));
+    }
+
+    #[test]
+    fn test_pairing_man_in_the_middle() {
+        // Mallory sits between alice and bob, relaying nothing but running her own pairing with each side
+        // using a guessed code.
+        let alice_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let bob_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (mallory_addr, bob_addr) = (alice_listener.local_addr().unwrap(), bob_listener.local_addr().unwrap());
+
+        let mallory = thread::spawn(move || {
+            let to_bob = thread::spawn(move || {
+                let stream = TcpStream::connect(bob_addr).unwrap();
+                SessionCryptData::start_pairing(stream, &IdentityKey::generate(), "alice", "0000", &mut PinStore::new()).is_err()
+            });
+            let stream = alice_listener.accept().unwrap().0;
+            let to_alice = SessionCryptData::recieve_pairing(stream, &IdentityKey::generate(), "bob", "0000", &mut PinStore::new());
+            (to_alice.is_err(), to_bob.join().unwrap())
+        });
+        let alice = thread::spawn(move || {
+            let stream = TcpStream::connect(mallory_addr).unwrap();
+            let mut pins = PinStore::new();
+            let result = SessionCryptData::start_pairing(stream, &IdentityKey::generate(), "alice", "4711", &mut pins);
+            result.is_err() && pins.get("bob").is_none()
+        });
+
+        let mut bob_pins = PinStore::new();
+        let stream = bob_listener.accept().unwrap().0;
+        let bob = SessionCryptData::recieve_pairing(stream, &IdentityKey::generate(), "bob", "4711", &mut bob_pins);
+
+        assert!(bob.is_err());
+        assert!(bob_pins.get("alice").is_none());
+        assert!(alice.join().unwrap());
+        assert_eq!(mallory.join().unwrap(), (true, true));
+    }
+
+    #[test]
+    fn test_session_rng_is_wiped() {
+        // A wiped generator has nothing left of its seed or position
+        let mut rng = ChaCha20Rng::from_seed([7; 32]);
+        rng.next_u64();
+        let mut session_rng = SessionRandom(Box::new(rng.clone()));
+        session_rng.0.wipe();
+        let (mut wiped, mut blank) = ([0u8; 64], [0u8; 64]);
+        session_rng.fill_bytes(&mut wiped);
+        ChaCha20Rng::from_seed([0; 32]).fill_bytes(&mut blank);
+        assert_eq!(wiped, blank);
+        assert_ne!(rng.next_u64().to_le_bytes(), wiped[..8]);
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn test_known_answer_handshake_and_frame() {
+        // With a seeded RNG the whole exchange is reproducible byte for byte. The test plays a classic
+        // responder by hand and checks exactly what goes over the wire.
+        let (client, mut server) = setup_tcp_pair();
+        let client_thread = thread::spawn(move || {
+            let mut session = SessionBuilder::new()
+                .key_exchange(KeyExchange::Classic)
+                .rng(ChaCha20Rng::from_seed([1; 32]))
+                .clock(ManualClock::new(1_700_000_000))
+                .start(client)
+                .unwrap();
+            let msg = Message {
+                sender_id: "alice".to_string(),
+                to_id: "bob".to_string(),
+                contents: "known answer".to_string(),
+                timestamp: session.timestamp(),
+            };
+            session.send_message(msg).unwrap();
+        });
+
+        let request = HandshakeData::read_length_prefixed(&mut server).unwrap();
+        assert_eq!(hex(&request), "d53b9fea1dd4d570dbe38180767c236456ca6633f103f1aa0637e7b613d2527400");
+
+        let responder_secret = EphemeralSecret::random_from_rng(ChaCha20Rng::from_seed([2; 32]));
+        let responder_public = PublicKey::from(&responder_secret);
+        let response = HandshakeData{ public_key: responder_public.to_bytes(), kem: None };
+        HandshakeData::write_length_prefixed(&mut server, &bincode::serialize(&response).unwrap()).unwrap();
+
+        let frame = HandshakeData::read_length_prefixed(&mut server).unwrap();
+        client_thread.join().unwrap();
+        assert_eq!(hex(&frame), concat!(
+            "1ecc3686b60ee3b84b6c7d321d70d5c06e9dac63a4d0a79d", // nonce
+            "3c00000000000000", // ciphertext length
+            "f8be350e2628a147f90a2261b0e0aa3479330dc7531e787549f3a82d356986c0",
+            "b57668e1c277bad70873277bc1afe03fb31fdb8fdb0a051b30b518bc",
+        ));
+
+        // And the frame decrypts to the stamped message under the classic key
+        let shared = responder_secret.diffie_hellman(&PublicKey::from(HandshakeData::decode(&request).unwrap().public_key));
+        let cipher = XChaCha20Poly1305::new_from_slice(SessionCryptData::<TcpStream>::derive_key(shared.as_bytes()).as_ref()).unwrap();
+        let frame: EncryptedMessage = bincode::deserialize(&frame).unwrap();
+        let plaintext = cipher.decrypt(XNonce::from_slice(&frame.nonce), frame.ciphertext.as_ref()).unwrap();
+        let message: String = bincode::deserialize(&plaintext).unwrap();
+        assert_eq!(message, "alice -> bob\n1700000000\nknown answer");
+    }
+
+    #[test]
+    fn test_seeded_sessions_are_reproducible() {
+        let run = || {
+            let (client, server) = setup_tcp_pair();
+            let client_thread = thread::spawn(move || {
+                let session = SessionBuilder::new().rng(ChaCha20Rng::from_seed([3; 32])).start(client).unwrap();
+                session.key_exchange()
+            });
+            let mut session = SessionBuilder::new().rng(ChaCha20Rng::from_seed([4; 32])).recieve(server).unwrap();
+            assert_eq!(client_thread.join().unwrap(), KeyExchange::Hybrid);
+            let mut nonce = [0u8; 24];
+            session.rng.fill_bytes(&mut nonce);
+            nonce
+        };
+        assert_eq!(run(), run());
+    }
+
+    #[test]
+    fn test_clock_skew() {
+        let (client, server) = setup_tcp_pair();
+        let client_clock = ManualClock::new(1_700_000_000);
+        let skewed = client_clock.clone();
+        let client_thread = thread::spawn(move || {
+            let mut session = SessionBuilder::new().clock(skewed).start(client).unwrap();
+            let msg = Message {
+                sender_id: "client".to_string(),
+                to_id: "server".to_string(),
+                contents: "what time is it?".to_string(),
+                timestamp: session.timestamp(),
+            };
+            session.send_message(msg).unwrap();
+        });
+        let server_clock = ManualClock::new(1_700_000_000);
+        server_clock.advance(std::time::Duration::from_secs(90));
+        let mut session = SessionBuilder::new().clock(server_clock).recieve(server).unwrap();
+        client_thread.join().unwrap();
+
+        session.wait_data_available().unwrap();
+        let received = session.recieve_message().unwrap();
+        assert_eq!(received.timestamp, 1_700_000_000);
+        assert_eq!(session.timestamp() - received.timestamp, 90);
+        client_clock.advance(std::time::Duration::from_secs(5));
+        assert_eq!(client_clock.unix_time(), 1_700_000_005);
+    }
+
+    #[test]
+    fn test_oversized_frame_rejected() {
+        let mut data = &((MAX_FRAME_LEN as u32 + 1).to_be_bytes())[..];
+        assert_eq!(HandshakeData::read_length_prefixed(&mut data).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_truncated_message_rejected() {
+        for text in ["", "alice -> bob", "alice\n12"] {
+            assert!(Message::from_string(text.to_string()).is_err());
+        }
+    }
+
+    #[test]
+    fn test_garbage_frame_keeps_session_usable() {
+        let (alice_end, bob_end) = MemoryTransport::pair();
+        let alice = thread::spawn(move || SessionCryptData::start_session(alice_end).unwrap());
+        let mut bob = SessionCryptData::recieve_session(bob_end).unwrap();
+        let mut alice = alice.join().unwrap();
+
+        bob.get_ref().with_inbox(|inbox| inbox.extend([0, 0, 0, 3, 1, 2, 3]));
+        assert!(bob.check_data_available().unwrap());
+        assert!(bob.recieve_message().is_err());
+        // The failed read must leave the stream non-blocking, or this would hang
+        assert!(!bob.check_data_available().unwrap());
+
+        alice.send_message(Message {
+            sender_id: "alice".to_string(),
+            to_id: "bob".to_string(),
+            contents: "after the noise".to_string(),
+            timestamp: 0,
+        }).unwrap();
+        assert_eq!(bob.recieve_message().unwrap().contents, "after the noise");
+    }
 }
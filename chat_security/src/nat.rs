@@ -0,0 +1,144 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{self, Error, ErrorKind};
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread;
+use std::time::Duration;
+
+use crate::udp::Datagram;
+
+/*
+    NAT emulator
+    A host behind a NAT, for testing hole punching without network namespaces. Its datagrams leave
+    through real UDP sockets on 127.0.0.1, which play the NAT's public side, and replies only get back
+    in from endpoints the host has already sent to, as with a port-restricted cone NAT.
+ */
+
+/// How the NAT picks the public port for outgoing traffic.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Mapping{
+    /// One public port for everything, so the endpoint a rendezvous server sees is the one peers can punch to.
+    EndpointIndependent,
+    /// A fresh public port per destination, which defeats hole punching as real symmetric NATs do.
+    Symmetric,
+}
+
+struct Binding{
+    public: UdpSocket,
+    permitted: Mutex<HashSet<SocketAddr>>,
+}
+
+struct Inside{
+    inbox: Mutex<VecDeque<(Vec<u8>, SocketAddr)>>,
+    ready: Condvar,
+    loss: AtomicU32,
+    packets: AtomicU32,
+}
+
+impl Inside{
+    /// Whether to drop the next datagram, in either direction.
+    fn lose(&self) -> bool{
+        let every = self.loss.load(Ordering::Relaxed);
+        every != 0 && self.packets.fetch_add(1, Ordering::Relaxed) % every == every - 1
+    }
+}
+
+/// Socket for a host behind an emulated NAT.
+pub struct NatSocket{
+    mapping: Mapping,
+    bindings: Mutex<HashMap<Option<SocketAddr>, Arc<Binding>>>,
+    inside: Arc<Inside>,
+    read_timeout: Mutex<Option<Duration>>,
+}
+
+impl NatSocket{
+    pub fn bind(mapping: Mapping) -> Result<Self, io::Error>{
+        Ok(NatSocket{
+            mapping,
+            bindings: Mutex::new(HashMap::new()),
+            inside: Arc::new(Inside{
+                inbox: Mutex::new(VecDeque::new()),
+                ready: Condvar::new(),
+                loss: AtomicU32::new(0),
+                packets: AtomicU32::new(0),
+            }),
+            read_timeout: Mutex::new(None),
+        })
+    }
+
+    /// Drops every `every`th datagram passing through the NAT, or none if 0.
+    pub fn set_loss(&self, every: u32){
+        self.inside.loss.store(every, Ordering::Relaxed);
+    }
+
+    fn binding(&self, destination: SocketAddr) -> Result<Arc<Binding>, io::Error>{
+        let key = match self.mapping{
+            Mapping::EndpointIndependent => None,
+            Mapping::Symmetric => Some(destination),
+        };
+        let mut bindings = self.bindings.lock().unwrap();
+        if let Some(binding) = bindings.get(&key){
+            return Ok(binding.clone());
+        }
+        let public = UdpSocket::bind("127.0.0.1:0")?;
+        public.set_read_timeout(Some(Duration::from_millis(100)))?;
+        let binding = Arc::new(Binding{ public, permitted: Mutex::new(HashSet::new()) });
+        let (forwarding, inside) = (binding.clone(), Arc::downgrade(&self.inside));
+        thread::spawn(move || forward_inbound(&forwarding, inside));
+        bindings.insert(key, binding.clone());
+        Ok(binding)
+    }
+}
+
+/// Lets datagrams from permitted endpoints through to the host, until the host's socket is dropped.
+fn forward_inbound(binding: &Binding, inside: Weak<Inside>){
+    let mut buf = [0u8; 65536];
+    loop{
+        let received = binding.public.recv_from(&mut buf);
+        let Some(inside) = inside.upgrade() else { return };
+        let Ok((n, from)) = received else { continue };
+        if binding.permitted.lock().unwrap().contains(&from) && !inside.lose(){
+            inside.inbox.lock().unwrap().push_back((buf[..n].to_vec(), from));
+            inside.ready.notify_all();
+        }
+    }
+}
+
+impl Datagram for NatSocket{
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize>{
+        let binding = self.binding(addr)?;
+        binding.permitted.lock().unwrap().insert(addr);
+        if self.inside.lose(){
+            return Ok(buf.len());
+        }
+        binding.public.send_to(buf, addr)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)>{
+        let read_timeout = *self.read_timeout.lock().unwrap();
+        let mut inbox = self.inside.inbox.lock().unwrap();
+        loop{
+            if let Some((datagram, from)) = inbox.pop_front(){
+                let n = buf.len().min(datagram.len());
+                buf[..n].copy_from_slice(&datagram[..n]);
+                return Ok((n, from));
+            }
+            inbox = match read_timeout{
+                Some(timeout) => {
+                    let (inbox, wait) = self.inside.ready.wait_timeout(inbox, timeout).unwrap();
+                    if wait.timed_out() && inbox.is_empty(){
+                        return Err(Error::new(ErrorKind::WouldBlock, "Read timed out"));
+                    }
+                    inbox
+                }
+                None => self.inside.ready.wait(inbox).unwrap(),
+            };
+        }
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>{
+        *self.read_timeout.lock().unwrap() = timeout;
+        Ok(())
+    }
+}
@@ -0,0 +1,125 @@
+use std::io::{Error, ErrorKind};
+
+use curve25519_dalek::ristretto::{CompressedRistretto, RistrettoPoint};
+use curve25519_dalek::scalar::Scalar;
+use rand::{CryptoRng, RngCore};
+use sha2::{Digest, Sha512};
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
+
+/*
+    CPace over ristretto255/SHA-512, following draft-irtf-cfrg-cpace.
+
+    Both peers derive the same generator from the shared pairing code and the session id, then run
+    an ephemeral Diffie-Hellman on that generator. Without the code, a man in the middle can only
+    test one guess per connection attempt.
+ */
+const DSI: &[u8] = b"CPaceRistretto255";
+const CHANNEL_ID: &[u8] = b"rustchat pairing";
+const HASH_BLOCK_SIZE: usize = 128;
+
+fn prepend_len(data: &[u8]) -> Vec<u8>{
+    // LEB128 length prefix
+    let mut out = Vec::with_capacity(data.len() + 2);
+    let mut len = data.len();
+    loop{
+        let byte = (len & 0x7f) as u8;
+        len >>= 7;
+        if len == 0{
+            out.push(byte);
+            break;
+        }
+        out.push(byte | 0x80);
+    }
+    out.extend_from_slice(data);
+    out
+}
+
+fn lv_cat(parts: &[&[u8]]) -> Vec<u8>{
+    parts.iter().flat_map(|part| prepend_len(part)).collect()
+}
+
+fn generator(password: &[u8], sid: &[u8]) -> RistrettoPoint{
+    let zpad_len = HASH_BLOCK_SIZE
+        .saturating_sub(1 + Zeroizing::new(prepend_len(password)).len() + prepend_len(DSI).len());
+    let zpad = vec![0u8; zpad_len];
+    let gen_str = Zeroizing::new(lv_cat(&[DSI, password, &zpad, CHANNEL_ID, sid]));
+    let uniform: Zeroizing<[u8; 64]> = Zeroizing::new(Sha512::digest(&*gen_str).into());
+    RistrettoPoint::from_uniform_bytes(&uniform)
+}
+
+/// One side of a CPace run: holds the secret scalar until the peer's share arrives.
+#[derive(Zeroize, ZeroizeOnDrop)]
+pub(crate) struct Cpace{
+    scalar: Scalar,
+    share: [u8; 32],
+    sid: Vec<u8>,
+}
+
+impl Cpace{
+    pub(crate) fn start<R: RngCore + CryptoRng>(password: &[u8], sid: &[u8], rng: &mut R) -> Self{
+        let mut wide = Zeroizing::new([0u8; 64]);
+        rng.fill_bytes(wide.as_mut());
+        let scalar = Scalar::from_bytes_mod_order_wide(&wide);
+        let share = (generator(password, sid) * scalar).compress().to_bytes();
+        Cpace{ scalar, share, sid: sid.to_vec() }
+    }
+
+    pub(crate) fn share(&self) -> [u8; 32]{
+        self.share
+    }
+
+    /// Combines our secret with the peer's share into the intermediate session key (ISK). Shares are
+    /// ordered initiator first so both sides hash the same transcript.
+    pub(crate) fn finish(self, peer_share: &[u8; 32], initiator: bool) -> Result<Zeroizing<[u8; 64]>, std::io::Error>{
+        let peer = CompressedRistretto(*peer_share).decompress()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Invalid pairing share"))?;
+        let mut k = (peer * self.scalar).compress();
+        if k == RistrettoPoint::default().compress(){
+            return Err(Error::new(ErrorKind::InvalidData, "Invalid pairing share"));
+        }
+        let (ya, yb) = if initiator { (&self.share, peer_share) } else { (peer_share, &self.share) };
+
+        let mut hasher = Sha512::new();
+        hasher.update(lv_cat(&[&[DSI, b"_ISK"].concat(), &self.sid, k.as_bytes()]));
+        hasher.update(lv_cat(&[ya, b""]));
+        hasher.update(lv_cat(&[yb, b""]));
+        k.0.zeroize();
+        Ok(Zeroizing::new(hasher.finalize().into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::OsRng;
+
+    #[test]
+    fn test_matching_passwords_agree() {
+        let a = Cpace::start(b"1234", b"sid", &mut OsRng);
+        let b = Cpace::start(b"1234", b"sid", &mut OsRng);
+        let (share_a, share_b) = (a.share(), b.share());
+        assert_eq!(*a.finish(&share_b, true).unwrap(), *b.finish(&share_a, false).unwrap());
+    }
+
+    #[test]
+    fn test_mismatched_passwords_disagree() {
+        let a = Cpace::start(b"1234", b"sid", &mut OsRng);
+        let b = Cpace::start(b"4321", b"sid", &mut OsRng);
+        let (share_a, share_b) = (a.share(), b.share());
+        assert_ne!(*a.finish(&share_b, true).unwrap(), *b.finish(&share_a, false).unwrap());
+    }
+
+    #[test]
+    fn test_identity_share_rejected() {
+        let a = Cpace::start(b"1234", b"sid", &mut OsRng);
+        assert!(a.finish(&[0u8; 32], true).is_err());
+    }
+
+    #[test]
+    fn test_secret_scalar_is_wiped() {
+        let mut a = Cpace::start(b"1234", b"sid", &mut OsRng);
+        assert_ne!(a.scalar, Scalar::ZERO);
+        a.zeroize();
+        assert_eq!((a.scalar, a.share, a.sid.len()), (Scalar::ZERO, [0; 32], 0));
+    }
+}
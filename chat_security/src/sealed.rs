@@ -0,0 +1,111 @@
+use std::io::{Error, ErrorKind};
+
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use hkdf::Hkdf;
+use rand::{CryptoRng, RngCore};
+use sha2::Sha256;
+use zeroize::{ZeroizeOnDrop, Zeroizing};
+
+use crate::x3dh::{dh, public_of, random_secret};
+use crate::{IdentityKey, IdentityPublic};
+
+/*
+    Sealed datagrams
+    For request/response exchanges that can't carry a handshake, such as UDP rendezvous with the name
+    server. The client encrypts its request under a fresh X25519 key to the server's identity key (in
+    its X25519 form, as in X3DH), so only the server can read it, and only the server can answer with
+    something that opens under the same key.
+
+        request = ephemeral public (32) || nonce (24) || ciphertext
+        reply   = nonce (24) || ciphertext
+ */
+
+const KDF_INFO: &[u8] = b"rustchat sealed datagram";
+const NONCE_LEN: usize = 24;
+
+/// Key for one sealed request and the replies to it.
+#[derive(ZeroizeOnDrop)]
+pub struct DatagramKey{
+    cipher: XChaCha20Poly1305,
+}
+
+impl DatagramKey{
+    fn derive(shared: &Zeroizing<[u8; 32]>, ephemeral: &[u8; 32], server: &[u8; 32]) -> Self{
+        let mut key = Zeroizing::new([0u8; 32]);
+        let info = [KDF_INFO, ephemeral, server].concat();
+        Hkdf::<Sha256>::new(None, shared.as_ref()).expand(&info, key.as_mut()).unwrap();
+        DatagramKey{ cipher: XChaCha20Poly1305::new_from_slice(key.as_ref()).unwrap() }
+    }
+
+    fn seal<R: RngCore + CryptoRng>(&self, plaintext: &[u8], aad: &[u8], rng: &mut R) -> Vec<u8>{
+        let nonce = XChaCha20Poly1305::generate_nonce(rng);
+        let ciphertext = self.cipher.encrypt(&nonce, Payload{ msg: plaintext, aad })
+            .expect("Failed to encrypt datagram");
+        [nonce.as_slice(), &ciphertext].concat()
+    }
+
+    fn open(&self, sealed: &[u8], aad: &[u8]) -> Result<Vec<u8>, std::io::Error>{
+        if sealed.len() < NONCE_LEN{
+            return Err(Error::new(ErrorKind::InvalidData, "Sealed datagram too short"));
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        self.cipher.decrypt(XNonce::from_slice(nonce), Payload{ msg: ciphertext, aad })
+            .map_err(|_| Error::new(ErrorKind::PermissionDenied, "Failed to decrypt datagram"))
+    }
+
+    /// Encrypts a request only `server` can read, returning the key its replies will open under.
+    pub fn seal_request<R: RngCore + CryptoRng>(server: &IdentityPublic, plaintext: &[u8], rng: &mut R)
+        -> Result<(Self, Vec<u8>), std::io::Error>{
+        let server = server.dh_public()?;
+        let secret = random_secret(rng);
+        let ephemeral = public_of(&secret);
+        let key = Self::derive(&dh(&secret, &server)?, &ephemeral, &server);
+        let sealed = key.seal(plaintext, b"request", rng);
+        Ok((key, [ephemeral.as_slice(), &sealed].concat()))
+    }
+
+    /// Decrypts a request sealed to `identity`, returning the key to answer it with.
+    pub fn open_request(identity: &IdentityKey, datagram: &[u8]) -> Result<(Self, Vec<u8>), std::io::Error>{
+        if datagram.len() < 32{
+            return Err(Error::new(ErrorKind::InvalidData, "Sealed datagram too short"));
+        }
+        let (ephemeral, sealed) = datagram.split_at(32);
+        let ephemeral: [u8; 32] = ephemeral.try_into().unwrap();
+        let server = identity.public().dh_public()?;
+        let key = Self::derive(&dh(&identity.dh_secret(), &ephemeral)?, &ephemeral, &server);
+        let plaintext = key.open(sealed, b"request")?;
+        Ok((key, plaintext))
+    }
+
+    pub fn seal_reply<R: RngCore + CryptoRng>(&self, plaintext: &[u8], rng: &mut R) -> Vec<u8>{
+        self.seal(plaintext, b"reply", rng)
+    }
+
+    pub fn open_reply(&self, datagram: &[u8]) -> Result<Vec<u8>, std::io::Error>{
+        self.open(datagram, b"reply")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::OsRng;
+
+    #[test]
+    fn test_request_and_reply() {
+        let server = IdentityKey::generate();
+        let (client_key, request) = DatagramKey::seal_request(&server.public(), b"listen", &mut OsRng).unwrap();
+        let (server_key, opened) = DatagramKey::open_request(&server, &request).unwrap();
+        assert_eq!(opened, b"listen");
+        let reply = server_key.seal_reply(b"listening", &mut OsRng);
+        assert_eq!(client_key.open_reply(&reply).unwrap(), b"listening");
+
+        // Only the server can read requests, and requests can't pass for replies
+        assert!(DatagramKey::open_request(&IdentityKey::generate(), &request).is_err());
+        assert!(client_key.open_reply(&request[32..]).is_err());
+        let mut tampered = reply.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(client_key.open_reply(&tampered).is_err());
+    }
+}
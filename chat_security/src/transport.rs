@@ -0,0 +1,157 @@
+use std::collections::VecDeque;
+use std::io::{self, Error, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+/// Byte stream a session runs over. `TcpStream` is the usual one; anything reliable and ordered works.
+pub trait Transport: Read + Write + Send{
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+
+    /// Like `TcpStream::peek`: returns `Ok(0)` once the peer has closed, `WouldBlock` if nothing is buffered
+    /// while non-blocking.
+    fn peek(&self, buf: &mut [u8]) -> io::Result<usize>;
+}
+
+impl Transport for TcpStream{
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>{
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+
+    fn peek(&self, buf: &mut [u8]) -> io::Result<usize>{
+        TcpStream::peek(self, buf)
+    }
+}
+
+// Lets callers pick the transport at runtime, e.g. whichever of several connection attempts succeeded
+impl<T: Transport + ?Sized> Transport for Box<T>{
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>{
+        (**self).set_nonblocking(nonblocking)
+    }
+
+    fn peek(&self, buf: &mut [u8]) -> io::Result<usize>{
+        (**self).peek(buf)
+    }
+}
+
+#[derive(Default)]
+struct PipeState{
+    buffer: VecDeque<u8>,
+    closed: bool,
+}
+
+#[derive(Default)]
+struct Pipe{
+    state: Mutex<PipeState>,
+    ready: Condvar,
+}
+
+impl Pipe{
+    fn close(&self){
+        self.state.lock().unwrap().closed = true;
+        self.ready.notify_all();
+    }
+}
+
+/// In-process transport: one end of a pair of pipes, for running sessions without sockets.
+pub struct MemoryTransport{
+    incoming: Arc<Pipe>,
+    outgoing: Arc<Pipe>,
+    nonblocking: AtomicBool,
+    read_timeout: Mutex<Option<Duration>>,
+}
+
+impl MemoryTransport{
+    pub fn pair() -> (MemoryTransport, MemoryTransport){
+        let (a, b) = (Arc::new(Pipe::default()), Arc::new(Pipe::default()));
+        let end = |incoming: &Arc<Pipe>, outgoing: &Arc<Pipe>| MemoryTransport{
+            incoming: incoming.clone(),
+            outgoing: outgoing.clone(),
+            nonblocking: AtomicBool::new(false),
+            read_timeout: Mutex::new(None),
+        };
+        (end(&a, &b), end(&b, &a))
+    }
+
+    /// Blocking reads give up with `TimedOut` after `timeout` without data.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>){
+        *self.read_timeout.lock().unwrap() = timeout;
+    }
+
+    /// Runs `f` on the bytes buffered for this end but not yet read, e.g. to inject or corrupt traffic.
+    pub fn with_inbox<R>(&self, f: impl FnOnce(&mut VecDeque<u8>) -> R) -> R{
+        let result = f(&mut self.incoming.state.lock().unwrap().buffer);
+        self.incoming.ready.notify_all();
+        result
+    }
+}
+
+impl Read for MemoryTransport{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>{
+        let read_timeout = *self.read_timeout.lock().unwrap();
+        let mut state = self.incoming.state.lock().unwrap();
+        while state.buffer.is_empty() && !state.closed {
+            if self.nonblocking.load(Ordering::Relaxed) {
+                return Err(Error::new(ErrorKind::WouldBlock, "No data available"));
+            }
+            state = match read_timeout {
+                Some(timeout) => {
+                    let (state, wait) = self.incoming.ready.wait_timeout(state, timeout).unwrap();
+                    if wait.timed_out() && state.buffer.is_empty() {
+                        return Err(Error::new(ErrorKind::TimedOut, "Read timed out"));
+                    }
+                    state
+                }
+                None => self.incoming.ready.wait(state).unwrap(),
+            };
+        }
+        let n = buf.len().min(state.buffer.len());
+        for (slot, byte) in buf.iter_mut().zip(state.buffer.drain(..n)) {
+            *slot = byte;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for MemoryTransport{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize>{
+        let mut state = self.outgoing.state.lock().unwrap();
+        if state.closed {
+            return Err(Error::new(ErrorKind::BrokenPipe, "Peer closed the connection"));
+        }
+        state.buffer.extend(buf);
+        self.outgoing.ready.notify_all();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()>{
+        Ok(())
+    }
+}
+
+impl Transport for MemoryTransport{
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>{
+        self.nonblocking.store(nonblocking, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn peek(&self, buf: &mut [u8]) -> io::Result<usize>{
+        let state = self.incoming.state.lock().unwrap();
+        if state.buffer.is_empty() {
+            return if state.closed { Ok(0) } else { Err(Error::new(ErrorKind::WouldBlock, "No data available")) };
+        }
+        let n = buf.len().min(state.buffer.len());
+        for (slot, byte) in buf.iter_mut().zip(state.buffer.iter()) {
+            *slot = *byte;
+        }
+        Ok(n)
+    }
+}
+
+impl Drop for MemoryTransport{
+    fn drop(&mut self){
+        self.incoming.close();
+        self.outgoing.close();
+    }
+}
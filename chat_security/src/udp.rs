@@ -0,0 +1,484 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{self, Error, ErrorKind, Read, Write};
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::transport::Transport;
+
+/*
+    Reliable transport over UDP
+    Sessions expect an ordered byte stream, so for peers that could only reach each other by hole
+    punching this numbers datagrams, acknowledges them cumulatively and retransmits what goes
+    unacknowledged: the first missing segment after three duplicate acks, or after `RETRANSMIT_TIMEOUT`
+    of silence. The receiver holds segments that arrive early until the gap before them is filled.
+    A background thread per connection does the receiving and retransmitting.
+
+    `punch` runs first: both peers send to each other's public endpoint (as seen by the name server)
+    until each has heard from the other, which opens the path through both NATs.
+ */
+
+/// Payload bytes per datagram, small enough to avoid fragmentation on any common path.
+const MAX_PAYLOAD: usize = 1200;
+/// Segments in flight before writes block.
+const WINDOW: usize = 64;
+const RETRANSMIT_TIMEOUT: Duration = Duration::from_millis(200);
+/// Retransmissions of the same segment before the peer is given up on.
+const MAX_RETRANSMITS: u32 = 25;
+/// An idle connection still sends this often, so NAT mappings stay open and the peer knows we're there.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+const PEER_TIMEOUT: Duration = Duration::from_secs(60);
+const PUNCH_INTERVAL: Duration = Duration::from_millis(100);
+const TICK: Duration = Duration::from_millis(20);
+
+/// A datagram socket the transport can run over. `UdpSocket` is the usual one.
+pub trait Datagram: Send + Sync + 'static{
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize>;
+
+    /// Like `UdpSocket::recv_from`: fails with `WouldBlock` or `TimedOut` once the read timeout passes.
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)>;
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl Datagram for UdpSocket{
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize>{
+        UdpSocket::send_to(self, buf, addr)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)>{
+        UdpSocket::recv_from(self, buf)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>{
+        UdpSocket::set_read_timeout(self, timeout)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+enum Packet{
+    /// Hole punching; `seen` once we have heard from the peer.
+    Punch{ seen: bool },
+    Data{ seq: u64, payload: Vec<u8> },
+    /// End of stream, sequenced like data so it arrives after all of it.
+    Fin{ seq: u64 },
+    /// Everything before `next` has arrived.
+    Ack{ next: u64 },
+}
+
+impl Packet{
+    fn seq(&self) -> u64{
+        match self{
+            Packet::Data{ seq, .. } | Packet::Fin{ seq } => *seq,
+            Packet::Punch{ .. } | Packet::Ack{ .. } => 0,
+        }
+    }
+}
+
+fn send_packet<S: Datagram>(socket: &S, packet: &Packet, peer: SocketAddr) -> io::Result<()>{
+    let data = bincode::serialize(packet).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+    socket.send_to(&data, peer).map(|_| ())
+}
+
+fn recv_packet<S: Datagram>(socket: &S, buf: &mut [u8]) -> io::Result<Option<(Packet, SocketAddr)>>{
+    match socket.recv_from(buf){
+        // Datagrams that don't decode are someone else's traffic
+        Ok((n, from)) => Ok(bincode::deserialize(&buf[..n]).ok().map(|packet| (packet, from))),
+        Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Opens a path to `peer` through both sides' NATs by sending to it until it has heard from us and we from it.
+/// The peer must be punching towards our public endpoint at the same time.
+pub fn punch<S: Datagram>(socket: &S, peer: SocketAddr, timeout: Duration) -> Result<(), io::Error>{
+    socket.set_read_timeout(Some(PUNCH_INTERVAL))?;
+    let deadline = Instant::now() + timeout;
+    let mut buf = [0u8; 2048];
+    let mut seen = false;
+    while Instant::now() < deadline{
+        send_packet(socket, &Packet::Punch{ seen }, peer)?;
+        let Some((packet, from)) = recv_packet(socket, &mut buf)? else { continue };
+        if from != peer{
+            continue;
+        }
+        match packet{
+            Packet::Punch{ seen: false } => seen = true,
+            Packet::Punch{ seen: true } => {
+                // The peer may still be punching; an ack tells it we're through
+                send_packet(socket, &Packet::Ack{ next: 0 }, peer)?;
+                return Ok(());
+            }
+            // The peer finished first and has moved on to the session
+            _ => return Ok(()),
+        }
+    }
+    Err(Error::new(ErrorKind::TimedOut, format!("Hole punching to {} timed out", peer)))
+}
+
+struct Connection{
+    inbox: VecDeque<u8>,
+    /// Segments that arrived ahead of `expected`.
+    early: BTreeMap<u64, Packet>,
+    expected: u64,
+    peer_closed: bool,
+    unacked: VecDeque<Packet>,
+    next_seq: u64,
+    /// While acks stay below this, each one that moves is taken to point at the next lost segment.
+    recover: u64,
+    /// When the oldest unacknowledged segment was last sent.
+    sent_at: Instant,
+    retransmits: u32,
+    duplicate_acks: u32,
+    last_sent: Instant,
+    last_heard: Instant,
+    closing: bool,
+    error: Option<ErrorKind>,
+}
+
+struct Shared<S>{
+    socket: S,
+    peer: SocketAddr,
+    state: Mutex<Connection>,
+    ready: Condvar,
+}
+
+impl<S: Datagram> Shared<S>{
+    fn send(&self, state: &mut Connection, packet: &Packet){
+        // Lost sends look like lost datagrams and are retransmitted the same way
+        let _ = send_packet(&self.socket, packet, self.peer);
+        state.last_sent = Instant::now();
+    }
+
+    fn retransmit(&self, state: &mut Connection, packet: &Packet){
+        self.send(state, packet);
+        state.sent_at = Instant::now();
+        state.recover = state.next_seq;
+    }
+
+    fn fail(&self, state: &mut Connection, kind: ErrorKind){
+        state.error.get_or_insert(kind);
+        self.ready.notify_all();
+    }
+
+    fn receive(&self, state: &mut Connection, packet: Packet){
+        state.last_heard = Instant::now();
+        match packet{
+            Packet::Data{ seq, .. } | Packet::Fin{ seq } => {
+                if seq >= state.expected && seq < state.expected + WINDOW as u64{
+                    state.early.insert(seq, packet);
+                }
+                while let Some(packet) = state.early.remove(&state.expected){
+                    match packet{
+                        Packet::Data{ payload, .. } => state.inbox.extend(payload),
+                        _ => state.peer_closed = true,
+                    }
+                    state.expected += 1;
+                    self.ready.notify_all();
+                }
+                let ack = Packet::Ack{ next: state.expected };
+                self.send(state, &ack);
+            }
+            Packet::Ack{ next } => {
+                let before = state.unacked.len();
+                while state.unacked.front().is_some_and(|packet| packet.seq() < next){
+                    state.unacked.pop_front();
+                }
+                let front = state.unacked.front().cloned();
+                if state.unacked.len() < before{
+                    state.sent_at = Instant::now();
+                    state.retransmits = 0;
+                    state.duplicate_acks = 0;
+                    self.ready.notify_all();
+                    if let Some(front) = front.filter(|_| next < state.recover){
+                        self.send(state, &front);
+                    }
+                }
+                else if let Some(front) = front{
+                    state.duplicate_acks += 1;
+                    if state.duplicate_acks == 3{
+                        self.retransmit(state, &front);
+                    }
+                }
+            }
+            // The peer missed the end of punching; an ack tells it we're through
+            Packet::Punch{ .. } => {
+                let ack = Packet::Ack{ next: state.expected };
+                self.send(state, &ack);
+            }
+        }
+    }
+
+    /// Retransmits and keepalives. Returns false once the connection has nothing left to do.
+    fn tick(&self, state: &mut Connection) -> bool{
+        let now = Instant::now();
+        if let Some(front) = state.unacked.front().cloned(){
+            if now.duration_since(state.sent_at) >= RETRANSMIT_TIMEOUT{
+                state.retransmits += 1;
+                if state.retransmits > MAX_RETRANSMITS{
+                    self.fail(state, ErrorKind::TimedOut);
+                    return false;
+                }
+                self.retransmit(state, &front);
+            }
+        }
+        else if state.closing{
+            return false;
+        }
+        if now.duration_since(state.last_heard) >= PEER_TIMEOUT{
+            self.fail(state, ErrorKind::TimedOut);
+            return false;
+        }
+        if now.duration_since(state.last_sent) >= KEEPALIVE_INTERVAL{
+            let ack = Packet::Ack{ next: state.expected };
+            self.send(state, &ack);
+        }
+        true
+    }
+
+    fn run(&self){
+        let mut buf = [0u8; 2048];
+        if let Err(e) = self.socket.set_read_timeout(Some(TICK)){
+            return self.fail(&mut self.state.lock().unwrap(), e.kind());
+        }
+        loop{
+            let received = recv_packet(&self.socket, &mut buf);
+            let mut state = self.state.lock().unwrap();
+            match received{
+                Ok(Some((packet, from))) if from == self.peer => self.receive(&mut state, packet),
+                Ok(_) => {}
+                Err(e) => return self.fail(&mut state, e.kind()),
+            }
+            if !self.tick(&mut state){
+                // Reads still drain what arrived, then see the end of the stream
+                state.peer_closed = true;
+                self.ready.notify_all();
+                return;
+            }
+        }
+    }
+}
+
+/// Reliable, ordered byte stream to one peer over a datagram socket, usually after `punch`.
+pub struct UdpTransport<S: Datagram = UdpSocket>{
+    shared: Arc<Shared<S>>,
+    nonblocking: AtomicBool,
+    read_timeout: Mutex<Option<Duration>>,
+}
+
+impl<S: Datagram> UdpTransport<S>{
+    /// Starts the connection to `peer`. Datagrams from anyone else on `socket` are ignored from here on.
+    pub fn new(socket: S, peer: SocketAddr) -> Self{
+        let now = Instant::now();
+        let shared = Arc::new(Shared{
+            socket,
+            peer,
+            state: Mutex::new(Connection{
+                inbox: VecDeque::new(),
+                early: BTreeMap::new(),
+                expected: 0,
+                peer_closed: false,
+                unacked: VecDeque::new(),
+                next_seq: 0,
+                recover: 0,
+                sent_at: now,
+                retransmits: 0,
+                duplicate_acks: 0,
+                last_sent: now,
+                last_heard: now,
+                closing: false,
+                error: None,
+            }),
+            ready: Condvar::new(),
+        });
+        let background = shared.clone();
+        thread::spawn(move || background.run());
+        UdpTransport{ shared, nonblocking: AtomicBool::new(false), read_timeout: Mutex::new(None) }
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr{
+        self.shared.peer
+    }
+
+    /// Blocking reads give up with `TimedOut` after `timeout` without data.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>){
+        *self.read_timeout.lock().unwrap() = timeout;
+    }
+
+    fn queue(&self, state: &mut Connection, packet: Packet){
+        if state.unacked.is_empty(){
+            state.sent_at = Instant::now();
+        }
+        self.shared.send(state, &packet);
+        state.unacked.push_back(packet);
+        state.next_seq += 1;
+    }
+}
+
+impl<S: Datagram> Read for UdpTransport<S>{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>{
+        let read_timeout = *self.read_timeout.lock().unwrap();
+        let mut state = self.shared.state.lock().unwrap();
+        while state.inbox.is_empty() && !state.peer_closed{
+            if let Some(kind) = state.error{
+                return Err(Error::new(kind, "UDP connection lost"));
+            }
+            if self.nonblocking.load(Ordering::Relaxed){
+                return Err(Error::new(ErrorKind::WouldBlock, "No data available"));
+            }
+            state = match read_timeout{
+                Some(timeout) => {
+                    let (state, wait) = self.shared.ready.wait_timeout(state, timeout).unwrap();
+                    if wait.timed_out() && state.inbox.is_empty(){
+                        return Err(Error::new(ErrorKind::TimedOut, "Read timed out"));
+                    }
+                    state
+                }
+                None => self.shared.ready.wait(state).unwrap(),
+            };
+        }
+        if let (true, Some(kind)) = (state.inbox.is_empty(), state.error){
+            return Err(Error::new(kind, "UDP connection lost"));
+        }
+        let n = buf.len().min(state.inbox.len());
+        for (slot, byte) in buf.iter_mut().zip(state.inbox.drain(..n)){
+            *slot = byte;
+        }
+        Ok(n)
+    }
+}
+
+impl<S: Datagram> Write for UdpTransport<S>{
+    /// Blocks while a full window is unacknowledged, even in non-blocking mode.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize>{
+        let mut state = self.shared.state.lock().unwrap();
+        for chunk in buf.chunks(MAX_PAYLOAD){
+            while state.unacked.len() >= WINDOW && state.error.is_none(){
+                state = self.shared.ready.wait(state).unwrap();
+            }
+            if let Some(kind) = state.error{
+                return Err(Error::new(kind, "UDP connection lost"));
+            }
+            let packet = Packet::Data{ seq: state.next_seq, payload: chunk.to_vec() };
+            self.queue(&mut state, packet);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()>{
+        Ok(())
+    }
+}
+
+impl<S: Datagram> Transport for UdpTransport<S>{
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>{
+        self.nonblocking.store(nonblocking, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn peek(&self, buf: &mut [u8]) -> io::Result<usize>{
+        let state = self.shared.state.lock().unwrap();
+        if state.inbox.is_empty(){
+            return match (state.peer_closed, state.error){
+                (_, Some(kind)) => Err(Error::new(kind, "UDP connection lost")),
+                (true, None) => Ok(0),
+                (false, None) => Err(Error::new(ErrorKind::WouldBlock, "No data available")),
+            };
+        }
+        let n = buf.len().min(state.inbox.len());
+        for (slot, byte) in buf.iter_mut().zip(state.inbox.iter()){
+            *slot = *byte;
+        }
+        Ok(n)
+    }
+}
+
+impl<S: Datagram> Drop for UdpTransport<S>{
+    fn drop(&mut self){
+        // The background thread keeps going until the peer has everything, ending with the Fin
+        let mut state = self.shared.state.lock().unwrap();
+        state.closing = true;
+        if state.error.is_none(){
+            let packet = Packet::Fin{ seq: state.next_seq };
+            self.queue(&mut state, packet);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nat::{Mapping, NatSocket};
+    use crate::{IdentityKey, SessionCryptData};
+
+    /// Stands in for the name server: learns both hosts' public endpoints from one datagram each and tells
+    /// each about the other.
+    fn rendezvous(a: &NatSocket, b: &NatSocket) -> (SocketAddr, SocketAddr) {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_addr = server.local_addr().unwrap();
+        a.send_to(b"hello", server_addr).unwrap();
+        let (_, a_public) = server.recv_from(&mut [0u8; 16]).unwrap();
+        b.send_to(b"hello", server_addr).unwrap();
+        let (_, b_public) = server.recv_from(&mut [0u8; 16]).unwrap();
+        (a_public, b_public)
+    }
+
+    fn punch_pair(a: NatSocket, b: NatSocket) -> (io::Result<UdpTransport<NatSocket>>, io::Result<UdpTransport<NatSocket>>) {
+        let (a_public, b_public) = rendezvous(&a, &b);
+        let b_side = thread::spawn(move || punch(&b, a_public, Duration::from_secs(2)).map(|_| UdpTransport::new(b, a_public)));
+        let a_side = punch(&a, b_public, Duration::from_secs(2)).map(|_| UdpTransport::new(a, b_public));
+        (a_side, b_side.join().unwrap())
+    }
+
+    #[test]
+    fn test_session_through_cone_nats() {
+        let (a, b) = (NatSocket::bind(Mapping::EndpointIndependent).unwrap(), NatSocket::bind(Mapping::EndpointIndependent).unwrap());
+        let (a, b) = punch_pair(a, b);
+        let (a, b) = (a.unwrap(), b.unwrap());
+
+        let (alice, bob) = (IdentityKey::generate(), IdentityKey::generate());
+        let bob_public = bob.public();
+        let responder = thread::spawn(move || SessionCryptData::recieve_authenticated_session(b, &bob, "bob"));
+        let mut alice_session = SessionCryptData::start_authenticated_session(a, &alice, "alice").unwrap();
+        let mut bob_session = responder.join().unwrap().unwrap();
+        assert_eq!(alice_session.peer().unwrap().key, bob_public);
+
+        let message = crate::Message{
+            sender_id: "alice".to_string(), to_id: "bob".to_string(), contents: "punched".to_string(), timestamp: 1,
+        };
+        alice_session.send_message(message).unwrap();
+        assert_eq!(bob_session.recieve_message().unwrap().contents, "punched");
+    }
+
+    #[test]
+    fn test_ordered_delivery_despite_loss() {
+        let (a, b) = (NatSocket::bind(Mapping::EndpointIndependent).unwrap(), NatSocket::bind(Mapping::EndpointIndependent).unwrap());
+        a.set_loss(10);
+        b.set_loss(13);
+        let (a, b) = punch_pair(a, b);
+        let (mut a, mut b) = (a.unwrap(), b.unwrap());
+
+        let sent: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        let expected = sent.clone();
+        let writer = thread::spawn(move || {
+            a.write_all(&sent).unwrap();
+            // Dropping closes the stream once everything is acknowledged
+        });
+        let mut received = Vec::new();
+        b.read_to_end(&mut received).unwrap();
+        writer.join().unwrap();
+        assert!(received == expected, "stream corrupted: {} of {} bytes", received.len(), expected.len());
+    }
+
+    #[test]
+    fn test_symmetric_nats_defeat_punching() {
+        let (a, b) = (NatSocket::bind(Mapping::Symmetric).unwrap(), NatSocket::bind(Mapping::Symmetric).unwrap());
+        let (a, b) = punch_pair(a, b);
+        assert_eq!(a.err().unwrap().kind(), ErrorKind::TimedOut);
+        assert_eq!(b.err().unwrap().kind(), ErrorKind::TimedOut);
+    }
+}
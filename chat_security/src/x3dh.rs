@@ -0,0 +1,377 @@
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use curve25519_dalek::montgomery::MontgomeryPoint;
+use hkdf::Hkdf;
+use rand::{CryptoRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
+
+use crate::{IdentityKey, IdentityPublic, Message};
+
+/*
+    X3DH, after the Signal specification, so a first message can be sent to someone who is offline.
+
+    The recipient publishes a signed prekey and a batch of one-time prekeys ahead of time. The sender
+    fetches a bundle (identity key, signed prekey, at most one one-time prekey), combines it with its
+    own identity key and a fresh ephemeral key, and encrypts the first message under the result:
+
+        DH1 = DH(IK_A, SPK_B)   DH2 = DH(EK_A, IK_B)   DH3 = DH(EK_A, SPK_B)   DH4 = DH(EK_A, OPK_B)
+        SK  = HKDF(0xFF * 32 || DH1 || DH2 || DH3 [|| DH4])
+
+    Identity keys are Ed25519 and take part in the DH through their X25519 form.
+ */
+
+const KDF_INFO: &[u8] = b"rustchat x3dh";
+
+pub(crate) fn dh(secret: &[u8; 32], public: &[u8; 32]) -> Result<Zeroizing<[u8; 32]>, std::io::Error>{
+    let shared = Zeroizing::new(MontgomeryPoint(*public).mul_clamped(*secret).to_bytes());
+    // A low-order public key forces the output to zero and would make the secret predictable
+    if *shared == [0u8; 32]{
+        return Err(Error::new(ErrorKind::InvalidData, "Invalid prekey"));
+    }
+    Ok(shared)
+}
+
+fn kdf(shared: &[Zeroizing<[u8; 32]>]) -> Zeroizing<[u8; 32]>{
+    let mut ikm = Zeroizing::new(vec![0xffu8; 32]);
+    for secret in shared{
+        ikm.extend_from_slice(secret.as_ref());
+    }
+    let mut key = Zeroizing::new([0u8; 32]);
+    Hkdf::<Sha256>::new(Some(&[0u8; 32]), &ikm).expand(KDF_INFO, key.as_mut()).unwrap();
+    key
+}
+
+pub(crate) fn random_secret<R: RngCore + CryptoRng>(rng: &mut R) -> Zeroizing<[u8; 32]>{
+    let mut secret = Zeroizing::new([0u8; 32]);
+    rng.fill_bytes(secret.as_mut());
+    secret
+}
+
+pub(crate) fn public_of(secret: &[u8; 32]) -> [u8; 32]{
+    MontgomeryPoint::mul_base_clamped(*secret).to_bytes()
+}
+
+/// Public half of a prekey, with the id its owner files the secret under.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Prekey{
+    pub id: u32,
+    pub public: [u8; 32],
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct SignedPrekey{
+    pub prekey: Prekey,
+    pub signature: Vec<u8>,
+}
+
+impl SignedPrekey{
+    fn signed_bytes(prekey: &Prekey) -> Vec<u8>{
+        let mut data = b"rustchat signed prekey".to_vec();
+        data.extend(prekey.id.to_be_bytes());
+        data.extend(prekey.public);
+        data
+    }
+
+    pub fn verify(&self, identity: &IdentityPublic) -> Result<(), std::io::Error>{
+        let signature: &[u8; 64] = self.signature.as_slice().try_into()
+            .map_err(|_| Error::new(ErrorKind::PermissionDenied, "Invalid prekey signature"))?;
+        identity.verify(&Self::signed_bytes(&self.prekey), signature)
+    }
+}
+
+/// Everything a sender needs to start a session with an offline recipient.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct PrekeyBundle{
+    pub identity: IdentityPublic,
+    pub signed_prekey: SignedPrekey,
+    /// Absent once the recipient's supply runs out; the exchange still works without it, just with less
+    /// protection against replay of the first message.
+    pub one_time_prekey: Option<Prekey>,
+}
+
+/// Sent in the clear ahead of the first message, telling the recipient which keys were used.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct X3dhHeader{
+    pub identity: IdentityPublic,
+    pub ephemeral: [u8; 32],
+    pub signed_prekey_id: u32,
+    pub one_time_prekey_id: Option<u32>,
+}
+
+/// A first message encrypted under an X3DH key, deliverable while the recipient is offline.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct InitialMessage{
+    pub header: X3dhHeader,
+    pub nonce: [u8; 24],
+    pub ciphertext: Vec<u8>,
+}
+
+/// Binds the ciphertext to both identities and the keys used, as X3DH's associated data.
+fn associated_data(header: &X3dhHeader, recipient: &IdentityPublic) -> Vec<u8>{
+    let mut data = bincode::serialize(header).expect("Failed to serialize X3DH header");
+    data.extend(recipient.as_bytes());
+    data
+}
+
+/// Runs the sender's side of X3DH against `bundle`, returning the header to send and the shared key.
+pub fn initiate<R: RngCore + CryptoRng>(identity: &IdentityKey, bundle: &PrekeyBundle, rng: &mut R)
+    -> Result<(X3dhHeader, Zeroizing<[u8; 32]>), std::io::Error>{
+    bundle.signed_prekey.verify(&bundle.identity)?;
+    let ephemeral = random_secret(rng);
+    let spk = &bundle.signed_prekey.prekey.public;
+
+    let mut shared = vec![
+        dh(&identity.dh_secret(), spk)?,
+        dh(&ephemeral, &bundle.identity.dh_public()?)?,
+        dh(&ephemeral, spk)?,
+    ];
+    if let Some(opk) = &bundle.one_time_prekey{
+        shared.push(dh(&ephemeral, &opk.public)?);
+    }
+
+    let header = X3dhHeader{
+        identity: identity.public(),
+        ephemeral: public_of(&ephemeral),
+        signed_prekey_id: bundle.signed_prekey.prekey.id,
+        one_time_prekey_id: bundle.one_time_prekey.map(|opk| opk.id),
+    };
+    Ok((header, kdf(&shared)))
+}
+
+/// Encrypts `message` as the first message of a session with the owner of `bundle`.
+pub fn seal<R: RngCore + CryptoRng>(identity: &IdentityKey, bundle: &PrekeyBundle, message: &Message, rng: &mut R)
+    -> Result<InitialMessage, std::io::Error>{
+    let (header, key) = initiate(identity, bundle, rng)?;
+    let cipher = XChaCha20Poly1305::new(Key::from_slice(key.as_ref()));
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut *rng);
+    let plaintext = Zeroizing::new(bincode::serialize(message)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e))?);
+    let aad = associated_data(&header, &bundle.identity);
+    let ciphertext = cipher.encrypt(&nonce, Payload{ msg: &plaintext, aad: &aad })
+        .expect("Failed to encrypt message");
+    Ok(InitialMessage{ header, nonce: *nonce.as_ref(), ciphertext })
+}
+
+/// Prekeys to hand to the name server. Contains no secrets.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct PrekeyUpload{
+    pub signed_prekey: SignedPrekey,
+    pub one_time_prekeys: Vec<Prekey>,
+}
+
+/// Secret halves of our published prekeys. Must be kept (see `to_bytes`) for as long as the public halves
+/// are on the name server, or messages sent to them can't be read.
+#[derive(Serialize, Deserialize)]
+pub struct PrekeyStore{
+    next_id: u32,
+    signed: (SignedPrekey, [u8; 32]),
+    // The signed prekey before the last rotation, for messages that were already in flight
+    previous_signed: Option<(u32, [u8; 32])>,
+    one_time: HashMap<u32, [u8; 32]>,
+}
+
+impl PrekeyStore{
+    /// Creates a signed prekey and `one_time` one-time prekeys, returning the store and the matching upload.
+    pub fn generate<R: RngCore + CryptoRng>(identity: &IdentityKey, one_time: usize, rng: &mut R) -> (Self, PrekeyUpload){
+        let mut store = PrekeyStore{
+            next_id: 0,
+            signed: (SignedPrekey{ prekey: Prekey{ id: 0, public: [0; 32] }, signature: Vec::new() }, [0; 32]),
+            previous_signed: None,
+            one_time: HashMap::new(),
+        };
+        store.rotate_signed(identity, rng);
+        store.previous_signed = None;
+        let one_time_prekeys = store.replenish(one_time, rng);
+        let signed_prekey = store.signed.0.clone();
+        (store, PrekeyUpload{ signed_prekey, one_time_prekeys })
+    }
+
+    fn allocate<R: RngCore + CryptoRng>(&mut self, rng: &mut R) -> (Prekey, Zeroizing<[u8; 32]>){
+        let secret = random_secret(rng);
+        let prekey = Prekey{ id: self.next_id, public: public_of(&secret) };
+        self.next_id = self.next_id.wrapping_add(1);
+        (prekey, secret)
+    }
+
+    /// Adds `count` one-time prekeys, returning their public halves to upload.
+    pub fn replenish<R: RngCore + CryptoRng>(&mut self, count: usize, rng: &mut R) -> Vec<Prekey>{
+        (0..count).map(|_| {
+            let (prekey, secret) = self.allocate(rng);
+            self.one_time.insert(prekey.id, *secret);
+            prekey
+        }).collect()
+    }
+
+    /// Replaces the signed prekey, keeping the old secret until the next rotation.
+    pub fn rotate_signed<R: RngCore + CryptoRng>(&mut self, identity: &IdentityKey, rng: &mut R) -> SignedPrekey{
+        let (prekey, secret) = self.allocate(rng);
+        let signature = identity.sign(&SignedPrekey::signed_bytes(&prekey)).to_vec();
+        let old = std::mem::replace(&mut self.signed, (SignedPrekey{ prekey, signature }, *secret));
+        if let Some((_, mut secret)) = self.previous_signed.replace((old.0.prekey.id, old.1)){
+            secret.zeroize();
+        }
+        self.signed.0.clone()
+    }
+
+    pub fn signed_prekey(&self) -> &SignedPrekey{
+        &self.signed.0
+    }
+
+    pub fn one_time_remaining(&self) -> usize{
+        self.one_time.len()
+    }
+
+    /// Runs the recipient's side of X3DH for `header`. Does not consume the one-time prekey; `open` does
+    /// once the message has authenticated.
+    pub fn accept(&self, identity: &IdentityKey, header: &X3dhHeader) -> Result<Zeroizing<[u8; 32]>, std::io::Error>{
+        let spk = if header.signed_prekey_id == self.signed.0.prekey.id{
+            &self.signed.1
+        }
+        else{
+            match &self.previous_signed{
+                Some((id, secret)) if *id == header.signed_prekey_id => secret,
+                _ => return Err(Error::new(ErrorKind::NotFound, "Unknown signed prekey")),
+            }
+        };
+
+        let mut shared = vec![
+            dh(spk, &header.identity.dh_public()?)?,
+            dh(&identity.dh_secret(), &header.ephemeral)?,
+            dh(spk, &header.ephemeral)?,
+        ];
+        if let Some(id) = header.one_time_prekey_id{
+            let opk = self.one_time.get(&id)
+                .ok_or_else(|| Error::new(ErrorKind::NotFound, "Unknown or already used one-time prekey"))?;
+            shared.push(dh(opk, &header.ephemeral)?);
+        }
+        Ok(kdf(&shared))
+    }
+
+    /// Decrypts an initial message, returning the sender's identity and the message. The one-time prekey
+    /// it used is deleted, so the same message can't be accepted twice.
+    pub fn open(&mut self, identity: &IdentityKey, message: &InitialMessage) -> Result<(IdentityPublic, Message), std::io::Error>{
+        let key = self.accept(identity, &message.header)?;
+        let cipher = XChaCha20Poly1305::new(Key::from_slice(key.as_ref()));
+        let aad = associated_data(&message.header, &identity.public());
+        let plaintext = Zeroizing::new(cipher
+            .decrypt(XNonce::from_slice(&message.nonce), Payload{ msg: &message.ciphertext, aad: &aad })
+            .map_err(|_| Error::new(ErrorKind::PermissionDenied, "Failed to decrypt message"))?);
+        let decoded = bincode::deserialize(&plaintext)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+
+        if let Some(id) = message.header.one_time_prekey_id
+            && let Some(mut secret) = self.one_time.remove(&id){
+            secret.zeroize();
+        }
+        Ok((message.header.identity, decoded))
+    }
+
+    pub fn to_bytes(&self) -> Zeroizing<Vec<u8>>{
+        Zeroizing::new(bincode::serialize(self).expect("Failed to serialize prekey store"))
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, std::io::Error>{
+        bincode::deserialize(bytes).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    }
+}
+
+// Written out because the signed prekey sits in a tuple with its secret; it is public, so only the
+// secret halves and the one-time secrets need wiping
+impl Drop for PrekeyStore{
+    fn drop(&mut self){
+        self.signed.1.zeroize();
+        if let Some((_, secret)) = &mut self.previous_signed{
+            secret.zeroize();
+        }
+        for secret in self.one_time.values_mut(){
+            secret.zeroize();
+        }
+    }
+}
+
+impl ZeroizeOnDrop for PrekeyStore {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::OsRng;
+
+    fn bundle(identity: &IdentityKey, store: &PrekeyStore, upload: &PrekeyUpload) -> PrekeyBundle {
+        PrekeyBundle{
+            identity: identity.public(),
+            signed_prekey: store.signed_prekey().clone(),
+            one_time_prekey: upload.one_time_prekeys.first().copied(),
+        }
+    }
+
+    fn message(contents: &str) -> Message {
+        Message{ sender_id: "alice".to_string(), to_id: "bob".to_string(), contents: contents.to_string(), timestamp: 1 }
+    }
+
+    #[test]
+    fn test_both_sides_derive_same_key() {
+        let (alice, bob) = (IdentityKey::generate(), IdentityKey::generate());
+        let (store, upload) = PrekeyStore::generate(&bob, 2, &mut OsRng);
+        for bundle in [bundle(&bob, &store, &upload), PrekeyBundle{ one_time_prekey: None, ..bundle(&bob, &store, &upload) }] {
+            let (header, key) = initiate(&alice, &bundle, &mut OsRng).unwrap();
+            assert_eq!(*store.accept(&bob, &header).unwrap(), *key);
+        }
+    }
+
+    #[test]
+    fn test_initial_message_roundtrip_and_single_use() {
+        let (alice, bob) = (IdentityKey::generate(), IdentityKey::generate());
+        let (mut store, upload) = PrekeyStore::generate(&bob, 1, &mut OsRng);
+        let sealed = seal(&alice, &bundle(&bob, &store, &upload), &message("hi bob"), &mut OsRng).unwrap();
+
+        let (sender, opened) = store.open(&bob, &sealed).unwrap();
+        assert_eq!(sender, alice.public());
+        assert_eq!(opened.contents, "hi bob");
+        assert_eq!(store.one_time_remaining(), 0);
+        assert_eq!(store.open(&bob, &sealed).unwrap_err().kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_forged_bundle_rejected() {
+        let (alice, bob, mallory) = (IdentityKey::generate(), IdentityKey::generate(), IdentityKey::generate());
+        let (mallory_store, _) = PrekeyStore::generate(&mallory, 0, &mut OsRng);
+        let forged = PrekeyBundle{
+            identity: bob.public(),
+            signed_prekey: mallory_store.signed_prekey().clone(),
+            one_time_prekey: None,
+        };
+        assert_eq!(initiate(&alice, &forged, &mut OsRng).unwrap_err().kind(), ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn test_tampered_message_rejected_without_burning_prekey() {
+        let (alice, bob) = (IdentityKey::generate(), IdentityKey::generate());
+        let (mut store, upload) = PrekeyStore::generate(&bob, 1, &mut OsRng);
+        let mut sealed = seal(&alice, &bundle(&bob, &store, &upload), &message("hi"), &mut OsRng).unwrap();
+        // Claiming another sender changes the associated data
+        let original = sealed.header.identity;
+        sealed.header.identity = IdentityKey::generate().public();
+        assert!(store.open(&bob, &sealed).is_err());
+        assert_eq!(store.one_time_remaining(), 1);
+        sealed.header.identity = original;
+        assert!(store.open(&bob, &sealed).is_ok());
+    }
+
+    #[test]
+    fn test_rotation_and_persistence() {
+        let (alice, bob) = (IdentityKey::generate(), IdentityKey::generate());
+        let (mut store, upload) = PrekeyStore::generate(&bob, 0, &mut OsRng);
+        let in_flight = seal(&alice, &bundle(&bob, &store, &upload), &message("sent before rotation"), &mut OsRng).unwrap();
+        store.rotate_signed(&bob, &mut OsRng);
+
+        let mut store = PrekeyStore::from_bytes(&store.to_bytes()).unwrap();
+        assert_eq!(store.open(&bob, &in_flight).unwrap().1.contents, "sent before rotation");
+        store.rotate_signed(&bob, &mut OsRng);
+        assert_eq!(store.open(&bob, &in_flight).unwrap_err().kind(), ErrorKind::NotFound);
+    }
+}
@@ -0,0 +1,171 @@
+use std::collections::HashMap;
+use std::io::{self, Error, ErrorKind};
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::time::{Duration, Instant};
+
+use chat_security::{IdentityKey, IdentityPublic, PinStore, SessionCryptData, Transport};
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+
+/*
+    LAN discovery
+    Peers on one network can find each other without a name server. A listening client announces a
+    DNS-SD service of type `_rustchat._tcp` over mDNS, whose TXT record carries its display name, its
+    key fingerprint and the key itself. `--discover` browses for those, and connecting checks in the
+    identity handshake that the peer holds the key it announced. Anyone on the network can announce
+    any name, so the fingerprint is what to compare with the person you mean to talk to.
+ */
+
+pub const SERVICE_TYPE: &str = "_rustchat._tcp.local.";
+/// How long to wait for a TCP connection to an announced address.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn mdns_error(e: mdns_sd::Error) -> io::Error{
+    Error::other(format!("mDNS: {}", e))
+}
+
+/// A peer announcing itself on the local network.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LocalPeer{
+    pub name: String,
+    pub addresses: Vec<IpAddr>,
+    pub port: u16,
+    pub key: IdentityPublic,
+}
+
+impl LocalPeer{
+    /// Reads a peer from its resolved service, or `None` if the TXT record is incomplete or its fingerprint
+    /// isn't that of its key.
+    fn from_service(info: &ServiceInfo) -> Option<Self>{
+        let key = IdentityPublic::from_hex(info.get_property_val_str("key")?).ok()?;
+        if info.get_property_val_str("fingerprint")? != key.fingerprint(){
+            return None;
+        }
+        let mut addresses: Vec<IpAddr> = info.get_addresses().iter().copied().collect();
+        addresses.sort();
+        Some(LocalPeer{ name: info.get_property_val_str("name")?.to_string(), addresses, port: info.get_port(), key })
+    }
+}
+
+/// An mDNS responder, announcing us and browsing for others.
+pub struct Discovery{
+    daemon: ServiceDaemon,
+    announced: Vec<String>,
+}
+
+impl Discovery{
+    pub fn new() -> Result<Self, io::Error>{
+        Ok(Discovery{ daemon: ServiceDaemon::new().map_err(mdns_error)?, announced: Vec::new() })
+    }
+
+    /// Only announces and browses on the loopback interface, for peers on this machine.
+    #[cfg(test)]
+    pub fn loopback() -> Result<Self, io::Error>{
+        let discovery = Self::new()?;
+        discovery.daemon.disable_interface(mdns_sd::IfKind::All).map_err(mdns_error)?;
+        discovery.daemon.enable_interface(mdns_sd::IfKind::LoopbackV4).map_err(mdns_error)?;
+        Ok(discovery)
+    }
+
+    /// Announces `name` listening on `port` under `key`, until dropped.
+    pub fn announce(&mut self, name: &str, port: u16, key: &IdentityPublic) -> Result<(), io::Error>{
+        let hex = key.to_hex();
+        // Display names aren't unique, so the instance takes a bit of the key too
+        let instance = format!("{} ({})", name, &hex[..8]);
+        let host = format!("rustchat-{}.local.", &hex[..16]);
+        let properties = [("name", name.to_string()), ("fingerprint", key.fingerprint()), ("key", hex)];
+        let info = ServiceInfo::new(SERVICE_TYPE, &instance, &host, "", port, &properties[..])
+            .map_err(mdns_error)?
+            .enable_addr_auto();
+        self.announced.push(info.get_fullname().to_string());
+        self.daemon.register(info).map_err(mdns_error)
+    }
+
+    /// Peers that announce themselves within `wait`.
+    pub fn browse(&self, wait: Duration) -> Result<Vec<LocalPeer>, io::Error>{
+        let events = self.daemon.browse(SERVICE_TYPE).map_err(mdns_error)?;
+        let deadline = Instant::now() + wait;
+        let mut peers = HashMap::new();
+        while let Some(remaining) = deadline.checked_duration_since(Instant::now()){
+            match events.recv_timeout(remaining){
+                Ok(ServiceEvent::ServiceResolved(info)) => {
+                    if let Some(peer) = LocalPeer::from_service(&info){
+                        peers.insert(info.get_fullname().to_string(), peer);
+                    }
+                }
+                Ok(ServiceEvent::ServiceRemoved(_, fullname)) => {
+                    peers.remove(&fullname);
+                }
+                Ok(_) => {}
+                Err(_) => break,
+            }
+        }
+        let _ = self.daemon.stop_browse(SERVICE_TYPE);
+        let mut peers: Vec<LocalPeer> = peers.into_values().collect();
+        peers.sort_by(|a, b| a.name.cmp(&b.name).then(a.addresses.cmp(&b.addresses)));
+        Ok(peers)
+    }
+}
+
+impl Drop for Discovery{
+    fn drop(&mut self){
+        // Say goodbye, so browsers drop us straight away rather than when the record expires
+        for fullname in &self.announced{
+            if let Ok(status) = self.daemon.unregister(fullname){
+                let _ = status.recv_timeout(Duration::from_secs(1));
+            }
+        }
+        let _ = self.daemon.shutdown();
+    }
+}
+
+/// Connects to a discovered peer, trying each address it announced, and checks it holds the key it announced.
+pub fn connect(peer: &LocalPeer, identity: &IdentityKey, self_name: &str) -> Result<SessionCryptData<Box<dyn Transport>>, io::Error>{
+    let mut failure = Error::new(ErrorKind::AddrNotAvailable, format!("{} announced no addresses", peer.name));
+    for ip in &peer.addresses{
+        let stream = match TcpStream::connect_timeout(&SocketAddr::new(*ip, peer.port), CONNECT_TIMEOUT){
+            Ok(stream) => stream,
+            Err(e) => {
+                failure = e;
+                continue;
+            }
+        };
+        let session = SessionCryptData::start_authenticated_session(Box::new(stream) as Box<dyn Transport>, identity, self_name)?;
+        let mut expected = PinStore::new();
+        expected.pin(&peer.name, peer.key);
+        session.verify_peer(&expected)
+            .map_err(|_| Error::new(ErrorKind::PermissionDenied, format!("Peer at {} is not the {} it announced", ip, peer.name)))?;
+        return Ok(session);
+    }
+    Err(failure)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn test_peers_discovered_on_loopback() {
+        let (alice, bob) = (IdentityKey::generate(), IdentityKey::generate());
+        let bob_key = bob.public();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut announcer = Discovery::loopback().unwrap();
+        announcer.announce("bob", port, &bob_key).unwrap();
+        let accepted = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            SessionCryptData::recieve_authenticated_session(Box::new(stream) as Box<dyn Transport>, &bob, "bob")
+        });
+
+        let peers = Discovery::loopback().unwrap().browse(Duration::from_secs(3)).unwrap();
+        // Other tests may be announcing too
+        let peer = peers.iter().find(|peer| peer.key == bob_key).unwrap();
+        assert_eq!((peer.name.as_str(), peer.port), ("bob", port));
+        assert!(peer.addresses.contains(&IpAddr::from([127, 0, 0, 1])));
+
+        let session = connect(peer, &alice, "alice").unwrap();
+        assert_eq!(session.peer().unwrap().name, "bob");
+        assert_eq!(accepted.join().unwrap().unwrap().peer().unwrap().key, alice.public());
+    }
+}
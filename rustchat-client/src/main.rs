@@ -1,17 +1,26 @@
-use std::{io, net::{SocketAddr, TcpListener, TcpStream},
-     time::{SystemTime, UNIX_EPOCH}};
+use std::{io::{self, BufRead, Write}, net::{SocketAddr, TcpListener, TcpStream, UdpSocket}, path::{Path, PathBuf}, sync::mpsc, thread, time::{Duration, SystemTime, UNIX_EPOCH}};
 
 use clap::{Parser, ArgGroup};
 
-use chat_security::{Message, SessionCryptData};
+use chat_security::{IdentityKey, IdentityPublic, Message, PinStore, SessionCryptData, Transport};
+use rustchat_name_server::invite::SignedInvite;
+use rustchat_name_server::policy::Policy;
+use rustchat_name_server::subscriptions::Event;
 
+mod discovery;
+mod prekeys;
+mod resolver;
 mod terminal;
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 #[command(group(
     ArgGroup::new("mode")
         .required(true)
-        .args(["send", "recieve"]),
+        .args(["send", "recieve", "discover", "invite", "accept_invite"]),
+))]
+#[command(group(
+    ArgGroup::new("target")
+        .args(["address", "to"]),
 ))]
 pub struct Args{
     
@@ -19,32 +28,85 @@ pub struct Args{
     /// Starts rustchat in recieving mode 
     recieve: bool,
     
-    #[arg(short, long, required_if_eq("recieve", "false"))]
-    /// Starts rustchat in send mode, with specified IP to chat with
+    #[arg(short, long, required_if_eq("recieve", "false"), requires = "target")]
+    /// Starts rustchat in send mode, with specified IP or name to chat with
     send: bool,
     
     #[arg(short, long, default_value_t = 0, requires = "recieve")]
     /// Specifies port to listen on (if left blank, system will choose for you)
     port: u16,
 
+    #[arg(long)]
+    /// Lists peers announcing themselves on the local network and chats with the one you pick
+    discover: bool,
+
+    #[arg(long, requires = "recieve", conflicts_with = "name_server")]
+    /// Announces you on the local network for --discover, listening on all interfaces
+    announce: bool,
+
+    #[arg(long, requires = "name_server")]
+    /// Prints an invite others can use with --accept-invite to add you as a contact. Register your name first
+    invite: bool,
+
+    #[arg(long, default_value_t = 86400, requires = "invite")]
+    /// Seconds the invite stays valid for, up to 30 days
+    invite_ttl: u64,
+
+    #[arg(long, requires = "invite")]
+    /// Makes the invite good for one use only
+    single_use: bool,
+
+    #[arg(long, requires_all = ["recieve", "name_server"])]
+    /// Hides you from name server lookups by anyone but your contacts, the people whose invites you accepted
+    contacts_only: bool,
+
+    #[arg(long, requires_all = ["recieve", "name_server"])]
+    /// Hides you from lookups by this identity key (64 hex digits); may be given more than once
+    block: Vec<String>,
+
+    #[arg(long, requires_all = ["recieve", "name_server"])]
+    /// Shows you as offline on the name server, so that peers leave messages instead of connecting
+    invisible: bool,
+
+    #[arg(long, conflicts_with = "name_server")]
+    /// Adds the sender of a rustchat://invite/ URI as a contact, then chats with them
+    accept_invite: Option<String>,
+
     #[arg(short, long, requires = "send")]
     /// IP to begin chatting with
     address: Option<String>,
 
+    #[arg(short, long, requires_all = ["send", "name_server"])]
+    /// Name to look up on the name server and begin chatting with, as `name` or `name@domain`
+    to: Option<String>,
+
+    #[arg(short, long, requires = "to")]
+    /// Leaves this message on the name server for the --to peer to collect, instead of starting a chat
+    message: Option<String>,
+
+    #[arg(long, requires = "name_server_key")]
+    /// Name server to resolve names with; in recieve mode, also registers your display name there.
+    /// Its replicas may follow, comma separated, to fall back on when it is down
+    name_server: Option<String>,
+
+    #[arg(long, requires = "name_server")]
+    /// The name server's public key (64 hex digits), as printed when it starts
+    name_server_key: Option<String>,
+
+    #[arg(long, default_value = "identity.key")]
+    /// File holding your identity key, created on first use
+    identity: PathBuf,
+
     #[arg(short, long)]
     /// Specifies your own display name
     name: String,
 
 }
 
-fn message(text: &str, name: &str) -> Message{
-    let time = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap().as_secs();
-    
+fn message(text: &str, name: &str, time: u64) -> Message{
     let (usr1, usr2) = (name, "Reciever");
     
-    return Message{
+    Message{
         sender_id: usr1.to_string(),
         to_id: usr2.to_string(),
         contents: text.to_string(),
@@ -55,53 +117,200 @@ fn message(text: &str, name: &str) -> Message{
 
 
 
+/// A resolver for `servers`, a name server followed by any of its replicas, comma separated.
+fn resolver_for(servers: &str, key: IdentityPublic) -> Result<resolver::Resolver, io::Error>{
+    let mut servers = servers.split(',').map(str::trim);
+    let mut resolver = resolver::Resolver::new(servers.next().unwrap_or_default(), key)?;
+    for replica in servers{
+        resolver = resolver.with_replica(replica)?;
+    }
+    Ok(resolver)
+}
+
+/// Has `resolver` tell the chat window about changes to our contacts, warning when one's key no longer
+/// matches the key we accepted.
+fn watch_contacts(resolver: &resolver::Resolver, contacts_path: &Path, notify: mpsc::Sender<String>) -> Result<(), io::Error>{
+    let contacts = PinStore::load(contacts_path)?;
+    // Our name server can only watch names at its own domain
+    let names: Vec<String> = contacts.names().into_iter().filter(|name| !name.contains('@')).collect();
+    if names.is_empty(){
+        return Ok(());
+    }
+    resolver.watch(names, move |event| {
+        let notice = match event{
+            Event::Online(entry) => format!("{} is online", entry.name),
+            Event::Offline(entry) => format!("{} went offline", entry.name),
+            Event::Moved(entry) => format!("{} is now at {}", entry.name, entry.address),
+            Event::KeyChanged(entry) => match contacts.verify(&entry.name, &entry.identity){
+                Ok(()) => format!("{} is back on the key you accepted", entry.name),
+                Err(_) => format!("WARNING: {} has a new identity key (fingerprint {}) that doesn't match the one you accepted",
+                    entry.name, entry.identity.fingerprint()),
+            },
+            Event::Removed{ name } => format!("{} is no longer registered", name),
+        };
+        let _ = notify.send(notice);
+    });
+    Ok(())
+}
+
 fn main() -> Result<(), io::Error>{
     let args = Args::parse();
-    let session: SessionCryptData;
-    if args.recieve{
+    let resolver = match (&args.name_server, &args.name_server_key){
+        (Some(name_server), Some(key)) => Some(resolver_for(name_server, IdentityPublic::from_hex(key)?)?
+            .with_identity(&IdentityKey::load_or_generate(&args.identity)?)),
+        _ => None,
+    };
+    // Keys of people whose invites we accepted
+    let contacts_path = args.identity.with_extension("contacts");
+    // Contact changes, shown in the chat window
+    let (notify, notices) = mpsc::channel();
+    if let (Some(resolver), true) = (&resolver, args.invite){
+        let identity = IdentityKey::load_or_generate(&args.identity)?;
+        let server = args.name_server.as_deref().expect("--invite requires --name-server");
+        let invite = resolver.invite(&args.name, server, &identity, Duration::from_secs(args.invite_ttl), args.single_use)?;
+        println!("{}", invite.to_uri());
+        return Ok(());
+    }
+    let session: SessionCryptData<Box<dyn Transport>> = if let Some(uri) = &args.accept_invite{
+        let identity = IdentityKey::load_or_generate(&args.identity)?;
+        let invite = SignedInvite::from_uri(uri)?;
+        let mut contacts = PinStore::load(&contacts_path)?;
+        // Checked before redeeming, so a refused invite isn't used up
+        if let Some(pinned) = contacts.get(&invite.invite.name).filter(|&&pinned| pinned != invite.invite.identity){
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, format!(
+                "{} is already a contact with fingerprint {}, but the invite is for fingerprint {}",
+                invite.invite.name, pinned.fingerprint(), invite.invite.identity.fingerprint())));
+        }
+        let resolver = resolver_for(&invite.invite.server, invite.invite.server_key)?.with_identity(&identity);
+        let entry = resolver.redeem_invite(&invite)?;
+        contacts.pin(&entry.name, entry.identity);
+        contacts.save()?;
+        println!("Added {} as a contact (fingerprint {})", entry.name, entry.identity.fingerprint());
+        watch_contacts(&resolver, &contacts_path, notify)?;
+        let mut session = resolver.connect(&entry.name, &identity, &args.name).map_err(|e| match e.kind(){
+            io::ErrorKind::NotConnected => io::Error::new(e.kind(), format!("{}; use --to {} --message to leave one", e, entry.name)),
+            _ => e,
+        })?;
+        resolver.gossip(&mut session)?;
+        println!("Connected to {}", entry.name);
+        session
+    }
+    else if let (Some(resolver), true) = (&resolver, args.recieve){
+        let identity = IdentityKey::load_or_generate(&args.identity)?;
+        let listener = TcpListener::bind(SocketAddr::from(([0, 0, 0, 0], args.port)))?;
+        let entry = resolver.register(&args.name, listener.local_addr()?, &identity)?;
+        println!("Registered {} at {}", entry.name, entry.address);
+        // Whatever isn't asked for this time is lifted, so the policy is always what the flags say
+        let contacts = PinStore::load(&contacts_path)?;
+        let policy = Policy{
+            contacts_only: args.contacts_only,
+            contacts: contacts.names().iter().filter_map(|name| contacts.get(name).copied()).collect(),
+            blocked: args.block.iter().map(|key| IdentityPublic::from_hex(key)).collect::<Result<_, _>>()?,
+            invisible: args.invisible,
+        };
+        resolver.set_policy(&entry.name, &identity, policy)?;
+        let prekey_store = args.identity.with_extension("prekeys");
+        resolver.publish_prekeys(&entry.name, &identity, &prekey_store)?;
+        for (message, verified) in resolver.collect_mail(&entry.name, &identity, &prekey_store)?{
+            let note = if verified { "" } else { " (sender not verified)" };
+            println!("[while offline] {}{}", message.displayable(), note);
+        }
+        resolver.keep_alive(entry, IdentityKey::from_bytes(&identity.to_bytes()));
+        watch_contacts(resolver, &contacts_path, notify)?;
+
+        // Peers that can't reach the listener punch through over UDP or come in through the relay; take
+        // whichever arrives first
+        let (sender, incoming) = mpsc::channel::<io::Result<Box<dyn Transport>>>();
+        let (relayed, relay, relay_identity, name) = (sender.clone(), resolver.clone(), IdentityKey::from_bytes(&identity.to_bytes()), args.name.clone());
+        thread::spawn(move || relayed.send(relay.park(&name, &relay_identity).map(|stream| Box::new(stream) as _)));
+        let (punched, rendezvous, punch_identity, name) = (sender.clone(), resolver.clone(), IdentityKey::from_bytes(&identity.to_bytes()), args.name.clone());
+        thread::spawn(move || punched.send(UdpSocket::bind(("0.0.0.0", 0))
+            .and_then(|socket| rendezvous.listen_udp(socket, &name, &punch_identity))
+            .map(|transport| Box::new(transport) as _)));
+        thread::spawn(move || sender.send(listener.accept().map(|(stream, _)| Box::new(stream) as _)));
+        // One way failing (say, the name server dropping the relay connection) still leaves the others
+        let mut failure = None;
+        let transport = loop{
+            match incoming.recv(){
+                Ok(Ok(transport)) => break transport,
+                Ok(Err(e)) => failure = Some(e),
+                Err(_) => return Err(failure.expect("Connection threads exited without reporting")),
+            }
+        };
+        let mut session = SessionCryptData::recieve_authenticated_session(transport, &identity, &args.name)?;
+        resolver.gossip(&mut session)?;
+        session
+    }
+    else if let (Some(resolver), Some(to)) = (&resolver, &args.to){
+        let identity = IdentityKey::load_or_generate(&args.identity)?;
+        if let Some(text) = &args.message{
+            let time = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+            let message = Message{ to_id: to.clone(), ..message(text, &args.name, time) };
+            resolver.leave_message(to, &identity, &message)?;
+            println!("Left a message for {}", to);
+            return Ok(());
+        }
+        let mut session = resolver.connect(to, &identity, &args.name).map_err(|e| match e.kind(){
+            io::ErrorKind::NotConnected => io::Error::new(e.kind(), format!("{}; use --message to leave one", e)),
+            _ => e,
+        })?;
+        let contacts = PinStore::load(&contacts_path)?;
+        if contacts.get(to).is_some(){
+            contacts.verify(to, &session.peer().expect("Authenticated session has a peer").key)?;
+        }
+        watch_contacts(resolver, &contacts_path, notify)?;
+        resolver.gossip(&mut session)?;
+        println!("Connected to {}", to);
+        session
+    }
+    else if let (true, true) = (args.recieve, args.announce){
+        let identity = IdentityKey::load_or_generate(&args.identity)?;
+        let listener = TcpListener::bind(SocketAddr::from(([0, 0, 0, 0], args.port)))?;
+        let mut discovery = discovery::Discovery::new()?;
+        discovery.announce(&args.name, listener.local_addr()?.port(), &identity.public())?;
+        println!("Announced {} on port {} (fingerprint {})", args.name, listener.local_addr()?.port(), identity.public().fingerprint());
+        let transport = Box::new(listener.accept()?.0) as _;
+        SessionCryptData::recieve_authenticated_session(transport, &identity, &args.name)?
+    }
+    else if args.discover{
+        let identity = IdentityKey::load_or_generate(&args.identity)?;
+        println!("Looking for peers on the local network...");
+        let peers = discovery::Discovery::new()?.browse(Duration::from_secs(3))?;
+        if peers.is_empty(){
+            return Err(io::Error::new(io::ErrorKind::NotFound, "No peers found on the local network"));
+        }
+        for (i, peer) in peers.iter().enumerate(){
+            let addresses: Vec<String> = peer.addresses.iter().map(|ip| SocketAddr::new(*ip, peer.port).to_string()).collect();
+            println!("{:>3}) {}  {}  fingerprint {}", i + 1, peer.name, addresses.join(", "), peer.key.fingerprint());
+        }
+        print!("Chat with which peer? ");
+        io::stdout().flush()?;
+        let mut choice = String::new();
+        io::stdin().lock().read_line(&mut choice)?;
+        let peer = choice.trim().parse::<usize>().ok()
+            .and_then(|i| peers.get(i.wrapping_sub(1)))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "No such peer"))?;
+        let session = discovery::connect(peer, &identity, &args.name)?;
+        println!("Connected to {}", peer.name);
+        session
+    }
+    else if args.recieve{
         let addr = SocketAddr::from(([127, 0, 0, 1], args.port));
         let listener = TcpListener::bind(addr)?;
         println!("Listening on {}", listener.local_addr()?);
-        session = SessionCryptData::recieve_session(listener.accept()?.0)?;
-        match terminal::ChatWindow::run_main(session, &args.name){
-            Err(e) => {
-                if e.kind() == io::ErrorKind::UnexpectedEof {
-                    println!("Session ended");
-                    return Ok(());
-                } else {
-                    return Err(e);
-                }
-                
-            }
-            _ => {
-                println!("Session ended");
-                return Ok(());
-            }
-        };
+        SessionCryptData::recieve_session(Box::new(listener.accept()?.0) as _)?
     }
     else{
         let addr: SocketAddr = args.address.unwrap().parse().unwrap();
         let stream = TcpStream::connect(addr)?;
         println!("Connected to {}", addr);
-        session = SessionCryptData::start_session(stream)?;
-        match terminal::ChatWindow::run_main(session, &args.name){
-            Err(e) => {
-                if e.kind() == io::ErrorKind::UnexpectedEof {
-                    println!("Session ended");
-                    return Ok(());
-                } else {
-                    return Err(e);
-                }
-                
-            }
-            _ => {
-                println!("Session ended");
-                return Ok(());
-            }
-        };
+        SessionCryptData::start_session(Box::new(stream) as _)?
+    };
+    match terminal::ChatWindow::run_main(session, &args.name, &notices){
+        Err(e) if e.kind() != io::ErrorKind::UnexpectedEof => Err(e),
+        _ => {
+            println!("Session ended");
+            Ok(())
+        }
     }
-    
 }
-
-
-
@@ -0,0 +1,35 @@
+use std::fs;
+use std::io::{self, ErrorKind, Write};
+use std::path::Path;
+
+use chat_security::IdentityKey;
+use chat_security::x3dh::PrekeyStore;
+use rand::rngs::OsRng;
+
+/// Loads our prekey secrets from `path`, creating a store with a fresh signed prekey on first run.
+pub fn load_or_generate(path: &Path, identity: &IdentityKey) -> Result<PrekeyStore, io::Error>{
+    match fs::read(path){
+        Ok(bytes) => PrekeyStore::from_bytes(&zeroize::Zeroizing::new(bytes)),
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            let (store, _) = PrekeyStore::generate(identity, 0, &mut OsRng);
+            save(path, &store)?;
+            Ok(store)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Writes the store out. Must happen before any of its prekeys are published, or messages sent to them
+/// become unreadable after a restart.
+pub fn save(path: &Path, store: &PrekeyStore) -> Result<(), io::Error>{
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(&temp)?;
+    file.write_all(&store.to_bytes())?;
+    file.sync_all()?;
+    fs::rename(&temp, path)
+}
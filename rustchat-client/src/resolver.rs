@@ -0,0 +1,779 @@
+use std::collections::HashMap;
+use std::io::{self, Error, ErrorKind};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use chat_security::udp::{self, Datagram, UdpTransport};
+use chat_security::x3dh::{self, InitialMessage, PrekeyUpload};
+use chat_security::{IdentityKey, IdentityPublic, Message, PinStore, SessionCryptData, Transport};
+use rand::rngs::OsRng;
+use rustchat_name_server::client::{NameServerClient, RendezvousClient};
+use rustchat_name_server::federation::split_address;
+use rustchat_name_server::invite::{Invite, SignedInvite, MAX_INVITE_LIFETIME};
+use rustchat_name_server::policy::Policy;
+use rustchat_name_server::protocol::{Entry, ErrorCode, Presence};
+use rustchat_name_server::subscriptions::{changes, Event};
+use rustchat_name_server::transparency::SignedTreeHead;
+
+use crate::prekeys;
+
+/// How long a direct connection gets before we try hole punching.
+const DIRECT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long both sides keep punching before giving up on UDP and falling back to the relay.
+const PUNCH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// One-time prekeys we try to keep on the name server.
+const ONE_TIME_PREKEY_TARGET: usize = 20;
+/// How long to wait before subscribing again after a subscription drops.
+const WATCH_RETRY: Duration = Duration::from_secs(5);
+
+fn unix_time() -> u64{
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Renew well before the lease runs out, so one lost heartbeat doesn't take us offline.
+fn heartbeat_interval(entry: &Entry) -> Duration{
+    match entry.presence{
+        Presence::Online{ lease_expires } => Duration::from_secs((lease_expires.saturating_sub(unix_time()) / 3).max(1)),
+        Presence::Offline{ .. } => Duration::from_secs(1),
+    }
+}
+
+fn describe_last_seen(last_seen: u64) -> String{
+    let ago = unix_time().saturating_sub(last_seen);
+    match ago{
+        0..60 => "just now".to_string(),
+        60..3600 => format!("{} minutes ago", ago / 60),
+        3600..86400 => format!("{} hours ago", ago / 3600),
+        _ => format!("{} days ago", ago / 86400),
+    }
+}
+
+/// Whether `e` from one name server is worth trying the next about: it couldn't be reached, or is a
+/// replica that can't help.
+fn should_fail_over(e: &io::Error) -> bool{
+    match e.get_ref().and_then(|e| e.downcast_ref::<ErrorCode>()){
+        Some(code) => matches!(code, ErrorCode::NotPrimary | ErrorCode::Unavailable),
+        None => matches!(e.kind(), ErrorKind::ConnectionRefused | ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted
+            | ErrorKind::BrokenPipe | ErrorKind::UnexpectedEof | ErrorKind::TimedOut | ErrorKind::WouldBlock
+            | ErrorKind::NotConnected | ErrorKind::HostUnreachable | ErrorKind::NetworkUnreachable),
+    }
+}
+
+/// Turns usernames into verified sessions using the configured name server.
+#[derive(Clone)]
+pub struct Resolver{
+    /// The name server and its replicas, tried in turn. All hold the same key.
+    servers: Arc<Vec<SocketAddr>>,
+    /// Index into `servers` of the last one that answered, which is tried first.
+    current: Arc<AtomicUsize>,
+    /// Follows the server's key rotations, shared between clones.
+    server_key: Arc<Mutex<IdentityPublic>>,
+    /// Newest head of the server's key log we've checked, shared between clones.
+    tree_head: Arc<Mutex<Option<SignedTreeHead>>>,
+    /// Who we connect to the name server as, if not anonymously.
+    identity: Option<Arc<IdentityKey>>,
+}
+
+impl Resolver{
+    /// Uses the name server at `name_server`, which must prove it holds `server_key`.
+    pub fn new(name_server: impl ToSocketAddrs, server_key: IdentityPublic) -> Result<Self, io::Error>{
+        let servers: Vec<SocketAddr> = name_server.to_socket_addrs()?.collect();
+        if servers.is_empty(){
+            return Err(Error::new(ErrorKind::InvalidInput, "Name server address did not resolve"));
+        }
+        Ok(Resolver{
+            servers: Arc::new(servers),
+            current: Arc::new(AtomicUsize::new(0)),
+            server_key: Arc::new(Mutex::new(server_key)),
+            tree_head: Arc::new(Mutex::new(None)),
+            identity: None,
+        })
+    }
+
+    /// Also uses `replica`, a replica of the name server, when the servers before it can't be reached.
+    pub fn with_replica(mut self, replica: impl ToSocketAddrs) -> Result<Self, io::Error>{
+        Arc::make_mut(&mut self.servers).extend(replica.to_socket_addrs()?);
+        Ok(self)
+    }
+
+    /// Connects to the name server as `identity`, so that names which keep to their contacts can be found by
+    /// those contacts.
+    pub fn with_identity(mut self, identity: &IdentityKey) -> Self{
+        self.identity = Some(Arc::new(IdentityKey::from_bytes(&identity.to_bytes())));
+        self
+    }
+
+    fn server_key(&self) -> IdentityPublic{
+        *self.server_key.lock().unwrap()
+    }
+
+    /// Runs `request` against each server in turn, starting with the one that last answered, until one
+    /// that is up and able to answers it. If none can, fails with what a server answered, e.g. that it is
+    /// only a replica, over that one couldn't be reached.
+    fn failover_to<T>(&self, mut request: impl FnMut(SocketAddr) -> Result<T, io::Error>) -> Result<T, io::Error>{
+        let start = self.current.load(Ordering::SeqCst);
+        let mut failure = None;
+        for i in 0..self.servers.len(){
+            let index = (start + i) % self.servers.len();
+            match request(self.servers[index]){
+                Err(e) if should_fail_over(&e) => {
+                    let answered = |e: &io::Error| e.get_ref().is_some_and(|e| e.is::<ErrorCode>());
+                    if !failure.as_ref().is_some_and(answered){
+                        failure = Some(e);
+                    }
+                }
+                result => {
+                    if result.is_ok(){
+                        self.current.store(index, Ordering::SeqCst);
+                    }
+                    return result;
+                }
+            }
+        }
+        Err(failure.expect("Resolver has at least one server"))
+    }
+
+    /// As `failover_to`, with a connection to each server.
+    fn failover<T>(&self, mut request: impl FnMut(NameServerClient) -> Result<T, io::Error>) -> Result<T, io::Error>{
+        self.failover_to(|addr| request(self.client(addr)?))
+    }
+
+    fn client(&self, addr: SocketAddr) -> Result<NameServerClient, io::Error>{
+        let client = match &self.identity{
+            Some(identity) => NameServerClient::connect_as(addr, &self.server_key(), identity)?,
+            None => NameServerClient::connect(addr, &self.server_key())?,
+        };
+        let mut server_key = self.server_key.lock().unwrap();
+        if client.server_key() != *server_key{
+            eprintln!("Name server key has been rotated to {}; update --name-server-key", client.server_key().to_hex());
+            *server_key = client.server_key();
+        }
+        Ok(client)
+    }
+
+    /// Checks `head`, which has a valid signature, against the newest head we had, keeping the larger.
+    /// Fails with `PermissionDenied` if the two can't both be true, i.e. the name server is showing
+    /// different people different directories.
+    fn observe(&self, client: &mut NameServerClient, head: SignedTreeHead) -> Result<(), io::Error>{
+        let known = self.tree_head.lock().unwrap().clone();
+        if let Some(known) = known.filter(|known| *known != head){
+            let checked = match client.check_consistency(&known.head, &head.head){
+                // A replica that is behind can't prove a range its primary signed for, so ask the others
+                Err(e) if e.get_ref().and_then(|e| e.downcast_ref()) == Some(&ErrorCode::InvalidLogRange) && self.servers.len() > 1 =>
+                    self.failover(|mut other| other.check_consistency(&known.head, &head.head).map_err(|e| {
+                        match e.get_ref().and_then(|e| e.downcast_ref()){
+                            Some(ErrorCode::InvalidLogRange) => ErrorCode::Unavailable.into(),
+                            _ => e,
+                        }
+                    })),
+                checked => checked,
+            };
+            // A server that can't even prove a range covered by a head it signed is hiding part of its log
+            checked.map_err(|e| match e.kind(){
+                ErrorKind::PermissionDenied | ErrorKind::InvalidInput =>
+                    Error::new(ErrorKind::PermissionDenied, format!("Name server has shown conflicting key logs: {}", e)),
+                _ => e,
+            })?;
+        }
+        let mut known = self.tree_head.lock().unwrap();
+        if known.as_ref().is_none_or(|known| known.head.size < head.head.size){
+            *known = Some(head);
+        }
+        Ok(())
+    }
+
+    /// Swaps key log heads with the peer at the other end of `session`, so each can check the other's
+    /// against its own. Both sides must call this right after the handshake.
+    pub fn gossip<T: Transport>(&self, session: &mut SessionCryptData<T>) -> Result<(), io::Error>{
+        let ours = self.tree_head.lock().unwrap().clone();
+        let data = bincode::serialize(&(self.server_key(), &ours)).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        session.send_data(&data)?;
+        let (server, theirs): (IdentityPublic, Option<SignedTreeHead>) = bincode::deserialize(&session.recieve_data()?)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        // A peer using another name server has nothing for us to compare
+        match theirs{
+            Some(theirs) if server == self.server_key() && ours.as_ref() != Some(&theirs) => {
+                theirs.verify(&server)?;
+                self.failover(|mut client| self.observe(&mut client, theirs.clone()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Publishes our own name, returning the entry as the name server stored it.
+    pub fn register(&self, name: &str, address: SocketAddr, identity: &IdentityKey) -> Result<Entry, io::Error>{
+        self.failover(|mut client| client.register(name, address, identity))
+    }
+
+    /// Replaces who may find `name` and see its presence.
+    pub fn set_policy(&self, name: &str, identity: &IdentityKey, policy: Policy) -> Result<Entry, io::Error>{
+        self.failover(|mut client| client.set_policy(name, identity, policy.clone()))
+    }
+
+    /// Publishes our signed prekey and tops the name server's supply of one-time prekeys back up, so
+    /// others can start sessions with us while we are offline. Secrets are kept in the store at `store_path`.
+    pub fn publish_prekeys(&self, name: &str, identity: &IdentityKey, store_path: &Path) -> Result<usize, io::Error>{
+        let mut store = prekeys::load_or_generate(store_path, identity)?;
+        self.failover(|mut client| {
+            let signed_prekey = store.signed_prekey().clone();
+            let available = client.publish_prekeys(name, identity, PrekeyUpload{ signed_prekey: signed_prekey.clone(), one_time_prekeys: Vec::new() })?;
+            if available >= ONE_TIME_PREKEY_TARGET{
+                return Ok(available);
+            }
+            let fresh = store.replenish(ONE_TIME_PREKEY_TARGET - available, &mut OsRng);
+            prekeys::save(store_path, &store)?;
+            client.publish_prekeys(name, identity, PrekeyUpload{ signed_prekey, one_time_prekeys: fresh })
+        })
+    }
+
+    /// Encrypts `message` to one of `name`'s prekeys and leaves it on the name server for them to collect.
+    pub fn leave_message(&self, name: &str, identity: &IdentityKey, message: &Message) -> Result<(), io::Error>{
+        let entry = self.resolve(name)?;
+        self.failover(|mut client| {
+            let bundle = client.fetch_prekeys(name)?;
+            if bundle.identity != entry.identity{
+                return Err(Error::new(ErrorKind::PermissionDenied,
+                    format!("Prekeys for {} are not under the key in the name server's key log", name)));
+            }
+            let sealed = x3dh::seal(identity, &bundle, message, &mut OsRng)?;
+            let payload = bincode::serialize(&sealed).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+            client.deposit(name, payload)?;
+            Ok(())
+        })
+    }
+
+    /// Collects and decrypts mail left for `name` while we were offline, acknowledging it on the server.
+    /// Each message comes with whether its sender name checks out against the directory.
+    pub fn collect_mail(&self, name: &str, identity: &IdentityKey, store_path: &Path) -> Result<Vec<(Message, bool)>, io::Error>{
+        self.failover(|mut client| {
+            let envelopes = client.fetch_mail(name, identity)?;
+            if envelopes.is_empty(){
+                return Ok(Vec::new());
+            }
+            let mut store = prekeys::load_or_generate(store_path, identity)?;
+            let mut messages = Vec::new();
+            for envelope in &envelopes{
+                // Anything that doesn't open now never will, so it's acknowledged along with the rest
+                let Ok(sealed) = bincode::deserialize::<InitialMessage>(&envelope.payload) else { continue };
+                let Ok((sender, message)) = store.open(identity, &sealed) else { continue };
+                let verified = client.lookup(&message.sender_id)?.is_some_and(|entry| entry.identity == sender);
+                messages.push((message, verified));
+            }
+            if let Some(head) = client.tree_head().cloned(){
+                self.observe(&mut client, head)?;
+            }
+            // Used one-time prekeys must be gone from disk before the server forgets the messages
+            prekeys::save(store_path, &store)?;
+            client.acknowledge(name, identity, envelopes.iter().map(|envelope| envelope.id).collect())?;
+            Ok(messages)
+        })
+    }
+
+    /// Keeps our registration online for as long as the process runs, sending heartbeats on a background
+    /// thread and registering again if the lease lapsed anyway (e.g. after a suspend).
+    pub fn keep_alive(&self, registered: Entry, identity: IdentityKey){
+        let resolver = self.clone();
+        thread::spawn(move || {
+            let mut interval = heartbeat_interval(&registered);
+            loop{
+                thread::sleep(interval);
+                let renewed = resolver.failover(|mut client| {
+                    match client.heartbeat(&registered.name, &identity){
+                        Err(e) if e.get_ref().and_then(|e| e.downcast_ref()) == Some(&ErrorCode::LeaseExpired) =>
+                            client.register(&registered.name, registered.address, &identity),
+                        result => result,
+                    }
+                });
+                // The name server may be briefly unreachable; keep trying at the same pace
+                if let Ok(entry) = renewed{
+                    interval = heartbeat_interval(&entry);
+                }
+            }
+        });
+    }
+
+    /// Watches `names` from a background thread, handing `on_change` each change the name server pushes. A
+    /// new key is only passed on once a lookup checks it against the key log. Subscribes again, failing
+    /// over if need be, whenever the subscription drops, passing on whatever changed in the meantime.
+    pub fn watch(&self, names: Vec<String>, mut on_change: impl FnMut(Event) + Send + 'static){
+        let resolver = self.clone();
+        thread::spawn(move || {
+            // Entries as last passed on
+            let mut known = HashMap::new();
+            loop{
+                let watched: Result<(), io::Error> = resolver.failover(|client| client.subscribe(&names)).and_then(|(entries, mut subscription)| {
+                    let mut events: Vec<Event> = names.iter()
+                        .flat_map(|name| changes(name, known.get(name), entries.iter().find(|entry| entry.name == *name)))
+                        .collect();
+                    loop{
+                        for event in events{
+                            resolver.pass_on(event, &mut known, &mut on_change);
+                        }
+                        events = subscription.wait()?;
+                    }
+                });
+                if let Err(e) = watched{
+                    eprintln!("Lost subscription to contacts: {}", e);
+                }
+                thread::sleep(WATCH_RETRY);
+            }
+        });
+    }
+
+    fn pass_on(&self, event: Event, known: &mut HashMap<String, Entry>, on_change: &mut impl FnMut(Event)){
+        let name = event.name().to_string();
+        let Some(mut entry) = event.entry().cloned() else {
+            known.remove(&name);
+            return on_change(event);
+        };
+        match (&event, known.get(&name)){
+            // Pushed entries aren't proven, so a key change waits for one that is
+            (Event::KeyChanged(_), _) => {
+                if !self.resolve(&name).is_ok_and(|verified| verified.identity == entry.identity){
+                    return;
+                }
+            }
+            // Nor does any other event get to change the key quietly
+            (_, Some(old)) => entry.identity = old.identity,
+            (_, None) => {}
+        }
+        known.insert(name, entry.clone());
+        on_change(match event{
+            Event::Online(_) => Event::Online(entry),
+            Event::Offline(_) => Event::Offline(entry),
+            Event::Moved(_) => Event::Moved(entry),
+            event => event,
+        });
+    }
+
+    /// Looks up `name`, checking the key against the name server's key log.
+    pub fn resolve(&self, name: &str) -> Result<Entry, io::Error>{
+        self.failover(|mut client| {
+            let entry = client.lookup(name)?
+                .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("{} is not registered with the name server", name)))?;
+            if let Some(head) = client.tree_head().cloned(){
+                self.observe(&mut client, head)?;
+            }
+            Ok(entry)
+        })
+    }
+
+    /// Makes an invite to `name`, which `identity` must own, reachable through this name server at `server`.
+    pub fn invite(&self, name: &str, server: &str, identity: &IdentityKey, lifetime: Duration, single_use: bool) -> Result<SignedInvite, io::Error>{
+        if lifetime > MAX_INVITE_LIFETIME{
+            return Err(Error::new(ErrorKind::InvalidInput,
+                format!("Invites can be valid for at most {} days", MAX_INVITE_LIFETIME.as_secs() / 86400)));
+        }
+        // Redeeming checks the name is ours, so an invite made before registering would be no use
+        if self.resolve(name)?.identity != identity.public(){
+            return Err(Error::new(ErrorKind::PermissionDenied, format!("{} is registered to another key", name)));
+        }
+        let invite = Invite::new(name, server, self.server_key(), identity.public(), unix_time() + lifetime.as_secs(), single_use);
+        Ok(SignedInvite::sign(invite, identity))
+    }
+
+    /// Checks `invite` with this name server, which must be the one it names, returning the inviter's entry.
+    pub fn redeem_invite(&self, invite: &SignedInvite) -> Result<Entry, io::Error>{
+        self.failover(|mut client| {
+            let entry = client.redeem_invite(invite)?;
+            if let Some(head) = client.tree_head().cloned(){
+                self.observe(&mut client, head)?;
+            }
+            Ok(entry)
+        })
+    }
+
+    /// Waits on the relay for a peer who can't reach us directly, returning the connection once one joins.
+    pub fn park(&self, name: &str, identity: &IdentityKey) -> Result<TcpStream, io::Error>{
+        self.failover(|client| client.park(name, identity))
+    }
+
+    /// Waits for a peer to punch through to us over UDP on `socket`, with the name server making the
+    /// introduction. Peers that give up before the punch completes are skipped.
+    pub fn listen_udp<S: Datagram>(&self, socket: S, name: &str, identity: &IdentityKey) -> Result<UdpTransport<S>, io::Error>{
+        loop{
+            let peer = self.failover_to(|addr| RendezvousClient::new(&socket, addr, self.server_key()).listen(name, identity))?;
+            match udp::punch(&socket, peer, PUNCH_TIMEOUT){
+                Ok(()) => return Ok(UdpTransport::new(socket, peer)),
+                Err(e) if e.kind() == ErrorKind::TimedOut => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn punch(&self, name: &str) -> Result<UdpTransport, io::Error>{
+        let socket = UdpSocket::bind(("0.0.0.0", 0))?;
+        let peer = self.failover_to(|addr| RendezvousClient::new(&socket, addr, self.server_key()).rendezvous(name))?;
+        udp::punch(&socket, peer, PUNCH_TIMEOUT)?;
+        Ok(UdpTransport::new(socket, peer))
+    }
+
+    /// Reaches `entry` directly if we can, else by hole punching, else through the relay.
+    fn open(&self, entry: &Entry) -> Result<Box<dyn Transport>, io::Error>{
+        let direct = match TcpStream::connect_timeout(&entry.address, DIRECT_CONNECT_TIMEOUT){
+            Ok(stream) => return Ok(Box::new(stream)),
+            Err(e) => e,
+        };
+        let punched = match self.punch(&entry.name){
+            Ok(transport) => return Ok(Box::new(transport)),
+            Err(e) => e,
+        };
+        match self.failover(|client| client.join(&entry.name)){
+            Ok(stream) => Ok(Box::new(stream)),
+            Err(relay) => Err(Error::new(direct.kind(), format!(
+                "Could not reach {} directly ({}), by hole punching ({}) or through the relay ({})",
+                entry.name, direct, punched, relay))),
+        }
+    }
+
+    /// Looks up `name`, connects to it (by hole punching or through the relay if a direct connection fails)
+    /// and runs an authenticated handshake, failing unless the peer proves it holds the identity key the
+    /// directory listed for that name.
+    pub fn connect(&self, name: &str, identity: &IdentityKey, self_name: &str) -> Result<SessionCryptData<Box<dyn Transport>>, io::Error>{
+        let entry = self.resolve(name)?;
+        if let Presence::Offline{ last_seen } = entry.presence{
+            return Err(Error::new(ErrorKind::NotConnected,
+                format!("{} is offline (last seen {})", name, describe_last_seen(last_seen))));
+        }
+        let session = SessionCryptData::start_authenticated_session(self.open(&entry)?, identity, self_name)?;
+
+        // Peers go by their bare name in the handshake, whatever domain they are at
+        let (local, _) = split_address(&entry.name)?;
+        let mut expected = PinStore::new();
+        expected.pin(local, entry.identity);
+        session.verify_peer(&expected)
+            .map_err(|_| Error::new(ErrorKind::PermissionDenied,
+                format!("Peer at {} is not the {} listed by the name server", entry.address, name)))?;
+        Ok(session)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+    use chat_security::nat::{Mapping, NatSocket};
+    use rustchat_name_server::directory::Directory;
+    use rustchat_name_server::server::NameServer;
+
+    fn spawn_name_server() -> Resolver {
+        let server = NameServer::bind("127.0.0.1:0").unwrap();
+        let resolver = Resolver::new(server.local_addr().unwrap(), server.public_key()).unwrap();
+        thread::spawn(move || server.run());
+        resolver
+    }
+
+    /// Listens as `name` holding `identity`, registering `registered` with the name server.
+    fn spawn_peer(resolver: &Resolver, name: &'static str, identity: IdentityKey, registered: &IdentityKey)
+        -> thread::JoinHandle<Result<SessionCryptData, io::Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        resolver.register(name, listener.local_addr().unwrap(), registered).unwrap();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept()?;
+            SessionCryptData::recieve_authenticated_session(stream, &identity, name)
+        })
+    }
+
+    #[test]
+    fn test_connect_by_name() {
+        let resolver = spawn_name_server();
+        let bob = IdentityKey::generate();
+        let peer = spawn_peer(&resolver, "bob", IdentityKey::from_bytes(&bob.to_bytes()), &bob);
+
+        let alice = IdentityKey::generate();
+        let session = resolver.connect("bob", &alice, "alice").unwrap();
+        assert_eq!(session.peer().unwrap().key, bob.public());
+        assert_eq!(peer.join().unwrap().unwrap().peer().unwrap().key, alice.public());
+    }
+
+    #[test]
+    fn test_connect_rejects_key_not_in_directory() {
+        let resolver = spawn_name_server();
+        let listed = IdentityKey::generate();
+        let _peer = spawn_peer(&resolver, "mallory", IdentityKey::generate(), &listed);
+
+        let err = resolver.connect("mallory", &IdentityKey::generate(), "alice").err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn test_offline_peer_reported_before_connecting() {
+        let clock = chat_security::ManualClock::new(unix_time());
+        let directory = Directory::with_clock(std::sync::Arc::new(clock.clone()), Duration::from_secs(60));
+        let server = NameServer::bind_with("127.0.0.1:0", directory).unwrap();
+        let resolver = Resolver::new(server.local_addr().unwrap(), server.public_key()).unwrap();
+        thread::spawn(move || server.run());
+
+        resolver.register("bob", "127.0.0.1:1".parse().unwrap(), &IdentityKey::generate()).unwrap();
+        clock.advance(Duration::from_secs(120));
+        let err = resolver.connect("bob", &IdentityKey::generate(), "alice").err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::NotConnected);
+        assert!(err.to_string().contains("bob is offline"), "{}", err);
+    }
+
+    #[test]
+    fn test_keep_alive_renews_lease() {
+        let clock = chat_security::ManualClock::new(unix_time());
+        let directory = Directory::with_clock(std::sync::Arc::new(clock.clone()), Duration::from_secs(3));
+        let server = NameServer::bind_with("127.0.0.1:0", directory).unwrap();
+        let resolver = Resolver::new(server.local_addr().unwrap(), server.public_key()).unwrap();
+        thread::spawn(move || server.run());
+
+        let bob = IdentityKey::generate();
+        let entry = resolver.register("bob", "127.0.0.1:1".parse().unwrap(), &bob).unwrap();
+        resolver.keep_alive(entry, IdentityKey::from_bytes(&bob.to_bytes()));
+        // Move the server's clock past the original lease in steps, giving the heartbeat thread time to run
+        for _ in 0..3 {
+            thread::sleep(Duration::from_millis(1500));
+            clock.advance(Duration::from_secs(1));
+        }
+        assert!(resolver.resolve("bob").unwrap().presence.is_online());
+    }
+
+    #[test]
+    fn test_published_prekeys_reach_sender() {
+        let resolver = spawn_name_server();
+        let store_path = std::env::temp_dir().join(format!("rustchat-prekeys-{}", std::process::id()));
+        let _ = std::fs::remove_file(&store_path);
+        let bob = IdentityKey::generate();
+        resolver.register("bob", "127.0.0.1:1".parse().unwrap(), &bob).unwrap();
+        assert_eq!(resolver.publish_prekeys("bob", &bob, &store_path).unwrap(), ONE_TIME_PREKEY_TARGET);
+
+        let alice = IdentityKey::generate();
+        let bundle = resolver.failover(|mut client| client.fetch_prekeys("bob")).unwrap();
+        let message = chat_security::Message{
+            sender_id: "alice".to_string(), to_id: "bob".to_string(), contents: "are you there?".to_string(), timestamp: 1,
+        };
+        let sealed = chat_security::x3dh::seal(&alice, &bundle, &message, &mut OsRng).unwrap();
+
+        // Topping up again only adds what was used
+        assert_eq!(resolver.publish_prekeys("bob", &bob, &store_path).unwrap(), ONE_TIME_PREKEY_TARGET);
+        let mut store = prekeys::load_or_generate(&store_path, &bob).unwrap();
+        assert_eq!(store.one_time_remaining(), ONE_TIME_PREKEY_TARGET + 1);
+        assert_eq!(store.open(&bob, &sealed).unwrap().1.contents, "are you there?");
+        std::fs::remove_file(&store_path).unwrap();
+    }
+
+    #[test]
+    fn test_message_left_while_offline() {
+        let resolver = spawn_name_server();
+        let store_path = std::env::temp_dir().join(format!("rustchat-mail-prekeys-{}", std::process::id()));
+        let _ = std::fs::remove_file(&store_path);
+        let (alice, bob) = (IdentityKey::generate(), IdentityKey::generate());
+        resolver.register("alice", "127.0.0.1:1".parse().unwrap(), &alice).unwrap();
+        resolver.register("bob", "127.0.0.1:2".parse().unwrap(), &bob).unwrap();
+        resolver.publish_prekeys("bob", &bob, &store_path).unwrap();
+
+        let message = |sender: &str, contents: &str| Message{
+            sender_id: sender.to_string(), to_id: "bob".to_string(), contents: contents.to_string(), timestamp: 1,
+        };
+        resolver.leave_message("bob", &alice, &message("alice", "call me")).unwrap();
+        // Signed with a key that isn't alice's
+        resolver.leave_message("bob", &IdentityKey::generate(), &message("alice", "it's me, alice")).unwrap();
+
+        let mail = resolver.collect_mail("bob", &bob, &store_path).unwrap();
+        assert_eq!(mail.iter().map(|(m, verified)| (m.contents.as_str(), *verified)).collect::<Vec<_>>(),
+            [("call me", true), ("it's me, alice", false)]);
+        assert!(resolver.collect_mail("bob", &bob, &store_path).unwrap().is_empty());
+        std::fs::remove_file(&store_path).unwrap();
+    }
+
+    #[test]
+    fn test_falls_back_to_relay() {
+        let resolver = spawn_name_server();
+        let bob = IdentityKey::generate();
+        // Registered at an address nobody listens on, as if behind NAT
+        let unreachable = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        resolver.register("bob", unreachable, &bob).unwrap();
+
+        let bob_resolver = resolver.clone();
+        let parked = thread::spawn(move || {
+            let stream = bob_resolver.park("bob", &bob)?;
+            SessionCryptData::recieve_authenticated_session(stream, &bob, "bob")
+        });
+        let alice = IdentityKey::generate();
+        let session = loop {
+            match resolver.connect("bob", &alice, "alice") {
+                Ok(session) => break session,
+                // Until bob has parked, neither route works
+                Err(e) if e.kind() == ErrorKind::ConnectionRefused => thread::sleep(Duration::from_millis(10)),
+                Err(e) => panic!("{}", e),
+            }
+        };
+        assert_eq!(session.peer().unwrap().name, "bob");
+        assert_eq!(parked.join().unwrap().unwrap().peer().unwrap().key, alice.public());
+    }
+
+    #[test]
+    fn test_hole_punching_before_relay() {
+        let resolver = spawn_name_server();
+        let bob = IdentityKey::generate();
+        let unreachable = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        resolver.register("bob", unreachable, &bob).unwrap();
+
+        let bob_resolver = resolver.clone();
+        let listening = thread::spawn(move || {
+            let socket = NatSocket::bind(Mapping::EndpointIndependent)?;
+            let transport = bob_resolver.listen_udp(socket, "bob", &bob)?;
+            SessionCryptData::recieve_authenticated_session(transport, &bob, "bob")
+        });
+        let alice = IdentityKey::generate();
+        let session = loop {
+            match resolver.connect("bob", &alice, "alice") {
+                Ok(session) => break session,
+                // Until bob is listening, nothing works
+                Err(e) if e.kind() == ErrorKind::ConnectionRefused => thread::sleep(Duration::from_millis(10)),
+                Err(e) => panic!("{}", e),
+            }
+        };
+        assert_eq!(session.peer().unwrap().name, "bob");
+        assert_eq!(listening.join().unwrap().unwrap().peer().unwrap().key, alice.public());
+    }
+
+    #[test]
+    fn test_invite_redeemed_once() {
+        let resolver = spawn_name_server();
+        let bob = IdentityKey::generate();
+        let peer = spawn_peer(&resolver, "bob", IdentityKey::from_bytes(&bob.to_bytes()), &bob);
+        let server = resolver.servers[0].to_string();
+        let uri = resolver.invite("bob", &server, &bob, Duration::from_secs(60), true).unwrap().to_uri();
+        let err = resolver.invite("bob", &server, &IdentityKey::generate(), Duration::from_secs(60), true).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+
+        // Everything alice needs is in the invite
+        let invite = SignedInvite::from_uri(&uri).unwrap();
+        let alice_resolver = Resolver::new(invite.invite.server.as_str(), invite.invite.server_key).unwrap();
+        let entry = alice_resolver.redeem_invite(&invite).unwrap();
+        assert_eq!(entry.identity, bob.public());
+        let alice = IdentityKey::generate();
+        let session = alice_resolver.connect(&entry.name, &alice, "alice").unwrap();
+        assert_eq!(session.peer().unwrap().key, bob.public());
+        assert_eq!(peer.join().unwrap().unwrap().peer().unwrap().key, alice.public());
+
+        let err = alice_resolver.redeem_invite(&invite).unwrap_err();
+        assert_eq!(err.get_ref().unwrap().downcast_ref::<ErrorCode>(), Some(&ErrorCode::InviteUsed));
+    }
+
+    #[test]
+    fn test_fails_over_to_replica() {
+        let identity = IdentityKey::generate();
+        let primary = NameServer::bind("127.0.0.1:0").unwrap().identity(IdentityKey::from_bytes(&identity.to_bytes())).primary();
+        let (primary_addr, key, handle) = (primary.local_addr().unwrap(), primary.public_key(), primary.handle().unwrap());
+        thread::spawn(move || primary.run());
+        let replica = NameServer::bind("127.0.0.1:0").unwrap().identity(identity).replica_of(&primary_addr.to_string());
+        let replica_addr = replica.local_addr().unwrap();
+        thread::spawn(move || replica.run());
+        let resolver = Resolver::new(primary_addr, key).unwrap().with_replica(replica_addr).unwrap();
+
+        let bob = IdentityKey::generate();
+        let peer = spawn_peer(&resolver, "bob", IdentityKey::from_bytes(&bob.to_bytes()), &bob);
+        let replica_only = Resolver::new(replica_addr, key).unwrap();
+        for _ in 0..50 {
+            if replica_only.resolve("bob").is_ok() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        // With the primary gone, names still resolve through the replica, but nothing can be registered
+        handle.stop();
+        let alice = IdentityKey::generate();
+        let session = resolver.connect("bob", &alice, "alice").unwrap();
+        assert_eq!(session.peer().unwrap().key, bob.public());
+        assert_eq!(peer.join().unwrap().unwrap().peer().unwrap().key, alice.public());
+        let err = resolver.register("carol", "127.0.0.1:2".parse().unwrap(), &IdentityKey::generate()).unwrap_err();
+        assert_eq!(err.get_ref().unwrap().downcast_ref::<ErrorCode>(), Some(&ErrorCode::NotPrimary));
+    }
+
+    #[test]
+    fn test_watch_passes_on_verified_changes() {
+        let resolver = spawn_name_server();
+        let (bob, mallory) = (IdentityKey::generate(), IdentityKey::generate());
+        let registered = resolver.register("bob", "127.0.0.1:1".parse().unwrap(), &bob).unwrap();
+        let (sender, events) = std::sync::mpsc::channel();
+        resolver.watch(vec!["bob".to_string()], move |event| sender.send(event).unwrap());
+        let next = || events.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(next(), Event::Online(registered));
+
+        let moved = resolver.register("bob", "127.0.0.1:2".parse().unwrap(), &bob).unwrap();
+        assert_eq!(next(), Event::Moved(moved));
+        resolver.failover(|mut client| client.transfer("bob", &bob, mallory.public())).unwrap();
+        match next() {
+            Event::KeyChanged(entry) => assert_eq!(entry.identity, mallory.public()),
+            event => panic!("expected a key change, got {:?}", event),
+        }
+        resolver.failover(|mut client| client.delete("bob", &mallory)).unwrap();
+        assert_eq!(next(), Event::Removed{ name: "bob".to_string() });
+    }
+
+    #[test]
+    fn test_contacts_only_name_found_by_contacts() {
+        let resolver = spawn_name_server();
+        let (bob, alice) = (IdentityKey::generate(), IdentityKey::generate());
+        let peer = spawn_peer(&resolver, "bob", IdentityKey::from_bytes(&bob.to_bytes()), &bob);
+        resolver.set_policy("bob", &bob, Policy{ contacts_only: true, contacts: vec![alice.public()], ..Policy::default() }).unwrap();
+
+        assert_eq!(resolver.connect("bob", &IdentityKey::generate(), "mallory").err().unwrap().kind(), ErrorKind::NotFound);
+        let session = resolver.clone().with_identity(&alice).connect("bob", &alice, "alice").unwrap();
+        assert_eq!(session.peer().unwrap().key, bob.public());
+        assert_eq!(peer.join().unwrap().unwrap().peer().unwrap().key, alice.public());
+    }
+
+    #[test]
+    fn test_unknown_name() {
+        let resolver = spawn_name_server();
+        let err = resolver.connect("nobody", &IdentityKey::generate(), "alice").err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
+
+    /// Runs `Resolver::gossip` between `a` and `b` over an in-memory session.
+    fn gossip(a: &Resolver, b: &Resolver) -> (Result<(), io::Error>, Result<(), io::Error>) {
+        let (a_end, b_end) = chat_security::MemoryTransport::pair();
+        let b = b.clone();
+        let other = thread::spawn(move || {
+            let mut session = SessionCryptData::recieve_session(b_end)?;
+            b.gossip(&mut session)
+        });
+        let mut session = SessionCryptData::start_session(a_end).unwrap();
+        (a.gossip(&mut session), other.join().unwrap())
+    }
+
+    #[test]
+    fn test_gossip_detects_split_view() {
+        // Two servers sharing a key stand in for one server showing two directories
+        let server_key = IdentityKey::generate();
+        let spawn = || {
+            let server = NameServer::bind("127.0.0.1:0").unwrap().identity(IdentityKey::from_bytes(&server_key.to_bytes()));
+            let resolver = Resolver::new(server.local_addr().unwrap(), server.public_key()).unwrap();
+            thread::spawn(move || server.run());
+            resolver
+        };
+        let (honest, forked) = (spawn(), spawn());
+        let (bob, mallory) = (IdentityKey::generate(), IdentityKey::generate());
+        honest.register("bob", "127.0.0.1:1".parse().unwrap(), &bob).unwrap();
+        forked.register("bob", "127.0.0.1:1".parse().unwrap(), &mallory).unwrap();
+
+        // Heads from the same log check out, and the newer one is kept
+        let (alice, carol) = (honest.clone(), Resolver::new(honest.servers[0], honest.server_key()).unwrap());
+        alice.resolve("bob").unwrap();
+        carol.register("carol", "127.0.0.1:2".parse().unwrap(), &IdentityKey::generate()).unwrap();
+        carol.resolve("carol").unwrap();
+        let (a, b) = gossip(&alice, &carol);
+        a.unwrap();
+        b.unwrap();
+        assert_eq!(alice.tree_head.lock().unwrap().as_ref().unwrap().head.size, 2);
+
+        // Whoever was shown mallory's key for bob finds out on meeting someone who wasn't
+        let dave = forked.clone();
+        assert_eq!(dave.resolve("bob").unwrap().identity, mallory.public());
+        let (a, b) = gossip(&alice, &dave);
+        assert_eq!(a.unwrap_err().kind(), ErrorKind::PermissionDenied);
+        assert_eq!(b.unwrap_err().kind(), ErrorKind::PermissionDenied);
+    }
+}
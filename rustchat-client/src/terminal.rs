@@ -1,4 +1,4 @@
-use chat_security::SessionCryptData;
+use chat_security::{SessionCryptData, Transport};
 use crossterm::{
     ExecutableCommand, QueueableCommand, cursor,
     event::{self, Event, KeyCode},
@@ -6,6 +6,7 @@ use crossterm::{
     terminal::{self, ClearType, disable_raw_mode, enable_raw_mode},
 };
 use std::io::{self, Write};
+use std::sync::mpsc::Receiver;
 
 use crate::message;
 
@@ -54,7 +55,8 @@ impl ChatWindow {
         Ok(())
     }
 
-    pub fn run_main(mut session: SessionCryptData, self_name: &str) -> io::Result<()> {
+    /// Chats over `session` until Esc, showing anything that arrives on `notices` along the way.
+    pub fn run_main<T: Transport>(mut session: SessionCryptData<T>, self_name: &str, notices: &Receiver<String>) -> io::Result<()> {
         enable_raw_mode()?;
         let mut stdout = io::stdout();
         let mut chat = ChatWindow::new()?;
@@ -62,6 +64,7 @@ impl ChatWindow {
             "Welcome to the chat! Type your messages below, or press Esc to quit".to_string(),
         );
         loop {
+            chat.messages.extend(notices.try_iter().map(|notice| format!("* {}", notice)));
             chat.draw(&mut stdout)?;
 
             match session.check_data_available()? {
@@ -74,27 +77,25 @@ impl ChatWindow {
                     // No data available
                 }
             }
-            if event::poll(std::time::Duration::from_millis(100))? {
-                if let Event::Key(key_event) = event::read()? {
-                    match key_event.code {
-                        KeyCode::Enter => {
-                            if !chat.input_buffer.is_empty() {
-                                chat.messages.push(format!("{}> {}", self_name, chat.input_buffer.clone()));
-                                session.send_message(message(&chat.input_buffer, self_name))?;
-                                chat.input_buffer.clear();
-                            }
-                        }
-                        KeyCode::Char(c) => {
-                            chat.input_buffer.push(c);
-                        }
-                        KeyCode::Backspace => {
-                            chat.input_buffer.pop();
-                        }
-                        KeyCode::Esc => {
-                            break;
-                        }
-                        _ => {}
+            if event::poll(std::time::Duration::from_millis(100))?
+                && let Event::Key(key_event) = event::read()? {
+                match key_event.code {
+                    KeyCode::Enter if !chat.input_buffer.is_empty() => {
+                        chat.messages.push(format!("{}> {}", self_name, chat.input_buffer.clone()));
+                        let msg = message(&chat.input_buffer, self_name, session.timestamp());
+                        session.send_message(msg)?;
+                        chat.input_buffer.clear();
                     }
+                    KeyCode::Char(c) => {
+                        chat.input_buffer.push(c);
+                    }
+                    KeyCode::Backspace => {
+                        chat.input_buffer.pop();
+                    }
+                    KeyCode::Esc => {
+                        break;
+                    }
+                    _ => {}
                 }
             }
         }
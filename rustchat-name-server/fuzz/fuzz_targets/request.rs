@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rustchat_name_server::protocol::{decode, Request};
+
+fuzz_target!(|data: &[u8]| {
+    let _ = decode::<Request>(data);
+});
@@ -0,0 +1,271 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io::{self, Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use chat_security::{IdentityKey, IdentityPublic};
+use serde::{Deserialize, Serialize};
+
+use crate::protocol::{Entry, ErrorCode};
+use crate::storage::Storage;
+
+/*
+    Administration
+    Operators manage the server over the same encrypted connections clients use, handshaking under an
+    admin key the server has in its admin list; the handshake proves they hold it. Admins can list and
+    inspect registrations, suspend them (the name stays taken, but lookups don't find it and its owner
+    can't change it), delete them outright, and rotate the server's own key.
+
+    Clients pin the server key, so a rotation is signed by the old key. Rotations are logged, and a
+    client that sees a key it doesn't know asks for the log and follows it from the key it pinned.
+    Replicas prove themselves with the server key too; the key a rotation replaced is still taken from
+    them for `ROTATION_GRACE`, long enough to restart them with the new key file, and then never again,
+    since keys are mostly rotated because the old one got out.
+ */
+
+/// How long replicas may keep handshaking under the key the last rotation replaced.
+pub const ROTATION_GRACE: Duration = Duration::from_secs(60 * 60);
+
+/// Keys allowed to administer the server.
+///
+/// File format is one key per line as 64 hex digits. `#` starts a comment.
+#[derive(Clone, Debug, Default)]
+pub struct AdminKeys{
+    keys: HashSet<IdentityPublic>,
+}
+
+impl AdminKeys{
+    pub fn new() -> Self{
+        Self::default()
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, io::Error>{
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(contents: &str) -> Result<Self, io::Error>{
+        let mut admins = AdminKeys::new();
+        for (number, line) in contents.lines().enumerate(){
+            let entry = line.split('#').next().unwrap_or("").trim();
+            if entry.is_empty(){
+                continue;
+            }
+            let key = IdentityPublic::from_hex(entry)
+                .map_err(|_| Error::new(ErrorKind::InvalidData, format!("Invalid admin key on line {}: {}", number + 1, entry)))?;
+            admins.add(key);
+        }
+        Ok(admins)
+    }
+
+    pub fn add(&mut self, key: IdentityPublic){
+        self.keys.insert(key);
+    }
+
+    pub fn contains(&self, key: &IdentityPublic) -> bool{
+        self.keys.contains(key)
+    }
+
+    pub fn len(&self) -> usize{
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool{
+        self.keys.is_empty()
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum AdminRequest{
+    /// Every registration, suspended ones included.
+    List,
+    Inspect{ name: String },
+    Suspend{ name: String, suspended: bool },
+    /// Deletes a registration without its owner's signature.
+    Delete{ name: String },
+    /// Replaces the server's identity key with a fresh one, signed over by the old.
+    RotateKey,
+}
+
+/// What an admin sees of a registration.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Registration{
+    pub entry: Entry,
+    pub lease_expires: u64,
+    pub last_seen: u64,
+    pub suspended: bool,
+    pub one_time_prekeys: usize,
+}
+
+/// The server's old key vouching for its new one.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct KeyRotation{
+    pub old: IdentityPublic,
+    pub new: IdentityPublic,
+    pub signature: Vec<u8>,
+}
+
+impl KeyRotation{
+    fn signed_data(old: &IdentityPublic, new: &IdentityPublic) -> Vec<u8>{
+        let mut data = b"rustchat key rotation".to_vec();
+        data.extend(old.as_bytes());
+        data.extend(new.as_bytes());
+        data
+    }
+
+    pub fn sign(old: &IdentityKey, new: IdentityPublic) -> Self{
+        let signature = old.sign(&Self::signed_data(&old.public(), &new)).to_vec();
+        KeyRotation{ old: old.public(), new, signature }
+    }
+
+    pub fn verify(&self) -> Result<(), ErrorCode>{
+        let signature: [u8; 64] = self.signature.as_slice().try_into().map_err(|_| ErrorCode::BadSignature)?;
+        self.old.verify(&Self::signed_data(&self.old, &self.new), &signature).map_err(|_| ErrorCode::BadSignature)
+    }
+}
+
+/// Whether `rotations` lead from the key a client pinned to the key the server presented.
+pub fn follow_rotations(pinned: &IdentityPublic, rotations: &[KeyRotation], presented: &IdentityPublic) -> bool{
+    let mut key = *pinned;
+    for rotation in rotations{
+        if rotation.old == key && rotation.verify().is_ok(){
+            key = rotation.new;
+        }
+    }
+    key == *presented
+}
+
+/// The server's identity key and the rotations that led to it, optionally kept in a key file and `Storage`.
+pub struct ServerKeys{
+    current: Arc<IdentityKey>,
+    rotations: Vec<KeyRotation>,
+    file: Option<PathBuf>,
+    storage: Option<Box<dyn Storage<KeyRotation>>>,
+    /// The key the last rotation replaced and when, until `ROTATION_GRACE` is up.
+    previous: Option<(IdentityPublic, Instant)>,
+}
+
+impl ServerKeys{
+    pub fn new(current: IdentityKey) -> Self{
+        ServerKeys{ current: Arc::new(current), rotations: Vec::new(), file: None, storage: None, previous: None }
+    }
+
+    /// Saves rotated keys to `file`, which `current` was loaded from, and logs the rotations in `storage`.
+    pub fn with_storage(mut self, file: impl AsRef<Path>, mut storage: Box<dyn Storage<KeyRotation>>) -> Result<Self, io::Error>{
+        let mut rotations = storage.load()?;
+        let public = self.current.public();
+        // A rotation logged but never saved to the key file didn't happen
+        if rotations.last().is_some_and(|rotation| rotation.old == public){
+            rotations.pop();
+            storage.compact(&rotations)?;
+        }
+        if rotations.last().is_some_and(|rotation| rotation.new != public){
+            return Err(Error::new(ErrorKind::InvalidData, "Key rotation log does not end at the server's key"));
+        }
+        self.rotations = rotations;
+        self.file = Some(file.as_ref().to_path_buf());
+        self.storage = Some(storage);
+        Ok(self)
+    }
+
+    pub fn current(&self) -> Arc<IdentityKey>{
+        self.current.clone()
+    }
+
+    pub fn rotations(&self) -> &[KeyRotation]{
+        &self.rotations
+    }
+
+    /// Whether `key` still speaks for this server: the current key, or the one it replaced while the
+    /// rotation is less than `ROTATION_GRACE` old. Replicas may handshake under it and updates may be
+    /// signed for it.
+    pub fn recognizes(&mut self, key: &IdentityPublic) -> bool{
+        if self.previous.is_some_and(|(_, rotated)| rotated.elapsed() >= ROTATION_GRACE){
+            self.previous = None;
+        }
+        self.current.public() == *key || self.previous.is_some_and(|(previous, _)| previous == *key)
+    }
+
+    /// Switches to a fresh key, returning the old key's signature over it.
+    pub fn rotate(&mut self) -> Result<KeyRotation, io::Error>{
+        let new = IdentityKey::generate();
+        let rotation = KeyRotation::sign(&self.current, new.public());
+        if let Some(storage) = &mut self.storage{
+            storage.append(&rotation)?;
+        }
+        if let Some(file) = &self.file{
+            new.save(file)?;
+        }
+        self.rotations.push(rotation.clone());
+        self.previous = Some((rotation.old, Instant::now()));
+        self.current = Arc::new(new);
+        Ok(rotation)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::LogStorage;
+
+    #[test]
+    fn test_rotations_followed_from_pinned_key() {
+        let mut keys = ServerKeys::new(IdentityKey::generate());
+        let pinned = keys.current().public();
+        keys.rotate().unwrap();
+        let middle = keys.current().public();
+        keys.rotate().unwrap();
+        let presented = keys.current().public();
+        assert!(follow_rotations(&pinned, keys.rotations(), &presented));
+        assert!(follow_rotations(&middle, keys.rotations(), &presented));
+        assert!(!follow_rotations(&IdentityKey::generate().public(), keys.rotations(), &presented));
+
+        let mut forged = keys.rotations().to_vec();
+        forged[1] = KeyRotation{ new: IdentityKey::generate().public(), ..forged[1].clone() };
+        assert!(!follow_rotations(&pinned, &forged, &forged[1].new));
+    }
+
+    #[test]
+    fn test_replicas_accepted_under_replaced_key_for_a_while() {
+        let mut keys = ServerKeys::new(IdentityKey::generate());
+        let oldest = keys.current().public();
+        keys.rotate().unwrap();
+        let middle = keys.current().public();
+        assert!(keys.recognizes(&oldest));
+        keys.rotate().unwrap();
+        // Only the key just replaced gets a grace period
+        assert!(keys.recognizes(&keys.current().public()) && keys.recognizes(&middle));
+        assert!(!keys.recognizes(&oldest));
+        let Some((_, rotated)) = &mut keys.previous else { panic!("no grace period after a rotation") };
+        *rotated = Instant::now().checked_sub(ROTATION_GRACE).unwrap();
+        assert!(!keys.recognizes(&middle));
+        assert!(keys.previous.is_none());
+    }
+
+    #[test]
+    fn test_rotated_key_survives_restart() {
+        let dir = std::env::temp_dir();
+        let (file, log) = (dir.join(format!("rustchat-server-key-{}", std::process::id())),
+            dir.join(format!("rustchat-rotations-{}", std::process::id())));
+        let (_, _) = (fs::remove_file(&file), fs::remove_file(&log));
+        let open = || ServerKeys::new(IdentityKey::load_or_generate(&file).unwrap())
+            .with_storage(&file, Box::new(LogStorage::open(&log).unwrap()));
+
+        let mut keys = open().unwrap();
+        let pinned = keys.current().public();
+        keys.rotate().unwrap();
+        let reopened = open().unwrap();
+        assert_eq!(reopened.current().public(), keys.current().public());
+        assert!(follow_rotations(&pinned, reopened.rotations(), &reopened.current().public()));
+
+        // A crash between logging a rotation and saving its key leaves the old key in charge
+        let mut storage = LogStorage::open(&log).unwrap();
+        storage.append(&KeyRotation::sign(&keys.current(), IdentityKey::generate().public())).unwrap();
+        let reopened = open().unwrap();
+        assert_eq!(reopened.rotations().len(), 1);
+        assert_eq!(reopened.current().public(), keys.current().public());
+
+        let (_, _) = (fs::remove_file(&file), fs::remove_file(&log));
+    }
+}
@@ -0,0 +1,164 @@
+use std::io;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use chat_security::{IdentityKey, IdentityPublic};
+use clap::{Parser, Subcommand};
+use serde_json::{json, Value};
+
+use rustchat_name_server::admin::Registration;
+use rustchat_name_server::client::NameServerClient;
+
+/*
+    Admin CLI
+    Manages a name server over its admin API. The server only answers keys in its `--admin-keys`
+    file; `rustchat-admin key` prints the one to add there.
+ */
+
+#[derive(Parser, Debug)]
+#[command(version, about = "Manages registrations on a rustchat name server", long_about = None)]
+pub struct Args{
+
+    #[arg(long, default_value = "127.0.0.1:7420")]
+    /// Name server to manage
+    server: String,
+
+    #[arg(long)]
+    /// The name server's public key (64 hex digits); needed for everything but `key`
+    server_key: Option<String>,
+
+    #[arg(long, default_value = "admin.key")]
+    /// File holding your admin key, created on first use
+    identity: PathBuf,
+
+    #[arg(long)]
+    /// Prints JSON instead of a table
+    json: bool,
+
+    #[command(subcommand)]
+    command: Command,
+
+}
+
+#[derive(Subcommand, Debug)]
+enum Command{
+    /// Prints your admin key, for the server's --admin-keys file
+    Key,
+    /// Lists every registration
+    List,
+    /// Shows one registration
+    Show{ name: String },
+    /// Hides a name from lookups and freezes it, without freeing it for others
+    Suspend{ name: String },
+    /// Lifts a suspension
+    Unsuspend{ name: String },
+    /// Deletes a registration, freeing the name
+    Delete{ name: String },
+    /// Replaces the server's key; clients follow the old key's signature over the new one
+    RotateKey,
+}
+
+fn now() -> u64{
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// `time` relative to now, e.g. "in 4m" or "3h ago".
+fn relative(time: u64) -> String{
+    let now = now();
+    let span = |secs: u64| match secs{
+        0..60 => format!("{}s", secs),
+        60..3600 => format!("{}m", secs / 60),
+        3600..86400 => format!("{}h", secs / 3600),
+        _ => format!("{}d", secs / 86400),
+    };
+    if time >= now { format!("in {}", span(time - now)) } else { format!("{} ago", span(now - time)) }
+}
+
+fn status(registration: &Registration) -> &'static str{
+    match (registration.suspended, registration.entry.presence.is_online()){
+        (true, _) => "suspended",
+        (false, true) => "online",
+        (false, false) => "offline",
+    }
+}
+
+fn to_json(registration: &Registration) -> Value{
+    let entry = &registration.entry;
+    json!({
+        "name": entry.name,
+        "status": status(registration),
+        "address": entry.address.to_string(),
+        "identity": entry.identity.to_hex(),
+        "fingerprint": entry.identity.fingerprint(),
+        "lease_expires": registration.lease_expires,
+        "last_seen": registration.last_seen,
+        "suspended": registration.suspended,
+        "one_time_prekeys": registration.one_time_prekeys,
+    })
+}
+
+fn print_table(rows: &[[String; 7]]){
+    let header = ["NAME", "STATUS", "ADDRESS", "LEASE", "LAST SEEN", "PREKEYS", "FINGERPRINT"].map(String::from);
+    let mut widths = header.clone().map(|cell| cell.len());
+    for row in rows{
+        for (width, cell) in widths.iter_mut().zip(row){
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    for row in std::iter::once(&header).chain(rows){
+        let cells: Vec<String> = row.iter().zip(widths).map(|(cell, width)| format!("{:<width$}", cell, width = width)).collect();
+        println!("{}", cells.join("  ").trim_end());
+    }
+}
+
+fn print_registrations(registrations: &[Registration], json: bool){
+    if json{
+        println!("{}", Value::Array(registrations.iter().map(to_json).collect()));
+        return;
+    }
+    let rows: Vec<[String; 7]> = registrations.iter().map(|registration| [
+        registration.entry.name.clone(),
+        status(registration).to_string(),
+        registration.entry.address.to_string(),
+        if registration.entry.presence.is_online() { relative(registration.lease_expires) } else { "expired".to_string() },
+        relative(registration.last_seen),
+        registration.one_time_prekeys.to_string(),
+        registration.entry.identity.fingerprint(),
+    ]).collect();
+    print_table(&rows);
+}
+
+fn main() -> Result<(), io::Error>{
+    let args = Args::parse();
+    let admin = IdentityKey::load_or_generate(&args.identity)?;
+    if let Command::Key = args.command{
+        println!("{}", admin.public().to_hex());
+        return Ok(());
+    }
+    let server_key = args.server_key.as_deref()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "--server-key is required"))
+        .and_then(IdentityPublic::from_hex)?;
+    let mut client = NameServerClient::connect_as_admin(&args.server, &server_key, &admin)?;
+    match args.command{
+        Command::Key => unreachable!("Handled before connecting"),
+        Command::List => print_registrations(&client.registrations()?, args.json),
+        Command::Show{ name } => print_registrations(&[client.registration(&name)?], args.json),
+        Command::Suspend{ name } => print_registrations(&[client.suspend(&name, true)?], args.json),
+        Command::Unsuspend{ name } => print_registrations(&[client.suspend(&name, false)?], args.json),
+        Command::Delete{ name } => {
+            client.remove(&name)?;
+            if args.json { println!("{}", json!({ "deleted": name })) } else { println!("Deleted {}", name) }
+        }
+        Command::RotateKey => {
+            let rotation = client.rotate_key()?;
+            if args.json{
+                println!("{}", json!({ "old": rotation.old.to_hex(), "new": rotation.new.to_hex() }));
+            }
+            else{
+                println!("Server key rotated from {} to {}", rotation.old.to_hex(), rotation.new.to_hex());
+                println!("Clients pinning the old key follow the rotation; give new clients the new key");
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,88 @@
+use std::collections::HashMap;
+use std::io::{self, Error, ErrorKind};
+use std::path::PathBuf;
+
+use chat_security::IdentityPublic;
+use clap::Parser;
+
+use rustchat_name_server::client::NameServerClient;
+use rustchat_name_server::protocol::validate_name;
+use rustchat_name_server::storage::LogStorage;
+use rustchat_name_server::transparency::{Binding, TransparencyLog};
+
+/*
+    Auditor
+    Keeps a copy of a name server's key log and checks, each time it runs, that the server's current
+    signed tree head covers exactly that copy plus whatever was appended since. A server that rewrote
+    or dropped a binding can't produce a head that passes. Run it from cron; it exits non-zero on
+    any failure.
+ */
+
+#[derive(Parser, Debug)]
+#[command(version, about = "Checks that a name server's key transparency log only ever grows", long_about = None)]
+pub struct Args{
+
+    #[arg(long)]
+    /// Name server to audit
+    server: String,
+
+    #[arg(long)]
+    /// The name server's public key (64 hex digits)
+    server_key: String,
+
+    #[arg(long, default_value = "audited-keys.log")]
+    /// Local copy of the log, extended on each run so only new entries are downloaded
+    copy: PathBuf,
+
+}
+
+/// Checks what the log says makes sense on its own: valid names, and no name released before it was held.
+fn check_bindings(bindings: &[Binding]) -> Result<(), io::Error>{
+    let mut held = HashMap::new();
+    for (index, binding) in bindings.iter().enumerate(){
+        validate_name(&binding.name)
+            .map_err(|_| Error::new(ErrorKind::InvalidData, format!("Entry {} has invalid name {:?}", index, binding.name)))?;
+        if binding.identity.is_none() && held.get(&binding.name).copied().flatten().is_none(){
+            return Err(Error::new(ErrorKind::InvalidData,
+                format!("Entry {} releases {}, which nobody held", index, binding.name)));
+        }
+        held.insert(&binding.name, binding.identity);
+    }
+    Ok(())
+}
+
+fn main() -> Result<(), io::Error>{
+    let args = Args::parse();
+    let server_key = IdentityPublic::from_hex(&args.server_key)?;
+    let mut copy = TransparencyLog::new().with_storage(Box::new(LogStorage::open(&args.copy)?))?;
+    let mut client = NameServerClient::connect(&args.server, &server_key)?;
+    let head = client.fetch_tree_head()?.head;
+    if head.size < copy.size(){
+        return Err(Error::new(ErrorKind::InvalidData,
+            format!("Log shrank from {} entries to {}", copy.size(), head.size)));
+    }
+
+    let mut fresh = Vec::new();
+    while copy.size() + (fresh.len() as u64) < head.size{
+        let batch = client.log_entries(copy.size() + fresh.len() as u64, head.size)?;
+        if batch.is_empty(){
+            return Err(Error::new(ErrorKind::InvalidData, "Name server stopped handing out log entries"));
+        }
+        fresh.extend(batch);
+    }
+    let audited = TransparencyLog::from_bindings(copy.bindings().iter().cloned().chain(fresh.iter().cloned()));
+    if audited.head() != head{
+        return Err(Error::new(ErrorKind::InvalidData, format!(
+            "Signed tree head for {} entries does not match the log (the server rewrote history or lied about it)", head.size)));
+    }
+    check_bindings(audited.bindings())?;
+
+    // Only entries the head vouched for go into the copy
+    let new = fresh.len();
+    for binding in fresh{
+        copy.append(binding)?;
+    }
+    let root: String = head.root.iter().map(|b| format!("{:02x}", b)).collect();
+    println!("Log consistent: {} entries ({} new), root {}", head.size, new, root);
+    Ok(())
+}
@@ -0,0 +1,497 @@
+use std::io::{self, Error, ErrorKind};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use chat_security::sealed::DatagramKey;
+use chat_security::udp::Datagram;
+use chat_security::x3dh::{PrekeyBundle, PrekeyUpload};
+use chat_security::{IdentityKey, IdentityPublic, SessionCryptData};
+use rand::rngs::OsRng;
+
+use crate::admin::{follow_rotations, AdminRequest, KeyRotation, Registration};
+use crate::federation::split_address;
+use crate::invite::SignedInvite;
+use crate::policy::Policy;
+use crate::replication::Batch;
+use crate::protocol::{decode, encode, read_frame, solve_registration_work, write_frame, Action, Entry, Envelope, ErrorCode,
+    Request, Response, SignedUpdate, Update, MAX_DATAGRAM_LEN, SERVER_NAME};
+use crate::rendezvous::LISTEN_TTL;
+use crate::subscriptions::{Event, SUBSCRIPTION_PING};
+use crate::transparency::{verify_consistency, Binding, InclusionProof, SignedTreeHead, TreeHead};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Most registration work we'll do for a server; each bit doubles it.
+const MAX_REGISTRATION_WORK: u8 = 28;
+/// Datagrams get lost, so rendezvous requests are retried this often, up to `REQUEST_TIMEOUT`.
+const DATAGRAM_RETRY: Duration = Duration::from_millis(500);
+
+static LAST_SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+/// Sequence numbers are microseconds since the epoch, bumped if needed so that they never repeat within
+/// this process. That keeps them increasing across restarts without storing any state.
+fn next_sequence() -> u64{
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_micros() as u64).unwrap_or(0);
+    let previous = LAST_SEQUENCE.fetch_max(now, Ordering::SeqCst);
+    if previous >= now { LAST_SEQUENCE.fetch_add(1, Ordering::SeqCst) + 1 } else { now }
+}
+
+fn signed_request(name: &str, action: Action, server: &IdentityPublic, signer: &IdentityKey) -> Request{
+    let update = Update{ name: name.to_string(), action, sequence: next_sequence() };
+    Request::Update(SignedUpdate::sign(update, server, signer))
+}
+
+/// Connection to a name server, for registering ourselves and resolving peers.
+pub struct NameServerClient{
+    session: SessionCryptData,
+    server_key: IdentityPublic,
+    tree_head: Option<SignedTreeHead>,
+}
+
+impl NameServerClient{
+    /// Connects to the name server at `addr`, failing unless it proves it holds `server_key`.
+    pub fn connect(addr: impl ToSocketAddrs, server_key: &IdentityPublic) -> Result<Self, io::Error>{
+        // The server has no use for our identity, so a throwaway key will do
+        Self::handshake(addr, server_key, &IdentityKey::generate(), "client")
+    }
+
+    /// Connects under `identity`, so that names whose privacy policy lists it as a contact can be found.
+    pub fn connect_as(addr: impl ToSocketAddrs, server_key: &IdentityPublic, identity: &IdentityKey) -> Result<Self, io::Error>{
+        Self::handshake(addr, server_key, identity, "client")
+    }
+
+    /// Connects as the name server holding `identity`, for forwarding lookups to a federated domain.
+    pub fn connect_as_server(addr: impl ToSocketAddrs, server_key: &IdentityPublic, identity: &IdentityKey) -> Result<Self, io::Error>{
+        Self::handshake(addr, server_key, identity, SERVER_NAME)
+    }
+
+    /// Connects under an admin key, for the admin requests below.
+    pub fn connect_as_admin(addr: impl ToSocketAddrs, server_key: &IdentityPublic, admin: &IdentityKey) -> Result<Self, io::Error>{
+        Self::handshake(addr, server_key, admin, "admin")
+    }
+
+    fn handshake(addr: impl ToSocketAddrs, server_key: &IdentityPublic, identity: &IdentityKey, name: &str) -> Result<Self, io::Error>{
+        let stream = TcpStream::connect(addr)?;
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        let session = SessionCryptData::start_authenticated_session(stream, identity, name)?;
+        let mismatch = || Error::new(ErrorKind::PermissionDenied, "Name server key does not match the configured key");
+        let presented = session.peer().ok_or_else(mismatch)?.key;
+        let mut client = NameServerClient{ session, server_key: presented, tree_head: None };
+        // A key we don't know may be one the server rotated to since we pinned ours
+        if presented != *server_key{
+            match client.call(&Request::KeyRotations){
+                Ok(Response::KeyRotations(rotations)) if follow_rotations(server_key, &rotations, &presented) => {}
+                _ => return Err(mismatch()),
+            }
+        }
+        Ok(client)
+    }
+
+    /// The key the server holds now, which differs from the one we connected with if it has been rotated.
+    pub fn server_key(&self) -> IdentityPublic{
+        self.server_key
+    }
+
+    pub(crate) fn call(&mut self, request: &Request) -> Result<Response, io::Error>{
+        write_frame(&mut self.session, request)?;
+        match read_frame(&mut self.session)?{
+            Response::Error(code) => Err(code.into()),
+            response => Ok(response),
+        }
+    }
+
+    fn update(&mut self, name: &str, action: Action, signer: &IdentityKey) -> Result<Response, io::Error>{
+        self.call(&signed_request(name, action, &self.server_key, signer))
+    }
+
+    /// Registers `name` at `address` under `identity`, returning the entry as stored (with the IP filled in
+    /// if `address` was unspecified). Does the proof of work first if the server wants some for a new name.
+    pub fn register(&mut self, name: &str, address: SocketAddr, identity: &IdentityKey) -> Result<Entry, io::Error>{
+        let register = |work| Action::Register{ address, identity: identity.public(), work };
+        let response = match self.update(name, register(0), identity){
+            Err(e) if e.get_ref().and_then(|e| e.downcast_ref()) == Some(&ErrorCode::InsufficientWork) => {
+                let bits = self.registration_work()?;
+                if bits > MAX_REGISTRATION_WORK{
+                    return Err(Error::new(ErrorKind::Unsupported,
+                        format!("Name server wants {} bits of registration work, more than we'll do", bits)));
+                }
+                self.update(name, register(solve_registration_work(name, &identity.public(), bits)), identity)?
+            }
+            response => response?,
+        };
+        match response{
+            Response::Registered(entry) => Ok(entry),
+            _ => Err(Error::new(ErrorKind::InvalidData, "Unexpected response to register")),
+        }
+    }
+
+    /// Leading zero bits of proof of work the server wants for claiming a new name.
+    pub fn registration_work(&mut self) -> Result<u8, io::Error>{
+        match self.call(&Request::RegistrationWork)?{
+            Response::RegistrationWork{ bits } => Ok(bits),
+            _ => Err(Error::new(ErrorKind::InvalidData, "Unexpected response to registration work request")),
+        }
+    }
+
+    /// Hands `name` over to `new_owner`. Must be signed by the current owner.
+    pub fn transfer(&mut self, name: &str, owner: &IdentityKey, new_owner: IdentityPublic) -> Result<Entry, io::Error>{
+        match self.update(name, Action::Transfer{ identity: new_owner }, owner)?{
+            Response::Registered(entry) => Ok(entry),
+            _ => Err(Error::new(ErrorKind::InvalidData, "Unexpected response to transfer")),
+        }
+    }
+
+    /// Replaces who may find `name` and see its presence. Must be signed by the owner.
+    pub fn set_policy(&mut self, name: &str, owner: &IdentityKey, policy: Policy) -> Result<Entry, io::Error>{
+        match self.update(name, Action::SetPolicy(policy), owner)?{
+            Response::Registered(entry) => Ok(entry),
+            _ => Err(Error::new(ErrorKind::InvalidData, "Unexpected response to set policy")),
+        }
+    }
+
+    /// Renews the lease on `name`. Fails with `LeaseExpired` once it has run out.
+    pub fn heartbeat(&mut self, name: &str, owner: &IdentityKey) -> Result<Entry, io::Error>{
+        match self.update(name, Action::Heartbeat, owner)?{
+            Response::Registered(entry) => Ok(entry),
+            _ => Err(Error::new(ErrorKind::InvalidData, "Unexpected response to heartbeat")),
+        }
+    }
+
+    /// Publishes prekeys for `name`, returning how many one-time prekeys the server now holds.
+    pub fn publish_prekeys(&mut self, name: &str, owner: &IdentityKey, upload: PrekeyUpload) -> Result<usize, io::Error>{
+        match self.update(name, Action::PublishPrekeys(upload), owner)?{
+            Response::PrekeysStored{ one_time } => Ok(one_time),
+            _ => Err(Error::new(ErrorKind::InvalidData, "Unexpected response to prekey upload")),
+        }
+    }
+
+    pub fn fetch_prekeys(&mut self, name: &str) -> Result<PrekeyBundle, io::Error>{
+        match self.call(&Request::FetchPrekeys{ name: name.to_string() })?{
+            Response::Prekeys(bundle) => Ok(bundle),
+            _ => Err(Error::new(ErrorKind::InvalidData, "Unexpected response to prekey fetch")),
+        }
+    }
+
+    /// Leaves an encrypted envelope for `to` to collect later, returning its id.
+    pub fn deposit(&mut self, to: &str, payload: Vec<u8>) -> Result<u64, io::Error>{
+        match self.call(&Request::Deposit{ to: to.to_string(), payload })?{
+            Response::Deposited{ id } => Ok(id),
+            _ => Err(Error::new(ErrorKind::InvalidData, "Unexpected response to deposit")),
+        }
+    }
+
+    /// Fetches the envelopes waiting for `name`. They are kept until `acknowledge`d.
+    pub fn fetch_mail(&mut self, name: &str, owner: &IdentityKey) -> Result<Vec<Envelope>, io::Error>{
+        match self.update(name, Action::FetchMail, owner)?{
+            Response::Mail(envelopes) => Ok(envelopes),
+            _ => Err(Error::new(ErrorKind::InvalidData, "Unexpected response to mail fetch")),
+        }
+    }
+
+    pub fn acknowledge(&mut self, name: &str, owner: &IdentityKey, ids: Vec<u64>) -> Result<usize, io::Error>{
+        match self.update(name, Action::Acknowledge{ ids }, owner)?{
+            Response::Acknowledged{ removed } => Ok(removed),
+            _ => Err(Error::new(ErrorKind::InvalidData, "Unexpected response to acknowledge")),
+        }
+    }
+
+    /// Waits on the name server's relay for someone to join `name`, returning the spliced connection. Blocks
+    /// until a peer arrives.
+    pub fn park(mut self, name: &str, owner: &IdentityKey) -> Result<TcpStream, io::Error>{
+        match self.update(name, Action::Park, owner)?{
+            Response::Parked => {}
+            _ => return Err(Error::new(ErrorKind::InvalidData, "Unexpected response to park")),
+        }
+        self.session.get_ref().set_read_timeout(None)?;
+        match read_frame(&mut self.session)?{
+            Response::Joined => self.into_stream(),
+            _ => Err(Error::new(ErrorKind::InvalidData, "Unexpected message while parked")),
+        }
+    }
+
+    /// Connects through the relay to whoever is parked under `to`.
+    pub fn join(mut self, to: &str) -> Result<TcpStream, io::Error>{
+        match self.call(&Request::Join{ to: to.to_string() })?{
+            Response::Joined => self.into_stream(),
+            _ => Err(Error::new(ErrorKind::InvalidData, "Unexpected response to join")),
+        }
+    }
+
+    /// Watches `names` for changes, returning the current entries of those that are registered and the
+    /// subscription the changes will arrive on. The entries aren't checked against the key log.
+    pub fn subscribe(mut self, names: &[String]) -> Result<(Vec<Entry>, Subscription), io::Error>{
+        match self.call(&Request::Subscribe{ names: names.to_vec() })?{
+            Response::Subscribed(entries) => {
+                // The server writes at least this often, so a quieter one is gone
+                self.session.get_ref().set_read_timeout(Some(2 * SUBSCRIPTION_PING))?;
+                Ok((entries, Subscription{ session: self.session }))
+            }
+            _ => Err(Error::new(ErrorKind::InvalidData, "Unexpected response to subscribe")),
+        }
+    }
+
+    /// The connection under our session with the server, which the relay has spliced to the peer's.
+    fn into_stream(self) -> Result<TcpStream, io::Error>{
+        let stream = self.session.get_ref().try_clone()?;
+        stream.set_read_timeout(None)?;
+        stream.set_nonblocking(false)?;
+        Ok(stream)
+    }
+
+    pub fn delete(&mut self, name: &str, owner: &IdentityKey) -> Result<(), io::Error>{
+        match self.update(name, Action::Delete, owner)?{
+            Response::Deleted => Ok(()),
+            _ => Err(Error::new(ErrorKind::InvalidData, "Unexpected response to delete")),
+        }
+    }
+
+    /// Every registration, suspended ones included. Needs an admin connection.
+    pub fn registrations(&mut self) -> Result<Vec<Registration>, io::Error>{
+        match self.call(&Request::Admin(AdminRequest::List))?{
+            Response::Registrations(registrations) => Ok(registrations),
+            _ => Err(Error::new(ErrorKind::InvalidData, "Unexpected response to list")),
+        }
+    }
+
+    pub fn registration(&mut self, name: &str) -> Result<Registration, io::Error>{
+        match self.call(&Request::Admin(AdminRequest::Inspect{ name: name.to_string() }))?{
+            Response::Registration(registration) => Ok(registration),
+            _ => Err(Error::new(ErrorKind::InvalidData, "Unexpected response to inspect")),
+        }
+    }
+
+    /// Suspends `name`, or reinstates it if `suspended` is false.
+    pub fn suspend(&mut self, name: &str, suspended: bool) -> Result<Registration, io::Error>{
+        match self.call(&Request::Admin(AdminRequest::Suspend{ name: name.to_string(), suspended }))?{
+            Response::Registration(registration) => Ok(registration),
+            _ => Err(Error::new(ErrorKind::InvalidData, "Unexpected response to suspend")),
+        }
+    }
+
+    /// Deletes `name` without its owner.
+    pub fn remove(&mut self, name: &str) -> Result<(), io::Error>{
+        match self.call(&Request::Admin(AdminRequest::Delete{ name: name.to_string() }))?{
+            Response::Deleted => Ok(()),
+            _ => Err(Error::new(ErrorKind::InvalidData, "Unexpected response to delete")),
+        }
+    }
+
+    /// Has the server switch to a fresh key, returning the old key's signature over the new.
+    pub fn rotate_key(&mut self) -> Result<KeyRotation, io::Error>{
+        match self.call(&Request::Admin(AdminRequest::RotateKey))?{
+            Response::KeyRotated(rotation) => {
+                if rotation.old != self.server_key{
+                    return Err(Error::new(ErrorKind::InvalidData, "Name server rotated a key other than its own"));
+                }
+                rotation.verify()?;
+                self.server_key = rotation.new;
+                Ok(rotation)
+            }
+            _ => Err(Error::new(ErrorKind::InvalidData, "Unexpected response to key rotation")),
+        }
+    }
+
+    /// Looks up `name`, checking that its key is the one the server's key log commits to. Absence isn't
+    /// proven; a server can always claim not to know a name.
+    pub fn lookup(&mut self, name: &str) -> Result<Option<Entry>, io::Error>{
+        Ok(self.lookup_with_proof(name)?.map(|(entry, _)| entry))
+    }
+
+    /// As `lookup`, also returning the checked inclusion proof. For `name@domain` at a federated domain the
+    /// proof is under the key of that domain's server, which our server vouches for.
+    pub fn lookup_with_proof(&mut self, name: &str) -> Result<Option<(Entry, InclusionProof)>, io::Error>{
+        let (local, _) = split_address(name)?;
+        match self.call(&Request::Lookup{ name: name.to_string() })?{
+            Response::Found{ entry, proof } => {
+                if entry.name != local{
+                    return Err(Error::new(ErrorKind::InvalidData, "Name server answered for a different name"));
+                }
+                let binding = Binding{ name: entry.name.clone(), identity: Some(entry.identity) };
+                proof.verify(&binding, &self.server_key)?;
+                self.tree_head = Some(proof.head.clone());
+                Ok(Some((entry, proof)))
+            }
+            // Another server's log, so not one to track heads of
+            Response::Forwarded{ entry, proof, server } => {
+                if entry.name != name || local == name{
+                    return Err(Error::new(ErrorKind::InvalidData, "Name server answered for a different name"));
+                }
+                proof.verify(&Binding{ name: local.to_string(), identity: Some(entry.identity) }, &server)?;
+                Ok(Some((entry, proof)))
+            }
+            Response::NotFound => Ok(None),
+            _ => Err(Error::new(ErrorKind::InvalidData, "Unexpected response to lookup")),
+        }
+    }
+
+    /// Checks `invite` with the server it names, which we must be connected to, using it up if it is single
+    /// use. Returns the inviter's entry, checked against the key log.
+    pub fn redeem_invite(&mut self, invite: &SignedInvite) -> Result<Entry, io::Error>{
+        match self.call(&Request::RedeemInvite(invite.clone()))?{
+            Response::InviteRedeemed{ entry, proof } => {
+                if entry.name != invite.invite.name || entry.identity != invite.invite.identity{
+                    return Err(Error::new(ErrorKind::InvalidData, "Name server answered for a different name"));
+                }
+                let binding = Binding{ name: entry.name.clone(), identity: Some(entry.identity) };
+                proof.verify(&binding, &self.server_key)?;
+                self.tree_head = Some(proof.head.clone());
+                Ok(entry)
+            }
+            _ => Err(Error::new(ErrorKind::InvalidData, "Unexpected response to invite")),
+        }
+    }
+
+    /// Asks the primary for the changes after `position` in `epoch`, and the key log entries after
+    /// `log_size`, waiting a while for some if there are none yet. Needs a connection under the server's key.
+    pub fn replicate(&mut self, epoch: u64, position: u64, log_size: u64) -> Result<Batch, io::Error>{
+        match self.call(&Request::Replicate{ epoch, position, log_size })?{
+            Response::Replication(batch) => Ok(batch),
+            _ => Err(Error::new(ErrorKind::InvalidData, "Unexpected response to replicate")),
+        }
+    }
+
+    /// The newest signed tree head this client has checked, from a lookup or `fetch_tree_head`. Worth
+    /// passing on to peers, who can check it against theirs.
+    pub fn tree_head(&self) -> Option<&SignedTreeHead>{
+        self.tree_head.as_ref()
+    }
+
+    pub fn fetch_tree_head(&mut self) -> Result<SignedTreeHead, io::Error>{
+        match self.call(&Request::TreeHead)?{
+            Response::TreeHead(head) => {
+                head.verify(&self.server_key)?;
+                self.tree_head = Some(head.clone());
+                Ok(head)
+            }
+            _ => Err(Error::new(ErrorKind::InvalidData, "Unexpected response to tree head request")),
+        }
+    }
+
+    /// Checks with the server that two tree heads it signed describe one log, the smaller a prefix of the
+    /// larger. Fails with `PermissionDenied` if they don't, which means the server has shown someone a
+    /// different history.
+    pub fn check_consistency(&mut self, a: &TreeHead, b: &TreeHead) -> Result<(), io::Error>{
+        let (old, new) = if a.size <= b.size { (a, b) } else { (b, a) };
+        let proof = if old.size == new.size{
+            Vec::new()
+        }
+        else{
+            match self.call(&Request::Consistency{ old: old.size, new: new.size })?{
+                Response::Consistency(proof) => proof,
+                _ => return Err(Error::new(ErrorKind::InvalidData, "Unexpected response to consistency request")),
+            }
+        };
+        if !verify_consistency(old, new, &proof){
+            return Err(Error::new(ErrorKind::PermissionDenied,
+                format!("Name server's key log at size {} is not a prefix of its log at size {}", old.size, new.size)));
+        }
+        Ok(())
+    }
+
+    /// Key log entries from `start` up to `end`, or fewer if the server cuts the reply short.
+    pub fn log_entries(&mut self, start: u64, end: u64) -> Result<Vec<Binding>, io::Error>{
+        match self.call(&Request::LogEntries{ start, end })?{
+            Response::LogEntries(bindings) if bindings.len() as u64 <= end.saturating_sub(start) => Ok(bindings),
+            _ => Err(Error::new(ErrorKind::InvalidData, "Unexpected response to log entries request")),
+        }
+    }
+}
+
+/// A connection the name server pushes changes to watched names down; see `NameServerClient::subscribe`.
+pub struct Subscription{
+    session: SessionCryptData,
+}
+
+impl Subscription{
+    /// Waits for the next changes. Fails if the server hangs up or goes quiet for too long.
+    pub fn wait(&mut self) -> Result<Vec<Event>, io::Error>{
+        loop{
+            match read_frame(&mut self.session)?{
+                Response::Events(events) if events.is_empty() => {}
+                Response::Events(events) => return Ok(events),
+                Response::Error(code) => return Err(code.into()),
+                _ => return Err(Error::new(ErrorKind::InvalidData, "Unexpected message on subscription")),
+            }
+        }
+    }
+}
+
+/// Hole punching rendezvous with a name server, over the UDP socket that will carry the punched connection.
+pub struct RendezvousClient<'a, S: Datagram>{
+    socket: &'a S,
+    server: SocketAddr,
+    server_key: IdentityPublic,
+}
+
+impl<'a, S: Datagram> RendezvousClient<'a, S>{
+    pub fn new(socket: &'a S, server: SocketAddr, server_key: IdentityPublic) -> Self{
+        RendezvousClient{ socket, server, server_key }
+    }
+
+    /// Sends `request` sealed to the server, returning the key its answers will be sealed under.
+    fn send(&self, request: &Request) -> Result<DatagramKey, io::Error>{
+        let (key, data) = DatagramKey::seal_request(&self.server_key, &encode(request)?, &mut OsRng)?;
+        self.socket.send_to(&data, self.server)?;
+        Ok(key)
+    }
+
+    /// Waits until `deadline` for the server's next datagram sealed under one of `keys`. Anything else
+    /// arriving on the socket is dropped.
+    fn receive(&self, keys: &[DatagramKey], deadline: Instant) -> Result<Option<Response>, io::Error>{
+        let mut buf = [0u8; MAX_DATAGRAM_LEN];
+        loop{
+            let Some(remaining) = deadline.checked_duration_since(Instant::now()).filter(|d| !d.is_zero()) else {
+                return Ok(None);
+            };
+            self.socket.set_read_timeout(Some(remaining))?;
+            match self.socket.recv_from(&mut buf){
+                Ok((n, from)) if from == self.server => {
+                    let Some(plaintext) = keys.iter().find_map(|key| key.open_reply(&buf[..n]).ok()) else { continue };
+                    match decode(&plaintext){
+                        Ok(Response::Error(code)) => return Err(code.into()),
+                        Ok(response) => return Ok(Some(response)),
+                        Err(_) => continue,
+                    }
+                }
+                Ok(_) => continue,
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => return Ok(None),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Listens as `name` until someone asks for a rendezvous, returning their public endpoint. Blocks until
+    /// then, repeating the listen often enough that it never lapses.
+    pub fn listen(&self, name: &str, owner: &IdentityKey) -> Result<SocketAddr, io::Error>{
+        // The server may still tell us under the key of the previous listen if this one was lost
+        let mut keys = Vec::new();
+        loop{
+            keys.insert(0, self.send(&signed_request(name, Action::Listen, &self.server_key, owner))?);
+            keys.truncate(2);
+            let deadline = Instant::now() + LISTEN_TTL / 3;
+            while let Some(response) = self.receive(&keys, deadline)?{
+                if let Response::Rendezvous(peer) = response{
+                    return Ok(peer);
+                }
+            }
+        }
+    }
+
+    /// Asks to be introduced to `to`, returning its public endpoint. `to` learns ours at the same time, and
+    /// both sides should start punching right away.
+    pub fn rendezvous(&self, to: &str) -> Result<SocketAddr, io::Error>{
+        let deadline = Instant::now() + REQUEST_TIMEOUT;
+        // A late answer to any of our tries will do
+        let mut keys = Vec::new();
+        while Instant::now() < deadline{
+            keys.push(self.send(&Request::Rendezvous{ to: to.to_string() })?);
+            if let Some(response) = self.receive(&keys, Instant::now() + DATAGRAM_RETRY)?{
+                return match response{
+                    Response::Rendezvous(peer) => Ok(peer),
+                    _ => Err(Error::new(ErrorKind::InvalidData, "Unexpected response to rendezvous")),
+                };
+            }
+        }
+        Err(Error::new(ErrorKind::TimedOut, "Name server did not answer the rendezvous request"))
+    }
+}
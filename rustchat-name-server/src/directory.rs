@@ -0,0 +1,602 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use chat_security::x3dh::{Prekey, PrekeyBundle, SignedPrekey};
+use chat_security::{Clock, IdentityPublic, SystemClock};
+use serde::{Deserialize, Serialize};
+
+use crate::admin::Registration;
+use crate::policy::Policy;
+use crate::protocol::{validate_name, Action, Entry, ErrorCode, Presence, SignedUpdate};
+use crate::replication::{Journal, Shipping};
+use crate::storage::{LogRecord, Storage};
+use crate::subscriptions::Subscriptions;
+
+pub const DEFAULT_LEASE: Duration = Duration::from_secs(300);
+
+/// One-time prekeys kept per name. Older ones are dropped first when an upload goes over.
+pub const MAX_ONE_TIME_PREKEYS: usize = 100;
+
+/// Compaction waits for at least this many appends, so small directories don't rewrite constantly.
+const MIN_COMPACTION_BACKLOG: usize = 1024;
+
+/// A name's stored state. Presence is derived from the lease when the entry is read.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Record{
+    pub entry: Entry,
+    pub lease_expires: u64,
+    pub last_seen: u64,
+    pub prekeys: Option<Prekeys>,
+    /// Set by an admin. The name stays taken, but isn't found and can't be changed.
+    pub suspended: bool,
+    pub policy: Policy,
+    /// When the owner last set `policy`, which is as recently as an invisible name is shown to have been seen.
+    pub policy_set: u64,
+}
+
+/// Prekeys the owner published for starting sessions while they are offline.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Prekeys{
+    pub signed: SignedPrekey,
+    pub one_time: VecDeque<Prekey>,
+}
+
+impl Record{
+    fn at(&self, now: u64) -> Entry{
+        let presence = if now < self.lease_expires{
+            Presence::Online{ lease_expires: self.lease_expires }
+        }
+        else{
+            Presence::Offline{ last_seen: self.last_seen }
+        };
+        Entry{ presence, ..self.entry.clone() }
+    }
+}
+
+/// Map from registered names to where and who they are, optionally backed by `Storage`.
+pub struct Directory{
+    records: HashMap<String, Record>,
+    // Outlives deletion, so a deleted name's old updates can't be replayed to resurrect it
+    sequences: HashMap<String, u64>,
+    clock: Arc<dyn Clock>,
+    lease: Duration,
+    storage: Option<Box<dyn Storage>>,
+    appended: usize,
+    compaction_backlog: usize,
+    subscriptions: Option<Arc<Subscriptions>>,
+}
+
+impl Default for Directory{
+    fn default() -> Self{
+        Directory{
+            records: HashMap::new(),
+            sequences: HashMap::new(),
+            clock: Arc::new(SystemClock),
+            lease: DEFAULT_LEASE,
+            storage: None,
+            appended: 0,
+            compaction_backlog: MIN_COMPACTION_BACKLOG,
+            subscriptions: None,
+        }
+    }
+}
+
+impl Directory{
+    pub fn new() -> Self{
+        Self::default()
+    }
+
+    pub fn with_clock(clock: Arc<dyn Clock>, lease: Duration) -> Self{
+        Directory{ clock, lease, ..Self::default() }
+    }
+
+    /// Recovers the state kept in `storage` and persists every later change there.
+    pub fn with_storage(mut self, mut storage: Box<dyn Storage>) -> Result<Self, io::Error>{
+        for record in storage.load()?{
+            self.replay(record);
+        }
+        // Starting from a compact log keeps recovery time proportional to the directory size
+        storage.compact(&self.snapshot())?;
+        self.storage = Some(storage);
+        Ok(self)
+    }
+
+    /// Rebuilds the state described by `records`, without attaching any storage.
+    pub fn from_records(records: impl IntoIterator<Item = LogRecord>) -> Self{
+        let mut directory = Self::default();
+        for record in records{
+            directory.replay(record);
+        }
+        directory
+    }
+
+    /// Sends every later change to `journal` as well as to storage, starting it off with the current state.
+    pub fn ship_to(&mut self, journal: Arc<Journal>){
+        journal.reset(&self.snapshot());
+        self.storage = Some(Box::new(Shipping::new(self.storage.take(), journal)));
+    }
+
+    /// Tells `subscriptions` about every later change, whether made here or shipped from a primary.
+    pub fn notify(&mut self, subscriptions: Arc<Subscriptions>){
+        self.subscriptions = Some(subscriptions);
+    }
+
+    /// Applies a change shipped from a primary, which has already checked it.
+    pub fn replicate(&mut self, record: LogRecord){
+        self.replay(record);
+    }
+
+    /// Forgets every name, for a replica about to copy its primary's directory afresh.
+    pub fn clear(&mut self){
+        self.records.clear();
+        self.sequences.clear();
+    }
+
+    fn replay(&mut self, record: LogRecord){
+        let name = match record{
+            LogRecord::Put{ name, record, sequence } => {
+                self.sequences.insert(name.clone(), sequence);
+                self.records.insert(name.clone(), *record);
+                name
+            }
+            LogRecord::Remove{ name, sequence } => {
+                self.sequences.insert(name.clone(), sequence);
+                self.records.remove(&name);
+                name
+            }
+        };
+        if let Some(subscriptions) = &self.subscriptions{
+            subscriptions.notify(&name);
+        }
+    }
+
+    /// The whole state as a minimal list of log records.
+    pub fn snapshot(&self) -> Vec<LogRecord>{
+        let mut names = self.sequences.keys().collect::<Vec<_>>();
+        names.sort();
+        names.into_iter().map(|name| {
+            let sequence = self.sequences[name];
+            match self.records.get(name){
+                Some(record) => LogRecord::Put{ name: name.clone(), record: Box::new(record.clone()), sequence },
+                None => LogRecord::Remove{ name: name.clone(), sequence },
+            }
+        }).collect()
+    }
+
+    /// Writes `record` ahead of applying it, so nothing is acknowledged that a restart would lose.
+    fn persist(&mut self, record: LogRecord) -> Result<(), ErrorCode>{
+        if let Some(storage) = &mut self.storage{
+            storage.append(&record).map_err(|e| {
+                eprintln!("Failed to persist update: {}", e);
+                ErrorCode::StorageFailure
+            })?;
+            self.appended += 1;
+        }
+        self.replay(record);
+
+        if self.appended >= self.compaction_backlog.max(2 * self.sequences.len()){
+            let snapshot = self.snapshot();
+            if let Some(storage) = &mut self.storage{
+                // A failed compaction leaves the old log in place, which is still complete
+                match storage.compact(&snapshot){
+                    Ok(()) => self.appended = 0,
+                    Err(e) => eprintln!("Failed to compact log: {}", e),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks `signed` against the current owner of its name and applies it. `observed_ip` fills in an
+    /// unspecified registration address. The caller checks that it was signed for this server.
+    ///
+    /// Returns the entry as stored, or `None` after a delete.
+    pub fn apply(&mut self, signed: SignedUpdate, observed_ip: IpAddr) -> Result<Option<Entry>, ErrorCode>{
+        let update = &signed.update;
+        validate_name(&update.name)?;
+        let now = self.clock.unix_time();
+        let current = self.records.get(&update.name);
+        if current.is_some_and(|record| record.suspended){
+            return Err(ErrorCode::Suspended);
+        }
+        let owner = match (&update.action, current){
+            (_, Some(record)) => record.entry.identity,
+            (Action::Register{ identity, .. }, None) => *identity,
+            (_, None) => return Err(ErrorCode::NotRegistered),
+        };
+        signed.verify(&owner)?;
+        if self.sequences.get(&update.name).is_some_and(|last| update.sequence <= *last){
+            return Err(ErrorCode::StaleSequence);
+        }
+
+        let lease_expires = now + self.lease.as_secs();
+        let record = match &update.action{
+            Action::Register{ address, identity, .. } => {
+                if *identity != owner{
+                    return Err(ErrorCode::NameTaken);
+                }
+                let mut address = *address;
+                if address.ip().is_unspecified(){
+                    address.set_ip(observed_ip);
+                }
+                if address.port() == 0 || address.ip().is_unspecified(){
+                    return Err(ErrorCode::InvalidAddress);
+                }
+                let presence = Presence::Online{ lease_expires };
+                Some(Record{
+                    entry: Entry{ name: update.name.clone(), address, identity: owner, presence },
+                    lease_expires,
+                    last_seen: now,
+                    prekeys: current.and_then(|record| record.prekeys.clone()),
+                    suspended: false,
+                    policy: current.map(|record| record.policy.clone()).unwrap_or_default(),
+                    policy_set: current.map_or(now, |record| record.policy_set),
+                })
+            }
+            // The old owner's prekeys would let senders start sessions the new owner can't read, and their
+            // contacts aren't the new owner's
+            Action::Transfer{ identity } => current.map(|record| Record{
+                entry: Entry{ identity: *identity, ..record.entry.clone() },
+                prekeys: None,
+                policy: Policy::default(),
+                policy_set: now,
+                ..record.clone()
+            }),
+            Action::SetPolicy(policy) => {
+                policy.validate()?;
+                current.map(|record| Record{ policy: policy.clone(), policy_set: now, ..record.clone() })
+            }
+            Action::Heartbeat => match current{
+                Some(record) if now < record.lease_expires => Some(Record{
+                    lease_expires,
+                    last_seen: now,
+                    ..record.clone()
+                }),
+                _ => return Err(ErrorCode::LeaseExpired),
+            },
+            Action::PublishPrekeys(upload) => {
+                upload.signed_prekey.verify(&owner).map_err(|_| ErrorCode::BadSignature)?;
+                current.map(|record| {
+                    let mut one_time = record.prekeys.as_ref()
+                        .map(|prekeys| prekeys.one_time.clone())
+                        .unwrap_or_default();
+                    one_time.extend(&upload.one_time_prekeys);
+                    let excess = one_time.len().saturating_sub(MAX_ONE_TIME_PREKEYS);
+                    one_time.drain(..excess);
+                    Record{ prekeys: Some(Prekeys{ signed: upload.signed_prekey.clone(), one_time }), ..record.clone() }
+                })
+            }
+            // Only authorized here; the mailbox, relay and rendezvous live outside the directory
+            Action::FetchMail | Action::Acknowledge{ .. } | Action::Park | Action::Listen => current.cloned(),
+            Action::Delete => None,
+        };
+
+        let (name, sequence) = (update.name.clone(), update.sequence);
+        let entry = record.as_ref().map(|record| record.at(now));
+        self.persist(match record{
+            Some(record) => LogRecord::Put{ name, record: Box::new(record), sequence },
+            None => LogRecord::Remove{ name, sequence },
+        })?;
+        Ok(entry)
+    }
+
+    /// Hands out a prekey bundle for `name`, using up one of its one-time prekeys if any are left.
+    pub fn fetch_prekeys(&mut self, name: &str) -> Result<PrekeyBundle, ErrorCode>{
+        let record = self.records.get(name).filter(|record| !record.suspended).ok_or(ErrorCode::NotRegistered)?;
+        let prekeys = record.prekeys.as_ref().ok_or(ErrorCode::NoPrekeys)?;
+        let mut record = record.clone();
+        let bundle = PrekeyBundle{
+            identity: record.entry.identity,
+            signed_prekey: prekeys.signed.clone(),
+            one_time_prekey: record.prekeys.as_mut().and_then(|prekeys| prekeys.one_time.pop_front()),
+        };
+        if bundle.one_time_prekey.is_some(){
+            // Persisted like any other change so a restart can't hand the same prekey out twice
+            let sequence = self.sequences[name];
+            self.persist(LogRecord::Put{ name: name.to_string(), record: Box::new(record), sequence })?;
+        }
+        Ok(bundle)
+    }
+
+    /// How many one-time prekeys `name` has left, so its owner knows when to upload more.
+    pub fn one_time_prekeys(&self, name: &str) -> usize{
+        self.records.get(name)
+            .and_then(|record| record.prekeys.as_ref())
+            .map_or(0, |prekeys| prekeys.one_time.len())
+    }
+
+    /// Looks up `name`, with its presence as of now.
+    pub fn lookup(&self, name: &str) -> Option<Entry>{
+        let now = self.clock.unix_time();
+        self.records.get(name).filter(|record| !record.suspended).map(|record| record.at(now))
+    }
+
+    /// Looks up `name` as its owner's policy lets `viewer` see it, where `None` is an anonymous client.
+    pub fn lookup_as(&self, name: &str, viewer: Option<&IdentityPublic>) -> Option<Entry>{
+        let now = self.clock.unix_time();
+        let record = self.records.get(name).filter(|record| !record.suspended)?;
+        let entry = record.at(now);
+        if viewer == Some(&record.entry.identity){
+            return Some(entry);
+        }
+        if !record.policy.allows(viewer){
+            return None;
+        }
+        // Where an invisible name is would give away that it's there, and its moves that it's online
+        if record.policy.invisible{
+            let address = SocketAddr::from(([0, 0, 0, 0], 0));
+            return Some(Entry{ address, presence: Presence::Offline{ last_seen: record.policy_set }, ..entry });
+        }
+        Some(entry)
+    }
+
+    /// The clock leases run by.
+    pub fn clock(&self) -> Arc<dyn Clock>{
+        self.clock.clone()
+    }
+
+    /// The privacy policy `name`'s owner has set, if it is registered.
+    pub fn policy(&self, name: &str) -> Option<&Policy>{
+        self.records.get(name).map(|record| &record.policy)
+    }
+
+    /// The key `name` is bound to, suspended or not.
+    pub fn owner(&self, name: &str) -> Option<IdentityPublic>{
+        self.records.get(name).map(|record| record.entry.identity)
+    }
+
+    pub fn registration(&self, name: &str) -> Option<Registration>{
+        let now = self.clock.unix_time();
+        self.records.get(name).map(|record| Registration{
+            entry: record.at(now),
+            lease_expires: record.lease_expires,
+            last_seen: record.last_seen,
+            suspended: record.suspended,
+            one_time_prekeys: record.prekeys.as_ref().map_or(0, |prekeys| prekeys.one_time.len()),
+        })
+    }
+
+    /// Every registration, by name.
+    pub fn registrations(&self) -> Vec<Registration>{
+        let mut names = self.records.keys().collect::<Vec<_>>();
+        names.sort();
+        names.into_iter().filter_map(|name| self.registration(name)).collect()
+    }
+
+    /// Suspends or reinstates `name` on an admin's say-so.
+    pub fn suspend(&mut self, name: &str, suspended: bool) -> Result<Registration, ErrorCode>{
+        let record = self.records.get(name).ok_or(ErrorCode::NotRegistered)?;
+        let record = Record{ suspended, ..record.clone() };
+        let sequence = self.sequences[name];
+        self.persist(LogRecord::Put{ name: name.to_string(), record: Box::new(record), sequence })?;
+        Ok(self.registration(name).expect("Suspended name is registered"))
+    }
+
+    /// Deletes `name` on an admin's say-so. Its last sequence number stays, as after any delete.
+    pub fn remove(&mut self, name: &str) -> Result<(), ErrorCode>{
+        if !self.records.contains_key(name){
+            return Err(ErrorCode::NotRegistered);
+        }
+        let sequence = self.sequences[name];
+        self.persist(LogRecord::Remove{ name: name.to_string(), sequence })
+    }
+
+    /// Names whose lease hasn't run out, suspended ones aside.
+    pub fn active_leases(&self) -> usize{
+        let now = self.clock.unix_time();
+        self.records.values().filter(|record| !record.suspended && now < record.lease_expires).count()
+    }
+
+    pub fn len(&self) -> usize{
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool{
+        self.records.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::Update;
+    use crate::storage::{read_records, LogStorage};
+    use chat_security::x3dh::{PrekeyStore, PrekeyUpload};
+    use rand::rngs::OsRng;
+    use chat_security::{IdentityKey, ManualClock};
+
+    const PEER: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 1));
+
+    // Which server an update was signed for is the server's to check, not the directory's
+    fn update(name: &str, action: Action, sequence: u64, signer: &IdentityKey) -> SignedUpdate {
+        SignedUpdate::sign(Update{ name: name.to_string(), action, sequence }, &IdentityKey::generate().public(), signer)
+    }
+
+    fn register(name: &str, address: &str, sequence: u64, identity: &IdentityKey) -> SignedUpdate {
+        let action = Action::Register{ address: address.parse().unwrap(), identity: identity.public(), work: 0 };
+        update(name, action, sequence, identity)
+    }
+
+    #[test]
+    fn test_register_and_lookup() {
+        let mut directory = Directory::new();
+        let alice = IdentityKey::generate();
+        directory.apply(register("alice", "10.0.0.1:4000", 1, &alice), PEER).unwrap();
+        assert_eq!(directory.lookup("alice").unwrap().address, "10.0.0.1:4000".parse().unwrap());
+        assert!(directory.lookup("bob").is_none());
+
+        directory.apply(register("alice", "0.0.0.0:4000", 2, &alice), PEER).unwrap();
+        assert_eq!(directory.lookup("alice").unwrap().address, "192.0.2.1:4000".parse().unwrap());
+        assert_eq!(directory.len(), 1);
+    }
+
+    #[test]
+    fn test_register_rejects_bad_entries() {
+        let mut directory = Directory::new();
+        let alice = IdentityKey::generate();
+        assert_eq!(directory.apply(register("al ice", "10.0.0.1:4000", 1, &alice), PEER), Err(ErrorCode::InvalidName));
+        assert_eq!(directory.apply(register("alice", "10.0.0.1:0", 1, &alice), PEER), Err(ErrorCode::InvalidAddress));
+        assert!(directory.is_empty());
+    }
+
+    #[test]
+    fn test_name_bound_to_first_key() {
+        let mut directory = Directory::new();
+        let (alice, mallory) = (IdentityKey::generate(), IdentityKey::generate());
+        directory.apply(register("alice", "10.0.0.1:4000", 1, &alice), PEER).unwrap();
+
+        assert_eq!(directory.apply(register("alice", "10.0.0.9:4000", 2, &mallory), PEER), Err(ErrorCode::BadSignature));
+        // Signed by the owner but naming another key is a transfer in disguise
+        let action = Action::Register{ address: "10.0.0.9:4000".parse().unwrap(), identity: mallory.public(), work: 0 };
+        assert_eq!(directory.apply(update("alice", action, 2, &alice), PEER), Err(ErrorCode::NameTaken));
+        assert_eq!(directory.apply(update("alice", Action::Delete, 2, &mallory), PEER), Err(ErrorCode::BadSignature));
+        assert_eq!(directory.lookup("alice").unwrap().identity, alice.public());
+    }
+
+    #[test]
+    fn test_replayed_update_rejected() {
+        let mut directory = Directory::new();
+        let alice = IdentityKey::generate();
+        let first = register("alice", "10.0.0.1:4000", 1, &alice);
+        directory.apply(first.clone(), PEER).unwrap();
+        directory.apply(register("alice", "10.0.0.2:4000", 2, &alice), PEER).unwrap();
+        assert_eq!(directory.apply(first.clone(), PEER), Err(ErrorCode::StaleSequence));
+
+        directory.apply(update("alice", Action::Delete, 3, &alice), PEER).unwrap();
+        assert_eq!(directory.apply(first, PEER), Err(ErrorCode::StaleSequence));
+    }
+
+    #[test]
+    fn test_transfer_and_delete() {
+        let mut directory = Directory::new();
+        let (alice, bob) = (IdentityKey::generate(), IdentityKey::generate());
+        assert_eq!(directory.apply(update("alice", Action::Delete, 1, &alice), PEER), Err(ErrorCode::NotRegistered));
+        directory.apply(register("alice", "10.0.0.1:4000", 1, &alice), PEER).unwrap();
+
+        let transferred = directory.apply(update("alice", Action::Transfer{ identity: bob.public() }, 2, &alice), PEER).unwrap();
+        assert_eq!(transferred.unwrap().identity, bob.public());
+        // Only the new owner may change the name now
+        assert_eq!(directory.apply(update("alice", Action::Delete, 3, &alice), PEER), Err(ErrorCode::BadSignature));
+        assert_eq!(directory.apply(update("alice", Action::Delete, 3, &bob), PEER), Ok(None));
+        assert!(directory.lookup("alice").is_none());
+
+        // Once deleted, anyone may claim the name afresh
+        directory.apply(register("alice", "10.0.0.3:4000", 4, &alice), PEER).unwrap();
+    }
+
+    #[test]
+    fn test_lease_expiry_and_heartbeats() {
+        let clock = ManualClock::new(1_000);
+        let mut directory = Directory::with_clock(Arc::new(clock.clone()), Duration::from_secs(60));
+        let alice = IdentityKey::generate();
+        let entry = directory.apply(register("alice", "10.0.0.1:4000", 1, &alice), PEER).unwrap().unwrap();
+        assert_eq!(entry.presence, Presence::Online{ lease_expires: 1_060 });
+
+        clock.advance(Duration::from_secs(50));
+        let entry = directory.apply(update("alice", Action::Heartbeat, 2, &alice), PEER).unwrap().unwrap();
+        assert_eq!(entry.presence, Presence::Online{ lease_expires: 1_110 });
+
+        clock.advance(Duration::from_secs(59));
+        assert!(directory.lookup("alice").unwrap().presence.is_online());
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(directory.lookup("alice").unwrap().presence, Presence::Offline{ last_seen: 1_050 });
+
+        // An expired lease can't be revived by heartbeat, only by registering again, and the name stays
+        // bound to its owner meanwhile
+        assert_eq!(directory.apply(update("alice", Action::Heartbeat, 3, &alice), PEER), Err(ErrorCode::LeaseExpired));
+        let mallory = IdentityKey::generate();
+        assert_eq!(directory.apply(register("alice", "10.0.0.9:4000", 3, &mallory), PEER), Err(ErrorCode::BadSignature));
+        directory.apply(register("alice", "10.0.0.2:4000", 3, &alice), PEER).unwrap();
+        assert!(directory.lookup("alice").unwrap().presence.is_online());
+    }
+
+    fn publish(name: &str, upload: &PrekeyUpload, sequence: u64, signer: &IdentityKey) -> SignedUpdate {
+        update(name, Action::PublishPrekeys(upload.clone()), sequence, signer)
+    }
+
+    #[test]
+    fn test_prekeys_handed_out_once() {
+        let mut directory = Directory::new();
+        let bob = IdentityKey::generate();
+        let (_, upload) = PrekeyStore::generate(&bob, 2, &mut OsRng);
+        assert_eq!(directory.apply(publish("bob", &upload, 1, &bob), PEER), Err(ErrorCode::NotRegistered));
+        directory.apply(register("bob", "10.0.0.1:4000", 1, &bob), PEER).unwrap();
+        assert_eq!(directory.fetch_prekeys("bob"), Err(ErrorCode::NoPrekeys));
+        directory.apply(publish("bob", &upload, 2, &bob), PEER).unwrap();
+
+        let first = directory.fetch_prekeys("bob").unwrap();
+        let second = directory.fetch_prekeys("bob").unwrap();
+        assert_eq!(first.identity, bob.public());
+        assert_ne!(first.one_time_prekey, second.one_time_prekey);
+        assert_eq!(directory.one_time_prekeys("bob"), 0);
+        // Without one-time prekeys the signed prekey alone still works
+        assert_eq!(directory.fetch_prekeys("bob").unwrap().one_time_prekey, None);
+    }
+
+    #[test]
+    fn test_prekeys_must_be_signed_by_owner() {
+        let mut directory = Directory::new();
+        let (bob, mallory) = (IdentityKey::generate(), IdentityKey::generate());
+        directory.apply(register("bob", "10.0.0.1:4000", 1, &bob), PEER).unwrap();
+        // Signed prekey from another identity, even inside an update signed by the owner
+        let (_, forged) = PrekeyStore::generate(&mallory, 1, &mut OsRng);
+        assert_eq!(directory.apply(publish("bob", &forged, 2, &bob), PEER), Err(ErrorCode::BadSignature));
+        let (_, upload) = PrekeyStore::generate(&bob, 1, &mut OsRng);
+        assert_eq!(directory.apply(publish("bob", &upload, 2, &mallory), PEER), Err(ErrorCode::BadSignature));
+
+        directory.apply(publish("bob", &upload, 2, &bob), PEER).unwrap();
+        directory.apply(update("bob", Action::Transfer{ identity: mallory.public() }, 3, &bob), PEER).unwrap();
+        assert_eq!(directory.fetch_prekeys("bob"), Err(ErrorCode::NoPrekeys));
+    }
+
+    #[test]
+    fn test_state_recovered_from_storage() {
+        let path = std::env::temp_dir().join(format!("rustchat-directory-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let open = || Directory::new().with_storage(Box::new(LogStorage::open(&path).unwrap())).unwrap();
+        let (alice, bob) = (IdentityKey::generate(), IdentityKey::generate());
+
+        let mut directory = open();
+        directory.apply(register("alice", "10.0.0.1:4000", 1, &alice), PEER).unwrap();
+        directory.apply(register("bob", "10.0.0.2:4000", 1, &bob), PEER).unwrap();
+        directory.apply(update("bob", Action::Delete, 2, &bob), PEER).unwrap();
+        drop(directory);
+
+        let mut directory = open();
+        assert_eq!(directory.lookup("alice").unwrap().identity, alice.public());
+        assert!(directory.lookup("bob").is_none());
+        // Ownership and replay protection survive the restart too
+        let mallory = IdentityKey::generate();
+        assert_eq!(directory.apply(register("alice", "10.0.0.9:4000", 5, &mallory), PEER), Err(ErrorCode::BadSignature));
+        assert_eq!(directory.apply(register("bob", "10.0.0.2:4000", 1, &bob), PEER), Err(ErrorCode::StaleSequence));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_log_compacted_as_it_grows() {
+        let path = std::env::temp_dir().join(format!("rustchat-directory-compact-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut directory = Directory::new().with_storage(Box::new(LogStorage::open(&path).unwrap())).unwrap();
+        directory.compaction_backlog = 16;
+        let alice = IdentityKey::generate();
+        directory.apply(register("alice", "10.0.0.1:4000", 1, &alice), PEER).unwrap();
+        for sequence in 2..100 {
+            directory.apply(update("alice", Action::Heartbeat, sequence, &alice), PEER).unwrap();
+        }
+        assert!(read_records::<LogRecord>(&path).unwrap().len() <= 16);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_heartbeat_requires_owner() {
+        let mut directory = Directory::new();
+        let alice = IdentityKey::generate();
+        assert_eq!(directory.apply(update("alice", Action::Heartbeat, 1, &alice), PEER), Err(ErrorCode::NotRegistered));
+        directory.apply(register("alice", "10.0.0.1:4000", 1, &alice), PEER).unwrap();
+        let mallory = IdentityKey::generate();
+        assert_eq!(directory.apply(update("alice", Action::Heartbeat, 2, &mallory), PEER), Err(ErrorCode::BadSignature));
+    }
+}
@@ -0,0 +1,282 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Error, ErrorKind};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chat_security::{Clock, IdentityKey, IdentityPublic, SystemClock};
+
+use crate::client::NameServerClient;
+use crate::protocol::{validate_name, Entry, ErrorCode};
+use crate::transparency::InclusionProof;
+
+/*
+    Federation
+    Names may carry a domain, as in `alice@chat.example`. A server answers for its own domain (and
+    for bare names) from its directory, and forwards lookups for any other domain it has a peer for
+    to that domain's server, which it reaches over the usual encrypted channel and authenticates by
+    a key from its federation config. It authenticates itself in the same handshake with its own
+    identity key, which is how the peer tells forwarded lookups from clients: peers are exempt from
+    per-IP limits, since one server speaks for many users, and are only ever answered locally, so
+    lookups can't loop. The peer's inclusion proof is passed on to the client together with the peer's
+    key, so the client can check the binding is in that domain's key log.
+
+    Answers (found or not) are cached for a while, so popular foreign names don't cost a handshake
+    each. Presence in a cached entry can be that much out of date.
+ */
+
+pub const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(60);
+/// Cached answers held at once; expired ones are pruned to make room.
+pub const MAX_CACHED: usize = 4096;
+pub const MAX_DOMAIN_LEN: usize = 253;
+
+/// Domains are DNS names: dot-separated labels of lowercase letters, digits and hyphens.
+pub fn validate_domain(domain: &str) -> Result<(), ErrorCode>{
+    let valid = domain.len() <= MAX_DOMAIN_LEN
+        && domain.split('.').all(|label| {
+            !label.is_empty() && label.len() <= 63 && !label.starts_with('-') && !label.ends_with('-')
+                && label.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-')
+        });
+    if valid { Ok(()) } else { Err(ErrorCode::InvalidName) }
+}
+
+/// Splits `alice@chat.example` into its name and domain. Bare names have no domain.
+pub fn split_address(address: &str) -> Result<(&str, Option<&str>), ErrorCode>{
+    let (name, domain) = match address.split_once('@'){
+        Some((name, domain)) => {
+            validate_domain(domain)?;
+            (name, Some(domain))
+        }
+        None => (address, None),
+    };
+    validate_name(name)?;
+    Ok((name, domain))
+}
+
+/// The server answering for a foreign domain.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Peer{
+    pub address: String,
+    pub key: IdentityPublic,
+}
+
+/// Where lookups for each foreign domain go.
+///
+/// File format is one `domain address key` line per peer, with the address as `host:port` and the key as
+/// 64 hex digits. `#` starts a comment.
+#[derive(Clone, Debug, Default)]
+pub struct Peers{
+    domains: HashMap<String, Peer>,
+}
+
+impl Peers{
+    pub fn new() -> Self{
+        Self::default()
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, io::Error>{
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(contents: &str) -> Result<Self, io::Error>{
+        let mut peers = Peers::new();
+        for (number, line) in contents.lines().enumerate(){
+            let entry = line.split('#').next().unwrap_or("").trim();
+            if entry.is_empty(){
+                continue;
+            }
+            let invalid = || Error::new(ErrorKind::InvalidData, format!("Invalid federation entry on line {}: {}", number + 1, entry));
+            let [domain, address, key] = entry.split_whitespace().collect::<Vec<_>>()[..] else { return Err(invalid()) };
+            let key = IdentityPublic::from_hex(key).map_err(|_| invalid())?;
+            peers.add(domain, address, key).map_err(|_| invalid())?;
+        }
+        Ok(peers)
+    }
+
+    pub fn add(&mut self, domain: &str, address: &str, key: IdentityPublic) -> Result<(), io::Error>{
+        validate_domain(domain)?;
+        self.domains.insert(domain.to_string(), Peer{ address: address.to_string(), key });
+        Ok(())
+    }
+
+    pub fn get(&self, domain: &str) -> Option<&Peer>{
+        self.domains.get(domain)
+    }
+
+    /// The domain whose server holds `key`, if it is one of ours.
+    pub fn domain_of(&self, key: &IdentityPublic) -> Option<&str>{
+        self.domains.iter().find(|(_, peer)| peer.key == *key).map(|(domain, _)| domain.as_str())
+    }
+
+    pub fn len(&self) -> usize{
+        self.domains.len()
+    }
+
+    pub fn is_empty(&self) -> bool{
+        self.domains.is_empty()
+    }
+}
+
+/// A foreign name as its domain's server answered for it. `entry.name` is the full address.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Remote{
+    pub entry: Entry,
+    pub proof: InclusionProof,
+    /// Key of the server whose log `proof` is for.
+    pub server: IdentityPublic,
+}
+
+pub struct Federation{
+    domain: Option<String>,
+    peers: Peers,
+    /// Answers by address, with when they expire.
+    cache: Mutex<HashMap<String, (u64, Option<Remote>)>>,
+    clock: Arc<dyn Clock>,
+    ttl: Duration,
+}
+
+impl Default for Federation{
+    fn default() -> Self{
+        Federation{
+            domain: None,
+            peers: Peers::new(),
+            cache: Mutex::new(HashMap::new()),
+            clock: Arc::new(SystemClock),
+            ttl: DEFAULT_CACHE_TTL,
+        }
+    }
+}
+
+impl Federation{
+    /// Answers for `domain` (if the server has one) and forwards to `peers`.
+    pub fn new(domain: Option<String>, peers: Peers) -> Result<Self, io::Error>{
+        if let Some(domain) = &domain{
+            validate_domain(domain)?;
+        }
+        Ok(Federation{ domain, peers, ..Self::default() })
+    }
+
+    /// Caches answers for `ttl` by `clock` instead of the defaults.
+    pub fn with_clock(self, clock: Arc<dyn Clock>, ttl: Duration) -> Self{
+        Federation{ clock, ttl, ..self }
+    }
+
+    /// The name `address` has in our own directory, or `None` if it belongs to another domain.
+    pub fn local<'a>(&self, address: &'a str) -> Result<Option<&'a str>, ErrorCode>{
+        Ok(match split_address(address)?{
+            (name, None) => Some(name),
+            (name, Some(domain)) if self.domain.as_deref() == Some(domain) => Some(name),
+            _ => None,
+        })
+    }
+
+    /// The domain of the peer server holding `key`, for telling forwarded lookups from clients.
+    pub fn peer_domain(&self, key: &IdentityPublic) -> Option<&str>{
+        self.peers.domain_of(key)
+    }
+
+    /// Looks up a foreign `address` at its domain's server, connecting as `identity`.
+    pub fn lookup(&self, address: &str, identity: &IdentityKey) -> Result<Option<Remote>, ErrorCode>{
+        let (name, Some(domain)) = split_address(address)? else { return Err(ErrorCode::UnknownDomain) };
+        let now = self.clock.unix_time();
+        if let Some((expires, remote)) = self.cache.lock().unwrap().get(address) && now < *expires{
+            return Ok(remote.clone());
+        }
+        let peer = self.peers.get(domain).ok_or(ErrorCode::UnknownDomain)?;
+        let found = NameServerClient::connect_as_server(&peer.address, &peer.key, identity)
+            .and_then(|mut client| client.lookup_with_proof(name))
+            .map_err(|e| {
+                eprintln!("Failed to look up {} at {}: {}", address, peer.address, e);
+                ErrorCode::FederationFailure
+            })?;
+        let remote = found.map(|(entry, proof)| Remote{ entry: Entry{ name: address.to_string(), ..entry }, proof, server: peer.key });
+
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= MAX_CACHED{
+            cache.retain(|_, (expires, _)| now < *expires);
+        }
+        if cache.len() < MAX_CACHED{
+            cache.insert(address.to_string(), (now + self.ttl.as_secs(), remote.clone()));
+        }
+        Ok(remote)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::limits::{Limiter, Limits};
+    use crate::server::NameServer;
+    use chat_security::ManualClock;
+    use std::net::SocketAddr;
+    use std::thread;
+
+    #[test]
+    fn test_split_address() {
+        assert_eq!(split_address("alice"), Ok(("alice", None)));
+        assert_eq!(split_address("alice@chat.example"), Ok(("alice", Some("chat.example"))));
+        assert_eq!(split_address("alice@Chat.Example"), Err(ErrorCode::InvalidName));
+        assert_eq!(split_address("alice@chat..example"), Err(ErrorCode::InvalidName));
+        assert_eq!(split_address("@chat.example"), Err(ErrorCode::InvalidName));
+        assert_eq!(split_address("alice@bob@chat.example"), Err(ErrorCode::InvalidName));
+    }
+
+    /// Binds a server for `domain` without running it, so its peers can be configured first.
+    fn bind(domain: &str, identity: IdentityKey) -> (NameServer, SocketAddr, IdentityPublic) {
+        let server = NameServer::bind("127.0.0.1:0").unwrap().identity(identity);
+        let (addr, key) = (server.local_addr().unwrap(), server.public_key());
+        let server = server.federation(Federation::new(Some(domain.to_string()), Peers::new()).unwrap());
+        (server, addr, key)
+    }
+
+    #[test]
+    fn test_lookups_forwarded_to_peer_domain() {
+        let clock = ManualClock::new(1_000);
+        let a_identity = IdentityKey::generate();
+        let (a, a_addr, a_key) = bind("a.example", IdentityKey::from_bytes(&a_identity.to_bytes()));
+        // b only lets each IP look up one name a minute, but a's forwarded lookups are exempt
+        let (b, b_addr, b_key) = bind("b.example", IdentityKey::generate());
+        let b = b.limiter(Limiter::new(Limits{ lookups: Some(1), ..Limits::default() }));
+        let mut peers = Peers::new();
+        peers.add("b.example", &b_addr.to_string(), b_key).unwrap();
+        peers.add("c.example", "127.0.0.1:1", IdentityKey::generate().public()).unwrap();
+        let a = a.federation(Federation::new(Some("a.example".to_string()), peers).unwrap()
+            .with_clock(Arc::new(clock.clone()), Duration::from_secs(60)));
+        let b = b.federation(Federation::new(Some("b.example".to_string()),
+            Peers::parse(&format!("a.example {} {}", a_addr, a_key.to_hex())).unwrap()).unwrap());
+        thread::spawn(move || a.run());
+        thread::spawn(move || b.run());
+
+        let (alice, carol) = (IdentityKey::generate(), IdentityKey::generate());
+        let mut at_b = NameServerClient::connect(b_addr, &b_key).unwrap();
+        at_b.register("alice", "127.0.0.1:5100".parse().unwrap(), &alice).unwrap();
+        at_b.register("carol", "127.0.0.1:5101".parse().unwrap(), &carol).unwrap();
+
+        let mut at_a = NameServerClient::connect(a_addr, &a_key).unwrap();
+        let entry = at_a.lookup("alice@b.example").unwrap().unwrap();
+        assert_eq!((entry.name.as_str(), entry.identity), ("alice@b.example", alice.public()));
+        assert_eq!(at_a.lookup("carol@b.example").unwrap().unwrap().identity, carol.public());
+        assert!(at_a.lookup("dave@b.example").unwrap().is_none());
+        // Our own domain is answered locally
+        assert!(at_a.lookup("alice@a.example").unwrap().is_none());
+        assert_eq!(at_a.lookup("alice@d.example").unwrap_err().kind(), ErrorKind::NotFound);
+        let err = at_a.lookup("alice@c.example").unwrap_err();
+        assert_eq!(err.get_ref().unwrap().downcast_ref::<ErrorCode>(), Some(&ErrorCode::FederationFailure));
+
+        // Answers are cached until they expire
+        let mallory = IdentityKey::generate();
+        at_b.transfer("alice", &alice, mallory.public()).unwrap();
+        assert_eq!(at_a.lookup("alice@b.example").unwrap().unwrap().identity, alice.public());
+        clock.advance(Duration::from_secs(61));
+        assert_eq!(at_a.lookup("alice@b.example").unwrap().unwrap().identity, mallory.public());
+
+        // Peers are answered locally only, so b can't be made to forward back
+        let mut as_a = NameServerClient::connect_as_server(b_addr, &b_key, &a_identity).unwrap();
+        assert_eq!(as_a.lookup("carol").unwrap().unwrap().identity, carol.public());
+        assert_eq!(as_a.lookup("alice@a.example").unwrap_err().kind(), ErrorKind::NotFound);
+        // Plain clients from the same IP are still limited
+        at_b.lookup("carol").unwrap();
+        assert_eq!(at_b.lookup("carol").unwrap_err().kind(), ErrorKind::QuotaExceeded);
+    }
+}
@@ -0,0 +1,277 @@
+use std::collections::HashMap;
+use std::io::{self, Error, ErrorKind};
+use std::sync::Arc;
+use std::time::Duration;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chat_security::{Clock, IdentityKey, IdentityPublic, SystemClock};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+
+use crate::protocol::{decode, encode, validate_name, ErrorCode};
+use crate::storage::Storage;
+
+/*
+    Invites
+    A user hands out an invite to be added as a contact without the other side typing in names and
+    keys: it carries their name, where their name server is and the key it pins, and their own identity
+    key, all signed by that identity key. It travels as a `rustchat://invite/` URI of base64url bincode,
+    a couple of hundred characters, fit for a chat message or a QR code.
+
+    The recipient redeems the invite at the name server it names before trusting it. The server checks
+    that the signer still owns the name and that the invite hasn't expired, and remembers single-use
+    invites until they expire so that a second redemption is turned down. Invites can't outlive
+    `MAX_INVITE_LIFETIME`, which keeps that memory bounded.
+ */
+
+pub const INVITE_SCHEME: &str = "rustchat://invite/";
+/// Longest an invite may stay valid for.
+pub const MAX_INVITE_LIFETIME: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+/// Redemptions logged since the last compaction before compacting again, at the least.
+const MIN_COMPACTION_BACKLOG: usize = 1024;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Invite{
+    /// Random, so that single-use invites can be told apart.
+    pub id: [u8; 16],
+    pub name: String,
+    /// Where the inviter's name server listens, as `host:port`, followed by any replicas, comma separated.
+    pub server: String,
+    pub server_key: IdentityPublic,
+    /// The inviter's identity key, which signs the invite.
+    pub identity: IdentityPublic,
+    /// Seconds since the unix epoch.
+    pub expires: u64,
+    pub single_use: bool,
+}
+
+impl Invite{
+    pub fn new(name: &str, server: &str, server_key: IdentityPublic, identity: IdentityPublic, expires: u64, single_use: bool) -> Self{
+        let mut id = [0u8; 16];
+        OsRng.fill_bytes(&mut id);
+        Invite{ id, name: name.to_string(), server: server.to_string(), server_key, identity, expires, single_use }
+    }
+
+    fn signed_bytes(&self) -> Vec<u8>{
+        let mut data = b"rustchat invite".to_vec();
+        data.extend(bincode::serialize(self).expect("Failed to serialize invite"));
+        data
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct SignedInvite{
+    pub invite: Invite,
+    pub signature: Vec<u8>,
+}
+
+impl SignedInvite{
+    /// Signs `invite` with `identity`, which must be the key the invite names.
+    pub fn sign(invite: Invite, identity: &IdentityKey) -> Self{
+        assert_eq!(invite.identity, identity.public(), "Invite signed by a key other than its own");
+        let signature = identity.sign(&invite.signed_bytes()).to_vec();
+        SignedInvite{ invite, signature }
+    }
+
+    /// Checks the signature against the identity key in the invite. Whether that key owns the name is
+    /// for the name server to say.
+    pub fn verify(&self) -> Result<(), ErrorCode>{
+        let signature: &[u8; 64] = self.signature.as_slice().try_into().map_err(|_| ErrorCode::BadSignature)?;
+        self.invite.identity.verify(&self.invite.signed_bytes(), signature).map_err(|_| ErrorCode::BadSignature)
+    }
+
+    pub fn to_uri(&self) -> String{
+        format!("{}{}", INVITE_SCHEME, URL_SAFE_NO_PAD.encode(encode(self).expect("Failed to serialize invite")))
+    }
+
+    /// Reads an invite from its URI, or from the token alone, and checks its signature.
+    pub fn from_uri(uri: &str) -> Result<Self, io::Error>{
+        let uri = uri.trim();
+        let token = uri.strip_prefix(INVITE_SCHEME).unwrap_or(uri);
+        let data = URL_SAFE_NO_PAD.decode(token)
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "Invalid invite (bad encoding)"))?;
+        let signed: SignedInvite = decode(&data)?;
+        validate_name(&signed.invite.name)?;
+        signed.verify()?;
+        Ok(signed)
+    }
+}
+
+/// A single-use invite that has been redeemed, kept until it would have expired anyway.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Redemption{
+    pub id: [u8; 16],
+    pub expires: u64,
+}
+
+/// The name server's record of redeemed single-use invites, optionally kept in `Storage`.
+pub struct Invites{
+    used: HashMap<[u8; 16], u64>,
+    clock: Arc<dyn Clock>,
+    storage: Option<Box<dyn Storage<Redemption>>>,
+    /// Redemptions logged since the last compaction.
+    appended: usize,
+}
+
+impl Default for Invites{
+    fn default() -> Self{
+        Invites{ used: HashMap::new(), clock: Arc::new(SystemClock), storage: None, appended: 0 }
+    }
+}
+
+impl Invites{
+    pub fn new() -> Self{
+        Self::default()
+    }
+
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self{
+        Invites{ clock, ..Self::default() }
+    }
+
+    /// Recovers the redemptions kept in `storage` and logs every later one there.
+    pub fn with_storage(mut self, mut storage: Box<dyn Storage<Redemption>>) -> Result<Self, io::Error>{
+        for redemption in storage.load()?{
+            self.used.insert(redemption.id, redemption.expires);
+        }
+        self.expire();
+        storage.compact(&self.snapshot())?;
+        self.storage = Some(storage);
+        Ok(self)
+    }
+
+    fn snapshot(&self) -> Vec<Redemption>{
+        self.used.iter().map(|(id, expires)| Redemption{ id: *id, expires: *expires }).collect()
+    }
+
+    /// Forgets invites that have expired; they are turned down for that anyway.
+    fn expire(&mut self){
+        let now = self.clock.unix_time();
+        self.used.retain(|_, expires| *expires > now);
+    }
+
+    /// Checks that `invite` may still be redeemed and, if it is single use, uses it up. The caller has
+    /// checked its signature and that the signer owns the name.
+    pub fn redeem(&mut self, invite: &Invite) -> Result<(), ErrorCode>{
+        let now = self.clock.unix_time();
+        if invite.expires <= now{
+            return Err(ErrorCode::InviteExpired);
+        }
+        if invite.expires > now + MAX_INVITE_LIFETIME.as_secs(){
+            return Err(ErrorCode::InvalidInvite);
+        }
+        if !invite.single_use{
+            return Ok(());
+        }
+        if self.used.contains_key(&invite.id){
+            return Err(ErrorCode::InviteUsed);
+        }
+        let redemption = Redemption{ id: invite.id, expires: invite.expires };
+        if let Some(storage) = &mut self.storage{
+            storage.append(&redemption).map_err(|e| {
+                eprintln!("Failed to persist invite redemption: {}", e);
+                ErrorCode::StorageFailure
+            })?;
+            self.appended += 1;
+        }
+        self.used.insert(redemption.id, redemption.expires);
+
+        if self.appended >= MIN_COMPACTION_BACKLOG.max(2 * self.used.len()){
+            self.expire();
+            let snapshot = self.snapshot();
+            if let Some(storage) = &mut self.storage{
+                match storage.compact(&snapshot){
+                    Ok(()) => self.appended = 0,
+                    Err(e) => eprintln!("Failed to compact invite log: {}", e),
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::NameServerClient;
+    use crate::server::NameServer;
+    use crate::storage::LogStorage;
+    use chat_security::ManualClock;
+    use std::thread;
+
+    #[test]
+    fn test_invite_uri_round_trip() {
+        let (alice, server) = (IdentityKey::generate(), IdentityKey::generate());
+        let invite = Invite::new("alice", "chat.example:7420", server.public(), alice.public(), 2_000, true);
+        let signed = SignedInvite::sign(invite, &alice);
+        let uri = signed.to_uri();
+        assert!(uri.starts_with(INVITE_SCHEME));
+        assert_eq!(SignedInvite::from_uri(&uri).unwrap(), signed);
+        assert_eq!(SignedInvite::from_uri(&uri[INVITE_SCHEME.len()..]).unwrap(), signed);
+
+        let mut forged = signed.clone();
+        forged.invite.server = "evil.example:7420".to_string();
+        let err = SignedInvite::from_uri(&forged.to_uri()).unwrap_err();
+        assert_eq!(err.get_ref().unwrap().downcast_ref::<ErrorCode>(), Some(&ErrorCode::BadSignature));
+        assert_eq!(SignedInvite::from_uri("rustchat://invite/!!").unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_single_use_invites_survive_restart() {
+        let path = std::env::temp_dir().join(format!("rustchat-invites-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let clock = ManualClock::new(1_000);
+        let open = || Invites::with_clock(Arc::new(clock.clone())).with_storage(Box::new(LogStorage::open(&path).unwrap())).unwrap();
+        let key = IdentityKey::generate().public();
+        let (once, short) = (Invite::new("alice", "localhost:7420", key, key, 5_000, true), Invite::new("alice", "localhost:7420", key, key, 1_500, true));
+
+        let mut invites = open();
+        invites.redeem(&once).unwrap();
+        invites.redeem(&short).unwrap();
+        assert_eq!(invites.redeem(&once), Err(ErrorCode::InviteUsed));
+        clock.advance(Duration::from_secs(1_000));
+        let mut invites = open();
+        assert_eq!(invites.redeem(&once), Err(ErrorCode::InviteUsed));
+        // Expired ones are forgotten
+        assert_eq!(invites.used.len(), 1);
+        assert_eq!(invites.redeem(&short), Err(ErrorCode::InviteExpired));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_server_enforces_invites() {
+        let clock = ManualClock::new(1_000);
+        let server = NameServer::bind("127.0.0.1:0").unwrap().invites(Invites::with_clock(Arc::new(clock.clone())));
+        let (addr, key) = (server.local_addr().unwrap(), server.public_key());
+        thread::spawn(move || server.run());
+        let (alice, mallory) = (IdentityKey::generate(), IdentityKey::generate());
+        let mut client = NameServerClient::connect(addr, &key).unwrap();
+        client.register("alice", "127.0.0.1:5300".parse().unwrap(), &alice).unwrap();
+        let invite = |single_use, expires, signer: &IdentityKey|
+            SignedInvite::sign(Invite::new("alice", &addr.to_string(), key, signer.public(), expires, single_use), signer);
+        let code = |e: io::Error| e.get_ref().and_then(|e| e.downcast_ref::<ErrorCode>()).copied();
+
+        let once = invite(true, 2_000, &alice);
+        assert_eq!(client.redeem_invite(&once).unwrap().identity, alice.public());
+        assert_eq!(code(client.redeem_invite(&once).unwrap_err()), Some(ErrorCode::InviteUsed));
+        let reusable = invite(false, 2_000, &alice);
+        client.redeem_invite(&reusable).unwrap();
+        client.redeem_invite(&reusable).unwrap();
+
+        assert_eq!(code(client.redeem_invite(&invite(false, 2_000, &mallory)).unwrap_err()), Some(ErrorCode::InvalidInvite));
+        assert_eq!(code(client.redeem_invite(&invite(false, 1_000 + MAX_INVITE_LIFETIME.as_secs() + 1, &alice)).unwrap_err()),
+            Some(ErrorCode::InvalidInvite));
+        let mut forged = once.clone();
+        forged.invite.single_use = false;
+        assert_eq!(code(client.redeem_invite(&forged).unwrap_err()), Some(ErrorCode::BadSignature));
+
+        clock.advance(Duration::from_secs(1_000));
+        assert_eq!(code(client.redeem_invite(&reusable).unwrap_err()), Some(ErrorCode::InviteExpired));
+        // Handing the name on voids its invites
+        let fresh = invite(false, 2_500, &alice);
+        client.redeem_invite(&fresh).unwrap();
+        client.transfer("alice", &alice, mallory.public()).unwrap();
+        assert_eq!(code(client.redeem_invite(&fresh).unwrap_err()), Some(ErrorCode::InvalidInvite));
+    }
+}
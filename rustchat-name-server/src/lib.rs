@@ -0,0 +1,17 @@
+pub mod admin;
+pub mod client;
+pub mod directory;
+pub mod federation;
+pub mod invite;
+pub mod limits;
+pub mod mailbox;
+pub mod metrics;
+pub mod policy;
+pub mod protocol;
+pub mod relay;
+pub mod replication;
+pub mod rendezvous;
+pub mod server;
+pub mod storage;
+pub mod subscriptions;
+pub mod transparency;
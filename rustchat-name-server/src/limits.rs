@@ -0,0 +1,359 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::hash::Hash;
+use std::io::{self, Error, ErrorKind};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use chat_security::{Clock, IdentityPublic, SystemClock};
+
+use crate::protocol::{check_registration_work, ErrorCode};
+
+/*
+    Abuse protection
+    Every request and datagram spends a token from its IP's bucket, and every signed update one from
+    its owner key's bucket, so neither many keys behind one IP nor one key hopping between IPs gets
+    more than its share. Lookups and prekey fetches also draw on a separate, smaller bucket per IP,
+    and one that finds nothing costs `MISS_COST` on top: walking the directory by guessing names is
+    mostly misses, while looking up contacts mostly isn't. Buckets hold a minute's worth of tokens and
+    refill continuously. Claiming a new name can additionally require proof of work.
+ */
+
+/// Extra lookup tokens a miss costs. Misses can drive a bucket negative, so scrapers dig themselves a hole.
+pub const MISS_COST: u32 = 4;
+/// Buckets tracked per kind before full ones are forgotten; a full bucket is the same as none.
+const MAX_TRACKED: usize = 65536;
+
+/// Rates are in requests per minute; `None` leaves that kind unlimited.
+#[derive(Clone, Debug, Default)]
+pub struct Limits{
+    /// Everything from one IP: requests, updates and datagrams.
+    pub per_ip: Option<u32>,
+    /// Signed updates by one identity key.
+    pub per_key: Option<u32>,
+    /// Lookups and prekey fetches from one IP.
+    pub lookups: Option<u32>,
+    /// Leading zero bits of proof of work needed to claim a name nobody holds; 0 for none.
+    pub registration_work: u8,
+    pub bans: BanList,
+}
+
+struct Bucket{
+    tokens: f64,
+    updated: SystemTime,
+}
+
+struct Buckets<K>{
+    per_minute: Option<u32>,
+    buckets: HashMap<K, Bucket>,
+}
+
+impl<K: Hash + Eq> Buckets<K>{
+    fn new(per_minute: Option<u32>) -> Self{
+        Buckets{ per_minute, buckets: HashMap::new() }
+    }
+
+    fn refill(bucket: &mut Bucket, capacity: f64, now: SystemTime){
+        let elapsed = now.duration_since(bucket.updated).unwrap_or_default().as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * capacity / 60.0).min(capacity);
+        bucket.updated = now;
+    }
+
+    /// Spends `cost` tokens if `key` has them.
+    fn take(&mut self, key: K, cost: u32, now: SystemTime) -> Result<(), ErrorCode>{
+        let Some(capacity) = self.per_minute.map(f64::from) else { return Ok(()) };
+        if self.buckets.len() >= MAX_TRACKED{
+            self.buckets.retain(|_, bucket| {
+                Self::refill(bucket, capacity, now);
+                bucket.tokens < capacity
+            });
+        }
+        let bucket = self.buckets.entry(key).or_insert(Bucket{ tokens: capacity, updated: now });
+        Self::refill(bucket, capacity, now);
+        if bucket.tokens < f64::from(cost){
+            return Err(ErrorCode::RateLimited);
+        }
+        bucket.tokens -= f64::from(cost);
+        Ok(())
+    }
+
+    /// Spends `cost` tokens whether or not `key` has them, down to a full bucket's worth of debt.
+    fn charge(&mut self, key: K, cost: u32, now: SystemTime){
+        let Some(capacity) = self.per_minute.map(f64::from) else { return };
+        let bucket = self.buckets.entry(key).or_insert(Bucket{ tokens: capacity, updated: now });
+        Self::refill(bucket, capacity, now);
+        bucket.tokens = (bucket.tokens - f64::from(cost)).max(-capacity);
+    }
+}
+
+/// Applies `Limits` to the requests a server sees.
+pub struct Limiter{
+    clock: Arc<dyn Clock>,
+    registration_work: u8,
+    bans: BanList,
+    ips: Buckets<IpAddr>,
+    keys: Buckets<IdentityPublic>,
+    lookups: Buckets<IpAddr>,
+}
+
+impl Default for Limiter{
+    fn default() -> Self{
+        Self::new(Limits::default())
+    }
+}
+
+impl Limiter{
+    pub fn new(limits: Limits) -> Self{
+        Self::with_clock(limits, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(limits: Limits, clock: Arc<dyn Clock>) -> Self{
+        Limiter{
+            clock,
+            registration_work: limits.registration_work,
+            bans: limits.bans,
+            ips: Buckets::new(limits.per_ip),
+            keys: Buckets::new(limits.per_key),
+            lookups: Buckets::new(limits.lookups),
+        }
+    }
+
+    pub fn registration_work(&self) -> u8{
+        self.registration_work
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool{
+        self.bans.is_banned_ip(ip)
+    }
+
+    /// Admits one request or datagram from `ip`.
+    pub fn check_request(&mut self, ip: IpAddr) -> Result<(), ErrorCode>{
+        if self.bans.is_banned_ip(ip){
+            return Err(ErrorCode::Banned);
+        }
+        self.ips.take(ip, 1, self.clock.now())
+    }
+
+    /// Admits one update signed by `key`. Check the signature first, or forgeries spend the owner's tokens.
+    pub fn check_key(&mut self, key: &IdentityPublic) -> Result<(), ErrorCode>{
+        if self.bans.is_banned_key(key){
+            return Err(ErrorCode::Banned);
+        }
+        self.keys.take(*key, 1, self.clock.now())
+    }
+
+    /// Whether `key` may be handed a name, e.g. by a transfer.
+    pub fn check_recipient(&self, key: &IdentityPublic) -> Result<(), ErrorCode>{
+        if self.bans.is_banned_key(key) { Err(ErrorCode::Banned) } else { Ok(()) }
+    }
+
+    /// Checks the proof of work on a registration that claims a new name.
+    pub fn check_work(&self, name: &str, key: &IdentityPublic, work: u64) -> Result<(), ErrorCode>{
+        if check_registration_work(name, key, work, self.registration_work) { Ok(()) } else { Err(ErrorCode::InsufficientWork) }
+    }
+
+    /// Admits one lookup or prekey fetch from `ip`.
+    pub fn check_lookup(&mut self, ip: IpAddr) -> Result<(), ErrorCode>{
+        self.lookups.take(ip, 1, self.clock.now())
+    }
+
+    /// Charges `ip` for a lookup that found nothing.
+    pub fn missed_lookup(&mut self, ip: IpAddr){
+        self.lookups.charge(ip, MISS_COST, self.clock.now());
+    }
+}
+
+/// IP addresses, networks and identity keys refused service.
+///
+/// File format is one entry per line: an IP address, a network as `address/prefix`, or an identity key as
+/// 64 hex digits. `#` starts a comment.
+#[derive(Clone, Debug, Default)]
+pub struct BanList{
+    networks: Vec<(IpAddr, u8)>,
+    keys: HashSet<IdentityPublic>,
+}
+
+impl BanList{
+    pub fn new() -> Self{
+        Self::default()
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, io::Error>{
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(contents: &str) -> Result<Self, io::Error>{
+        let mut bans = BanList::new();
+        for (number, line) in contents.lines().enumerate(){
+            let entry = line.split('#').next().unwrap_or("").trim();
+            if entry.is_empty(){
+                continue;
+            }
+            let invalid = || Error::new(ErrorKind::InvalidData, format!("Invalid ban list entry on line {}: {}", number + 1, entry));
+            if let Ok(ip) = entry.parse::<IpAddr>(){
+                bans.ban_ip(ip);
+            }
+            else if let Some((ip, prefix)) = entry.split_once('/'){
+                let ip = ip.parse().map_err(|_| invalid())?;
+                let prefix = prefix.parse().map_err(|_| invalid())?;
+                bans.ban_network(ip, prefix).map_err(|_| invalid())?;
+            }
+            else{
+                bans.ban_key(IdentityPublic::from_hex(entry).map_err(|_| invalid())?);
+            }
+        }
+        Ok(bans)
+    }
+
+    pub fn ban_ip(&mut self, ip: IpAddr){
+        let prefix = if ip.is_ipv4() { 32 } else { 128 };
+        self.networks.push((ip, prefix));
+    }
+
+    pub fn ban_network(&mut self, ip: IpAddr, prefix: u8) -> Result<(), io::Error>{
+        if prefix > if ip.is_ipv4() { 32 } else { 128 }{
+            return Err(Error::new(ErrorKind::InvalidInput, "Network prefix too long"));
+        }
+        self.networks.push((ip, prefix));
+        Ok(())
+    }
+
+    pub fn ban_key(&mut self, key: IdentityPublic){
+        self.keys.insert(key);
+    }
+
+    pub fn is_banned_ip(&self, ip: IpAddr) -> bool{
+        // Dual-stack listeners see IPv4 peers as mapped IPv6 addresses
+        let ip = match ip{
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            ip => ip,
+        };
+        self.networks.iter().any(|(network, prefix)| match (network, ip){
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(*prefix)).unwrap_or(0);
+                u32::from(*network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(*prefix)).unwrap_or(0);
+                u128::from(*network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        })
+    }
+
+    pub fn is_banned_key(&self, key: &IdentityPublic) -> bool{
+        self.keys.contains(key)
+    }
+
+    pub fn len(&self) -> usize{
+        self.networks.len() + self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool{
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::solve_registration_work;
+    use chat_security::{IdentityKey, ManualClock};
+    use std::time::Duration;
+
+    fn limiter(limits: Limits) -> (Limiter, ManualClock) {
+        let clock = ManualClock::new(1_000);
+        (Limiter::with_clock(limits, Arc::new(clock.clone())), clock)
+    }
+
+    #[test]
+    fn test_bucket_refills_over_time() {
+        let (mut limiter, clock) = limiter(Limits{ per_ip: Some(60), ..Limits::default() });
+        let (ip, other) = ("192.0.2.1".parse().unwrap(), "192.0.2.2".parse().unwrap());
+        for _ in 0..60 {
+            limiter.check_request(ip).unwrap();
+        }
+        assert_eq!(limiter.check_request(ip), Err(ErrorCode::RateLimited));
+        assert!(limiter.check_request(other).is_ok());
+
+        clock.advance(Duration::from_secs(5));
+        for _ in 0..5 {
+            limiter.check_request(ip).unwrap();
+        }
+        assert_eq!(limiter.check_request(ip), Err(ErrorCode::RateLimited));
+        // Refilling stops at a minute's worth
+        clock.advance(Duration::from_secs(3600));
+        for _ in 0..60 {
+            limiter.check_request(ip).unwrap();
+        }
+        assert_eq!(limiter.check_request(ip), Err(ErrorCode::RateLimited));
+    }
+
+    #[test]
+    fn test_key_limited_across_ips() {
+        let (mut limiter, _clock) = limiter(Limits{ per_key: Some(3), ..Limits::default() });
+        let key = IdentityKey::generate().public();
+        for _ in 0..3 {
+            limiter.check_key(&key).unwrap();
+        }
+        assert_eq!(limiter.check_key(&key), Err(ErrorCode::RateLimited));
+        assert!(limiter.check_key(&IdentityKey::generate().public()).is_ok());
+    }
+
+    #[test]
+    fn test_misses_throttle_scraping() {
+        let (mut limiter, clock) = limiter(Limits{ lookups: Some(30), ..Limits::default() });
+        let (scraper, user) = ("198.51.100.7".parse().unwrap(), "198.51.100.8".parse().unwrap());
+        let mut guesses = 0;
+        while limiter.check_lookup(scraper).is_ok() {
+            limiter.missed_lookup(scraper);
+            guesses += 1;
+        }
+        assert_eq!(guesses, 6);
+        for _ in 0..30 {
+            limiter.check_lookup(user).unwrap();
+        }
+
+        // Ten seconds buys five lookups, which is one more guess
+        clock.advance(Duration::from_secs(10));
+        limiter.check_lookup(scraper).unwrap();
+        limiter.missed_lookup(scraper);
+        assert!(limiter.check_lookup(scraper).is_err());
+    }
+
+    #[test]
+    fn test_registration_work() {
+        let (limiter, _clock) = limiter(Limits{ registration_work: 8, ..Limits::default() });
+        let key = IdentityKey::generate().public();
+        let work = solve_registration_work("alice", &key, 8);
+        assert!(limiter.check_work("alice", &key, work).is_ok());
+        // Work is bound to the name and key it was done for
+        let other = IdentityKey::generate().public();
+        assert!((0..4).any(|n| limiter.check_work("bob", &key, work + n).is_err()));
+        assert!((0..4).any(|n| limiter.check_work("alice", &other, work + n).is_err()));
+        assert!(Limiter::new(Limits::default()).check_work("alice", &key, 0).is_ok());
+    }
+
+    #[test]
+    fn test_ban_list() {
+        let banned = IdentityKey::generate().public();
+        let bans = BanList::parse(&format!("# abusers\n203.0.113.9\n10.1.0.0/16  # whole network\n{}\n\n2001:db8::/32\n", banned.to_hex())).unwrap();
+        assert_eq!(bans.len(), 4);
+        assert!(bans.is_banned_ip("203.0.113.9".parse().unwrap()));
+        assert!(!bans.is_banned_ip("203.0.113.10".parse().unwrap()));
+        assert!(bans.is_banned_ip("10.1.200.3".parse().unwrap()));
+        assert!(bans.is_banned_ip("::ffff:10.1.0.1".parse().unwrap()));
+        assert!(!bans.is_banned_ip("10.2.0.1".parse().unwrap()));
+        assert!(bans.is_banned_ip("2001:db8::1".parse().unwrap()));
+        assert!(bans.is_banned_key(&banned));
+        assert!(BanList::parse("10.0.0.0/33").is_err());
+        assert!(BanList::parse("not an entry").is_err());
+
+        let (mut limiter, _clock) = limiter(Limits{ bans, ..Limits::default() });
+        assert_eq!(limiter.check_request("10.1.2.3".parse().unwrap()), Err(ErrorCode::Banned));
+        assert_eq!(limiter.check_key(&banned), Err(ErrorCode::Banned));
+        assert_eq!(limiter.check_recipient(&banned), Err(ErrorCode::Banned));
+    }
+}
@@ -0,0 +1,256 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chat_security::{Clock, SystemClock};
+use serde::{Deserialize, Serialize};
+
+use crate::protocol::{Envelope, ErrorCode};
+use crate::storage::Storage;
+
+/// How long an envelope waits for its recipient before it is dropped.
+pub const DEFAULT_MAIL_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+pub const MAX_ENVELOPE_LEN: usize = 16 * 1024;
+pub const MAX_ENVELOPES_PER_RECIPIENT: usize = 100;
+pub const MAX_BYTES_PER_RECIPIENT: usize = 512 * 1024;
+
+const MIN_COMPACTION_BACKLOG: usize = 1024;
+
+/// One change to the mailboxes, as persisted.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum MailRecord{
+    Deposit{ to: String, envelope: Envelope },
+    Remove{ to: String, ids: Vec<u64> },
+    /// Written at the head of every compacted log so ids stay unique even once every mailbox is empty.
+    NextId(u64),
+}
+
+/*
+    Store-and-forward
+    Senders deposit envelopes for a name while its owner is offline; the owner fetches them later
+    and acknowledges what it has processed, which deletes them. Envelopes are ciphertext produced by
+    the sender's chat_security (e.g. an X3DH initial message) and are only ever stored and returned
+    byte for byte. Quotas bound what one recipient can cost us, and envelopes nobody collects expire.
+ */
+
+/// Envelopes queued per recipient name, optionally backed by `Storage`.
+pub struct Mailbox{
+    boxes: HashMap<String, VecDeque<Envelope>>,
+    next_id: u64,
+    clock: Arc<dyn Clock>,
+    ttl: Duration,
+    storage: Option<Box<dyn Storage<MailRecord>>>,
+    appended: usize,
+}
+
+impl Default for Mailbox{
+    fn default() -> Self{
+        Mailbox{
+            boxes: HashMap::new(),
+            next_id: 1,
+            clock: Arc::new(SystemClock),
+            ttl: DEFAULT_MAIL_TTL,
+            storage: None,
+            appended: 0,
+        }
+    }
+}
+
+impl Mailbox{
+    pub fn new() -> Self{
+        Self::default()
+    }
+
+    pub fn with_clock(clock: Arc<dyn Clock>, ttl: Duration) -> Self{
+        Mailbox{ clock, ttl, ..Self::default() }
+    }
+
+    /// Recovers the envelopes kept in `storage` and persists every later change there.
+    pub fn with_storage(mut self, mut storage: Box<dyn Storage<MailRecord>>) -> Result<Self, io::Error>{
+        for record in storage.load()?{
+            self.replay(record);
+        }
+        self.expire();
+        storage.compact(&self.snapshot())?;
+        self.storage = Some(storage);
+        Ok(self)
+    }
+
+    fn replay(&mut self, record: MailRecord){
+        match record{
+            MailRecord::Deposit{ to, envelope } => {
+                self.next_id = self.next_id.max(envelope.id + 1);
+                self.boxes.entry(to).or_default().push_back(envelope);
+            }
+            MailRecord::Remove{ to, ids } => {
+                if let Some(queue) = self.boxes.get_mut(&to){
+                    queue.retain(|envelope| !ids.contains(&envelope.id));
+                    if queue.is_empty(){
+                        self.boxes.remove(&to);
+                    }
+                }
+            }
+            MailRecord::NextId(id) => self.next_id = self.next_id.max(id),
+        }
+    }
+
+    fn snapshot(&self) -> Vec<MailRecord>{
+        let deposits = self.boxes.iter()
+            .flat_map(|(to, queue)| queue.iter().map(|envelope| MailRecord::Deposit{ to: to.clone(), envelope: envelope.clone() }));
+        std::iter::once(MailRecord::NextId(self.next_id)).chain(deposits).collect()
+    }
+
+    fn persist(&mut self, record: MailRecord) -> Result<(), ErrorCode>{
+        if let Some(storage) = &mut self.storage{
+            storage.append(&record).map_err(|e| {
+                eprintln!("Failed to persist mail: {}", e);
+                ErrorCode::StorageFailure
+            })?;
+            self.appended += 1;
+        }
+        self.replay(record);
+
+        let live = self.boxes.values().map(VecDeque::len).sum::<usize>();
+        if self.appended >= MIN_COMPACTION_BACKLOG.max(2 * live){
+            let snapshot = self.snapshot();
+            if let Some(storage) = &mut self.storage{
+                match storage.compact(&snapshot){
+                    Ok(()) => self.appended = 0,
+                    Err(e) => eprintln!("Failed to compact mail log: {}", e),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Drops envelopes older than the TTL. Not logged: replaying the log and expiring again gives the
+    /// same result.
+    fn expire(&mut self){
+        let cutoff = self.clock.unix_time().saturating_sub(self.ttl.as_secs());
+        self.boxes.retain(|_, queue| {
+            queue.retain(|envelope| envelope.received > cutoff);
+            !queue.is_empty()
+        });
+    }
+
+    /// Queues `payload` for `to`, returning the envelope id. The caller checks that `to` is registered.
+    pub fn deposit(&mut self, to: &str, payload: Vec<u8>) -> Result<u64, ErrorCode>{
+        if payload.len() > MAX_ENVELOPE_LEN{
+            return Err(ErrorCode::EnvelopeTooLarge);
+        }
+        self.expire();
+        if let Some(queue) = self.boxes.get(to){
+            let bytes = queue.iter().map(|envelope| envelope.payload.len()).sum::<usize>();
+            if queue.len() >= MAX_ENVELOPES_PER_RECIPIENT || bytes + payload.len() > MAX_BYTES_PER_RECIPIENT{
+                return Err(ErrorCode::MailboxFull);
+            }
+        }
+        let envelope = Envelope{ id: self.next_id, received: self.clock.unix_time(), payload };
+        let id = envelope.id;
+        self.persist(MailRecord::Deposit{ to: to.to_string(), envelope })?;
+        Ok(id)
+    }
+
+    /// Everything waiting for `to`, oldest first. Nothing is removed until acknowledged.
+    pub fn fetch(&mut self, to: &str) -> Vec<Envelope>{
+        self.expire();
+        self.boxes.get(to).map(|queue| queue.iter().cloned().collect()).unwrap_or_default()
+    }
+
+    /// Deletes the envelopes in `ids` from `to`'s mailbox, returning how many were there.
+    pub fn acknowledge(&mut self, to: &str, ids: &[u64]) -> Result<usize, ErrorCode>{
+        let removed = self.boxes.get(to)
+            .map_or(0, |queue| queue.iter().filter(|envelope| ids.contains(&envelope.id)).count());
+        if removed > 0{
+            self.persist(MailRecord::Remove{ to: to.to_string(), ids: ids.to_vec() })?;
+        }
+        Ok(removed)
+    }
+
+    /// Drops all mail for `to`, e.g. when the name changes hands and the new owner couldn't decrypt it.
+    pub fn clear(&mut self, to: &str) -> Result<(), ErrorCode>{
+        let ids = self.fetch(to).iter().map(|envelope| envelope.id).collect::<Vec<_>>();
+        self.acknowledge(to, &ids).map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::LogStorage;
+    use chat_security::ManualClock;
+
+    #[test]
+    fn test_fetch_until_acknowledged() {
+        let mut mailbox = Mailbox::new();
+        let first = mailbox.deposit("bob", b"one".to_vec()).unwrap();
+        let second = mailbox.deposit("bob", b"two".to_vec()).unwrap();
+        assert!(mailbox.fetch("alice").is_empty());
+
+        let mail = mailbox.fetch("bob");
+        assert_eq!(mail.iter().map(|e| e.payload.as_slice()).collect::<Vec<_>>(), [b"one", b"two"]);
+        assert_eq!(mailbox.fetch("bob").len(), 2);
+        assert_eq!(mailbox.acknowledge("bob", &[first]).unwrap(), 1);
+        assert_eq!(mailbox.fetch("bob")[0].id, second);
+        // Acknowledging someone else's envelope id does nothing
+        assert_eq!(mailbox.acknowledge("alice", &[second]).unwrap(), 0);
+        assert_eq!(mailbox.fetch("bob").len(), 1);
+    }
+
+    #[test]
+    fn test_quotas() {
+        let mut mailbox = Mailbox::new();
+        assert_eq!(mailbox.deposit("bob", vec![0; MAX_ENVELOPE_LEN + 1]), Err(ErrorCode::EnvelopeTooLarge));
+        for _ in 0..MAX_ENVELOPES_PER_RECIPIENT {
+            mailbox.deposit("bob", vec![0; 16]).unwrap();
+        }
+        assert_eq!(mailbox.deposit("bob", vec![0; 16]), Err(ErrorCode::MailboxFull));
+        // Quotas are per recipient
+        mailbox.deposit("carol", vec![0; 16]).unwrap();
+
+        let mut mailbox = Mailbox::new();
+        for _ in 0..MAX_BYTES_PER_RECIPIENT / MAX_ENVELOPE_LEN {
+            mailbox.deposit("bob", vec![0; MAX_ENVELOPE_LEN]).unwrap();
+        }
+        assert_eq!(mailbox.deposit("bob", vec![0; 1]), Err(ErrorCode::MailboxFull));
+    }
+
+    #[test]
+    fn test_expiry() {
+        let clock = ManualClock::new(1_000);
+        let mut mailbox = Mailbox::with_clock(Arc::new(clock.clone()), Duration::from_secs(60));
+        mailbox.deposit("bob", b"old".to_vec()).unwrap();
+        clock.advance(Duration::from_secs(30));
+        mailbox.deposit("bob", b"new".to_vec()).unwrap();
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(mailbox.fetch("bob").len(), 1);
+        clock.advance(Duration::from_secs(30));
+        assert!(mailbox.fetch("bob").is_empty());
+    }
+
+    #[test]
+    fn test_mail_survives_restart() {
+        let path = std::env::temp_dir().join(format!("rustchat-mail-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let open = || Mailbox::new().with_storage(Box::new(LogStorage::open(&path).unwrap())).unwrap();
+
+        let mut mailbox = open();
+        let first = mailbox.deposit("bob", b"one".to_vec()).unwrap();
+        mailbox.deposit("bob", b"two".to_vec()).unwrap();
+        mailbox.acknowledge("bob", &[first]).unwrap();
+        let carol = mailbox.deposit("carol", b"gone".to_vec()).unwrap();
+        mailbox.acknowledge("carol", &[carol]).unwrap();
+        drop(mailbox);
+
+        let mut mailbox = open();
+        let mail = mailbox.fetch("bob");
+        assert_eq!(mail.len(), 1);
+        assert_eq!(mail[0].payload, b"two");
+        // Ids are never reused, so a stale acknowledgement can't hit a new envelope
+        drop(mailbox);
+        let mut mailbox = open();
+        assert!(mailbox.deposit("bob", b"three".to_vec()).unwrap() > carol);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
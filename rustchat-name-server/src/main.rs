@@ -1,3 +1,197 @@
-fn main() {
-    println!("Hello, world!");
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use chat_security::{IdentityKey, SystemClock};
+use clap::{Parser, Subcommand};
+
+use rustchat_name_server::admin::{AdminKeys, ServerKeys};
+use rustchat_name_server::directory::{Directory, DEFAULT_LEASE};
+use rustchat_name_server::federation::{Federation, Peers};
+use rustchat_name_server::invite::Invites;
+use rustchat_name_server::limits::{BanList, Limiter, Limits};
+use rustchat_name_server::mailbox::{Mailbox, DEFAULT_MAIL_TTL};
+use rustchat_name_server::server::NameServer;
+use rustchat_name_server::storage::{read_log, read_records, write_records, LogRecord, LogStorage};
+use rustchat_name_server::transparency::TransparencyLog;
+
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+pub struct Args{
+
+    #[arg(short, long, default_value = "0.0.0.0:7420")]
+    /// Address to accept client connections on
+    listen: String,
+
+    #[arg(long, default_value_t = DEFAULT_LEASE.as_secs())]
+    /// Seconds a registration stays online without a heartbeat
+    lease: u64,
+
+    #[arg(long, default_value = "server.key")]
+    /// File holding the server's identity key, created on first use. Clients pin its public half
+    identity: PathBuf,
+
+    #[arg(long, default_value = "rotations.log")]
+    /// Log file of the server's key rotations, which clients follow from the key they pinned
+    key_rotations: PathBuf,
+
+    #[arg(long)]
+    /// File of admin keys, one per line, allowed to manage registrations with rustchat-admin
+    admin_keys: Option<PathBuf>,
+
+    #[arg(long, default_value = "names.log")]
+    /// Log file the directory is kept in
+    store: PathBuf,
+
+    #[arg(long, default_value = "keys.log")]
+    /// Log file the key transparency log is kept in
+    key_log: PathBuf,
+
+    #[arg(long, default_value = "mail.log")]
+    /// Log file offline mail is kept in
+    mail_store: PathBuf,
+
+    #[arg(long, default_value = "invites.log")]
+    /// Log file redeemed single-use invites are kept in until they expire
+    invite_store: PathBuf,
+
+    #[arg(long, default_value_t = DEFAULT_MAIL_TTL.as_secs())]
+    /// Seconds undelivered mail is kept before it is dropped
+    mail_ttl: u64,
+
+    #[arg(long, default_value_t = 300)]
+    /// Requests per minute allowed from one IP address (0 for no limit)
+    ip_rate: u32,
+
+    #[arg(long, default_value_t = 60)]
+    /// Signed updates per minute allowed from one identity key (0 for no limit)
+    key_rate: u32,
+
+    #[arg(long, default_value_t = 60)]
+    /// Lookups per minute allowed from one IP address; lookups that find nothing count extra (0 for no limit)
+    lookup_rate: u32,
+
+    #[arg(long, default_value_t = 0)]
+    /// Leading zero bits of proof of work required to claim a new name
+    registration_work: u8,
+
+    #[arg(long)]
+    /// File of banned IP addresses, networks and identity keys, one per line
+    ban_list: Option<PathBuf>,
+
+    #[arg(long)]
+    /// Domain this server answers for, so that `name@DOMAIN` finds the local `name`
+    domain: Option<String>,
+
+    #[arg(long)]
+    /// File of federated domains, one `domain host:port key` per line; lookups for them are forwarded
+    federation: Option<PathBuf>,
+
+    #[arg(long)]
+    /// Runs as a read-only replica of the name server at HOST:PORT, which must have the same --identity key.
+    /// Replicas keep everything in memory, so the store options don't apply
+    replica_of: Option<String>,
+
+    #[arg(long)]
+    /// Address to serve Prometheus metrics (/metrics) and health checks (/healthz) on over HTTP
+    metrics: Option<String>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+
+}
+
+#[derive(Subcommand, Debug)]
+enum Command{
+    /// Writes a compacted copy of the store to FILE. Safe while the server is running
+    Snapshot{ file: PathBuf },
+    /// Replaces the store with a snapshot taken earlier. Stop the server first
+    Restore{ file: PathBuf },
+}
+
+fn main() -> Result<(), io::Error>{
+    let args = Args::parse();
+    match args.command{
+        Some(Command::Snapshot{ file }) => {
+            let snapshot = Directory::from_records(read_log(&args.store)?).snapshot();
+            write_records(&file, &snapshot)?;
+            println!("Wrote {} records to {}", snapshot.len(), file.display());
+            Ok(())
+        }
+        Some(Command::Restore{ file }) => {
+            let records: Vec<LogRecord> = read_records(&file)?;
+            write_records(&args.store, &records)?;
+            println!("Restored {} records into {}", records.len(), args.store.display());
+            Ok(())
+        }
+        None if args.replica_of.is_some() => {
+            let primary = args.replica_of.as_deref().expect("Checked above");
+            let directory = Directory::with_clock(Arc::new(SystemClock), Duration::from_secs(args.lease));
+            // A replica serves under its primary's key, so a fresh one would only lock clients out
+            let identity = IdentityKey::load(&args.identity).map_err(|e| io::Error::new(e.kind(),
+                format!("Replica needs the primary's key in {}: {}", args.identity.display(), e)))?;
+            let server = NameServer::bind_with(&args.listen, directory)?
+                .identity(identity)
+                .federation(Federation::new(args.domain, load_peers(args.federation.as_deref())?)?)
+                .replica_of(primary);
+            println!("Replica of {} listening on {}", primary, server.local_addr()?);
+            serve(server, args.metrics.as_deref())
+        }
+        None => {
+            let directory = Directory::with_clock(Arc::new(SystemClock), Duration::from_secs(args.lease))
+                .with_storage(Box::new(LogStorage::open(&args.store)?))?;
+            println!("Loaded {} names from {}", directory.len(), args.store.display());
+            let mailbox = Mailbox::with_clock(Arc::new(SystemClock), Duration::from_secs(args.mail_ttl))
+                .with_storage(Box::new(LogStorage::open(&args.mail_store)?))?;
+            let invites = Invites::new().with_storage(Box::new(LogStorage::open(&args.invite_store)?))?;
+            let log = TransparencyLog::new().with_storage(Box::new(LogStorage::open(&args.key_log)?))?;
+            let bans = args.ban_list.as_ref().map(BanList::load).transpose()?.unwrap_or_default();
+            if !bans.is_empty(){
+                println!("Loaded {} bans", bans.len());
+            }
+            let peers = load_peers(args.federation.as_deref())?;
+            let admins = args.admin_keys.as_ref().map(AdminKeys::load).transpose()?.unwrap_or_default();
+            if !admins.is_empty(){
+                println!("Loaded {} admin keys", admins.len());
+            }
+            let keys = ServerKeys::new(IdentityKey::load_or_generate(&args.identity)?)
+                .with_storage(&args.identity, Box::new(LogStorage::open(&args.key_rotations)?))?;
+            let limits = Limits{
+                per_ip: Some(args.ip_rate).filter(|rate| *rate > 0),
+                per_key: Some(args.key_rate).filter(|rate| *rate > 0),
+                lookups: Some(args.lookup_rate).filter(|rate| *rate > 0),
+                registration_work: args.registration_work,
+                bans,
+            };
+            let server = NameServer::bind_with(&args.listen, directory)?
+                .keys(keys)
+                .admins(admins)
+                .transparency_log(log)?
+                .mailbox(mailbox)
+                .invites(invites)
+                .limiter(Limiter::new(limits))
+                .federation(Federation::new(args.domain, peers)?)
+                .primary();
+            println!("Name server listening on {}", server.local_addr()?);
+            serve(server, args.metrics.as_deref())
+        }
+    }
+}
+
+fn load_peers(path: Option<&Path>) -> Result<Peers, io::Error>{
+    let peers = path.map(Peers::load).transpose()?.unwrap_or_default();
+    if !peers.is_empty(){
+        println!("Federating with {} domains", peers.len());
+    }
+    Ok(peers)
+}
+
+fn serve(mut server: NameServer, metrics: Option<&str>) -> Result<(), io::Error>{
+    if let Some(addr) = metrics{
+        server = server.metrics(addr)?;
+        println!("Metrics on http://{}/metrics", server.metrics_addr()?.expect("Metrics listener was just bound"));
+    }
+    println!("Server key (give clients this as --name-server-key): {}", server.public_key().to_hex());
+    server.run()
 }
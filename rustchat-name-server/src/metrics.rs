@@ -0,0 +1,205 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::protocol::{ErrorCode, Response};
+
+/*
+    Metrics
+    Counters and request latency histograms, served over plain HTTP in the Prometheus text format
+    (`GET /metrics`), with `GET /healthz` for liveness checks. Scrapers are trusted operators on a
+    private address, so the HTTP side is as small as it can be: one request per connection, no
+    keep-alive, and anything but a short GET turned away.
+ */
+
+/// Upper bounds, in seconds, of the request latency histogram buckets.
+pub const LATENCY_BUCKETS: [f64; 11] = [0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0];
+/// Longest HTTP request head we read; scrapers send a few hundred bytes.
+const MAX_HTTP_REQUEST: usize = 8192;
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Default)]
+struct Histogram{
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram{
+    fn observe(&mut self, seconds: f64){
+        for (bucket, bound) in self.buckets.iter_mut().zip(LATENCY_BUCKETS){
+            if seconds <= bound{
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+}
+
+#[derive(Default)]
+struct Counters{
+    latency: BTreeMap<&'static str, Histogram>,
+    rejected: BTreeMap<String, u64>,
+    registrations: u64,
+    lookup_hits: u64,
+    lookup_misses: u64,
+}
+
+/// `RateLimited` as `rate_limited`, for label values.
+fn snake_case(code: ErrorCode) -> String{
+    let mut label = String::new();
+    for (i, c) in format!("{:?}", code).chars().enumerate(){
+        if c.is_ascii_uppercase() && i > 0{
+            label.push('_');
+        }
+        label.push(c.to_ascii_lowercase());
+    }
+    label
+}
+
+#[derive(Default)]
+pub struct Metrics{
+    counters: Mutex<Counters>,
+}
+
+impl Metrics{
+    pub fn new() -> Self{
+        Self::default()
+    }
+
+    /// Records a request of `kind` (see `Request::kind`) answered with `response` after `elapsed`.
+    pub fn observe(&self, kind: &'static str, response: &Response, elapsed: Duration){
+        let mut counters = self.counters.lock().unwrap();
+        counters.latency.entry(kind).or_default().observe(elapsed.as_secs_f64());
+        match (kind, response){
+            (_, Response::Error(code)) => *counters.rejected.entry(snake_case(*code)).or_default() += 1,
+            ("register", Response::Registered(_)) => counters.registrations += 1,
+            ("lookup", Response::Found{ .. } | Response::Forwarded{ .. }) => counters.lookup_hits += 1,
+            ("lookup", Response::NotFound) => counters.lookup_misses += 1,
+            _ => {}
+        }
+    }
+
+    /// Records a request turned away before it was read, e.g. by the rate limiter.
+    pub fn reject(&self, code: ErrorCode){
+        *self.counters.lock().unwrap().rejected.entry(snake_case(code)).or_default() += 1;
+    }
+
+    /// Everything in the Prometheus text format, along with the directory's current size.
+    pub fn render(&self, registered: usize, active_leases: usize) -> String{
+        let counters = self.counters.lock().unwrap();
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: &[(String, String)]|{
+            let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind);
+            for (labels, value) in samples{
+                let _ = writeln!(out, "{}{} {}", name, labels, value);
+            }
+        };
+        metric("rustchat_registrations_total", "counter", "Successful registrations, new or renewed.",
+            &[(String::new(), counters.registrations.to_string())]);
+        metric("rustchat_lookups_total", "counter", "Lookups by whether the name was found.", &[
+            ("{result=\"hit\"}".to_string(), counters.lookup_hits.to_string()),
+            ("{result=\"miss\"}".to_string(), counters.lookup_misses.to_string()),
+        ]);
+        metric("rustchat_rejected_requests_total", "counter", "Requests answered with an error, by reason.",
+            &counters.rejected.iter().map(|(reason, count)| (format!("{{reason=\"{}\"}}", reason), count.to_string())).collect::<Vec<_>>());
+        metric("rustchat_registered_names", "gauge", "Names in the directory.", &[(String::new(), registered.to_string())]);
+        metric("rustchat_active_leases", "gauge", "Names whose lease hasn't run out.", &[(String::new(), active_leases.to_string())]);
+
+        let mut samples = Vec::new();
+        for (kind, histogram) in &counters.latency{
+            for (count, bound) in histogram.buckets.iter().zip(LATENCY_BUCKETS){
+                samples.push((format!("_bucket{{kind=\"{}\",le=\"{}\"}}", kind, bound), count.to_string()));
+            }
+            samples.push((format!("_bucket{{kind=\"{}\",le=\"+Inf\"}}", kind), histogram.count.to_string()));
+            samples.push((format!("_sum{{kind=\"{}\"}}", kind), histogram.sum.to_string()));
+            samples.push((format!("_count{{kind=\"{}\"}}", kind), histogram.count.to_string()));
+        }
+        metric("rustchat_request_duration_seconds", "histogram", "Time taken to answer requests, by kind.", &samples);
+        out
+    }
+}
+
+/// Answers one HTTP request on `stream` with the status and body `route` gives for its path.
+pub(crate) fn serve_http(mut stream: TcpStream, route: impl FnOnce(&str) -> (u16, String)) -> Result<(), io::Error>{
+    stream.set_read_timeout(Some(HTTP_TIMEOUT))?;
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !head.windows(4).any(|window| window == b"\r\n\r\n"){
+        let n = stream.read(&mut buf)?;
+        if n == 0 || head.len() + n > MAX_HTTP_REQUEST{
+            return Ok(());
+        }
+        head.extend(&buf[..n]);
+    }
+    let line = String::from_utf8_lossy(&head);
+    let mut parts = line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()){
+        (Some("GET"), Some(path)) => route(path.split('?').next().unwrap_or(path)),
+        _ => (405, "method not allowed\n".to_string()),
+    };
+    let reason = match status{
+        200 => "OK",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _ => "Service Unavailable",
+    };
+    let response = format!("HTTP/1.1 {} {}\r\nContent-Type: text/plain; version=0.0.4; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, reason, body.len(), body);
+    stream.write_all(response.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::NameServerClient;
+    use crate::server::NameServer;
+    use chat_security::IdentityKey;
+    use std::net::SocketAddr;
+    use std::thread;
+
+    fn get(addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_metrics_endpoint() {
+        let server = NameServer::bind("127.0.0.1:0").unwrap().metrics("127.0.0.1:0").unwrap();
+        let (addr, key, metrics) = (server.local_addr().unwrap(), server.public_key(), server.metrics_addr().unwrap().unwrap());
+        thread::spawn(move || server.run());
+
+        let mut client = NameServerClient::connect(addr, &key).unwrap();
+        client.register("alice", "127.0.0.1:5200".parse().unwrap(), &IdentityKey::generate()).unwrap();
+        client.lookup("alice").unwrap().unwrap();
+        assert!(client.lookup("bob").unwrap().is_none());
+        client.register("bad name", "127.0.0.1:5201".parse().unwrap(), &IdentityKey::generate()).unwrap_err();
+        // Joins hand the connection to the relay, which says how they were answered once it has sent it
+        NameServerClient::connect(addr, &key).unwrap().join("alice").unwrap_err();
+
+        let mut response = get(metrics, "/metrics");
+        for _ in 0..100 {
+            if response.contains("not_parked") {
+                break;
+            }
+            thread::sleep(std::time::Duration::from_millis(10));
+            response = get(metrics, "/metrics");
+        }
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        for line in ["rustchat_registrations_total 1", "rustchat_lookups_total{result=\"hit\"} 1", "rustchat_lookups_total{result=\"miss\"} 1",
+            "rustchat_rejected_requests_total{reason=\"invalid_name\"} 1", "rustchat_rejected_requests_total{reason=\"not_parked\"} 1",
+            "rustchat_registered_names 1", "rustchat_active_leases 1",
+            "rustchat_request_duration_seconds_bucket{kind=\"lookup\",le=\"+Inf\"} 2", "rustchat_request_duration_seconds_count{kind=\"register\"} 2"]{
+            assert!(response.lines().any(|l| l == line), "missing {:?} in\n{}", line, response);
+        }
+        assert!(get(metrics, "/healthz").starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(get(metrics, "/nope").starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
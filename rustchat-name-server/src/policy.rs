@@ -0,0 +1,203 @@
+use chat_security::IdentityPublic;
+use serde::{Deserialize, Serialize};
+
+use crate::protocol::ErrorCode;
+
+/*
+    Privacy policies
+    Each name's owner decides who may find it. A policy can hide the name from everyone but a list of
+    contacts, block particular identity keys outright, and make its presence invisible, so that the
+    name always shows as offline and at no address. Owners publish their policy as a signed update like
+    any other, so it is kept, replicated and checked for ownership the same way, and a transfer resets
+    it for the new owner.
+
+    The server knows who is asking from the key a client handshakes under. Clients that want to see
+    contacts-only names handshake under their identity key; anonymous ones and lookups forwarded from
+    other domains count as strangers. A hidden name is answered as if it weren't registered, for
+    lookups, subscriptions, prekey fetches, mail and relay joins alike. Hole punching rendezvous runs
+    over datagrams that don't say who sent them, so it is refused for any name whose policy hides it
+    from anyone or hides its presence; contacts reach such names over the relay instead.
+
+    Its binding is still in the key log, which auditors read in full, and those who already know where
+    it is can still reach it; the policy keeps the name out of the directory's answers, not out of the
+    world.
+ */
+
+/// Most keys a policy can list, contacts and blocked together, which keeps updates within a frame.
+pub const MAX_POLICY_KEYS: usize = 512;
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Policy{
+    /// Hides the name from everyone whose key isn't in `contacts`.
+    pub contacts_only: bool,
+    pub contacts: Vec<IdentityPublic>,
+    /// Keys the name is hidden from, contacts or not.
+    pub blocked: Vec<IdentityPublic>,
+    /// Shows the name as offline to everyone, its owner aside.
+    pub invisible: bool,
+}
+
+impl Policy{
+    pub fn validate(&self) -> Result<(), ErrorCode>{
+        if self.contacts.len() + self.blocked.len() > MAX_POLICY_KEYS{
+            return Err(ErrorCode::InvalidPolicy);
+        }
+        Ok(())
+    }
+
+    /// Whether anyone at all may reach the name without saying who they are, as over hole punching
+    /// rendezvous.
+    pub fn allows_anonymous(&self) -> bool{
+        !self.contacts_only && self.blocked.is_empty() && !self.invisible
+    }
+
+    /// Whether `viewer`, or an anonymous client if `None`, may find the name.
+    pub fn allows(&self, viewer: Option<&IdentityPublic>) -> bool{
+        match viewer{
+            Some(viewer) if self.blocked.contains(viewer) => false,
+            Some(viewer) if self.contacts_only => self.contacts.contains(viewer),
+            Some(_) => true,
+            None => !self.contacts_only,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{NameServerClient, RendezvousClient};
+    use crate::invite::{Invite, SignedInvite};
+    use crate::protocol::Presence;
+    use crate::server::NameServer;
+    use crate::subscriptions::Event;
+    use chat_security::IdentityKey;
+    use std::io::ErrorKind;
+    use std::net::UdpSocket;
+    use std::thread;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    #[test]
+    fn test_policy_allows() {
+        let (friend, stranger, pest) = (IdentityKey::generate().public(), IdentityKey::generate().public(), IdentityKey::generate().public());
+        let open = Policy{ blocked: vec![pest], ..Policy::default() };
+        assert!(open.allows(Some(&stranger)) && open.allows(None));
+        assert!(!open.allows(Some(&pest)));
+        // Blocking wins over being a contact
+        let closed = Policy{ contacts_only: true, contacts: vec![friend, pest], blocked: vec![pest], invisible: false };
+        assert!(closed.allows(Some(&friend)));
+        assert!(!closed.allows(Some(&stranger)) && !closed.allows(Some(&pest)) && !closed.allows(None));
+        let huge = Policy{ blocked: vec![pest; MAX_POLICY_KEYS + 1], ..Policy::default() };
+        assert_eq!(huge.validate(), Err(ErrorCode::InvalidPolicy));
+    }
+
+    #[test]
+    fn test_server_enforces_policy() {
+        let server = NameServer::bind("127.0.0.1:0").unwrap();
+        let (addr, key) = (server.local_addr().unwrap(), server.public_key());
+        thread::spawn(move || server.run());
+        let (alice, friend, pest) = (IdentityKey::generate(), IdentityKey::generate(), IdentityKey::generate());
+        let mut owner = NameServerClient::connect_as(addr, &key, &alice).unwrap();
+        owner.register("alice", "127.0.0.1:5600".parse().unwrap(), &alice).unwrap();
+        let policy = Policy{ contacts_only: true, contacts: vec![friend.public(), pest.public()], blocked: vec![pest.public()], invisible: true };
+        owner.set_policy("alice", &alice, policy).unwrap();
+        let code = |e: std::io::Error| e.get_ref().and_then(|e| e.downcast_ref::<ErrorCode>()).copied();
+
+        let mut stranger = NameServerClient::connect(addr, &key).unwrap();
+        assert_eq!(stranger.lookup("alice").unwrap(), None);
+        assert_eq!(code(stranger.fetch_prekeys("alice").unwrap_err()), Some(ErrorCode::NotRegistered));
+        assert_eq!(code(stranger.deposit("alice", vec![1]).unwrap_err()), Some(ErrorCode::NotRegistered));
+        assert_eq!(NameServerClient::connect_as(addr, &key, &pest).unwrap().lookup("alice").unwrap(), None);
+        // Contacts find the name, but never see it online; its owner sees it as it is
+        let mut contact = NameServerClient::connect_as(addr, &key, &friend).unwrap();
+        let seen = contact.lookup("alice").unwrap().unwrap();
+        assert!(!seen.presence.is_online() && seen.address.ip().is_unspecified());
+        assert!(owner.lookup("alice").unwrap().unwrap().presence.is_online());
+        // Nor does an invite get anyone further than a lookup would, or get used up trying
+        let expires = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() + 60;
+        let invite = SignedInvite::sign(Invite::new("alice", &addr.to_string(), key, alice.public(), expires, true), &alice);
+        assert_eq!(code(stranger.redeem_invite(&invite).unwrap_err()), Some(ErrorCode::InvalidInvite));
+        let mut pest_client = NameServerClient::connect_as(addr, &key, &pest).unwrap();
+        assert_eq!(code(pest_client.redeem_invite(&invite).unwrap_err()), Some(ErrorCode::InvalidInvite));
+        assert_eq!(contact.redeem_invite(&invite).unwrap(), seen);
+
+        let (entries, mut subscription) = NameServerClient::connect(addr, &key).unwrap().subscribe(&["alice".to_string()]).unwrap();
+        assert!(entries.is_empty());
+        owner.set_policy("alice", &alice, Policy::default()).unwrap();
+        match subscription.wait().unwrap().as_slice() {
+            [Event::Online(entry)] => assert_eq!(entry.name, "alice"),
+            events => panic!("expected alice to appear, got {:?}", events),
+        }
+        assert!(matches!(stranger.lookup("alice").unwrap().unwrap().presence, Presence::Online{ .. }));
+
+        let huge = Policy{ blocked: vec![pest.public(); MAX_POLICY_KEYS + 1], ..Policy::default() };
+        assert_eq!(code(owner.set_policy("alice", &alice, huge).unwrap_err()), Some(ErrorCode::InvalidPolicy));
+        // Only the owner sets the policy, and the next owner starts from an open one
+        assert_eq!(code(contact.set_policy("alice", &friend, Policy{ contacts_only: true, ..Policy::default() }).unwrap_err()),
+            Some(ErrorCode::BadSignature));
+        owner.set_policy("alice", &alice, Policy{ contacts_only: true, ..Policy::default() }).unwrap();
+        owner.transfer("alice", &alice, friend.public()).unwrap();
+        assert!(stranger.lookup("alice").unwrap().is_some());
+    }
+
+    #[test]
+    fn test_relay_refuses_hidden_names() {
+        let server = NameServer::bind("127.0.0.1:0").unwrap();
+        let (addr, key) = (server.local_addr().unwrap(), server.public_key());
+        thread::spawn(move || server.run());
+        let (alice, friend, pest) = (IdentityKey::generate(), IdentityKey::generate(), IdentityKey::generate());
+        let mut owner = NameServerClient::connect_as(addr, &key, &alice).unwrap();
+        owner.register("alice", "127.0.0.1:5601".parse().unwrap(), &alice).unwrap();
+        owner.set_policy("alice", &alice, Policy{ blocked: vec![pest.public()], ..Policy::default() }).unwrap();
+        let parked = thread::spawn(move || owner.park("alice", &alice).map(drop));
+
+        let join = |identity: &IdentityKey| NameServerClient::connect_as(addr, &key, identity).unwrap().join("alice")
+            .map(drop).map_err(|e| e.get_ref().and_then(|e| e.downcast_ref::<ErrorCode>()).copied());
+        for _ in 0..10 {
+            assert_eq!(join(&pest), Err(Some(ErrorCode::NotParked)));
+            thread::sleep(Duration::from_millis(20));
+        }
+        // Alice was parked all along, and others still get through
+        let mut joined = join(&friend);
+        for _ in 0..100 {
+            if joined.is_ok() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+            joined = join(&friend);
+        }
+        joined.unwrap();
+        parked.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_rendezvous_needs_open_policy() {
+        let server = NameServer::bind("127.0.0.1:0").unwrap();
+        let (addr, key) = (server.local_addr().unwrap(), server.public_key());
+        thread::spawn(move || server.run());
+        let bob = IdentityKey::generate();
+        let mut owner = NameServerClient::connect_as(addr, &key, &bob).unwrap();
+        owner.register("bob", "127.0.0.1:1".parse().unwrap(), &bob).unwrap();
+        owner.set_policy("bob", &bob, Policy{ blocked: vec![IdentityKey::generate().public()], ..Policy::default() }).unwrap();
+        let (bob_socket, alice_socket) = (UdpSocket::bind("127.0.0.1:0").unwrap(), UdpSocket::bind("127.0.0.1:0").unwrap());
+        let bob_endpoint = bob_socket.local_addr().unwrap();
+        let listener = IdentityKey::from_bytes(&bob.to_bytes());
+        let listening = thread::spawn(move || RendezvousClient::new(&bob_socket, addr, key).listen("bob", &listener));
+
+        // Anyone could be behind a datagram, the blocked key included
+        let alice = RendezvousClient::new(&alice_socket, addr, key);
+        for _ in 0..10 {
+            assert_eq!(alice.rendezvous("bob").unwrap_err().kind(), ErrorKind::NotFound);
+            thread::sleep(Duration::from_millis(20));
+        }
+        owner.set_policy("bob", &bob, Policy::default()).unwrap();
+        let endpoint = loop {
+            match alice.rendezvous("bob") {
+                Ok(endpoint) => break endpoint,
+                Err(e) if e.kind() == ErrorKind::NotFound => thread::sleep(Duration::from_millis(10)),
+                Err(e) => panic!("{}", e),
+            }
+        };
+        assert_eq!(endpoint, bob_endpoint);
+        listening.join().unwrap().unwrap();
+    }
+}
@@ -0,0 +1,506 @@
+use std::fmt;
+use std::io::{self, Error, ErrorKind};
+use std::net::SocketAddr;
+
+use chat_security::x3dh::{PrekeyBundle, PrekeyUpload};
+use chat_security::{IdentityKey, IdentityPublic, SessionCryptData, Transport};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::admin::{AdminRequest, KeyRotation, Registration};
+use crate::invite::SignedInvite;
+use crate::policy::Policy;
+use crate::replication::Batch;
+use crate::subscriptions::Event;
+use crate::transparency::{Binding, Hash, InclusionProof, SignedTreeHead};
+
+/*
+    Wire format
+    A connection starts with a chat_security handshake in which the server proves it holds the identity
+    key clients have pinned in their config. Clients handshake under a throwaway key, as the server has
+    nothing to check theirs against; updates carry their own signatures. After that every request and
+    response is one encrypted frame of bincode, and a connection carries any number of request/response
+    pairs, strictly alternating, until either side closes it. Lookups and registrations are then as
+    private as the chats they set up. Relaying and subscriptions are the exceptions: once the server
+    accepts one, it takes the connection over for good.
+
+    Hole punching rendezvous runs over UDP on the same port instead, one bincode message per datagram
+    sealed to the server's key (see `chat_security::sealed`), with the reply sealed under the same key.
+ */
+
+/// Name the server gives in its handshake. Clients go by the key, not this.
+pub const SERVER_NAME: &str = "name-server";
+/// Largest datagram the server reads; rendezvous requests are far smaller.
+pub const MAX_DATAGRAM_LEN: usize = 2048;
+/// Requests and responses are tiny; anything bigger is rejected before allocating.
+pub const MAX_FRAME_LEN: usize = 64 * 1024;
+pub const MAX_NAME_LEN: usize = 64;
+
+/// What the directory knows about a name.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Entry{
+    pub name: String,
+    /// Where the owner was last reachable. Only meaningful while `presence` is online.
+    pub address: SocketAddr,
+    pub identity: IdentityPublic,
+    pub presence: Presence,
+}
+
+/// Times are seconds since the unix epoch, by the name server's clock.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Presence{
+    Online{ lease_expires: u64 },
+    Offline{ last_seen: u64 },
+}
+
+impl Presence{
+    pub fn is_online(&self) -> bool{
+        matches!(self, Presence::Online{ .. })
+    }
+}
+
+/*
+    Ownership
+    The first registration of a name binds it to the identity key that signed it. From then on every
+    change to the name (new address, transfer to another key, deletion) must be signed by that key.
+    Each change carries a sequence number that must exceed the last one accepted for the name, so a
+    captured request can't be replayed later.
+
+    Leases
+    A registration only keeps the name online for the server's lease period. The owner renews it with
+    heartbeats; once a lease runs out the entry turns offline and keeps just its last-seen time until
+    the owner registers again. The name itself stays bound to its key.
+
+    Registration work
+    A server can make claiming a name cost some CPU: the registration must carry a `work` value such
+    that SHA-256 over the name, the key and that value starts with the server's number of zero bits.
+    Renewing a name already held costs nothing.
+ */
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum Action{
+    /// Claims the name, or moves it to a new address. An unspecified IP (0.0.0.0 or ::) is replaced by the
+    /// address the request came from, so clients behind a single NAT needn't know their public IP.
+    Register{ address: SocketAddr, identity: IdentityPublic, work: u64 },
+    /// Hands the name over to another identity key.
+    Transfer{ identity: IdentityPublic },
+    /// Renews the lease on a registration that hasn't expired yet.
+    Heartbeat,
+    /// Replaces the signed prekey and adds one-time prekeys, for senders to start sessions while we're
+    /// offline.
+    PublishPrekeys(PrekeyUpload),
+    /// Collects envelopes waiting for the name. They stay queued until acknowledged.
+    FetchMail,
+    /// Deletes delivered envelopes.
+    Acknowledge{ ids: Vec<u64> },
+    /// Parks this connection on the relay until someone joins the name. The server answers `Parked`,
+    /// then `Joined` once a peer arrives, after which the connection carries the peer's bytes.
+    Park,
+    /// Waits for hole punching peers. Only accepted over UDP, where the server sees the public endpoint to
+    /// hand out; it is remembered for `rendezvous::LISTEN_TTL`, so listeners repeat this while waiting.
+    Listen,
+    /// Replaces who may find the name and see its presence; see `policy`.
+    SetPolicy(Policy),
+    Delete,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Update{
+    pub name: String,
+    pub action: Action,
+    pub sequence: u64,
+}
+
+impl Update{
+    fn signed_bytes(&self, server: &IdentityPublic) -> Vec<u8>{
+        let mut data = b"rustchat name update".to_vec();
+        data.extend(server.as_bytes());
+        data.extend(bincode::serialize(self).expect("Failed to serialize update"));
+        data
+    }
+}
+
+/// An `Update` signed by the name's owner (for a first registration, by the key being registered).
+///
+/// The signature also covers the key of the server it was made for, which refuses updates meant for
+/// another server, so one can't be replayed at a replica's primary or a federated domain.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct SignedUpdate{
+    pub update: Update,
+    pub server: IdentityPublic,
+    pub signature: Vec<u8>,
+}
+
+impl SignedUpdate{
+    pub fn sign(update: Update, server: &IdentityPublic, identity: &IdentityKey) -> Self{
+        let signature = identity.sign(&update.signed_bytes(server)).to_vec();
+        SignedUpdate{ update, server: *server, signature }
+    }
+
+    pub fn verify(&self, key: &IdentityPublic) -> Result<(), ErrorCode>{
+        let signature: &[u8; 64] = self.signature.as_slice().try_into()
+            .map_err(|_| ErrorCode::BadSignature)?;
+        key.verify(&self.update.signed_bytes(&self.server), signature)
+            .map_err(|_| ErrorCode::BadSignature)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum Request{
+    Update(SignedUpdate),
+    /// Looks up a bare name, or `name@domain`, which the server forwards to that domain's server unless
+    /// it is its own.
+    Lookup{ name: String },
+    /// Takes a prekey bundle for `name`, using up one of its one-time prekeys.
+    FetchPrekeys{ name: String },
+    /// Queues an end-to-end encrypted envelope for a registered name until its owner fetches it. The
+    /// payload is opaque to the server.
+    Deposit{ to: String, payload: Vec<u8> },
+    /// Connects to whoever has parked under `to`. After the `Joined` reply the connection carries the
+    /// peer's bytes.
+    Join{ to: String },
+    /// Over UDP: asks the server to introduce us to `to`, which must be listening. Both sides get a
+    /// `Rendezvous` with the other's public endpoint and start punching towards it.
+    Rendezvous{ to: String },
+    /// How much proof of work claiming a new name takes.
+    RegistrationWork,
+    /// The current signed head of the key log.
+    TreeHead,
+    /// Proof that the key log at size `old` is a prefix of the log at size `new`.
+    Consistency{ old: u64, new: u64 },
+    /// Key log entries `start..end`, for auditors. May be cut short; ask again from where it stopped.
+    LogEntries{ start: u64, end: u64 },
+    /// The log of the server's key rotations, for clients whose pinned key is out of date.
+    KeyRotations,
+    /// Checks an invite against the directory before its recipient trusts it, using it up if it is single use.
+    RedeemInvite(SignedInvite),
+    /// Only for connections handshaken under an admin key.
+    Admin(AdminRequest),
+    /// From a replica, handshaken under the server's own key: the changes after `position` in the
+    /// journal's `epoch` and the key log entries after `log_size`. Held until there are some.
+    Replicate{ epoch: u64, position: u64, log_size: u64 },
+    /// Watches up to `subscriptions::MAX_WATCHED` names at our domain. The server answers `Subscribed`,
+    /// then pushes `Events` down the connection until either side closes it.
+    Subscribe{ names: Vec<String> },
+}
+
+impl Request{
+    /// Short name of what is being asked, for metrics.
+    pub fn kind(&self) -> &'static str{
+        match self{
+            Request::Update(signed) => match signed.update.action{
+                Action::Register{ .. } => "register",
+                Action::Transfer{ .. } => "transfer",
+                Action::Heartbeat => "heartbeat",
+                Action::PublishPrekeys(_) => "publish_prekeys",
+                Action::FetchMail => "fetch_mail",
+                Action::Acknowledge{ .. } => "acknowledge",
+                Action::Park => "park",
+                Action::Listen => "listen",
+                Action::SetPolicy(_) => "set_policy",
+                Action::Delete => "delete",
+            },
+            Request::Lookup{ .. } => "lookup",
+            Request::FetchPrekeys{ .. } => "fetch_prekeys",
+            Request::Deposit{ .. } => "deposit",
+            Request::Join{ .. } => "join",
+            Request::Rendezvous{ .. } => "rendezvous",
+            Request::RegistrationWork => "registration_work",
+            Request::TreeHead => "tree_head",
+            Request::Consistency{ .. } => "consistency",
+            Request::LogEntries{ .. } => "log_entries",
+            Request::KeyRotations => "key_rotations",
+            Request::RedeemInvite(_) => "redeem_invite",
+            Request::Admin(_) => "admin",
+            Request::Replicate{ .. } => "replicate",
+            Request::Subscribe{ .. } => "subscribe",
+        }
+    }
+}
+
+/// An envelope held for its recipient. `received` is in seconds since the unix epoch.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Envelope{
+    pub id: u64,
+    pub received: u64,
+    pub payload: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum Response{
+    /// The entry as stored after a register, transfer or heartbeat.
+    Registered(Entry),
+    Deleted,
+    /// The entry, with proof that its key is the name's latest binding in the key log.
+    Found{ entry: Entry, proof: InclusionProof },
+    NotFound,
+    /// A name at another domain, as that domain's server answered for it. `proof` is under `server`'s key,
+    /// for the binding of the name without its domain.
+    Forwarded{ entry: Entry, proof: InclusionProof, server: IdentityPublic },
+    /// Reply to `PublishPrekeys`: one-time prekeys now held for the name.
+    PrekeysStored{ one_time: usize },
+    Prekeys(PrekeyBundle),
+    Deposited{ id: u64 },
+    Mail(Vec<Envelope>),
+    Acknowledged{ removed: usize },
+    Parked,
+    Joined,
+    Listening,
+    /// The public UDP endpoint of the peer to punch towards.
+    Rendezvous(SocketAddr),
+    /// Leading zero bits the work on a new registration needs.
+    RegistrationWork{ bits: u8 },
+    TreeHead(SignedTreeHead),
+    Consistency(Vec<Hash>),
+    LogEntries(Vec<Binding>),
+    KeyRotations(Vec<KeyRotation>),
+    Registrations(Vec<Registration>),
+    Registration(Registration),
+    KeyRotated(KeyRotation),
+    /// The inviter's entry, proven as for `Found`.
+    InviteRedeemed{ entry: Entry, proof: InclusionProof },
+    Replication(Batch),
+    /// The current entries of the watched names that are registered.
+    Subscribed(Vec<Entry>),
+    /// Changes to watched names. Empty now and then, to show the subscription is alive.
+    Events(Vec<Event>),
+    Error(ErrorCode),
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorCode{
+    MalformedRequest,
+    InvalidName,
+    InvalidAddress,
+    /// The signature doesn't verify under the key that owns (or is claiming) the name.
+    BadSignature,
+    /// The name is bound to a different identity key.
+    NameTaken,
+    /// Transfer or delete of a name nobody has registered.
+    NotRegistered,
+    /// The sequence number isn't above the last one accepted for this name.
+    StaleSequence,
+    /// Heartbeat for a lease that already ran out; the owner must register again.
+    LeaseExpired,
+    /// The server couldn't record the change; nothing was applied.
+    StorageFailure,
+    /// The name has no prekeys published.
+    NoPrekeys,
+    /// The recipient's mailbox is at its envelope or byte quota.
+    MailboxFull,
+    EnvelopeTooLarge,
+    /// Nobody is parked on the relay under that name.
+    NotParked,
+    /// The relay has no room for another parked connection.
+    RelayBusy,
+    /// Nobody is listening for hole punching under that name.
+    NotListening,
+    /// The server has no room for another listener.
+    RendezvousBusy,
+    /// Too many requests from this IP or key; try again later.
+    RateLimited,
+    /// The IP or key is on the server's ban list.
+    Banned,
+    /// Claiming a new name needs (more) proof of work; see `Request::RegistrationWork`.
+    InsufficientWork,
+    /// The key log range asked for is backwards or past the end of the log.
+    InvalidLogRange,
+    /// The name is at a domain the server doesn't federate with.
+    UnknownDomain,
+    /// The domain's server couldn't be reached or gave a bad answer.
+    FederationFailure,
+    /// An admin has suspended the name.
+    Suspended,
+    /// The request needs an admin key.
+    NotAdmin,
+    /// The invite's signer doesn't own the name, or it is valid for longer than the server allows.
+    InvalidInvite,
+    InviteExpired,
+    /// The single-use invite has already been redeemed.
+    InviteUsed,
+    /// The server is a read-only replica; changes, mail, relaying and hole punching need the primary.
+    NotPrimary,
+    /// The replica hasn't caught up with its primary yet; try another server.
+    Unavailable,
+    /// Replication needs the server's own key.
+    NotReplica,
+    /// The server has no room for another subscription.
+    SubscriptionsBusy,
+    /// The privacy policy lists more keys than the server takes.
+    InvalidPolicy,
+    /// The update was signed for a different server.
+    WrongServer,
+}
+
+impl fmt::Display for ErrorCode{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result{
+        let text = match self{
+            ErrorCode::MalformedRequest => "malformed request",
+            ErrorCode::InvalidName => "invalid name",
+            ErrorCode::InvalidAddress => "invalid address",
+            ErrorCode::BadSignature => "bad signature",
+            ErrorCode::NameTaken => "name is registered to another key",
+            ErrorCode::NotRegistered => "name is not registered",
+            ErrorCode::StaleSequence => "stale sequence number",
+            ErrorCode::LeaseExpired => "lease expired",
+            ErrorCode::StorageFailure => "server storage failure",
+            ErrorCode::NoPrekeys => "no prekeys published",
+            ErrorCode::MailboxFull => "mailbox full",
+            ErrorCode::EnvelopeTooLarge => "envelope too large",
+            ErrorCode::NotParked => "peer is not parked on the relay",
+            ErrorCode::RelayBusy => "relay is busy",
+            ErrorCode::NotListening => "peer is not listening for hole punching",
+            ErrorCode::RendezvousBusy => "rendezvous is busy",
+            ErrorCode::RateLimited => "rate limited",
+            ErrorCode::Banned => "banned",
+            ErrorCode::InsufficientWork => "registration needs proof of work",
+            ErrorCode::InvalidLogRange => "invalid key log range",
+            ErrorCode::UnknownDomain => "unknown domain",
+            ErrorCode::FederationFailure => "domain's name server failed",
+            ErrorCode::Suspended => "name is suspended",
+            ErrorCode::NotAdmin => "not an admin",
+            ErrorCode::InvalidInvite => "invalid invite",
+            ErrorCode::InviteExpired => "invite expired",
+            ErrorCode::InviteUsed => "invite already used",
+            ErrorCode::NotPrimary => "server is a read-only replica",
+            ErrorCode::Unavailable => "replica is catching up with its primary",
+            ErrorCode::NotReplica => "not a replica",
+            ErrorCode::SubscriptionsBusy => "too many subscriptions",
+            ErrorCode::InvalidPolicy => "invalid privacy policy",
+            ErrorCode::WrongServer => "update was signed for another server",
+        };
+        f.write_str(text)
+    }
+}
+
+impl std::error::Error for ErrorCode {}
+
+// The code stays reachable through `io::Error::get_ref`
+impl From<ErrorCode> for io::Error{
+    fn from(code: ErrorCode) -> io::Error{
+        let kind = match code{
+            ErrorCode::MalformedRequest | ErrorCode::InvalidName | ErrorCode::InvalidAddress
+                | ErrorCode::EnvelopeTooLarge | ErrorCode::InvalidLogRange | ErrorCode::InvalidPolicy => ErrorKind::InvalidInput,
+            ErrorCode::MailboxFull | ErrorCode::RateLimited => ErrorKind::QuotaExceeded,
+            ErrorCode::BadSignature | ErrorCode::NameTaken | ErrorCode::StaleSequence | ErrorCode::Banned
+                | ErrorCode::InsufficientWork | ErrorCode::Suspended | ErrorCode::NotAdmin | ErrorCode::InvalidInvite
+                | ErrorCode::InviteUsed | ErrorCode::NotReplica | ErrorCode::WrongServer => ErrorKind::PermissionDenied,
+            ErrorCode::NotRegistered | ErrorCode::NoPrekeys | ErrorCode::NotParked
+                | ErrorCode::NotListening | ErrorCode::UnknownDomain => ErrorKind::NotFound,
+            ErrorCode::RelayBusy | ErrorCode::RendezvousBusy | ErrorCode::Unavailable
+                | ErrorCode::SubscriptionsBusy => ErrorKind::ResourceBusy,
+            ErrorCode::NotPrimary => ErrorKind::Unsupported,
+            ErrorCode::LeaseExpired | ErrorCode::InviteExpired => ErrorKind::TimedOut,
+            ErrorCode::StorageFailure | ErrorCode::FederationFailure => ErrorKind::Other,
+        };
+        io::Error::new(kind, code)
+    }
+}
+
+/// Names are what users type to reach each other: 1 to `MAX_NAME_LEN` bytes, printable, without whitespace or `@`.
+pub fn validate_name(name: &str) -> Result<(), ErrorCode>{
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name.chars().all(|c| !c.is_whitespace() && !c.is_control() && c != '@');
+    if valid { Ok(()) } else { Err(ErrorCode::InvalidName) }
+}
+
+fn work_digest(name: &str, identity: &IdentityPublic, work: u64) -> [u8; 32]{
+    let mut hasher = Sha256::new();
+    hasher.update(b"rustchat registration work");
+    hasher.update((name.len() as u32).to_be_bytes());
+    hasher.update(name.as_bytes());
+    hasher.update(identity.as_bytes());
+    hasher.update(work.to_be_bytes());
+    hasher.finalize().into()
+}
+
+fn leading_zero_bits(digest: &[u8]) -> u32{
+    let zero_bytes = digest.iter().take_while(|byte| **byte == 0).count();
+    let partial = digest.get(zero_bytes).map_or(0, |byte| byte.leading_zeros());
+    zero_bytes as u32 * 8 + partial
+}
+
+pub fn check_registration_work(name: &str, identity: &IdentityPublic, work: u64, bits: u8) -> bool{
+    leading_zero_bits(&work_digest(name, identity, work)) >= u32::from(bits)
+}
+
+/// Finds a `work` value for registering `name` under `identity`. Takes around 2^`bits` hashes.
+pub fn solve_registration_work(name: &str, identity: &IdentityPublic, bits: u8) -> u64{
+    (0..).find(|work| check_registration_work(name, identity, *work, bits)).expect("Ran out of work values")
+}
+
+pub fn encode<T: Serialize>(message: &T) -> Result<Vec<u8>, io::Error>{
+    bincode::serialize(message)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
+
+pub fn decode<T: DeserializeOwned>(data: &[u8]) -> Result<T, io::Error>{
+    if data.len() > MAX_FRAME_LEN{
+        return Err(Error::new(ErrorKind::InvalidData, "Frame too large"));
+    }
+    bincode::deserialize(data)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
+
+pub fn write_frame<S: Transport, T: Serialize>(session: &mut SessionCryptData<S>, message: &T) -> Result<(), io::Error>{
+    session.send_data(&encode(message)?)
+}
+
+pub fn read_frame<S: Transport, T: DeserializeOwned>(session: &mut SessionCryptData<S>) -> Result<T, io::Error>{
+    decode(&session.recieve_data()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn register(identity: &IdentityKey) -> Update {
+        Update{
+            name: "alice".to_string(),
+            action: Action::Register{ address: "127.0.0.1:4000".parse().unwrap(), identity: identity.public(), work: 0 },
+            sequence: 1,
+        }
+    }
+
+    #[test]
+    fn test_frame_roundtrip() {
+        let request = Request::Update(SignedUpdate::sign(register(&IdentityKey::generate()), &IdentityKey::generate().public(), &IdentityKey::generate()));
+        let decoded: Request = decode(&encode(&request).unwrap()).unwrap();
+        assert_eq!(decoded, request);
+    }
+
+    #[test]
+    fn test_oversized_frame_rejected() {
+        let result: Result<Request, _> = decode(&vec![0; MAX_FRAME_LEN + 1]);
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_signed_update_covers_every_field() {
+        let identity = IdentityKey::generate();
+        let signed = SignedUpdate::sign(register(&identity), &IdentityKey::generate().public(), &identity);
+        assert!(signed.verify(&identity.public()).is_ok());
+        assert_eq!(signed.verify(&IdentityKey::generate().public()), Err(ErrorCode::BadSignature));
+
+        let mut tampered = signed.clone();
+        tampered.update.sequence += 1;
+        assert_eq!(tampered.verify(&identity.public()), Err(ErrorCode::BadSignature));
+        let mut tampered = signed.clone();
+        tampered.update.action = Action::Delete;
+        assert_eq!(tampered.verify(&identity.public()), Err(ErrorCode::BadSignature));
+        let mut tampered = signed;
+        tampered.server = IdentityKey::generate().public();
+        assert_eq!(tampered.verify(&identity.public()), Err(ErrorCode::BadSignature));
+    }
+
+    #[test]
+    fn test_validate_name() {
+        assert!(validate_name("alice").is_ok());
+        assert!(validate_name("").is_err());
+        assert!(validate_name("alice smith").is_err());
+        assert!(validate_name("alice@chat.example").is_err());
+        assert!(validate_name(&"a".repeat(MAX_NAME_LEN + 1)).is_err());
+    }
+}
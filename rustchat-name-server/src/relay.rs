@@ -0,0 +1,154 @@
+use std::collections::HashMap;
+use std::io::{self, ErrorKind};
+use std::net::{Shutdown, TcpStream};
+use std::sync::Mutex;
+use std::thread;
+
+use chat_security::SessionCryptData;
+
+use crate::protocol::{write_frame, ErrorCode, Response};
+
+/*
+    Relay
+    For peers that can't accept connections (typically behind NAT). The receiving side parks an
+    outbound connection under its name; a sender that can't reach it directly joins that name, and
+    from then on the relay copies bytes between the two sockets, below the sessions each had with the
+    server. Both ends run the normal encrypted handshake over the spliced pipe, so the relay only ever
+    sees ciphertext.
+ */
+
+/// Parked connections kept at once. Each costs a socket, so this bounds what idle parkers can take.
+pub const MAX_PARKED: usize = 256;
+
+#[derive(Default)]
+pub struct Relay{
+    parked: Mutex<HashMap<String, SessionCryptData>>,
+}
+
+impl Relay{
+    pub fn new() -> Self{
+        Self::default()
+    }
+
+    /// Holds `session` until someone joins `name`, replacing any connection parked there before, and returns
+    /// the response it was sent. The caller has already checked that `session` speaks for `name`.
+    pub fn park(&self, name: &str, mut session: SessionCryptData) -> Result<Response, io::Error>{
+        let mut parked = self.parked.lock().unwrap();
+        if parked.len() >= MAX_PARKED{
+            parked.retain(|_, session| is_open(session.get_ref()));
+        }
+        if parked.len() >= MAX_PARKED && !parked.contains_key(name){
+            let response = Response::Error(ErrorCode::RelayBusy);
+            write_frame(&mut session, &response)?;
+            return Ok(response);
+        }
+        write_frame(&mut session, &Response::Parked)?;
+        parked.insert(name.to_string(), session);
+        Ok(Response::Parked)
+    }
+
+    /// Hangs up on every parked connection.
+    pub fn clear(&self){
+        self.parked.lock().unwrap().clear();
+    }
+
+    /// Connects `session` to whoever is parked under `to` and tells both sides, returning the response
+    /// `session` was sent and, if joined, the splice to run.
+    pub fn join(&self, to: &str, mut session: SessionCryptData) -> Result<(Response, Option<Splice>), io::Error>{
+        let parked = self.parked.lock().unwrap().remove(to).filter(|parked| is_open(parked.get_ref()));
+        let Some(mut parked) = parked else {
+            let response = Response::Error(ErrorCode::NotParked);
+            write_frame(&mut session, &response)?;
+            return Ok((response, None));
+        };
+        write_frame(&mut parked, &Response::Joined)?;
+        write_frame(&mut session, &Response::Joined)?;
+        Ok((Response::Joined, Some(Splice(parked.get_ref().try_clone()?, session.get_ref().try_clone()?))))
+    }
+}
+
+/// Two joined connections, not yet copying between each other.
+pub struct Splice(TcpStream, TcpStream);
+
+impl Splice{
+    /// Copies between the two connections until either hangs up.
+    pub fn run(self) -> Result<(), io::Error>{
+        splice(self.0, self.1)
+    }
+}
+
+/// Whether the peer of an idle socket is still there. Parked clients send nothing, so any readable byte
+/// or EOF means the connection is no good for splicing.
+fn is_open(stream: &TcpStream) -> bool{
+    if stream.set_nonblocking(true).is_err(){
+        return false;
+    }
+    let open = matches!(stream.peek(&mut [0u8; 1]), Err(e) if e.kind() == ErrorKind::WouldBlock);
+    open && stream.set_nonblocking(false).is_ok()
+}
+
+fn splice(a: TcpStream, b: TcpStream) -> Result<(), io::Error>{
+    // Spliced sessions can sit idle for as long as the chat does, and the server's sessions left both
+    // sockets non-blocking between frames
+    for stream in [&a, &b]{
+        stream.set_read_timeout(None)?;
+        stream.set_nonblocking(false)?;
+    }
+    let (mut a_read, mut b_write) = (a.try_clone()?, b.try_clone()?);
+    let forward = thread::spawn(move || {
+        let _ = io::copy(&mut a_read, &mut b_write);
+        let _ = b_write.shutdown(Shutdown::Write);
+    });
+    let (mut b_read, mut a_write) = (b, a);
+    let _ = io::copy(&mut b_read, &mut a_write);
+    let _ = a_write.shutdown(Shutdown::Write);
+    let _ = forward.join();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::client::NameServerClient;
+    use crate::server::NameServer;
+    use chat_security::{IdentityKey, SessionCryptData};
+    use std::io::ErrorKind;
+    use std::thread;
+
+    #[test]
+    fn test_session_over_relay() {
+        let server = NameServer::bind("127.0.0.1:0").unwrap();
+        let (addr, key) = (server.local_addr().unwrap(), server.public_key());
+        thread::spawn(move || server.run());
+
+        let bob = IdentityKey::generate();
+        let mut client = NameServerClient::connect(addr, &key).unwrap();
+        client.register("bob", "127.0.0.1:1".parse().unwrap(), &bob).unwrap();
+        assert_eq!(NameServerClient::connect(addr, &key).unwrap().join("bob").unwrap_err().kind(), ErrorKind::NotFound);
+
+        let mallory = IdentityKey::generate();
+        assert_eq!(NameServerClient::connect(addr, &key).unwrap().park("bob", &mallory).unwrap_err().kind(), ErrorKind::PermissionDenied);
+
+        let parked = thread::spawn(move || {
+            let stream = client.park("bob", &bob)?;
+            SessionCryptData::recieve_authenticated_session(stream, &bob, "bob")
+        });
+        // Joining races the park; retry until bob is in place
+        let alice = IdentityKey::generate();
+        let stream = loop {
+            match NameServerClient::connect(addr, &key).unwrap().join("bob") {
+                Ok(stream) => break stream,
+                Err(e) if e.kind() == ErrorKind::NotFound => thread::sleep(std::time::Duration::from_millis(10)),
+                Err(e) => panic!("{}", e),
+            }
+        };
+        let mut alice_session = SessionCryptData::start_authenticated_session(stream, &alice, "alice").unwrap();
+        let mut bob_session = parked.join().unwrap().unwrap();
+        assert_eq!(alice_session.peer().unwrap().name, "bob");
+
+        let message = chat_security::Message{
+            sender_id: "alice".to_string(), to_id: "bob".to_string(), contents: "via relay".to_string(), timestamp: 1,
+        };
+        alice_session.send_message(message).unwrap();
+        assert_eq!(bob_session.recieve_message().unwrap().contents, "via relay");
+    }
+}
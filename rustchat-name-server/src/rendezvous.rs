@@ -0,0 +1,98 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chat_security::sealed::DatagramKey;
+
+/*
+    Rendezvous
+    For hole punching between peers that are both behind NAT. A receiving peer sends a signed `Listen`
+    over UDP, and the endpoint that datagram arrived from is its public mapping. A sender asks for a
+    `Rendezvous` from its own UDP socket; the server tells each side the other's endpoint, and both
+    start sending to each other at once, which opens the mappings on both NATs. The listener is told
+    under the key its last `Listen` was sealed with, since it has no other way to check who sent it.
+ */
+
+/// How long a `Listen` is good for. NATs tend to drop idle UDP mappings after 30 seconds or more, so a
+/// listener repeating itself well within this also keeps its mapping alive.
+pub const LISTEN_TTL: Duration = Duration::from_secs(30);
+/// Listeners held at once; expired ones are pruned to make room.
+pub const MAX_LISTENERS: usize = 4096;
+
+struct Listener{
+    endpoint: SocketAddr,
+    key: Arc<DatagramKey>,
+    since: Instant,
+}
+
+#[derive(Default)]
+pub struct Rendezvous{
+    listeners: Mutex<HashMap<String, Listener>>,
+}
+
+impl Rendezvous{
+    pub fn new() -> Self{
+        Self::default()
+    }
+
+    /// Records that `name` is listening at `endpoint`, to be told of rendezvous under `key`. Returns false if
+    /// there's no room. The caller has already checked that the request speaks for `name`.
+    pub fn listen(&self, name: &str, endpoint: SocketAddr, key: Arc<DatagramKey>) -> bool{
+        let mut listeners = self.listeners.lock().unwrap();
+        if listeners.len() >= MAX_LISTENERS{
+            listeners.retain(|_, listener| listener.since.elapsed() < LISTEN_TTL);
+        }
+        if listeners.len() >= MAX_LISTENERS && !listeners.contains_key(name){
+            return false;
+        }
+        listeners.insert(name.to_string(), Listener{ endpoint, key, since: Instant::now() });
+        true
+    }
+
+    /// Where `name` is listening and the key to tell it under, if it has said so recently.
+    pub fn endpoint(&self, name: &str) -> Option<(SocketAddr, Arc<DatagramKey>)>{
+        self.listeners.lock().unwrap().get(name)
+            .filter(|listener| listener.since.elapsed() < LISTEN_TTL)
+            .map(|listener| (listener.endpoint, listener.key.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::client::{NameServerClient, RendezvousClient};
+    use crate::server::NameServer;
+    use chat_security::IdentityKey;
+    use std::io::ErrorKind;
+    use std::net::UdpSocket;
+    use std::thread;
+
+    #[test]
+    fn test_endpoints_exchanged() {
+        let server = NameServer::bind("127.0.0.1:0").unwrap();
+        let (addr, key) = (server.local_addr().unwrap(), server.public_key());
+        thread::spawn(move || server.run());
+
+        let bob = IdentityKey::generate();
+        NameServerClient::connect(addr, &key).unwrap().register("bob", "127.0.0.1:1".parse().unwrap(), &bob).unwrap();
+        let (bob_socket, alice_socket) = (UdpSocket::bind("127.0.0.1:0").unwrap(), UdpSocket::bind("127.0.0.1:0").unwrap());
+        let (bob_endpoint, alice_endpoint) = (bob_socket.local_addr().unwrap(), alice_socket.local_addr().unwrap());
+        let alice = RendezvousClient::new(&alice_socket, addr, key);
+        assert_eq!(alice.rendezvous("bob").unwrap_err().kind(), ErrorKind::NotFound);
+
+        let mallory = IdentityKey::generate();
+        assert_eq!(RendezvousClient::new(&bob_socket, addr, key).listen("bob", &mallory).unwrap_err().kind(), ErrorKind::PermissionDenied);
+
+        let listening = thread::spawn(move || RendezvousClient::new(&bob_socket, addr, key).listen("bob", &bob));
+        // Rendezvous races the listen; retry until bob is in place
+        let endpoint = loop {
+            match alice.rendezvous("bob") {
+                Ok(endpoint) => break endpoint,
+                Err(e) if e.kind() == ErrorKind::NotFound => thread::sleep(std::time::Duration::from_millis(10)),
+                Err(e) => panic!("{}", e),
+            }
+        };
+        assert_eq!(endpoint, bob_endpoint);
+        assert_eq!(listening.join().unwrap().unwrap(), alice_endpoint);
+    }
+}
@@ -0,0 +1,371 @@
+use std::io::{self, Error, ErrorKind};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+use rand::random;
+use serde::{Deserialize, Serialize};
+
+use crate::directory::Directory;
+use crate::protocol::MAX_FRAME_LEN;
+use crate::storage::{LogRecord, Storage};
+use crate::transparency::{Binding, TransparencyLog};
+
+/*
+    Replication
+    A primary ships its directory's log to read-only replicas, which answer lookups while it is down.
+    Every change the directory logs also goes into an in-memory journal, which starts out as a snapshot
+    of the directory and starts over from a fresh snapshot, in a new epoch, whenever the directory log
+    is compacted. A replica asks for the journal records after the last one it applied, along with the
+    key log entries after the last one it has, and the primary holds the request until there are some.
+    A replica still in an old epoch starts over from the new snapshot.
+
+    Replicas run under the server's own identity key, since clients pin one key for the primary and
+    all its replicas; holding that key is also how a replica proves itself to the primary. After an
+    admin rotates the primary's key, it still takes replicas under the key just replaced for a grace
+    period (see `admin`), and they follow the rotation to its new key the way clients do. They have to
+    be restarted with the new key file before the grace period is up.
+
+    Replicas keep everything in memory and sync from scratch when they start. Until a replica has caught
+    up it turns clients away, so that they try another server rather than read a half-copied directory.
+    Writes, mail, the relay and hole punching only happen on the primary.
+ */
+
+/// How long the primary holds a replication request with nothing to send.
+pub const REPLICATION_WAIT: Duration = Duration::from_secs(5);
+/// Most of a frame a batch may take, leaving room for the rest of the response.
+const MAX_BATCH_LEN: u64 = MAX_FRAME_LEN as u64 / 2;
+
+/// Changes a replica hasn't seen yet.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Batch{
+    pub epoch: u64,
+    /// Position of the first record in the epoch's journal.
+    pub start: u64,
+    pub records: Vec<LogRecord>,
+    /// Key log entries from the size the replica asked from.
+    pub bindings: Vec<Binding>,
+    /// Whether this brings the replica all the way up to the primary.
+    pub complete: bool,
+}
+
+struct JournalState{
+    epoch: u64,
+    records: Vec<LogRecord>,
+}
+
+/// The directory log since its last compaction, for shipping to replicas.
+pub struct Journal{
+    state: Mutex<JournalState>,
+    changed: Condvar,
+}
+
+impl Journal{
+    pub fn new() -> Arc<Self>{
+        Arc::new(Journal{ state: Mutex::new(JournalState{ epoch: random(), records: Vec::new() }), changed: Condvar::new() })
+    }
+
+    fn push(&self, record: &LogRecord){
+        self.state.lock().unwrap().records.push(record.clone());
+        self.changed.notify_all();
+    }
+
+    /// Starts a new epoch from `snapshot`.
+    pub(crate) fn reset(&self, snapshot: &[LogRecord]){
+        let mut state = self.state.lock().unwrap();
+        // Epochs are random so that a restarted primary doesn't reuse one
+        *state = JournalState{ epoch: random(), records: snapshot.to_vec() };
+        self.changed.notify_all();
+    }
+
+    /// Waits up to `timeout` for records past `position` in `epoch`, returning early if the epoch is over.
+    pub fn wait(&self, epoch: u64, position: u64, timeout: Duration){
+        let state = self.state.lock().unwrap();
+        let _ = self.changed.wait_timeout_while(state, timeout,
+            |state| state.epoch == epoch && state.records.len() as u64 <= position).unwrap();
+    }
+
+    /// Records from `position` in `epoch`, or from the start of the current epoch if that one is over, up
+    /// to `budget` bytes. Returns the epoch, the position they start at, and whether they reach the end.
+    fn read(&self, epoch: u64, position: u64, budget: &mut u64) -> (u64, u64, Vec<LogRecord>, bool){
+        let state = self.state.lock().unwrap();
+        let start = if state.epoch == epoch { position.min(state.records.len() as u64) } else { 0 };
+        let records = take_within(&state.records[start as usize..], budget);
+        let complete = start as usize + records.len() == state.records.len();
+        (state.epoch, start, records, complete)
+    }
+}
+
+/// The leading items of `items` that fit in `budget` bytes, taking at least one so a batch always moves on.
+fn take_within<T: Serialize + Clone>(items: &[T], budget: &mut u64) -> Vec<T>{
+    let mut taken = Vec::new();
+    for item in items{
+        let len = bincode::serialized_size(item).unwrap_or(u64::MAX);
+        if len > *budget && !taken.is_empty(){
+            break;
+        }
+        *budget = budget.saturating_sub(len);
+        taken.push(item.clone());
+    }
+    taken
+}
+
+/// The next batch for a replica at `position` in `epoch` whose key log has `log_size` entries. The caller
+/// holds the directory lock, so the journal and `log` agree.
+pub(crate) fn next_batch(journal: &Journal, log: &TransparencyLog, epoch: u64, position: u64, log_size: u64) -> Batch{
+    let mut budget = MAX_BATCH_LEN;
+    let (epoch, start, records, records_complete) = journal.read(epoch, position, &mut budget);
+    let pending = log.bindings().get(log_size as usize..).unwrap_or_default();
+    let bindings = if budget > 0 { take_within(pending, &mut budget) } else { Vec::new() };
+    let complete = records_complete && bindings.len() == pending.len();
+    Batch{ epoch, start, records, bindings, complete }
+}
+
+/// Storage that also feeds every change into a `Journal`.
+pub(crate) struct Shipping{
+    inner: Option<Box<dyn Storage>>,
+    journal: Arc<Journal>,
+}
+
+impl Shipping{
+    pub(crate) fn new(inner: Option<Box<dyn Storage>>, journal: Arc<Journal>) -> Self{
+        Shipping{ inner, journal }
+    }
+}
+
+impl Storage for Shipping{
+    fn load(&mut self) -> Result<Vec<LogRecord>, io::Error>{
+        self.inner.as_mut().map_or(Ok(Vec::new()), |inner| inner.load())
+    }
+
+    fn append(&mut self, record: &LogRecord) -> Result<(), io::Error>{
+        if let Some(inner) = &mut self.inner{
+            inner.append(record)?;
+        }
+        self.journal.push(record);
+        Ok(())
+    }
+
+    fn compact(&mut self, records: &[LogRecord]) -> Result<(), io::Error>{
+        if let Some(inner) = &mut self.inner{
+            inner.compact(records)?;
+        }
+        self.journal.reset(records);
+        Ok(())
+    }
+}
+
+/// A read-only copy of another server's directory and key log.
+pub struct Replica{
+    /// Where the primary listens, as `host:port`.
+    pub primary: String,
+    epoch: Mutex<Option<u64>>,
+    position: Mutex<u64>,
+    synced: AtomicBool,
+}
+
+impl Replica{
+    pub fn new(primary: &str) -> Self{
+        Replica{ primary: primary.to_string(), epoch: Mutex::new(None), position: Mutex::new(0), synced: AtomicBool::new(false) }
+    }
+
+    /// Whether the replica has caught up with its primary. It stays so if the primary goes away.
+    pub fn is_synced(&self) -> bool{
+        self.synced.load(Ordering::SeqCst)
+    }
+
+    /// The epoch and position to ask the primary for next.
+    pub(crate) fn progress(&self) -> (u64, u64){
+        (self.epoch.lock().unwrap().unwrap_or(0), *self.position.lock().unwrap())
+    }
+
+    /// Applies `batch` to `directory` and `log`, which the caller has locked.
+    pub(crate) fn apply(&self, batch: Batch, directory: &mut Directory, log: &mut TransparencyLog) -> Result<(), io::Error>{
+        let (mut epoch, mut position) = (self.epoch.lock().unwrap(), self.position.lock().unwrap());
+        if *epoch != Some(batch.epoch){
+            if batch.start != 0{
+                return Err(Error::new(ErrorKind::InvalidData, "Primary started a new epoch mid-journal"));
+            }
+            // A new epoch means a fresh snapshot, and the directory is half copied until we have it all
+            self.synced.store(false, Ordering::SeqCst);
+            directory.clear();
+            *epoch = Some(batch.epoch);
+            *position = 0;
+        }
+        if batch.start != *position{
+            return Err(Error::new(ErrorKind::InvalidData, "Primary sent records out of order"));
+        }
+        *position += batch.records.len() as u64;
+        for record in batch.records{
+            directory.replicate(record);
+        }
+        for binding in batch.bindings{
+            log.append(binding)?;
+        }
+        if batch.complete && !self.synced.swap(true, Ordering::SeqCst){
+            println!("Replica caught up with {} ({} names)", self.primary, directory.len());
+        }
+        else if !batch.complete{
+            self.synced.store(false, Ordering::SeqCst);
+        }
+        Ok(())
+    }
+
+    /// Starts over from nothing, e.g. when our key log doesn't fit the primary's.
+    pub(crate) fn reset(&self, directory: &mut Directory, log: &mut TransparencyLog){
+        self.synced.store(false, Ordering::SeqCst);
+        *self.epoch.lock().unwrap() = None;
+        *self.position.lock().unwrap() = 0;
+        directory.clear();
+        *log = TransparencyLog::new();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::admin::AdminKeys;
+    use crate::client::NameServerClient;
+    use crate::server::NameServer;
+    use crate::protocol::ErrorCode;
+    use chat_security::IdentityKey;
+    use std::thread;
+    use std::time::Instant;
+
+    fn code(e: io::Error) -> Option<ErrorCode> {
+        e.get_ref().and_then(|e| e.downcast_ref::<ErrorCode>()).copied()
+    }
+
+    /// Retries `f` until it succeeds, for waiting on a replica to catch up.
+    fn eventually<T>(mut f: impl FnMut() -> Result<T, io::Error>) -> T {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            match f() {
+                Ok(value) => return value,
+                Err(_) if Instant::now() < deadline => thread::sleep(Duration::from_millis(20)),
+                Err(e) => panic!("{}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn test_batches_fit_in_a_frame() {
+        let journal = Journal::new();
+        let records: Vec<LogRecord> = (0..5000).map(|i| LogRecord::Remove{ name: format!("user{}", i), sequence: 1 }).collect();
+        journal.reset(&records);
+        let epoch = journal.state.lock().unwrap().epoch;
+        let log = TransparencyLog::new();
+        let first = next_batch(&journal, &log, epoch, 0, 0);
+        assert!(!first.complete);
+        assert!(crate::protocol::encode(&first).unwrap().len() < MAX_FRAME_LEN);
+        let rest = next_batch(&journal, &log, epoch, first.records.len() as u64, 0);
+        assert_eq!(rest.start, first.records.len() as u64);
+        assert_eq!(rest.records[0], records[first.records.len()]);
+        // A replica from another epoch starts over
+        assert_eq!(next_batch(&journal, &log, epoch ^ 1, 5, 0).start, 0);
+    }
+
+    #[test]
+    fn test_replica_serves_reads_after_primary_dies() {
+        let identity = IdentityKey::generate();
+        let primary = NameServer::bind("127.0.0.1:0").unwrap().identity(IdentityKey::from_bytes(&identity.to_bytes())).primary();
+        let (primary_addr, key) = (primary.local_addr().unwrap(), primary.public_key());
+        let primary_handle = primary.handle().unwrap();
+        thread::spawn(move || primary.run());
+        let alice = IdentityKey::generate();
+        let mut client = NameServerClient::connect(primary_addr, &key).unwrap();
+        client.register("alice", "127.0.0.1:5400".parse().unwrap(), &alice).unwrap();
+
+        let replica = NameServer::bind("127.0.0.1:0").unwrap()
+            .identity(identity)
+            .replica_of(&primary_addr.to_string());
+        let replica_addr = replica.local_addr().unwrap();
+        thread::spawn(move || replica.run());
+        let mut reader = eventually(|| {
+            let mut reader = NameServerClient::connect(replica_addr, &key)?;
+            reader.lookup("alice")?;
+            Ok(reader)
+        });
+        assert_eq!(reader.lookup("alice").unwrap().unwrap().identity, alice.public());
+
+        // Changes keep flowing while the primary is up, and writes go nowhere but the primary
+        let bob = IdentityKey::generate();
+        client.register("bob", "127.0.0.1:5401".parse().unwrap(), &bob).unwrap();
+        eventually(|| reader.lookup("bob")?.ok_or_else(|| Error::new(ErrorKind::NotFound, "bob not replicated yet")));
+        let err = reader.register("carol", "127.0.0.1:5402".parse().unwrap(), &IdentityKey::generate()).unwrap_err();
+        assert_eq!(code(err), Some(ErrorCode::NotPrimary));
+        // Proofs from the replica check out under the key clients pin, against the same log
+        assert_eq!(reader.fetch_tree_head().unwrap().head, client.fetch_tree_head().unwrap().head);
+
+        // Kill the primary in the middle of a stream of registrations
+        let writer = thread::spawn(move || {
+            let mut registered = 0;
+            for i in 0.. {
+                let result = NameServerClient::connect(primary_addr, &key)
+                    .and_then(|mut client| client.register(&format!("user{}", i), "127.0.0.1:5403".parse().unwrap(), &IdentityKey::generate()));
+                match result {
+                    Ok(_) => registered += 1,
+                    Err(_) => return registered,
+                }
+            }
+            unreachable!()
+        });
+        thread::sleep(Duration::from_millis(200));
+        primary_handle.stop();
+        let registered = writer.join().unwrap();
+        assert!(registered > 0);
+        NameServerClient::connect(primary_addr, &key).err().unwrap();
+
+        // The replica keeps answering with what it had. Shipping is asynchronous, so the last changes
+        // may not have made it, but what did is a prefix of what the primary applied
+        let mut reader = NameServerClient::connect(replica_addr, &key).unwrap();
+        assert_eq!(reader.lookup("alice").unwrap().unwrap().identity, alice.public());
+        assert_eq!(reader.lookup("bob").unwrap().unwrap().identity, bob.public());
+        let replicated = (0..=registered).take_while(|i| reader.lookup(&format!("user{}", i)).unwrap().is_some()).count();
+        assert!(replicated > 0);
+        for i in replicated..registered + 2 {
+            assert!(reader.lookup(&format!("user{}", i)).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn test_replicas_follow_key_rotation() {
+        let (identity, admin) = (IdentityKey::generate(), IdentityKey::generate());
+        let mut admins = AdminKeys::new();
+        admins.add(admin.public());
+        let primary = NameServer::bind("127.0.0.1:0").unwrap().identity(IdentityKey::from_bytes(&identity.to_bytes())).admins(admins).primary();
+        let (primary_addr, key) = (primary.local_addr().unwrap(), primary.public_key());
+        thread::spawn(move || primary.run());
+        let spawn_replica = || {
+            let replica = NameServer::bind("127.0.0.1:0").unwrap()
+                .identity(IdentityKey::from_bytes(&identity.to_bytes()))
+                .replica_of(&primary_addr.to_string());
+            let addr = replica.local_addr().unwrap();
+            thread::spawn(move || replica.run());
+            addr
+        };
+        let mut client = NameServerClient::connect(primary_addr, &key).unwrap();
+        let alice = IdentityKey::generate();
+        client.register("alice", "127.0.0.1:5410".parse().unwrap(), &alice).unwrap();
+        let running = spawn_replica();
+        eventually(|| NameServerClient::connect(running, &key)?.lookup("alice")?.ok_or_else(|| Error::new(ErrorKind::NotFound, "alice not replicated yet")));
+
+        let rotation = NameServerClient::connect_as_admin(primary_addr, &key, &admin).unwrap().rotate_key().unwrap();
+        assert_eq!(NameServerClient::connect(primary_addr, &key).unwrap().server_key(), rotation.new);
+        // Both the replica that was running and one started since under the old key keep up
+        let started = spawn_replica();
+        let bob = IdentityKey::generate();
+        NameServerClient::connect(primary_addr, &key).unwrap().register("bob", "127.0.0.1:5411".parse().unwrap(), &bob).unwrap();
+        for replica in [running, started] {
+            let entry = eventually(|| NameServerClient::connect(replica, &key)?.lookup("bob")?
+                .ok_or_else(|| Error::new(ErrorKind::NotFound, "bob not replicated yet")));
+            assert_eq!(entry.identity, bob.public());
+        }
+        // Strangers still can't pass for one, and neither can a key two rotations old
+        let err = NameServerClient::connect_as_server(primary_addr, &key, &IdentityKey::generate()).unwrap().replicate(0, 0, 0).unwrap_err();
+        assert_eq!(code(err), Some(ErrorCode::NotReplica));
+        NameServerClient::connect_as_admin(primary_addr, &key, &admin).unwrap().rotate_key().unwrap();
+        let err = NameServerClient::connect_as_server(primary_addr, &key, &identity).unwrap().replicate(0, 0, 0).unwrap_err();
+        assert_eq!(code(err), Some(ErrorCode::NotReplica));
+    }
+}
@@ -0,0 +1,1064 @@
+use std::collections::HashMap;
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chat_security::sealed::DatagramKey;
+use chat_security::{IdentityKey, IdentityPublic, SessionCryptData};
+use rand::rngs::OsRng;
+
+use crate::admin::{AdminKeys, AdminRequest, ServerKeys};
+use crate::client::NameServerClient;
+use crate::directory::Directory;
+use crate::federation::Federation;
+use crate::invite::Invites;
+use crate::limits::Limiter;
+use crate::mailbox::Mailbox;
+use crate::policy::Policy;
+use crate::metrics::{serve_http, Metrics};
+use crate::relay::{Relay, Splice};
+use crate::replication::{next_batch, Journal, Replica, REPLICATION_WAIT};
+use crate::rendezvous::Rendezvous;
+use crate::storage::LogRecord;
+use crate::subscriptions::{Subscriptions, MAX_WATCHED};
+use crate::transparency::{SignedTreeHead, TransparencyLog};
+use crate::protocol::{decode, encode, read_frame, write_frame, Action, Entry, ErrorCode, Request, Response, SignedUpdate,
+    MAX_DATAGRAM_LEN, SERVER_NAME};
+
+/// Idle connections are dropped after this long so they don't pin a thread forever.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long a replica waits before reconnecting to a primary it lost.
+const REPLICA_RETRY: Duration = Duration::from_secs(1);
+
+// Lock order is directory, then key log, then replication journal, then mailbox, then invites, then limiter, then keys,
+// then subscriptions
+struct State{
+    keys: Mutex<ServerKeys>,
+    admins: AdminKeys,
+    directory: Mutex<Directory>,
+    log: Mutex<TransparencyLog>,
+    mailbox: Mutex<Mailbox>,
+    invites: Mutex<Invites>,
+    limiter: Mutex<Limiter>,
+    relay: Relay,
+    subscriptions: Arc<Subscriptions>,
+    rendezvous: Rendezvous,
+    /// Takes no lock of ours while it waits on other servers.
+    federation: Federation,
+    metrics: Metrics,
+    /// Set on a primary, which ships its directory log to replicas.
+    journal: Option<Arc<Journal>>,
+    /// Set on a replica, which only serves reads.
+    replica: Option<Replica>,
+    stopped: AtomicBool,
+    /// Open client connections, so that stopping the server can cut them off.
+    connections: Mutex<HashMap<u64, TcpStream>>,
+    next_connection: AtomicU64,
+}
+
+impl State{
+    /// The current server key, held onto rather than borrowed as a rotation may replace it meanwhile.
+    fn identity(&self) -> Arc<IdentityKey>{
+        self.keys.lock().unwrap().current()
+    }
+
+    /// Keeps a handle on `stream` for `ServerHandle::stop`, or returns `None` if the server is stopping.
+    fn track(&self, stream: &TcpStream) -> Option<u64>{
+        let id = self.next_connection.fetch_add(1, Ordering::SeqCst);
+        self.connections.lock().unwrap().insert(id, stream.try_clone().ok()?);
+        if self.stopped.load(Ordering::SeqCst){
+            self.connections.lock().unwrap().remove(&id);
+            return None;
+        }
+        Some(id)
+    }
+}
+
+/// Whether `request` needs the primary: it changes state, or uses state replicas don't have.
+fn needs_primary(request: &Request) -> bool{
+    matches!(request, Request::Update(_) | Request::FetchPrekeys{ .. } | Request::Deposit{ .. } | Request::Join{ .. }
+        | Request::Rendezvous{ .. } | Request::RedeemInvite(_)
+        | Request::Admin(AdminRequest::Suspend{ .. } | AdminRequest::Delete{ .. } | AdminRequest::RotateKey))
+}
+
+/// Who is on the other end of a connection, by the key it handshook under.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Caller{
+    Client,
+    /// A federated server forwarding lookups.
+    Peer,
+    Admin,
+    /// One of our replicas, holding our own key or, for a while after a rotation, the one it replaced.
+    Replica,
+}
+
+/// Stops a running server as if its process had been killed: it stops listening and cuts off every
+/// open connection, answering nothing in flight.
+#[derive(Clone)]
+pub struct ServerHandle{
+    state: Arc<State>,
+    addrs: Vec<SocketAddr>,
+}
+
+impl ServerHandle{
+    pub fn stop(&self){
+        self.state.stopped.store(true, Ordering::SeqCst);
+        for (_, stream) in self.state.connections.lock().unwrap().drain(){
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        self.state.relay.clear();
+        self.state.subscriptions.clear();
+        // Wake the threads blocked accepting connections and datagrams, so they see the flag
+        for addr in &self.addrs{
+            let mut addr = *addr;
+            if addr.ip().is_unspecified(){
+                addr.set_ip(if addr.is_ipv4() { Ipv4Addr::LOCALHOST.into() } else { Ipv6Addr::LOCALHOST.into() });
+            }
+            let _ = TcpStream::connect_timeout(&addr, Duration::from_secs(1));
+            let unspecified: IpAddr = if addr.is_ipv4() { Ipv4Addr::UNSPECIFIED.into() } else { Ipv6Addr::UNSPECIFIED.into() };
+            let _ = UdpSocket::bind((unspecified, 0)).and_then(|socket| socket.send_to(&[], addr));
+        }
+    }
+}
+
+pub struct NameServer{
+    listener: TcpListener,
+    /// Bound to the same address as `listener`, for hole punching rendezvous.
+    socket: UdpSocket,
+    /// For the HTTP metrics endpoint, if there is one.
+    metrics: Option<TcpListener>,
+    state: Arc<State>,
+}
+
+impl NameServer{
+    pub fn bind(addr: impl ToSocketAddrs) -> Result<Self, io::Error>{
+        Self::bind_with(addr, Directory::new())
+    }
+
+    /// Serves an existing directory, e.g. one with a custom clock or lease period.
+    pub fn bind_with(addr: impl ToSocketAddrs, directory: Directory) -> Result<Self, io::Error>{
+        let listener = TcpListener::bind(addr)?;
+        let socket = UdpSocket::bind(listener.local_addr()?)?;
+        let mut log = TransparencyLog::new();
+        Self::reconcile(&directory, &mut log)?;
+        Ok(NameServer{
+            listener,
+            socket,
+            state: Arc::new(State{
+                keys: Mutex::new(ServerKeys::new(IdentityKey::generate())),
+                admins: AdminKeys::new(),
+                directory: Mutex::new(directory),
+                log: Mutex::new(log),
+                mailbox: Mutex::new(Mailbox::new()),
+                invites: Mutex::new(Invites::new()),
+                limiter: Mutex::new(Limiter::default()),
+                relay: Relay::new(),
+                subscriptions: Arc::new(Subscriptions::new()),
+                rendezvous: Rendezvous::new(),
+                federation: Federation::default(),
+                metrics: Metrics::new(),
+                journal: None,
+                replica: None,
+                stopped: AtomicBool::new(false),
+                connections: Mutex::new(HashMap::new()),
+                next_connection: AtomicU64::new(0),
+            }),
+            metrics: None,
+        })
+    }
+
+    /// Authenticates the server with `identity`, the key clients pin, instead of a throwaway one. Call
+    /// before `run`.
+    pub fn identity(mut self, identity: IdentityKey) -> Self{
+        let state = Arc::get_mut(&mut self.state).expect("Server already running");
+        state.keys = Mutex::new(ServerKeys::new(identity));
+        self
+    }
+
+    /// Authenticates the server with `keys`, which may have been rotated, instead of a throwaway key. Call
+    /// before `run`.
+    pub fn keys(mut self, keys: ServerKeys) -> Self{
+        let state = Arc::get_mut(&mut self.state).expect("Server already running");
+        state.keys = Mutex::new(keys);
+        self
+    }
+
+    /// Lets connections handshaken under one of `admins` manage registrations and the server key. Call
+    /// before `run`.
+    pub fn admins(mut self, admins: AdminKeys) -> Self{
+        let state = Arc::get_mut(&mut self.state).expect("Server already running");
+        state.admins = admins;
+        self
+    }
+
+    /// Keeps the key log in `log` instead of a fresh in-memory one. Call before `run`.
+    pub fn transparency_log(mut self, mut log: TransparencyLog) -> Result<Self, io::Error>{
+        let state = Arc::get_mut(&mut self.state).expect("Server already running");
+        Self::reconcile(&state.directory.lock().unwrap(), &mut log)?;
+        state.log = Mutex::new(log);
+        Ok(self)
+    }
+
+    /// Logs whatever bindings in `directory` the key log doesn't have yet: all of them the first time a
+    /// directory is served with a log, or the last change if the server stopped between writing the two.
+    fn reconcile(directory: &Directory, log: &mut TransparencyLog) -> Result<(), io::Error>{
+        for record in directory.snapshot(){
+            let (name, identity) = match record{
+                LogRecord::Put{ name, record, .. } => (name, Some(record.entry.identity)),
+                LogRecord::Remove{ name, .. } => (name, None),
+            };
+            log.record(&name, identity)?;
+        }
+        Ok(())
+    }
+
+    /// Holds offline mail in `mailbox` instead of a fresh in-memory one. Call before `run`.
+    pub fn mailbox(mut self, mailbox: Mailbox) -> Self{
+        let state = Arc::get_mut(&mut self.state).expect("Server already running");
+        state.mailbox = Mutex::new(mailbox);
+        self
+    }
+
+    /// Remembers redeemed single-use invites in `invites` instead of a fresh in-memory record. Call before
+    /// `run`.
+    pub fn invites(mut self, invites: Invites) -> Self{
+        let state = Arc::get_mut(&mut self.state).expect("Server already running");
+        state.invites = Mutex::new(invites);
+        self
+    }
+
+    /// Enforces rate limits, bans and registration work. Without this the server serves everyone. Call
+    /// before `run`.
+    pub fn limiter(mut self, limiter: Limiter) -> Self{
+        let state = Arc::get_mut(&mut self.state).expect("Server already running");
+        state.limiter = Mutex::new(limiter);
+        self
+    }
+
+    /// Answers for a domain and forwards lookups for others to their servers. Without this the server
+    /// only knows bare names. Call before `run`.
+    pub fn federation(mut self, federation: Federation) -> Self{
+        let state = Arc::get_mut(&mut self.state).expect("Server already running");
+        state.federation = federation;
+        self
+    }
+
+    /// Ships the directory log to replicas that ask for it. Call before `run`, after anything that replaces
+    /// the directory.
+    pub fn primary(mut self) -> Self{
+        let state = Arc::get_mut(&mut self.state).expect("Server already running");
+        let journal = Journal::new();
+        state.directory.get_mut().unwrap().ship_to(journal.clone());
+        state.journal = Some(journal);
+        self
+    }
+
+    /// Serves a read-only copy of the directory and key log of the server at `primary`, which must hold
+    /// the same identity key. Call before `run`.
+    pub fn replica_of(mut self, primary: &str) -> Self{
+        let state = Arc::get_mut(&mut self.state).expect("Server already running");
+        state.replica = Some(Replica::new(primary));
+        self
+    }
+
+    /// For stopping the server once it is running.
+    pub fn handle(&self) -> Result<ServerHandle, io::Error>{
+        let mut addrs = vec![self.local_addr()?];
+        addrs.extend(self.metrics_addr()?);
+        Ok(ServerHandle{ state: self.state.clone(), addrs })
+    }
+
+    /// Serves Prometheus metrics at `/metrics` and a health check at `/healthz` over HTTP on `addr`. Call
+    /// before `run`.
+    pub fn metrics(mut self, addr: impl ToSocketAddrs) -> Result<Self, io::Error>{
+        self.metrics = Some(TcpListener::bind(addr)?);
+        Ok(self)
+    }
+
+    pub fn metrics_addr(&self) -> Result<Option<SocketAddr>, io::Error>{
+        self.metrics.as_ref().map(TcpListener::local_addr).transpose()
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, io::Error>{
+        self.listener.local_addr()
+    }
+
+    /// The key clients need in their config to reach this server.
+    pub fn public_key(&self) -> IdentityPublic{
+        self.state.keys.lock().unwrap().current().public()
+    }
+
+    /// Accepts connections forever, serving each on its own thread. Datagrams are served on one more.
+    pub fn run(self) -> Result<(), io::Error>{
+        let (socket, state) = (self.socket, self.state.clone());
+        self.state.directory.lock().unwrap().notify(self.state.subscriptions.clone());
+        thread::spawn(move || Self::serve_datagrams(socket, &state));
+        if self.state.replica.is_some(){
+            let state = self.state.clone();
+            thread::spawn(move || Self::follow_primary(&state));
+        }
+        if let Some(listener) = self.metrics{
+            let state = self.state.clone();
+            thread::spawn(move || Self::serve_metrics(listener, state));
+        }
+        for stream in self.listener.incoming(){
+            if self.state.stopped.load(Ordering::SeqCst){
+                break;
+            }
+            let stream = match stream{
+                Ok(stream) => stream,
+                Err(e) => {
+                    eprintln!("Failed to accept connection: {}", e);
+                    continue;
+                }
+            };
+            let state = self.state.clone();
+            thread::spawn(move || {
+                let Some(id) = state.track(&stream) else { return };
+                // Connections cut off by `ServerHandle::stop` are no news
+                if let Err(e) = Self::serve_connection(stream, &state) && !state.stopped.load(Ordering::SeqCst){
+                    eprintln!("Connection error: {}", e);
+                }
+                state.connections.lock().unwrap().remove(&id);
+            });
+        }
+        Ok(())
+    }
+
+    /// Copies the primary's directory and key log for as long as the server runs, reconnecting whenever
+    /// the primary goes away.
+    fn follow_primary(state: &State){
+        let replica = state.replica.as_ref().expect("Only replicas follow a primary");
+        while !state.stopped.load(Ordering::SeqCst){
+            let identity = state.identity();
+            let followed = NameServerClient::connect_as_server(replica.primary.as_str(), &identity.public(), &identity)
+                .and_then(|mut primary| loop{
+                    if state.stopped.load(Ordering::SeqCst){
+                        return Ok(());
+                    }
+                    let (epoch, position) = replica.progress();
+                    let log_size = state.log.lock().unwrap().size();
+                    let batch = match primary.replicate(epoch, position, log_size){
+                        // Our key log isn't a prefix of the primary's, so start again from nothing
+                        Err(e) if e.get_ref().and_then(|e| e.downcast_ref()) == Some(&ErrorCode::InvalidLogRange) => {
+                            let mut directory = state.directory.lock().unwrap();
+                            replica.reset(&mut directory, &mut state.log.lock().unwrap());
+                            continue;
+                        }
+                        batch => batch?,
+                    };
+                    let mut directory = state.directory.lock().unwrap();
+                    replica.apply(batch, &mut directory, &mut state.log.lock().unwrap())?;
+                });
+            if let Err(e) = followed{
+                eprintln!("Lost primary {}: {}", replica.primary, e);
+            }
+            thread::sleep(REPLICA_RETRY);
+        }
+    }
+
+    fn serve_metrics(listener: TcpListener, state: Arc<State>){
+        for stream in listener.incoming(){
+            if state.stopped.load(Ordering::SeqCst){
+                break;
+            }
+            let Ok(stream) = stream else { continue };
+            let state = state.clone();
+            thread::spawn(move || {
+                let served = serve_http(stream, |path| match path{
+                    "/metrics" => {
+                        let directory = state.directory.lock().unwrap();
+                        let (registered, active) = (directory.len(), directory.active_leases());
+                        drop(directory);
+                        (200, state.metrics.render(registered, active))
+                    }
+                    // A panic while holding state poisons it for good, and the server wants restarting
+                    "/healthz" if state.directory.lock().is_ok() && state.log.lock().is_ok() && state.mailbox.lock().is_ok() => (200, "ok\n".to_string()),
+                    "/healthz" => (503, "unhealthy\n".to_string()),
+                    _ => (404, "not found\n".to_string()),
+                });
+                if let Err(e) = served{
+                    eprintln!("Metrics request failed: {}", e);
+                }
+            });
+        }
+    }
+
+    fn serve_connection(stream: TcpStream, state: &State) -> Result<(), io::Error>{
+        let peer = stream.peer_addr()?;
+        if state.limiter.lock().unwrap().is_banned(peer.ip()){
+            return Ok(());
+        }
+        stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+        let mut session = SessionCryptData::recieve_authenticated_session(stream, &state.identity(), SERVER_NAME)?;
+        let caller = match session.peer(){
+            Some(peer) if state.keys.lock().unwrap().recognizes(&peer.key) => Caller::Replica,
+            Some(peer) if state.admins.contains(&peer.key) => Caller::Admin,
+            Some(peer) if state.federation.peer_domain(&peer.key).is_some() => Caller::Peer,
+            _ => Caller::Client,
+        };
+        // Whose eyes privacy policies judge lookups by; peers forward lookups for clients we can't see
+        let viewer = match caller{
+            Caller::Peer => None,
+            _ => session.peer().map(|peer| peer.key),
+        };
+        loop{
+            let request = match read_frame::<_, Request>(&mut session){
+                Ok(request) => request,
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) if e.kind() == ErrorKind::InvalidData => {
+                    // Framing is lost after a bad frame, so answer once and hang up
+                    return write_frame(&mut session, &Response::Error(ErrorCode::MalformedRequest));
+                }
+                Err(e) => return Err(e),
+            };
+            // Federated servers forward lookups for all their users, so per-IP limits don't fit them
+            let allowed = match caller{
+                Caller::Peer | Caller::Replica => Ok(()),
+                Caller::Client | Caller::Admin => state.limiter.lock().unwrap().check_request(peer.ip()),
+            };
+            if let Err(code) = allowed{
+                state.metrics.reject(code);
+                write_frame(&mut session, &Response::Error(code))?;
+                continue;
+            }
+            let (kind, started) = (request.kind(), Instant::now());
+            // Relay and subscription requests hand the connection over for good once they succeed
+            let response = match request{
+                request if state.replica.is_some() && needs_primary(&request) => Response::Error(ErrorCode::NotPrimary),
+                Request::Update(signed) if signed.update.action == Action::Park => {
+                    let name = signed.update.name.clone();
+                    match Self::apply(state, &mut state.directory.lock().unwrap(), signed, peer.ip()){
+                        Ok(_) => {
+                            let response = state.relay.park(&name, session)?;
+                            state.metrics.observe(kind, &response, started.elapsed());
+                            return Ok(());
+                        }
+                        Err(code) => Response::Error(code),
+                    }
+                }
+                // A name hidden from the caller is no more parked than an unregistered one
+                Request::Join{ to } if state.directory.lock().unwrap().lookup_as(&to, viewer.as_ref()).is_none() =>
+                    Response::Error(ErrorCode::NotParked),
+                Request::Join{ to } => {
+                    let (response, splice) = state.relay.join(&to, session)?;
+                    state.metrics.observe(kind, &response, started.elapsed());
+                    return splice.map_or(Ok(()), Splice::run);
+                }
+                Request::Subscribe{ names } => match Self::check_subscription(state, names, peer, viewer.as_ref()){
+                    Ok(names) => {
+                        let lookup = |name: &str| state.directory.lock().unwrap().lookup_as(name, viewer.as_ref());
+                        let (response, subscription) = state.subscriptions.subscribe(names, session, lookup)?;
+                        state.metrics.observe(kind, &response, started.elapsed());
+                        let clock = state.directory.lock().unwrap().clock();
+                        return subscription.map_or(Ok(()), |subscription| subscription.run(clock.as_ref(), lookup));
+                    }
+                    Err(code) => Response::Error(code),
+                },
+                request => Self::handle_request(state, request, peer, caller, viewer.as_ref()).unwrap_or_else(Response::Error),
+            };
+            state.metrics.observe(kind, &response, started.elapsed());
+            write_frame(&mut session, &response)?;
+        }
+    }
+
+    fn serve_datagrams(socket: UdpSocket, state: &State){
+        let mut buf = [0u8; MAX_DATAGRAM_LEN];
+        loop{
+            let received = socket.recv_from(&mut buf);
+            if state.stopped.load(Ordering::SeqCst){
+                return;
+            }
+            let (n, from) = match received{
+                Ok(received) => received,
+                Err(e) => {
+                    eprintln!("Failed to receive datagram: {}", e);
+                    continue;
+                }
+            };
+            // Garbage and refused requests get no reply, so spoofed sources can't use us to bounce traffic.
+            // The limiter goes first so that floods don't cost us a key agreement each
+            if state.limiter.lock().unwrap().check_request(from.ip()).is_err(){
+                continue;
+            }
+            let Ok((key, plaintext)) = DatagramKey::open_request(&state.identity(), &buf[..n]) else { continue };
+            let Ok(request) = decode::<Request>(&plaintext) else { continue };
+            let key = Arc::new(key);
+            let (kind, started) = (request.kind(), Instant::now());
+            let response = if state.replica.is_some(){
+                Response::Error(ErrorCode::NotPrimary)
+            }
+            else{
+                Self::handle_datagram(state, &socket, request, &key, from).unwrap_or_else(Response::Error)
+            };
+            state.metrics.observe(kind, &response, started.elapsed());
+            Self::send_datagram(&socket, &key, &response, from);
+        }
+    }
+
+    fn send_datagram(socket: &UdpSocket, key: &DatagramKey, response: &Response, to: SocketAddr){
+        let data = key.seal_reply(&encode(response).expect("Failed to serialize response"), &mut OsRng);
+        if let Err(e) = socket.send_to(&data, to){
+            eprintln!("Failed to send datagram to {}: {}", to, e);
+        }
+    }
+
+    fn handle_datagram(state: &State, socket: &UdpSocket, request: Request, key: &Arc<DatagramKey>, from: SocketAddr)
+        -> Result<Response, ErrorCode>{
+        match request{
+            Request::Update(signed) if signed.update.action == Action::Listen => {
+                let name = signed.update.name.clone();
+                Self::apply(state, &mut state.directory.lock().unwrap(), signed, from.ip())?;
+                if !state.rendezvous.listen(&name, from, key.clone()){
+                    return Err(ErrorCode::RendezvousBusy);
+                }
+                Ok(Response::Listening)
+            }
+            Request::Rendezvous{ to } => {
+                // Datagrams are anonymous, so only names open to anyone can be punched to
+                if !state.directory.lock().unwrap().policy(&to).is_some_and(Policy::allows_anonymous){
+                    return Err(ErrorCode::NotListening);
+                }
+                let (endpoint, listener_key) = state.rendezvous.endpoint(&to).ok_or(ErrorCode::NotListening)?;
+                Self::send_datagram(socket, &listener_key, &Response::Rendezvous(from), endpoint);
+                Ok(Response::Rendezvous(endpoint))
+            }
+            _ => Err(ErrorCode::MalformedRequest),
+        }
+    }
+
+    fn handle_request(state: &State, request: Request, peer: SocketAddr, caller: Caller, viewer: Option<&IdentityPublic>)
+        -> Result<Response, ErrorCode>{
+        // Better another server than a half-copied directory
+        if state.replica.as_ref().is_some_and(|replica| !replica.is_synced()){
+            return Err(ErrorCode::Unavailable);
+        }
+        match request{
+            // Only meaningful over UDP, where the server sees the endpoint to punch towards
+            Request::Update(signed) if signed.update.action == Action::Listen => Err(ErrorCode::MalformedRequest),
+            Request::Rendezvous{ .. } => Err(ErrorCode::MalformedRequest),
+            Request::Update(signed) => Self::handle_update(state, signed, peer),
+            Request::Lookup{ name } => {
+                let federated = caller == Caller::Peer;
+                if !federated{
+                    state.limiter.lock().unwrap().check_lookup(peer.ip())?;
+                }
+                let name = match state.federation.local(&name)?{
+                    Some(local) => local.to_string(),
+                    // Peers ask only for names at our domain, so lookups can never go round in a loop
+                    None if federated => return Err(ErrorCode::UnknownDomain),
+                    None => return Ok(match state.federation.lookup(&name, &state.identity())?{
+                        Some(remote) => Response::Forwarded{ entry: remote.entry, proof: remote.proof, server: remote.server },
+                        None => {
+                            state.limiter.lock().unwrap().missed_lookup(peer.ip());
+                            Response::NotFound
+                        }
+                    }),
+                };
+                let identity = state.identity();
+                let directory = state.directory.lock().unwrap();
+                let entry = directory.lookup_as(&name, viewer);
+                let proof = state.log.lock().unwrap().prove(&name, &identity);
+                drop(directory);
+                Ok(match (entry, proof){
+                    (Some(entry), Some(proof)) => Response::Found{ entry, proof },
+                    // The directory and the log change together under the directory lock
+                    (Some(_), None) => return Err(ErrorCode::StorageFailure),
+                    (None, _) => {
+                        if !federated{
+                            state.limiter.lock().unwrap().missed_lookup(peer.ip());
+                        }
+                        Response::NotFound
+                    }
+                })
+            }
+            Request::FetchPrekeys{ name } => {
+                state.limiter.lock().unwrap().check_lookup(peer.ip())?;
+                let mut directory = state.directory.lock().unwrap();
+                let bundle = match directory.lookup_as(&name, viewer){
+                    Some(_) => directory.fetch_prekeys(&name),
+                    None => Err(ErrorCode::NotRegistered),
+                };
+                drop(directory);
+                if bundle.is_err(){
+                    state.limiter.lock().unwrap().missed_lookup(peer.ip());
+                }
+                bundle.map(Response::Prekeys)
+            }
+            Request::RedeemInvite(signed) => {
+                // Answers with an entry, so it costs what a lookup does
+                state.limiter.lock().unwrap().check_lookup(peer.ip())?;
+                signed.verify()?;
+                let invite = &signed.invite;
+                let identity = state.identity();
+                let directory = state.directory.lock().unwrap();
+                // A name handed on, suspended or hidden from the caller since voids its invites
+                let entry = directory.lookup_as(&invite.name, viewer)
+                    .filter(|entry| entry.identity == invite.identity)
+                    .ok_or(ErrorCode::InvalidInvite)?;
+                let proof = state.log.lock().unwrap().prove(&invite.name, &identity).ok_or(ErrorCode::StorageFailure)?;
+                state.invites.lock().unwrap().redeem(invite)?;
+                Ok(Response::InviteRedeemed{ entry, proof })
+            }
+            Request::TreeHead => {
+                let identity = state.identity();
+                Ok(Response::TreeHead(SignedTreeHead::sign(state.log.lock().unwrap().head(), &identity)))
+            }
+            Request::Consistency{ old, new } => state.log.lock().unwrap().consistency(old, new).map(Response::Consistency),
+            Request::LogEntries{ start, end } => state.log.lock().unwrap().entries(start, end).map(Response::LogEntries),
+            Request::KeyRotations => Ok(Response::KeyRotations(state.keys.lock().unwrap().rotations().to_vec())),
+            Request::Admin(request) if caller == Caller::Admin => Self::handle_admin(state, request),
+            Request::Admin(_) => Err(ErrorCode::NotAdmin),
+            Request::Replicate{ .. } if caller != Caller::Replica => Err(ErrorCode::NotReplica),
+            Request::Replicate{ epoch, position, log_size } => {
+                let journal = state.journal.as_ref().ok_or(ErrorCode::NotPrimary)?;
+                let size = state.log.lock().unwrap().size();
+                if log_size > size{
+                    return Err(ErrorCode::InvalidLogRange);
+                }
+                if log_size == size{
+                    journal.wait(epoch, position, REPLICATION_WAIT);
+                }
+                let directory = state.directory.lock().unwrap();
+                let batch = next_batch(journal, &state.log.lock().unwrap(), epoch, position, log_size);
+                drop(directory);
+                Ok(Response::Replication(batch))
+            }
+            Request::RegistrationWork => Ok(Response::RegistrationWork{ bits: state.limiter.lock().unwrap().registration_work() }),
+            Request::Join{ .. } | Request::Subscribe{ .. } => unreachable!("Handed over by serve_connection"),
+            Request::Deposit{ to, payload } => {
+                let directory = state.directory.lock().unwrap();
+                if directory.lookup_as(&to, viewer).is_none(){
+                    return Err(ErrorCode::NotRegistered);
+                }
+                let id = state.mailbox.lock().unwrap().deposit(&to, payload)?;
+                Ok(Response::Deposited{ id })
+            }
+        }
+    }
+
+    /// The names at our domain among `names`, if a client may watch them all.
+    fn check_subscription(state: &State, names: Vec<String>, peer: SocketAddr, viewer: Option<&IdentityPublic>)
+        -> Result<Vec<String>, ErrorCode>{
+        if state.replica.as_ref().is_some_and(|replica| !replica.is_synced()){
+            return Err(ErrorCode::Unavailable);
+        }
+        if names.is_empty() || names.len() > MAX_WATCHED{
+            return Err(ErrorCode::MalformedRequest);
+        }
+        let names = names.iter()
+            .map(|name| state.federation.local(name)?.map(str::to_string).ok_or(ErrorCode::UnknownDomain))
+            .collect::<Result<Vec<_>, _>>()?;
+        // Costs a lookup, and one more miss for each name nobody holds, so it is no way to walk the directory
+        state.limiter.lock().unwrap().check_lookup(peer.ip())?;
+        let directory = state.directory.lock().unwrap();
+        let misses = names.iter().filter(|name| directory.lookup_as(name, viewer).is_none()).count();
+        drop(directory);
+        let mut limiter = state.limiter.lock().unwrap();
+        for _ in 0..misses{
+            limiter.missed_lookup(peer.ip());
+        }
+        Ok(names)
+    }
+
+    fn handle_admin(state: &State, request: AdminRequest) -> Result<Response, ErrorCode>{
+        match request{
+            AdminRequest::List => Ok(Response::Registrations(state.directory.lock().unwrap().registrations())),
+            AdminRequest::Inspect{ name } => state.directory.lock().unwrap().registration(&name)
+                .map(Response::Registration)
+                .ok_or(ErrorCode::NotRegistered),
+            AdminRequest::Suspend{ name, suspended } => state.directory.lock().unwrap().suspend(&name, suspended).map(Response::Registration),
+            AdminRequest::Delete{ name } => {
+                let mut directory = state.directory.lock().unwrap();
+                directory.remove(&name)?;
+                state.log.lock().unwrap().record(&name, None)?;
+                state.mailbox.lock().unwrap().clear(&name)?;
+                Ok(Response::Deleted)
+            }
+            AdminRequest::RotateKey => {
+                let rotation = state.keys.lock().unwrap().rotate().map_err(|e| {
+                    eprintln!("Failed to rotate server key: {}", e);
+                    ErrorCode::StorageFailure
+                })?;
+                println!("Server key rotated to {}", rotation.new.to_hex());
+                Ok(Response::KeyRotated(rotation))
+            }
+        }
+    }
+
+    /// Applies a signed update once the limiter has let it through.
+    fn apply(state: &State, directory: &mut Directory, signed: SignedUpdate, ip: IpAddr) -> Result<Option<Entry>, ErrorCode>{
+        if !state.keys.lock().unwrap().recognizes(&signed.server){
+            return Err(ErrorCode::WrongServer);
+        }
+        let update = &signed.update;
+        let name = update.name.clone();
+        let current = directory.owner(&update.name);
+        let owner = match (&update.action, current){
+            (_, Some(owner)) => owner,
+            (Action::Register{ identity, .. }, None) => *identity,
+            // The directory turns this down without doing any work
+            (_, None) => return directory.apply(signed, ip),
+        };
+        // Checked ahead of the directory so that forgeries don't spend the owner's allowance
+        signed.verify(&owner)?;
+        let mut limiter = state.limiter.lock().unwrap();
+        limiter.check_key(&owner)?;
+        match (&update.action, current){
+            (Action::Register{ identity, work, .. }, None) => limiter.check_work(&update.name, identity, *work)?,
+            (Action::Transfer{ identity }, _) => limiter.check_recipient(identity)?,
+            _ => {}
+        }
+        drop(limiter);
+        let entry = directory.apply(signed, ip)?;
+        state.log.lock().unwrap().record(&name, entry.as_ref().map(|entry| entry.identity))?;
+        Ok(entry)
+    }
+
+    fn handle_update(state: &State, signed: SignedUpdate, peer: SocketAddr) -> Result<Response, ErrorCode>{
+        let mut directory = state.directory.lock().unwrap();
+        let (name, action) = (signed.update.name.clone(), signed.update.action.clone());
+        let entry = Self::apply(state, &mut directory, signed, peer.ip())?;
+        Ok(match (action, entry){
+            (Action::PublishPrekeys(_), _) => Response::PrekeysStored{ one_time: directory.one_time_prekeys(&name) },
+            (Action::FetchMail, _) => Response::Mail(state.mailbox.lock().unwrap().fetch(&name)),
+            (Action::Acknowledge{ ids }, _) => Response::Acknowledged{ removed: state.mailbox.lock().unwrap().acknowledge(&name, &ids)? },
+            (Action::Transfer{ .. }, Some(entry)) => {
+                // Mail for the old owner is unreadable to the new one
+                state.mailbox.lock().unwrap().clear(&name)?;
+                Response::Registered(entry)
+            }
+            (_, Some(entry)) => Response::Registered(entry),
+            (_, None) => {
+                state.mailbox.lock().unwrap().clear(&name)?;
+                Response::Deleted
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::NameServerClient;
+    use crate::limits::{BanList, Limits};
+    use crate::protocol::{check_registration_work, Presence, Update, MAX_FRAME_LEN};
+    use crate::transparency::TreeHead;
+    use chat_security::ManualClock;
+
+    fn spawn_server() -> (SocketAddr, IdentityPublic) {
+        let server = NameServer::bind("127.0.0.1:0").unwrap();
+        let (addr, key) = (server.local_addr().unwrap(), server.public_key());
+        thread::spawn(move || server.run());
+        (addr, key)
+    }
+
+    #[test]
+    fn test_register_then_lookup_from_another_client() {
+        let (addr, key) = spawn_server();
+        let alice = IdentityKey::generate();
+
+        let mut client = NameServerClient::connect(addr, &key).unwrap();
+        let entry = client.register("alice", "127.0.0.1:5000".parse().unwrap(), &alice).unwrap();
+        assert_eq!(entry.address, "127.0.0.1:5000".parse().unwrap());
+
+        let mut other = NameServerClient::connect(addr, &key).unwrap();
+        let found = other.lookup("alice").unwrap().unwrap();
+        assert_eq!(found.address, "127.0.0.1:5000".parse().unwrap());
+        assert_eq!(found.identity, alice.public());
+        assert!(other.lookup("bob").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_unspecified_address_uses_observed_ip() {
+        let (addr, key) = spawn_server();
+        let mut client = NameServerClient::connect(addr, &key).unwrap();
+        let entry = client.register("carol", "0.0.0.0:5001".parse().unwrap(), &IdentityKey::generate()).unwrap();
+        assert_eq!(entry.address, "127.0.0.1:5001".parse().unwrap());
+    }
+
+    #[test]
+    fn test_invalid_registration_reports_code() {
+        let (addr, key) = spawn_server();
+        let mut client = NameServerClient::connect(addr, &key).unwrap();
+        let err = client.register("not valid", "127.0.0.1:5002".parse().unwrap(), &IdentityKey::generate())
+            .unwrap_err();
+        assert_eq!(err.get_ref().unwrap().downcast_ref::<ErrorCode>(), Some(&ErrorCode::InvalidName));
+        // The connection stays usable after an application-level error
+        assert!(client.lookup("alice").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_only_owner_can_change_name() {
+        let (addr, key) = spawn_server();
+        let (alice, bob, mallory) = (IdentityKey::generate(), IdentityKey::generate(), IdentityKey::generate());
+        let mut client = NameServerClient::connect(addr, &key).unwrap();
+        client.register("alice", "127.0.0.1:5003".parse().unwrap(), &alice).unwrap();
+
+        let err = client.register("alice", "127.0.0.1:6666".parse().unwrap(), &mallory).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+        let err = client.delete("alice", &mallory).unwrap_err();
+        assert_eq!(err.get_ref().unwrap().downcast_ref::<ErrorCode>(), Some(&ErrorCode::BadSignature));
+
+        assert_eq!(client.transfer("alice", &alice, bob.public()).unwrap().identity, bob.public());
+        client.delete("alice", &bob).unwrap();
+        assert!(client.lookup("alice").unwrap().is_none());
+        assert_eq!(client.delete("alice", &bob).unwrap_err().kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_lookup_reports_presence() {
+        let clock = ManualClock::new(1_000);
+        let directory = Directory::with_clock(Arc::new(clock.clone()), Duration::from_secs(30));
+        let server = NameServer::bind_with("127.0.0.1:0", directory).unwrap();
+        let (addr, key) = (server.local_addr().unwrap(), server.public_key());
+        thread::spawn(move || server.run());
+
+        let alice = IdentityKey::generate();
+        let mut client = NameServerClient::connect(addr, &key).unwrap();
+        client.register("alice", "127.0.0.1:5004".parse().unwrap(), &alice).unwrap();
+        clock.advance(Duration::from_secs(20));
+        assert_eq!(client.heartbeat("alice", &alice).unwrap().presence, Presence::Online{ lease_expires: 1_050 });
+
+        clock.advance(Duration::from_secs(30));
+        let mut other = NameServerClient::connect(addr, &key).unwrap();
+        assert_eq!(other.lookup("alice").unwrap().unwrap().presence, Presence::Offline{ last_seen: 1_020 });
+        let err = client.heartbeat("alice", &alice).unwrap_err();
+        assert_eq!(err.get_ref().unwrap().downcast_ref::<ErrorCode>(), Some(&ErrorCode::LeaseExpired));
+    }
+
+    #[test]
+    fn test_mail_held_for_owner() {
+        let (addr, key) = spawn_server();
+        let (bob, mallory) = (IdentityKey::generate(), IdentityKey::generate());
+        let mut client = NameServerClient::connect(addr, &key).unwrap();
+        assert_eq!(client.deposit("bob", b"ciphertext".to_vec()).unwrap_err().kind(), ErrorKind::NotFound);
+        client.register("bob", "127.0.0.1:5005".parse().unwrap(), &bob).unwrap();
+        let id = client.deposit("bob", b"ciphertext".to_vec()).unwrap();
+
+        assert_eq!(client.fetch_mail("bob", &mallory).unwrap_err().kind(), ErrorKind::PermissionDenied);
+        assert_eq!(client.acknowledge("bob", &mallory, vec![id]).unwrap_err().kind(), ErrorKind::PermissionDenied);
+        let mail = client.fetch_mail("bob", &bob).unwrap();
+        assert_eq!(mail.len(), 1);
+        assert_eq!(mail[0].payload, b"ciphertext");
+        assert_eq!(client.acknowledge("bob", &bob, vec![id]).unwrap(), 1);
+        assert!(client.fetch_mail("bob", &bob).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_transfer_drops_mail() {
+        let (addr, key) = spawn_server();
+        let (alice, bob) = (IdentityKey::generate(), IdentityKey::generate());
+        let mut client = NameServerClient::connect(addr, &key).unwrap();
+        client.register("alice", "127.0.0.1:5006".parse().unwrap(), &alice).unwrap();
+        client.deposit("alice", b"for the old owner".to_vec()).unwrap();
+        client.transfer("alice", &alice, bob.public()).unwrap();
+        assert!(client.fetch_mail("alice", &bob).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_key_changes_are_logged() {
+        let (addr, key) = spawn_server();
+        let (alice, bob) = (IdentityKey::generate(), IdentityKey::generate());
+        let mut client = NameServerClient::connect(addr, &key).unwrap();
+        client.register("alice", "127.0.0.1:5014".parse().unwrap(), &alice).unwrap();
+        client.heartbeat("alice", &alice).unwrap();
+        assert_eq!(client.lookup("alice").unwrap().unwrap().identity, alice.public());
+        let before = client.tree_head().unwrap().head;
+
+        client.transfer("alice", &alice, bob.public()).unwrap();
+        client.delete("alice", &bob).unwrap();
+        client.register("alice", "127.0.0.1:5015".parse().unwrap(), &alice).unwrap();
+        assert_eq!(client.lookup("alice").unwrap().unwrap().identity, alice.public());
+        let after = client.fetch_tree_head().unwrap().head;
+        client.check_consistency(&before, &after).unwrap();
+
+        // Heartbeats don't change the binding, so only ownership changes are in the log
+        let identities = client.log_entries(0, after.size).unwrap().into_iter().map(|binding| binding.identity).collect::<Vec<_>>();
+        assert_eq!(identities, [Some(alice.public()), Some(bob.public()), None, Some(alice.public())]);
+        assert_eq!(client.log_entries(2, 1).unwrap_err().kind(), ErrorKind::InvalidInput);
+
+        // Another log of the same size isn't consistent with this one
+        let forged = TreeHead{ size: after.size, root: [0; 32] };
+        assert_eq!(client.check_consistency(&forged, &after).unwrap_err().kind(), ErrorKind::PermissionDenied);
+        assert_eq!(client.check_consistency(&before, &TreeHead{ size: after.size + 1, ..after }).unwrap_err().kind(),
+            ErrorKind::InvalidInput);
+    }
+
+    fn spawn_limited_server(limits: Limits) -> (SocketAddr, IdentityPublic, ManualClock) {
+        let clock = ManualClock::new(1_000);
+        let server = NameServer::bind("127.0.0.1:0").unwrap().limiter(Limiter::with_clock(limits, Arc::new(clock.clone())));
+        let (addr, key) = (server.local_addr().unwrap(), server.public_key());
+        thread::spawn(move || server.run());
+        (addr, key, clock)
+    }
+
+    #[test]
+    fn test_rate_limits_recover_with_time() {
+        let (addr, key, clock) = spawn_limited_server(Limits{ per_ip: Some(30), lookups: Some(10), ..Limits::default() });
+        let mut client = NameServerClient::connect(addr, &key).unwrap();
+        client.register("alice", "127.0.0.1:5007".parse().unwrap(), &IdentityKey::generate()).unwrap();
+        for _ in 0..10 {
+            client.lookup("alice").unwrap();
+        }
+        let err = client.lookup("alice").unwrap_err();
+        assert_eq!(err.get_ref().unwrap().downcast_ref::<ErrorCode>(), Some(&ErrorCode::RateLimited));
+        // Lookups have their own budget; other requests still go through
+        client.register("bob", "127.0.0.1:5008".parse().unwrap(), &IdentityKey::generate()).unwrap();
+
+        clock.advance(Duration::from_secs(6));
+        assert!(client.lookup("alice").unwrap().is_some());
+        // A miss costs more than the one token refilled since
+        clock.advance(Duration::from_secs(6));
+        assert!(client.lookup("nobody").unwrap().is_none());
+        clock.advance(Duration::from_secs(6));
+        assert_eq!(client.lookup("alice").unwrap_err().kind(), ErrorKind::QuotaExceeded);
+    }
+
+    #[test]
+    fn test_forged_updates_spend_nothing() {
+        let (addr, key, _clock) = spawn_limited_server(Limits{ per_key: Some(3), ..Limits::default() });
+        let (alice, mallory) = (IdentityKey::generate(), IdentityKey::generate());
+        let mut client = NameServerClient::connect(addr, &key).unwrap();
+        client.register("alice", "127.0.0.1:5009".parse().unwrap(), &alice).unwrap();
+        for _ in 0..5 {
+            assert_eq!(client.heartbeat("alice", &mallory).unwrap_err().kind(), ErrorKind::PermissionDenied);
+        }
+        client.heartbeat("alice", &alice).unwrap();
+        client.heartbeat("alice", &alice).unwrap();
+        assert_eq!(client.heartbeat("alice", &alice).unwrap_err().kind(), ErrorKind::QuotaExceeded);
+    }
+
+    #[test]
+    fn test_new_names_need_work() {
+        let (addr, key, _clock) = spawn_limited_server(Limits{ registration_work: 8, ..Limits::default() });
+        let alice = IdentityKey::generate();
+        let mut client = NameServerClient::connect(addr, &key).unwrap();
+        assert_eq!(client.registration_work().unwrap(), 8);
+
+        let unsolved = (0..).find(|work| !check_registration_work("alice", &alice.public(), *work, 8)).unwrap();
+        let update = Update{
+            name: "alice".to_string(),
+            action: Action::Register{ address: "127.0.0.1:5010".parse().unwrap(), identity: alice.public(), work: unsolved },
+            sequence: 1,
+        };
+        let err = client.call(&Request::Update(SignedUpdate::sign(update, &key, &alice))).unwrap_err();
+        assert_eq!(err.get_ref().unwrap().downcast_ref::<ErrorCode>(), Some(&ErrorCode::InsufficientWork));
+
+        // The client does the work when asked to, and renewals don't need any
+        client.register("alice", "127.0.0.1:5010".parse().unwrap(), &alice).unwrap();
+        let update = Update{
+            name: "alice".to_string(),
+            action: Action::Register{ address: "127.0.0.1:5011".parse().unwrap(), identity: alice.public(), work: unsolved },
+            sequence: u64::MAX,
+        };
+        assert!(matches!(client.call(&Request::Update(SignedUpdate::sign(update, &key, &alice))).unwrap(), Response::Registered(_)));
+    }
+
+    #[test]
+    fn test_updates_for_another_server_refused() {
+        let (addr, key, _clock) = spawn_limited_server(Limits::default());
+        let alice = IdentityKey::generate();
+        let mut client = NameServerClient::connect(addr, &key).unwrap();
+        client.register("alice", "127.0.0.1:5012".parse().unwrap(), &alice).unwrap();
+
+        // A delete alice signed for some other server can't be replayed here
+        let update = Update{ name: "alice".to_string(), action: Action::Delete, sequence: u64::MAX };
+        let elsewhere = SignedUpdate::sign(update, &IdentityKey::generate().public(), &alice);
+        let err = client.call(&Request::Update(elsewhere)).unwrap_err();
+        assert_eq!(err.get_ref().unwrap().downcast_ref::<ErrorCode>(), Some(&ErrorCode::WrongServer));
+        assert!(client.lookup("alice").unwrap().is_some());
+    }
+
+    #[test]
+    fn test_banned_ip_and_key() {
+        let banned = IdentityKey::generate();
+        let mut bans = BanList::new();
+        bans.ban_key(banned.public());
+        let (addr, key, _clock) = spawn_limited_server(Limits{ bans, ..Limits::default() });
+        let mut client = NameServerClient::connect(addr, &key).unwrap();
+        let err = client.register("spam", "127.0.0.1:5012".parse().unwrap(), &banned).unwrap_err();
+        assert_eq!(err.get_ref().unwrap().downcast_ref::<ErrorCode>(), Some(&ErrorCode::Banned));
+        let alice = IdentityKey::generate();
+        client.register("alice", "127.0.0.1:5013".parse().unwrap(), &alice).unwrap();
+        assert_eq!(client.transfer("alice", &alice, banned.public()).unwrap_err().kind(), ErrorKind::PermissionDenied);
+
+        let (addr, key, _clock) = spawn_limited_server(Limits{ bans: BanList::parse("127.0.0.0/8").unwrap(), ..Limits::default() });
+        // Banned addresses are hung up on before the handshake
+        let err = NameServerClient::connect(addr, &key).err().unwrap();
+        assert!(matches!(err.kind(), ErrorKind::UnexpectedEof | ErrorKind::ConnectionReset | ErrorKind::BrokenPipe), "{}", err);
+    }
+
+    #[test]
+    fn test_oversized_frame_gets_malformed_response() {
+        let (addr, _key) = spawn_server();
+        let stream = TcpStream::connect(addr).unwrap();
+        let mut session = SessionCryptData::start_authenticated_session(stream, &IdentityKey::generate(), "client").unwrap();
+        session.send_data(&vec![0; MAX_FRAME_LEN + 1]).unwrap();
+        let response: Response = read_frame(&mut session).unwrap();
+        assert_eq!(response, Response::Error(ErrorCode::MalformedRequest));
+    }
+
+    #[test]
+    fn test_server_key_is_checked() {
+        let server = NameServer::bind("127.0.0.1:0").unwrap().identity(IdentityKey::generate());
+        let (addr, key) = (server.local_addr().unwrap(), server.public_key());
+        thread::spawn(move || server.run());
+        let err = NameServerClient::connect(addr, &IdentityKey::generate().public()).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+        assert!(NameServerClient::connect(addr, &key).unwrap().lookup("alice").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_admin_manages_registrations() {
+        let admin = IdentityKey::generate();
+        let mut admins = AdminKeys::new();
+        admins.add(admin.public());
+        let server = NameServer::bind("127.0.0.1:0").unwrap().admins(admins);
+        let (addr, key) = (server.local_addr().unwrap(), server.public_key());
+        thread::spawn(move || server.run());
+
+        let (alice, bob) = (IdentityKey::generate(), IdentityKey::generate());
+        let mut client = NameServerClient::connect(addr, &key).unwrap();
+        client.register("alice", "127.0.0.1:5014".parse().unwrap(), &alice).unwrap();
+        client.register("bob", "127.0.0.1:5015".parse().unwrap(), &bob).unwrap();
+        let err = client.registrations().unwrap_err();
+        assert_eq!(err.get_ref().unwrap().downcast_ref::<ErrorCode>(), Some(&ErrorCode::NotAdmin));
+        let err = NameServerClient::connect_as_admin(addr, &key, &IdentityKey::generate()).unwrap().remove("bob").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+
+        let mut operator = NameServerClient::connect_as_admin(addr, &key, &admin).unwrap();
+        let names = operator.registrations().unwrap().into_iter().map(|registration| registration.entry.name).collect::<Vec<_>>();
+        assert_eq!(names, ["alice", "bob"]);
+        let registration = operator.registration("alice").unwrap();
+        assert_eq!((registration.entry.identity, registration.suspended), (alice.public(), false));
+        assert!(registration.entry.presence.is_online());
+
+        // Suspended names stay taken but can't be found or changed
+        assert!(operator.suspend("alice", true).unwrap().suspended);
+        assert!(client.lookup("alice").unwrap().is_none());
+        let err = client.register("alice", "127.0.0.1:5016".parse().unwrap(), &alice).unwrap_err();
+        assert_eq!(err.get_ref().unwrap().downcast_ref::<ErrorCode>(), Some(&ErrorCode::Suspended));
+        assert!(client.register("alice", "127.0.0.1:5016".parse().unwrap(), &bob).is_err());
+        operator.suspend("alice", false).unwrap();
+        assert_eq!(client.lookup("alice").unwrap().unwrap().identity, alice.public());
+
+        operator.remove("bob").unwrap();
+        assert!(client.lookup("bob").unwrap().is_none());
+        assert_eq!(operator.registration("bob").unwrap_err().kind(), ErrorKind::NotFound);
+        let head = client.fetch_tree_head().unwrap().head;
+        assert_eq!(client.log_entries(head.size - 1, head.size).unwrap()[0].identity, None);
+
+        // Clients that pinned the old key follow the rotation; proofs are under the new one
+        let rotation = operator.rotate_key().unwrap();
+        assert_eq!((rotation.old, operator.server_key()), (key, rotation.new));
+        let mut pinned_old = NameServerClient::connect(addr, &key).unwrap();
+        assert_eq!(pinned_old.server_key(), rotation.new);
+        assert_eq!(pinned_old.lookup("alice").unwrap().unwrap().identity, alice.public());
+        let err = NameServerClient::connect(addr, &IdentityKey::generate().public()).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+    }
+}
@@ -0,0 +1,248 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Error, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::directory::Record;
+
+/// One change to the directory, as persisted.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum LogRecord{
+    Put{ name: String, record: Box<Record>, sequence: u64 },
+    /// Deletion. The sequence is kept so old updates can't bring the name back.
+    Remove{ name: String, sequence: u64 },
+}
+
+/// Where server state (`R` is the kind of change recorded) is kept between restarts.
+pub trait Storage<R = LogRecord>: Send{
+    /// Everything stored so far, oldest first.
+    fn load(&mut self) -> Result<Vec<R>, io::Error>;
+
+    /// Durably records one change. Must not return until the record would survive a crash.
+    fn append(&mut self, record: &R) -> Result<(), io::Error>;
+
+    /// Replaces the stored history with `records`, which describe the same state in fewer entries.
+    fn compact(&mut self, records: &[R]) -> Result<(), io::Error>;
+}
+
+/*
+    Log format
+    A sequence of frames, each a 4 byte big-endian payload length, a 4 byte big-endian CRC-32 of the
+    payload, then the bincode payload. A crash can only leave a torn frame at the very end, which
+    `load` cuts off. Compaction writes a fresh file beside the log and renames it into place, so the
+    log on disk is always either the old or the new one in full.
+ */
+
+const FRAME_HEADER_LEN: usize = 8;
+
+fn encode_frame<R: Serialize>(record: &R) -> Result<Vec<u8>, io::Error>{
+    let payload = bincode::serialize(record).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+    let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+    frame.extend((payload.len() as u32).to_be_bytes());
+    frame.extend(crc32fast::hash(&payload).to_be_bytes());
+    frame.extend(payload);
+    Ok(frame)
+}
+
+/// Decodes frames from the start of `data`, stopping at the first damaged one. Returns the records and
+/// how many bytes they span.
+fn decode_frames<R: DeserializeOwned>(data: &[u8]) -> (Vec<R>, usize){
+    let mut records = Vec::new();
+    let mut offset = 0;
+    while data.len() - offset >= FRAME_HEADER_LEN{
+        let header = &data[offset..offset + FRAME_HEADER_LEN];
+        let length = u32::from_be_bytes(header[..4].try_into().unwrap()) as usize;
+        let checksum = u32::from_be_bytes(header[4..].try_into().unwrap());
+        let Some(payload) = data.get(offset + FRAME_HEADER_LEN..offset + FRAME_HEADER_LEN + length) else { break };
+        if crc32fast::hash(payload) != checksum{
+            break;
+        }
+        let Ok(record) = bincode::deserialize(payload) else { break };
+        records.push(record);
+        offset += FRAME_HEADER_LEN + length;
+    }
+    (records, offset)
+}
+
+/// Reads the intact records of a log that may be in use, ignoring a torn frame at its end.
+pub fn read_log<R: DeserializeOwned>(path: impl AsRef<Path>) -> Result<Vec<R>, io::Error>{
+    Ok(decode_frames(&fs::read(path)?).0)
+}
+
+/// Reads a complete log or snapshot, failing on any damage rather than skipping it.
+pub fn read_records<R: DeserializeOwned>(path: impl AsRef<Path>) -> Result<Vec<R>, io::Error>{
+    let data = fs::read(path)?;
+    let (records, used) = decode_frames(&data);
+    if used != data.len(){
+        return Err(Error::new(ErrorKind::InvalidData, format!("Damaged record at byte {}", used)));
+    }
+    Ok(records)
+}
+
+/// Replaces `path` with `records` so that a crash leaves either the old file or the new one.
+pub fn write_records<R: Serialize>(path: impl AsRef<Path>, records: &[R]) -> Result<(), io::Error>{
+    let path = path.as_ref();
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    let temp = PathBuf::from(temp);
+
+    let mut file = File::create(&temp)?;
+    for record in records{
+        file.write_all(&encode_frame(record)?)?;
+    }
+    file.sync_all()?;
+    fs::rename(&temp, path)?;
+    sync_parent(path)
+}
+
+fn sync_parent(path: &Path) -> Result<(), io::Error>{
+    // Makes the rename itself durable. Directories can't be opened as files on Windows, where
+    // renames are durable once they return anyway.
+    #[cfg(unix)]
+    {
+        let parent = match path.parent(){
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        File::open(parent)?.sync_all()?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+/// Append-only log file, holding records of any one type.
+pub struct LogStorage{
+    path: PathBuf,
+    file: File,
+}
+
+impl LogStorage{
+    /// Opens the log at `path`, creating an empty one if needed.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, io::Error>{
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new().read(true).append(true).create(true).open(&path)?;
+        Ok(LogStorage{ path, file })
+    }
+}
+
+impl<R: Serialize + DeserializeOwned> Storage<R> for LogStorage{
+    fn load(&mut self) -> Result<Vec<R>, io::Error>{
+        let data = fs::read(&self.path)?;
+        let (records, used) = decode_frames(&data);
+        if used != data.len(){
+            eprintln!("Discarding {} bytes of incomplete log at the end of {}", data.len() - used, self.path.display());
+            self.file.set_len(used as u64)?;
+            self.file.sync_all()?;
+        }
+        Ok(records)
+    }
+
+    fn append(&mut self, record: &R) -> Result<(), io::Error>{
+        self.file.write_all(&encode_frame(record)?)?;
+        self.file.sync_data()
+    }
+
+    fn compact(&mut self, records: &[R]) -> Result<(), io::Error>{
+        write_records(&self.path, records)?;
+        self.file = OpenOptions::new().read(true).append(true).open(&self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policy::Policy;
+    use crate::protocol::{Entry, Presence};
+    use chat_security::IdentityKey;
+
+    fn load(log: &mut LogStorage) -> Vec<LogRecord> {
+        Storage::<LogRecord>::load(log).unwrap()
+    }
+
+    fn temp_path(test: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("rustchat-names-{}-{}", test, std::process::id()))
+    }
+
+    fn put(name: &str, sequence: u64) -> LogRecord {
+        let entry = Entry{
+            name: name.to_string(),
+            address: "10.0.0.1:4000".parse().unwrap(),
+            identity: IdentityKey::generate().public(),
+            presence: Presence::Online{ lease_expires: 100 },
+        };
+        LogRecord::Put{ name: name.to_string(), record: Box::new(Record{ entry, lease_expires: 100, last_seen: 40, prekeys: None, suspended: false, policy: Policy::default(), policy_set: 40 }), sequence }
+    }
+
+    #[test]
+    fn test_log_survives_reopen() {
+        let path = temp_path("reopen");
+        let _ = fs::remove_file(&path);
+        let records = vec![put("alice", 1), put("bob", 2), LogRecord::Remove{ name: "alice".to_string(), sequence: 3 }];
+        let mut log = LogStorage::open(&path).unwrap();
+        for record in &records {
+            log.append(record).unwrap();
+        }
+        drop(log);
+
+        assert_eq!(load(&mut LogStorage::open(&path).unwrap()), records);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_torn_tail_is_discarded() {
+        let path = temp_path("torn");
+        let _ = fs::remove_file(&path);
+        let mut log = LogStorage::open(&path).unwrap();
+        log.append(&put("alice", 1)).unwrap();
+        log.append(&put("bob", 2)).unwrap();
+        drop(log);
+
+        // Simulate a crash part way through writing the second frame
+        let full = fs::read(&path).unwrap();
+        fs::write(&path, &full[..full.len() - 3]).unwrap();
+
+        let mut log = LogStorage::open(&path).unwrap();
+        let records = load(&mut log);
+        assert_eq!(records.len(), 1);
+        // Appends after recovery land after the last good frame
+        log.append(&put("carol", 3)).unwrap();
+        assert_eq!(load(&mut LogStorage::open(&path).unwrap()).len(), 2);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_corrupt_frame_detected() {
+        let path = temp_path("corrupt");
+        write_records(&path, &[put("alice", 1)]).unwrap();
+        let mut data = fs::read(&path).unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 0xff;
+        fs::write(&path, &data).unwrap();
+
+        assert_eq!(read_records::<LogRecord>(&path).unwrap_err().kind(), ErrorKind::InvalidData);
+        assert!(load(&mut LogStorage::open(&path).unwrap()).is_empty());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_compact_replaces_history() {
+        let path = temp_path("compact");
+        let _ = fs::remove_file(&path);
+        let mut log = LogStorage::open(&path).unwrap();
+        for sequence in 1..10 {
+            log.append(&put("alice", sequence)).unwrap();
+        }
+        log.compact(&[put("alice", 9)]).unwrap();
+        log.append(&put("bob", 10)).unwrap();
+        drop(log);
+
+        let records = load(&mut LogStorage::open(&path).unwrap());
+        assert_eq!(records.len(), 2);
+        assert!(!path.with_extension("tmp").exists());
+        fs::remove_file(&path).unwrap();
+    }
+}
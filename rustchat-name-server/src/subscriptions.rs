@@ -0,0 +1,331 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chat_security::{Clock, SessionCryptData};
+use serde::{Deserialize, Serialize};
+
+use crate::protocol::{write_frame, Entry, ErrorCode, Presence, Response};
+
+/*
+    Subscriptions
+    Rather than polling its contacts, a client can hold a connection open that the server pushes their
+    changes down: coming online or going offline, moving to a new address, handing the name to a new
+    key, or dropping it. The directory tells the subscriptions about every name it changes; each
+    subscription then reads its names again and sends what differs from what it last sent. Leases run
+    out without any change being recorded, so subscriptions also wake when the soonest one they watch
+    is due.
+
+    Events are hints, not proof. A client that is told of a new key looks the name up again, checking
+    the server's key log, before it believes it.
+ */
+
+/// Most names one subscription can watch, which keeps a full set of events within a frame.
+pub const MAX_WATCHED: usize = 256;
+/// Subscriptions held at once. Each costs a socket and a thread, like a parked connection.
+pub const MAX_SUBSCRIPTIONS: usize = 1024;
+/// An empty `Events` goes out this often on a quiet subscription, so both sides notice a dead one.
+pub const SUBSCRIPTION_PING: Duration = Duration::from_secs(30);
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum Event{
+    /// The name registered, or renewed a lease that had run out.
+    Online(Entry),
+    /// The name's lease ran out.
+    Offline(Entry),
+    /// The name registered again from another address while online.
+    Moved(Entry),
+    /// The name was transferred to another identity key.
+    KeyChanged(Entry),
+    /// The name was deleted, or suspended by an admin.
+    Removed{ name: String },
+}
+
+impl Event{
+    pub fn name(&self) -> &str{
+        match self{
+            Event::Online(entry) | Event::Offline(entry) | Event::Moved(entry) | Event::KeyChanged(entry) => &entry.name,
+            Event::Removed{ name } => name,
+        }
+    }
+
+    /// The name's entry after the change, or `None` once it is gone.
+    pub fn entry(&self) -> Option<&Entry>{
+        match self{
+            Event::Online(entry) | Event::Offline(entry) | Event::Moved(entry) | Event::KeyChanged(entry) => Some(entry),
+            Event::Removed{ .. } => None,
+        }
+    }
+}
+
+/// What changed between `old` and `new`, two reads of one name.
+pub fn changes(name: &str, old: Option<&Entry>, new: Option<&Entry>) -> Vec<Event>{
+    let Some(new) = new else {
+        return match old{
+            Some(_) => vec![Event::Removed{ name: name.to_string() }],
+            None => Vec::new(),
+        };
+    };
+    let mut events = Vec::new();
+    if old.is_some_and(|old| old.identity != new.identity){
+        events.push(Event::KeyChanged(new.clone()));
+    }
+    match (old.map(|old| old.presence.is_online()), new.presence.is_online()){
+        (Some(false) | None, true) => events.push(Event::Online(new.clone())),
+        (Some(true), false) => events.push(Event::Offline(new.clone())),
+        (Some(true), true) if old.is_some_and(|old| old.address != new.address) => events.push(Event::Moved(new.clone())),
+        _ => {}
+    }
+    events
+}
+
+#[derive(Default)]
+struct Watchers{
+    next_id: u64,
+    /// Subscriptions by the names they watch.
+    names: HashMap<String, HashMap<u64, Sender<String>>>,
+    count: usize,
+}
+
+/// Every open subscription, for the directory to tell about the names it changes.
+#[derive(Default)]
+pub struct Subscriptions{
+    watchers: Mutex<Watchers>,
+}
+
+impl Subscriptions{
+    pub fn new() -> Self{
+        Self::default()
+    }
+
+    /// Tells the subscriptions watching `name` that it changed.
+    pub fn notify(&self, name: &str){
+        let watchers = self.watchers.lock().unwrap();
+        for sender in watchers.names.get(name).into_iter().flat_map(|senders| senders.values()){
+            let _ = sender.send(name.to_string());
+        }
+    }
+
+    /// Hangs up on every subscription.
+    pub fn clear(&self){
+        let mut watchers = self.watchers.lock().unwrap();
+        watchers.names.clear();
+        watchers.count = 0;
+    }
+
+    /// Starts watching `names`, or fails with `SubscriptionsBusy` if there are too many subscriptions.
+    fn watch(self: &Arc<Self>, names: &[String]) -> Result<Watch, ErrorCode>{
+        let mut watchers = self.watchers.lock().unwrap();
+        if watchers.count >= MAX_SUBSCRIPTIONS{
+            return Err(ErrorCode::SubscriptionsBusy);
+        }
+        let (sender, changed) = mpsc::channel();
+        let id = watchers.next_id;
+        watchers.next_id += 1;
+        watchers.count += 1;
+        for name in names{
+            watchers.names.entry(name.clone()).or_default().insert(id, sender.clone());
+        }
+        Ok(Watch{ subscriptions: self.clone(), id, names: names.to_vec(), changed })
+    }
+
+    /// Answers `Subscribed` on `session` with the current entries for `names`, returning the response sent
+    /// and, unless refused, the subscription to run. `lookup` reads a name as clients would see it.
+    pub(crate) fn subscribe(self: &Arc<Self>, names: Vec<String>, mut session: SessionCryptData,
+        lookup: impl Fn(&str) -> Option<Entry>) -> Result<(Response, Option<Subscription>), io::Error>{
+        let watch = match self.watch(&names){
+            Ok(watch) => watch,
+            Err(code) => {
+                let response = Response::Error(code);
+                write_frame(&mut session, &response)?;
+                return Ok((response, None));
+            }
+        };
+        // Read after watching, so that nothing changes unseen in between
+        let sent: HashMap<String, Entry> = names.iter().filter_map(|name| Some((name.clone(), lookup(name)?))).collect();
+        let response = Response::Subscribed(sent.values().cloned().collect());
+        write_frame(&mut session, &response)?;
+        Ok((response, Some(Subscription{ names, session, watch, sent })))
+    }
+}
+
+/// An accepted subscription, not yet pushing changes.
+pub(crate) struct Subscription{
+    names: Vec<String>,
+    session: SessionCryptData,
+    watch: Watch,
+    sent: HashMap<String, Entry>,
+}
+
+impl Subscription{
+    /// Pushes changes to the subscribed names until the client hangs up. `clock` is the one their leases
+    /// run by.
+    pub(crate) fn run(self, clock: &dyn Clock, lookup: impl Fn(&str) -> Option<Entry>) -> Result<(), io::Error>{
+        let Subscription{ names, mut session, watch, mut sent } = self;
+        let mut last_write = Instant::now();
+        loop{
+            let now = clock.unix_time();
+            let next_expiry = sent.values().filter_map(|entry| match entry.presence{
+                Presence::Online{ lease_expires } => Some(Duration::from_secs(lease_expires.saturating_sub(now).max(1))),
+                Presence::Offline{ .. } => None,
+            }).min();
+            let ping = SUBSCRIPTION_PING.saturating_sub(last_write.elapsed());
+            let wait = next_expiry.map_or(ping, |expiry| expiry.min(ping));
+            let mut changed = match watch.changed.recv_timeout(wait){
+                Ok(name) => vec![name],
+                // Nothing changed, but a lease may have run out
+                Err(RecvTimeoutError::Timeout) => names.clone(),
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            };
+            changed.extend(watch.changed.try_iter());
+            changed.sort();
+            changed.dedup();
+            let mut events = Vec::new();
+            for name in changed{
+                let entry = lookup(&name);
+                events.extend(changes(&name, sent.get(&name), entry.as_ref()));
+                match entry{
+                    Some(entry) => sent.insert(name, entry),
+                    None => sent.remove(&name),
+                };
+            }
+            if !events.is_empty() || last_write.elapsed() >= SUBSCRIPTION_PING{
+                write_frame(&mut session, &Response::Events(events))?;
+                last_write = Instant::now();
+            }
+        }
+    }
+}
+
+/// One subscription's place among the watchers, given up when dropped.
+struct Watch{
+    subscriptions: Arc<Subscriptions>,
+    id: u64,
+    names: Vec<String>,
+    changed: Receiver<String>,
+}
+
+impl Drop for Watch{
+    fn drop(&mut self){
+        let mut watchers = self.subscriptions.watchers.lock().unwrap();
+        let mut removed = false;
+        for name in &self.names{
+            if let Some(senders) = watchers.names.get_mut(name){
+                removed |= senders.remove(&self.id).is_some();
+                if senders.is_empty(){
+                    watchers.names.remove(name);
+                }
+            }
+        }
+        // Already gone if the subscriptions were cleared
+        if removed{
+            watchers.count -= 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::NameServerClient;
+    use crate::directory::Directory;
+    use crate::server::NameServer;
+    use chat_security::{IdentityKey, ManualClock};
+    use crate::protocol::read_frame;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    fn entry(address: &str, identity: &IdentityKey, online: bool) -> Entry {
+        let presence = if online { Presence::Online{ lease_expires: 2_000 } } else { Presence::Offline{ last_seen: 1_000 } };
+        Entry{ name: "alice".to_string(), address: address.parse().unwrap(), identity: identity.public(), presence }
+    }
+
+    #[test]
+    fn test_changes() {
+        let (alice, mallory) = (IdentityKey::generate(), IdentityKey::generate());
+        let online = entry("127.0.0.1:5000", &alice, true);
+        assert_eq!(changes("alice", None, Some(&online)), vec![Event::Online(online.clone())]);
+        assert_eq!(changes("alice", Some(&online), Some(&online)), vec![]);
+        let moved = entry("127.0.0.1:5001", &alice, true);
+        assert_eq!(changes("alice", Some(&online), Some(&moved)), vec![Event::Moved(moved.clone())]);
+        let offline = entry("127.0.0.1:5001", &alice, false);
+        assert_eq!(changes("alice", Some(&moved), Some(&offline)), vec![Event::Offline(offline.clone())]);
+        // Coming back from somewhere else is just coming online
+        assert_eq!(changes("alice", Some(&offline), Some(&online)), vec![Event::Online(online.clone())]);
+        let taken = entry("127.0.0.1:5000", &mallory, true);
+        assert_eq!(changes("alice", Some(&online), Some(&taken)), vec![Event::KeyChanged(taken.clone())]);
+        assert_eq!(changes("alice", Some(&taken), None), vec![Event::Removed{ name: "alice".to_string() }]);
+        assert_eq!(changes("alice", None, None), vec![]);
+    }
+
+    #[test]
+    fn test_changes_are_pushed() {
+        let server = NameServer::bind("127.0.0.1:0").unwrap();
+        let (addr, key) = (server.local_addr().unwrap(), server.public_key());
+        thread::spawn(move || server.run());
+        let (alice, mallory) = (IdentityKey::generate(), IdentityKey::generate());
+        let mut client = NameServerClient::connect(addr, &key).unwrap();
+        client.register("alice", "127.0.0.1:5500".parse().unwrap(), &alice).unwrap();
+
+        let names = ["alice".to_string(), "bob".to_string()];
+        let (entries, mut subscription) = NameServerClient::connect(addr, &key).unwrap().subscribe(&names).unwrap();
+        assert_eq!(entries.iter().map(|entry| entry.name.as_str()).collect::<Vec<_>>(), ["alice"]);
+        let moved = client.register("alice", "127.0.0.1:5501".parse().unwrap(), &alice).unwrap();
+        assert_eq!(subscription.wait().unwrap(), vec![Event::Moved(moved)]);
+        // Renewals change nothing a contact cares about
+        client.heartbeat("alice", &alice).unwrap();
+        let bob = client.register("bob", "127.0.0.1:5502".parse().unwrap(), &IdentityKey::generate()).unwrap();
+        assert_eq!(subscription.wait().unwrap(), vec![Event::Online(bob)]);
+        let taken = client.transfer("alice", &alice, mallory.public()).unwrap();
+        assert_eq!(subscription.wait().unwrap(), vec![Event::KeyChanged(taken)]);
+        client.delete("alice", &mallory).unwrap();
+        assert_eq!(subscription.wait().unwrap(), vec![Event::Removed{ name: "alice".to_string() }]);
+
+        let too_many: Vec<String> = (0..=MAX_WATCHED).map(|i| format!("user{}", i)).collect();
+        let err = NameServerClient::connect(addr, &key).unwrap().subscribe(&too_many).err().unwrap();
+        assert_eq!(err.get_ref().unwrap().downcast_ref::<ErrorCode>(), Some(&ErrorCode::MalformedRequest));
+    }
+
+    #[test]
+    fn test_busy_subscribe_reports_refusal() {
+        let subscriptions = Arc::new(Subscriptions::new());
+        let _watches: Vec<Watch> = (0..MAX_SUBSCRIPTIONS).map(|_| subscriptions.watch(&["alice".to_string()]).unwrap()).collect();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut session = SessionCryptData::start_session(TcpStream::connect(addr).unwrap()).unwrap();
+            read_frame::<_, Response>(&mut session).unwrap()
+        });
+        let session = SessionCryptData::recieve_session(listener.accept().unwrap().0).unwrap();
+
+        // The server records the refusal it sent, not a subscription
+        let (response, subscription) = subscriptions.subscribe(vec!["alice".to_string()], session, |_| None).unwrap();
+        assert_eq!(response, Response::Error(ErrorCode::SubscriptionsBusy));
+        assert!(subscription.is_none());
+        assert_eq!(client.join().unwrap(), response);
+    }
+
+    #[test]
+    fn test_lapsed_lease_is_pushed() {
+        // Far from the system clock, so that only the directory's can tell when the lease runs out
+        let clock = ManualClock::new(4_000_000_000);
+        let directory = Directory::with_clock(Arc::new(clock.clone()), Duration::from_secs(60));
+        let server = NameServer::bind_with("127.0.0.1:0", directory).unwrap();
+        let (addr, key) = (server.local_addr().unwrap(), server.public_key());
+        thread::spawn(move || server.run());
+        let mut client = NameServerClient::connect(addr, &key).unwrap();
+        client.register("alice", "127.0.0.1:5504".parse().unwrap(), &IdentityKey::generate()).unwrap();
+        clock.advance(Duration::from_secs(59));
+        let (entries, mut subscription) = NameServerClient::connect(addr, &key).unwrap().subscribe(&["alice".to_string()]).unwrap();
+        assert!(entries[0].presence.is_online());
+        clock.advance(Duration::from_secs(2));
+        let started = Instant::now();
+        match subscription.wait().unwrap().as_slice() {
+            [Event::Offline(entry)] => assert_eq!(entry.name, "alice"),
+            events => panic!("expected alice to go offline, got {:?}", events),
+        }
+        assert!(started.elapsed() < SUBSCRIPTION_PING / 2);
+    }
+}
@@ -0,0 +1,443 @@
+use std::collections::HashMap;
+use std::io::{self, Error, ErrorKind};
+
+use chat_security::{IdentityKey, IdentityPublic};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::protocol::ErrorCode;
+use crate::storage::Storage;
+
+/*
+    Key transparency
+    Every change to which key owns a name is appended to a Merkle tree (as in RFC 6962) that only
+    ever grows. Lookups come with a proof that the returned binding is the latest one in the tree
+    and a tree head signed by the server, so the server can't hand a client a key it hasn't
+    committed to publicly. Clients pass the heads they have seen on to their peers and check that
+    any two heads are consistent, meaning the smaller tree is a prefix of the larger one; a server
+    showing different people different directories has to sign two heads that aren't. Auditors
+    download the whole log and check that it matches the heads.
+
+    The tree is kept as one level of hashes per height, where each level holds the roots of the
+    complete, aligned subtrees of that size. Every left child on the way down from any root is such
+    a subtree, so roots and proofs take O(log n) hashes.
+ */
+
+/// Most log entries handed out per request, so replies stay well within a frame.
+pub const MAX_LOG_ENTRIES: u64 = 256;
+
+pub type Hash = [u8; 32];
+
+/// One change to who owns a name. `identity` is `None` once the name is deleted.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Binding{
+    pub name: String,
+    pub identity: Option<IdentityPublic>,
+}
+
+impl Binding{
+    pub fn leaf_hash(&self) -> Hash{
+        let mut hasher = Sha256::new();
+        hasher.update([0u8]);
+        hasher.update(bincode::serialize(self).expect("Failed to serialize binding"));
+        hasher.finalize().into()
+    }
+}
+
+fn node_hash(left: &Hash, right: &Hash) -> Hash{
+    let mut hasher = Sha256::new();
+    hasher.update([1u8]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+/// Largest power of two below `n`, for `n` of at least 2.
+fn split(n: u64) -> u64{
+    1 << (63 - (n - 1).leading_zeros())
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TreeHead{
+    pub size: u64,
+    pub root: Hash,
+}
+
+impl TreeHead{
+    fn signed_bytes(&self) -> Vec<u8>{
+        let mut data = b"rustchat tree head".to_vec();
+        data.extend(self.size.to_be_bytes());
+        data.extend(self.root);
+        data
+    }
+}
+
+/// A `TreeHead` signed by the name server's identity key.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct SignedTreeHead{
+    pub head: TreeHead,
+    pub signature: Vec<u8>,
+}
+
+impl SignedTreeHead{
+    pub fn sign(head: TreeHead, identity: &IdentityKey) -> Self{
+        SignedTreeHead{ head, signature: identity.sign(&head.signed_bytes()).to_vec() }
+    }
+
+    pub fn verify(&self, server_key: &IdentityPublic) -> Result<(), io::Error>{
+        let signature: &[u8; 64] = self.signature.as_slice().try_into()
+            .map_err(|_| Error::new(ErrorKind::PermissionDenied, "Malformed tree head signature"))?;
+        server_key.verify(&self.head.signed_bytes(), signature)
+    }
+}
+
+/// Proof that a name's latest binding is in the tree under `head`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct InclusionProof{
+    pub index: u64,
+    pub path: Vec<Hash>,
+    pub head: SignedTreeHead,
+}
+
+impl InclusionProof{
+    /// Checks that `binding` is in the tree and that the server signed the head.
+    pub fn verify(&self, binding: &Binding, server_key: &IdentityPublic) -> Result<(), io::Error>{
+        self.head.verify(server_key)?;
+        if !verify_inclusion(&binding.leaf_hash(), self.index, &self.path, &self.head.head){
+            return Err(Error::new(ErrorKind::PermissionDenied,
+                format!("Name server's key log does not include the binding for {}", binding.name)));
+        }
+        Ok(())
+    }
+}
+
+/// Checks an RFC 9162 inclusion proof for the leaf at `index`.
+pub fn verify_inclusion(leaf: &Hash, index: u64, path: &[Hash], head: &TreeHead) -> bool{
+    if index >= head.size{
+        return false;
+    }
+    let (mut fn_, mut sn, mut hash) = (index, head.size - 1, *leaf);
+    for sibling in path{
+        if sn == 0{
+            return false;
+        }
+        if fn_ & 1 == 1 || fn_ == sn{
+            hash = node_hash(sibling, &hash);
+            while fn_ & 1 == 0 && fn_ != 0{
+                fn_ >>= 1;
+                sn >>= 1;
+            }
+        }
+        else{
+            hash = node_hash(&hash, sibling);
+        }
+        fn_ >>= 1;
+        sn >>= 1;
+    }
+    sn == 0 && hash == head.root
+}
+
+/// Checks an RFC 9162 consistency proof that `old` is a prefix of `new`.
+pub fn verify_consistency(old: &TreeHead, new: &TreeHead, proof: &[Hash]) -> bool{
+    if old.size > new.size{
+        return false;
+    }
+    if old.size == new.size{
+        return proof.is_empty() && old.root == new.root;
+    }
+    if old.size == 0{
+        return proof.is_empty();
+    }
+    let mut path = proof.to_vec();
+    if old.size.is_power_of_two(){
+        path.insert(0, old.root);
+    }
+    let Some((first, rest)) = path.split_first() else { return false };
+    let (mut fn_, mut sn) = (old.size - 1, new.size - 1);
+    while fn_ & 1 == 1{
+        fn_ >>= 1;
+        sn >>= 1;
+    }
+    let (mut old_hash, mut new_hash) = (*first, *first);
+    for hash in rest{
+        if sn == 0{
+            return false;
+        }
+        if fn_ & 1 == 1 || fn_ == sn{
+            old_hash = node_hash(hash, &old_hash);
+            new_hash = node_hash(hash, &new_hash);
+            while fn_ & 1 == 0 && fn_ != 0{
+                fn_ >>= 1;
+                sn >>= 1;
+            }
+        }
+        else{
+            new_hash = node_hash(&new_hash, hash);
+        }
+        fn_ >>= 1;
+        sn >>= 1;
+    }
+    sn == 0 && old_hash == old.root && new_hash == new.root
+}
+
+/// Append-only log of name bindings, optionally backed by `Storage`.
+#[derive(Default)]
+pub struct TransparencyLog{
+    bindings: Vec<Binding>,
+    /// `levels[k][i]` is the root of the complete subtree over leaves `i * 2^k .. (i + 1) * 2^k`.
+    levels: Vec<Vec<Hash>>,
+    /// Index of each name's latest binding.
+    latest: HashMap<String, u64>,
+    storage: Option<Box<dyn Storage<Binding>>>,
+}
+
+impl TransparencyLog{
+    pub fn new() -> Self{
+        Self::default()
+    }
+
+    /// Recovers the log kept in `storage` and persists every later binding there. Never compacted, as
+    /// every entry is part of the tree.
+    pub fn with_storage(mut self, mut storage: Box<dyn Storage<Binding>>) -> Result<Self, io::Error>{
+        for binding in storage.load()?{
+            self.push(binding);
+        }
+        self.storage = Some(storage);
+        Ok(self)
+    }
+
+    /// Rebuilds the log from `bindings`, e.g. ones an auditor downloaded, without attaching any storage.
+    pub fn from_bindings(bindings: impl IntoIterator<Item = Binding>) -> Self{
+        let mut log = Self::default();
+        for binding in bindings{
+            log.push(binding);
+        }
+        log
+    }
+
+    fn push(&mut self, binding: Binding){
+        let index = self.bindings.len() as u64;
+        let mut hash = binding.leaf_hash();
+        self.latest.insert(binding.name.clone(), index);
+        self.bindings.push(binding);
+        // Completing a subtree completes its parent whenever it is a right child
+        let mut level = 0;
+        loop{
+            if self.levels.len() == level{
+                self.levels.push(Vec::new());
+            }
+            self.levels[level].push(hash);
+            let len = self.levels[level].len();
+            if len % 2 == 1{
+                break;
+            }
+            hash = node_hash(&self.levels[level][len - 2], &self.levels[level][len - 1]);
+            level += 1;
+        }
+    }
+
+    /// Records that `name` now belongs to `identity`, if that's news to the log.
+    pub fn record(&mut self, name: &str, identity: Option<IdentityPublic>) -> Result<(), ErrorCode>{
+        if self.binding(name).and_then(|binding| binding.identity) == identity{
+            return Ok(());
+        }
+        self.append(Binding{ name: name.to_string(), identity })
+    }
+
+    /// Appends `binding` whether or not it changes anything, as when copying another log.
+    pub fn append(&mut self, binding: Binding) -> Result<(), ErrorCode>{
+        if let Some(storage) = &mut self.storage{
+            storage.append(&binding).map_err(|e| {
+                eprintln!("Failed to persist key log: {}", e);
+                ErrorCode::StorageFailure
+            })?;
+        }
+        self.push(binding);
+        Ok(())
+    }
+
+    pub fn bindings(&self) -> &[Binding]{
+        &self.bindings
+    }
+
+    /// The latest binding for `name`, if it has ever had one.
+    pub fn binding(&self, name: &str) -> Option<&Binding>{
+        self.latest.get(name).map(|index| &self.bindings[*index as usize])
+    }
+
+    pub fn size(&self) -> u64{
+        self.bindings.len() as u64
+    }
+
+    /// Root of the tree over leaves `start..end`.
+    fn subtree(&self, start: u64, end: u64) -> Hash{
+        let n = end - start;
+        if n == 0{
+            return Sha256::digest([]).into();
+        }
+        if n.is_power_of_two() && start.is_multiple_of(n){
+            return self.levels[n.trailing_zeros() as usize][(start / n) as usize];
+        }
+        let k = split(n);
+        node_hash(&self.subtree(start, start + k), &self.subtree(start + k, end))
+    }
+
+    pub fn head(&self) -> TreeHead{
+        TreeHead{ size: self.size(), root: self.subtree(0, self.size()) }
+    }
+
+    fn inclusion_path(&self, index: u64, start: u64, end: u64) -> Vec<Hash>{
+        if end - start == 1{
+            return Vec::new();
+        }
+        let k = split(end - start);
+        if index < start + k{
+            let mut path = self.inclusion_path(index, start, start + k);
+            path.push(self.subtree(start + k, end));
+            path
+        }
+        else{
+            let mut path = self.inclusion_path(index, start + k, end);
+            path.push(self.subtree(start, start + k));
+            path
+        }
+    }
+
+    /// Proves `name`'s latest binding is in the current tree, whose head is signed with `identity`.
+    pub fn prove(&self, name: &str, identity: &IdentityKey) -> Option<InclusionProof>{
+        let index = *self.latest.get(name)?;
+        Some(InclusionProof{
+            index,
+            path: self.inclusion_path(index, 0, self.size()),
+            head: SignedTreeHead::sign(self.head(), identity),
+        })
+    }
+
+    fn subproof(&self, m: u64, start: u64, end: u64, complete: bool) -> Vec<Hash>{
+        if m == end - start{
+            return if complete { Vec::new() } else { vec![self.subtree(start, end)] };
+        }
+        let k = split(end - start);
+        if m <= k{
+            let mut proof = self.subproof(m, start, start + k, complete);
+            proof.push(self.subtree(start + k, end));
+            proof
+        }
+        else{
+            let mut proof = self.subproof(m - k, start + k, end, false);
+            proof.push(self.subtree(start, start + k));
+            proof
+        }
+    }
+
+    /// Proves the tree of size `old` is a prefix of the tree of size `new`.
+    pub fn consistency(&self, old: u64, new: u64) -> Result<Vec<Hash>, ErrorCode>{
+        if old > new || new > self.size(){
+            return Err(ErrorCode::InvalidLogRange);
+        }
+        if old == 0 || old == new{
+            return Ok(Vec::new());
+        }
+        Ok(self.subproof(old, 0, new, true))
+    }
+
+    /// Bindings `start..end`, cut short at `MAX_LOG_ENTRIES`.
+    pub fn entries(&self, start: u64, end: u64) -> Result<Vec<Binding>, ErrorCode>{
+        if start > end || end > self.size(){
+            return Err(ErrorCode::InvalidLogRange);
+        }
+        let end = end.min(start + MAX_LOG_ENTRIES);
+        Ok(self.bindings[start as usize..end as usize].to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::LogStorage;
+
+    fn log_of(size: usize, keys: &[IdentityPublic]) -> TransparencyLog {
+        let mut log = TransparencyLog::new();
+        for i in 0..size {
+            log.record(&format!("user{}", i), Some(keys[i % keys.len()])).unwrap();
+        }
+        log
+    }
+
+    #[test]
+    fn test_inclusion_proofs_verify() {
+        let keys = [IdentityKey::generate().public(), IdentityKey::generate().public()];
+        for size in 1..=20 {
+            let log = log_of(size, &keys);
+            let head = log.head();
+            for i in 0..size as u64 {
+                let path = log.inclusion_path(i, 0, size as u64);
+                let binding = &log.bindings[i as usize];
+                assert!(verify_inclusion(&binding.leaf_hash(), i, &path, &head), "size {} index {}", size, i);
+                let forged = Binding{ identity: Some(keys[(i as usize + 1) % 2]), ..binding.clone() };
+                assert!(!verify_inclusion(&forged.leaf_hash(), i, &path, &head));
+            }
+        }
+
+        // Proofs only count under a head the server signed
+        let server = IdentityKey::generate();
+        let log = log_of(5, &keys);
+        let proof = log.prove("user3", &server).unwrap();
+        let binding = log.binding("user3").unwrap();
+        assert!(proof.verify(binding, &server.public()).is_ok());
+        assert!(proof.verify(binding, &keys[0]).is_err());
+        assert!(log.prove("nobody", &server).is_none());
+    }
+
+    #[test]
+    fn test_consistency_proofs_verify() {
+        let keys = [IdentityKey::generate().public()];
+        let log = log_of(20, &keys);
+        let heads = (0..=20).map(|size| log_of(size, &keys).head()).collect::<Vec<_>>();
+        for old in 0..=20 {
+            for new in old..=20 {
+                let proof = log.consistency(old as u64, new as u64).unwrap();
+                assert!(verify_consistency(&heads[old], &heads[new], &proof), "{} -> {}", old, new);
+            }
+        }
+
+        // A tree that rewrote history isn't consistent with the original
+        let mut rewritten = log_of(6, &keys);
+        rewritten.record("user2", Some(IdentityKey::generate().public())).unwrap();
+        let proof = rewritten.consistency(5, 7).unwrap();
+        let forked = TransparencyLog::from_bindings(rewritten.entries(0, 5).unwrap().into_iter()
+            .map(|binding| if binding.name == "user3" { Binding{ identity: None, ..binding } } else { binding }));
+        let prefix = TransparencyLog::from_bindings(rewritten.entries(0, 5).unwrap());
+        assert!(verify_consistency(&prefix.head(), &rewritten.head(), &proof));
+        assert!(!verify_consistency(&forked.head(), &rewritten.head(), &proof));
+        assert_eq!(log.consistency(3, 21), Err(ErrorCode::InvalidLogRange));
+    }
+
+    #[test]
+    fn test_unchanged_bindings_not_logged() {
+        let key = IdentityKey::generate().public();
+        let mut log = TransparencyLog::new();
+        log.record("alice", Some(key)).unwrap();
+        log.record("alice", Some(key)).unwrap();
+        // Deleting a name nobody held is no change either
+        log.record("bob", None).unwrap();
+        assert_eq!(log.size(), 1);
+        log.record("alice", None).unwrap();
+        assert_eq!(log.binding("alice").unwrap().identity, None);
+        assert_eq!(log.size(), 2);
+    }
+
+    #[test]
+    fn test_log_recovered_from_storage() {
+        let path = std::env::temp_dir().join(format!("rustchat-keylog-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let open = || TransparencyLog::new().with_storage(Box::new(LogStorage::open(&path).unwrap())).unwrap();
+        let mut log = open();
+        log.record("alice", Some(IdentityKey::generate().public())).unwrap();
+        log.record("bob", Some(IdentityKey::generate().public())).unwrap();
+        let head = log.head();
+        drop(log);
+        assert_eq!(open().head(), head);
+        std::fs::remove_file(&path).unwrap();
+    }
+}